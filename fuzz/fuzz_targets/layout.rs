@@ -0,0 +1,54 @@
+//! Feeds arbitrary controller responses through the layout parsing and mapping code,
+//! which should reject anything it can't use rather than panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// leafpipe is a binary crate, so the modules under test are pulled in directly. Much
+// of them goes unused here.
+#[path = "../../src/layout.rs"]
+#[allow(dead_code)]
+mod layout;
+#[path = "../../src/mask.rs"]
+#[allow(dead_code)]
+mod mask;
+#[path = "../../src/nanoleaf.rs"]
+#[allow(dead_code)]
+mod nanoleaf;
+#[path = "../../src/panel_graph.rs"]
+#[allow(dead_code)]
+mod panel_graph;
+#[path = "../../src/report.rs"]
+#[allow(dead_code)]
+mod report;
+#[path = "../../src/shapes.rs"]
+#[allow(dead_code)]
+mod shapes;
+
+use layout::Layout;
+use mask::PanelMask;
+use nanoleaf::{NanoleafEffectPayload, NanoleafLayoutResponse};
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte says how many of the panel IDs that follow to mask out, and the
+    // rest is the layout JSON.
+    let Some((&exclude_count, rest)) = data.split_first() else {
+        return;
+    };
+    let exclude_len = (exclude_count as usize * 2).min(rest.len() & !1);
+    let (exclude, json) = rest.split_at(exclude_len);
+    let mask = PanelMask {
+        exclude: exclude.chunks_exact(2).map(|id| u16::from_le_bytes([id[0], id[1]])).collect(),
+        color: None,
+    };
+
+    let Ok(response) = serde_json::from_slice::<NanoleafLayoutResponse>(json) else {
+        return;
+    };
+    let Ok(layout) = Layout::new(&response, mask) else {
+        return;
+    };
+    let mut payload = NanoleafEffectPayload::new(layout.num_panels);
+    let reports = layout.write_frame(std::iter::repeat(Some([255, 0, 0])), &mut payload);
+    assert_eq!(reports.len(), layout.num_panels);
+});
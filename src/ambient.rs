@@ -0,0 +1,173 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use colors_transform::Hsl;
+use serde::Deserialize;
+
+use crate::clock;
+use crate::crash;
+use crate::effects::{decay, PostProcess};
+use crate::oklab::ColorSpace;
+
+/**
+ * Where the kernel exposes industrial I/O devices, including ambient light sensors.
+ */
+const IIO_DEVICES: &str = "/sys/bus/iio/devices";
+
+/**
+ * How often to read the sensor. Room brightness changes slowly, so there's no need
+ * to read it every frame.
+ */
+const SENSOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * How much of the way towards the sensor's brightness to move per frame (scaled to
+ * how long frames actually take), so a shadow passing over the sensor doesn't make
+ * the lights jump.
+ */
+const BRIGHTNESS_EASING: f32 = 0.02;
+
+fn default_dark_lux() -> f32 {
+    5.0
+}
+
+fn default_bright_lux() -> f32 {
+    500.0
+}
+
+fn default_min_brightness() -> f32 {
+    0.2
+}
+
+fn default_max_brightness() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AmbientLightConfig {
+    /// The iio device to read, e.g. "/sys/bus/iio/devices/iio:device0". The first
+    /// device with an illuminance channel is used if not set.
+    pub sensor: Option<PathBuf>,
+    /// Room brightness at or below which the panels are at `min_brightness`.
+    #[serde(default = "default_dark_lux")]
+    pub dark_lux: f32,
+    /// Room brightness at or above which the panels are at `max_brightness`.
+    #[serde(default = "default_bright_lux")]
+    pub bright_lux: f32,
+    #[serde(default = "default_min_brightness")]
+    pub min_brightness: f32,
+    #[serde(default = "default_max_brightness")]
+    pub max_brightness: f32,
+}
+
+impl AmbientLightConfig {
+    /// The overall panel brightness for a room brightness. Eyes respond to light
+    /// logarithmically, so brightness is interpolated on a log scale.
+    fn brightness_for_lux(&self, lux: f32) -> f32 {
+        let dark = self.dark_lux.max(0.1).ln();
+        let bright = self.bright_lux.max(self.dark_lux.max(0.1) * 1.01).ln();
+        let position = ((lux.max(0.1).ln() - dark) / (bright - dark)).clamp(0.0, 1.0);
+        self.min_brightness + (self.max_brightness - self.min_brightness) * position
+    }
+}
+
+/// An iio device with an illuminance channel.
+struct LightSensor {
+    device: PathBuf,
+}
+
+impl LightSensor {
+    fn find(config: &AmbientLightConfig) -> Result<Self, Box<dyn Error>> {
+        if let Some(device) = &config.sensor {
+            return Ok(LightSensor { device: device.clone() });
+        }
+        for entry in fs::read_dir(IIO_DEVICES)? {
+            let device = entry?.path();
+            if device.join("in_illuminance_input").exists() || device.join("in_illuminance_raw").exists() {
+                return Ok(LightSensor { device });
+            }
+        }
+        Err(format!("No ambient light sensor found in {}", IIO_DEVICES).into())
+    }
+
+    fn read_value(path: &Path) -> Result<f32, Box<dyn Error>> {
+        Ok(fs::read_to_string(path)?.trim().parse()?)
+    }
+
+    /// Read the room brightness in lux. Some drivers provide it directly, others
+    /// need the raw reading scaled.
+    fn read_lux(&self) -> Result<f32, Box<dyn Error>> {
+        if let Ok(lux) = Self::read_value(&self.device.join("in_illuminance_input")) {
+            return Ok(lux);
+        }
+        let raw = Self::read_value(&self.device.join("in_illuminance_raw"))?;
+        let offset = Self::read_value(&self.device.join("in_illuminance_offset")).unwrap_or(0.0);
+        let scale = Self::read_value(&self.device.join("in_illuminance_scale")).unwrap_or(1.0);
+        Ok((raw + offset) * scale)
+    }
+}
+
+/// Scales the brightness of every panel with the brightness of the room.
+pub struct AmbientBrightness {
+    target: Arc<Mutex<f32>>,
+    brightness: f32,
+    color_space: ColorSpace,
+    last_frame: Option<Instant>,
+}
+
+impl AmbientBrightness {
+    /// Find the sensor and start reading it on a new thread.
+    pub fn start(config: AmbientLightConfig, color_space: ColorSpace) -> Result<Self, Box<dyn Error>> {
+        let sensor = LightSensor::find(&config)?;
+        let lux = sensor.read_lux()?;
+        let brightness = config.brightness_for_lux(lux);
+        log::info!("Reading ambient light from {} ({} lux)", sensor.device.display(), lux);
+
+        let target = Arc::new(Mutex::new(brightness));
+        let sensor_target = target.clone();
+        crash::spawn("ambient light", move || loop {
+            thread::sleep(SENSOR_INTERVAL);
+            match sensor.read_lux() {
+                Ok(lux) => *sensor_target.lock().unwrap() = config.brightness_for_lux(lux),
+                Err(err) => log::debug!("Failed to read ambient light sensor: {}", err),
+            }
+        });
+        Ok(AmbientBrightness { target, brightness, color_space, last_frame: None })
+    }
+}
+
+impl PostProcess for AmbientBrightness {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        let target = *self.target.lock().unwrap();
+        let now = clock::now();
+        let delta = self.last_frame.replace(now).map_or(Duration::ZERO, |last| now.saturating_duration_since(last));
+        self.brightness += (target - self.brightness) * (1.0 - decay(1.0 - BRIGHTNESS_EASING, delta));
+        for hsl in colors.iter_mut().flatten() {
+            *hsl = self.color_space.scale(hsl, self.brightness);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_brightness_for_lux() {
+        let config = AmbientLightConfig {
+            sensor: None,
+            dark_lux: default_dark_lux(),
+            bright_lux: default_bright_lux(),
+            min_brightness: default_min_brightness(),
+            max_brightness: default_max_brightness(),
+        };
+        assert_eq!(config.brightness_for_lux(0.0), 0.2);
+        assert_eq!(config.brightness_for_lux(10000.0), 1.0);
+        // 50 lux is halfway between 5 and 500 on a log scale.
+        assert!((config.brightness_for_lux(50.0) - 0.6).abs() < 0.001);
+    }
+}
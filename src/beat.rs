@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+
+/**
+ * Number of intervals of bass energy to compare the current interval against.
+ */
+const BEAT_HISTORY: usize = 20;
+
+/**
+ * How far above the recent average the bass energy must jump to count as a beat.
+ */
+const BEAT_SENSITIVITY: f32 = 1.35;
+
+/**
+ * Bass energy below this is treated as silence, so noise never triggers beats.
+ */
+const BEAT_ENERGY_FLOOR: f32 = 0.05;
+
+/// Detects beats as sudden rises in the energy of the lowest bands.
+pub struct BeatDetector {
+    history: VecDeque<f32>,
+    /// Whether the previous interval was a beat, so a single hit isn't counted twice.
+    in_beat: bool,
+}
+
+impl BeatDetector {
+    pub fn new() -> Self {
+        BeatDetector {
+            history: VecDeque::with_capacity(BEAT_HISTORY),
+            in_beat: false,
+        }
+    }
+
+    /// Feed the band energies of the next interval, returning whether it starts a beat.
+    pub fn update(&mut self, bands: &[f32]) -> bool {
+        if bands.is_empty() {
+            return false;
+        }
+        let bass_bands = (bands.len() / 4).max(1);
+        let energy = bands[..bass_bands].iter().sum::<f32>() / bass_bands as f32;
+        let average = if self.history.is_empty() {
+            energy
+        } else {
+            self.history.iter().sum::<f32>() / self.history.len() as f32
+        };
+
+        if self.history.len() == BEAT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(energy);
+
+        let above = energy > BEAT_ENERGY_FLOOR && energy > average * BEAT_SENSITIVITY;
+        let beat = above && !self.in_beat;
+        self.in_beat = above;
+        beat
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_beat_detector() {
+        let mut detector = BeatDetector::new();
+        let beats: Vec<bool> = [1.0, 1.0, 1.0, 1.0, 3.0, 3.0, 1.0, 1.0, 3.0]
+            .iter()
+            .map(|energy| detector.update(&[*energy, 0.0, 0.0, 0.0]))
+            .collect();
+        assert_eq!(beats, vec![false, false, false, false, true, false, false, false, true]);
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use colors_transform::{Color, Hsl};
+
+use crate::effects::PostProcess;
+use crate::oklab::ColorSpace;
+
+/// Makes up for the controller's own brightness setting, in percent, as read from it
+/// every few seconds. The controller scales every frame by the setting, so frames are
+/// brightened by as much for the panels to show the brightness they were made with.
+/// Colours brighter than the setting lets the panels go are held at the brightest
+/// they can show, keeping their hue.
+pub struct BrightnessLimit {
+    limit: Arc<AtomicU8>,
+    color_space: ColorSpace,
+}
+
+impl BrightnessLimit {
+    pub fn new(limit: Arc<AtomicU8>, color_space: ColorSpace) -> Self {
+        BrightnessLimit { limit, color_space }
+    }
+}
+
+impl PostProcess for BrightnessLimit {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        let limit = self.limit.load(Ordering::Relaxed).min(100);
+        // Nothing shows with the controller turned all the way down.
+        if limit == 100 || limit == 0 {
+            return;
+        }
+        let gain = 100.0 / limit as f32;
+        for hsl in colors.iter_mut().flatten() {
+            let (r, g, b) = hsl.to_rgb().as_tuple();
+            let brightest = r.max(g).max(b);
+            if brightest > 0.0 {
+                *hsl = self.color_space.scale(hsl, gain.min(255.0 / brightest));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_brightness_limit() {
+        let limit = Arc::new(AtomicU8::new(100));
+        let mut post_process = BrightnessLimit::new(limit.clone(), ColorSpace::Hsl);
+        let white = Hsl::from(0.0, 0.0, 100.0);
+        let grey = Hsl::from(0.0, 0.0, 20.0);
+        let mut colors = vec![Some(white), Some(grey), None];
+        post_process.apply(&mut colors);
+        assert_eq!(colors, [Some(white), Some(grey), None]);
+
+        // At 40%, what the panels show is each colour brightened by 2.5 then scaled by
+        // 0.4: unchanged, as long as the panels can go that bright.
+        limit.store(40, Ordering::Relaxed);
+        post_process.apply(&mut colors);
+        let shown = |hsl: Option<Hsl>| hsl.unwrap().to_rgb().as_tuple().0 * 0.4;
+        assert!((shown(colors[1]) - 51.0).abs() < 0.5);
+        assert!((shown(colors[0]) - 102.0).abs() < 0.5);
+        assert_eq!(colors[2], None);
+    }
+}
@@ -0,0 +1,147 @@
+use rustfft::num_complex::Complex;
+
+use crate::simd;
+
+/**
+ * Lowest frequency considered when building a chromagram (roughly C2).
+ */
+const CHROMA_FLOOR_FREQ: f32 = 65.0;
+
+/**
+ * Highest frequency considered when building a chromagram (roughly C7).
+ */
+const CHROMA_CEILING_FREQ: f32 = 2100.0;
+
+/**
+ * Krumhansl-Kessler key profiles, starting from the tonic.
+ */
+const MAJOR_PROFILE: [f32; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f32; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+pub type Chroma = [f32; 12];
+
+/// A detected musical key. `root` is a pitch class where 0 is C.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key {
+    pub root: usize,
+    pub minor: bool,
+    /// Correlation of the chromagram against the key profile, in the range -1..1.
+    pub confidence: f32,
+}
+
+/// Fold the first half of the spectrum from an FFT of `size` samples into 12 pitch
+/// classes, normalised so the strongest class is 1.0.
+pub fn chroma_from_spectrum(spectrum: &[Complex<f32>], size: usize, rate: f32) -> Chroma {
+    let mut chroma = [0.0f32; 12];
+    let magnitudes = simd::magnitudes(&spectrum[..spectrum.len().min(size / 2)]);
+    for (index, magnitude) in magnitudes.into_iter().enumerate().skip(1) {
+        let freq = index as f32 * rate / size as f32;
+        if !(CHROMA_FLOOR_FREQ..=CHROMA_CEILING_FREQ).contains(&freq) {
+            continue;
+        }
+        // MIDI note 69 is A4 at 440Hz, and MIDI note 0 is a C.
+        let note = (69.0 + 12.0 * f32::log2(freq / 440.0)).round() as usize;
+        chroma[note % 12] += magnitude;
+    }
+    let max = chroma.iter().cloned().fold(0.0f32, f32::max);
+    if max > 0.0 {
+        chroma.iter_mut().for_each(|v| *v /= max);
+    }
+    chroma
+}
+
+fn correlation(chroma: &Chroma, profile: &[f32; 12], root: usize) -> f32 {
+    let chroma_mean = chroma.iter().sum::<f32>() / 12.0;
+    let profile_mean = profile.iter().sum::<f32>() / 12.0;
+    let mut numerator = 0.0;
+    let mut chroma_var = 0.0;
+    let mut profile_var = 0.0;
+    for (pitch, value) in chroma.iter().enumerate() {
+        let c = value - chroma_mean;
+        let p = profile[(pitch + 12 - root) % 12] - profile_mean;
+        numerator += c * p;
+        chroma_var += c * c;
+        profile_var += p * p;
+    }
+    let denominator = f32::sqrt(chroma_var * profile_var);
+    if denominator == 0.0 {
+        return 0.0;
+    }
+    numerator / denominator
+}
+
+/// Estimate the key of a chromagram using the Krumhansl-Schmuckler algorithm.
+pub fn detect_key(chroma: &Chroma) -> Key {
+    let mut best = Key { root: 0, minor: false, confidence: f32::MIN };
+    for root in 0..12 {
+        for (minor, profile) in [(false, &MAJOR_PROFILE), (true, &MINOR_PROFILE)] {
+            let confidence = correlation(chroma, profile, root);
+            if confidence > best.confidence {
+                best = Key { root, minor, confidence };
+            }
+        }
+    }
+    best
+}
+
+/// Map a pitch class onto a hue by walking the circle of fifths, so that harmonically
+/// related notes end up with neighbouring colours.
+pub fn pitch_hue(pitch_class: usize) -> f32 {
+    ((pitch_class * 7) % 12) as f32 * 30.0
+}
+
+/// Hue for a key. Minor keys share the hue of their relative major.
+pub fn key_hue(key: &Key) -> f32 {
+    if key.minor {
+        pitch_hue((key.root + 3) % 12)
+    } else {
+        pitch_hue(key.root)
+    }
+}
+
+/// Return the pitch classes of the chromagram ordered by strength, strongest first.
+pub fn strongest_pitches(chroma: &Chroma) -> [usize; 12] {
+    let mut pitches: [usize; 12] = core::array::from_fn(|i| i);
+    pitches.sort_by(|a, b| chroma[*b].total_cmp(&chroma[*a]));
+    pitches
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn triad(root: usize, minor: bool) -> Chroma {
+        let mut chroma = [0.05f32; 12];
+        chroma[root] = 1.0;
+        chroma[(root + if minor { 3 } else { 4 }) % 12] = 0.8;
+        chroma[(root + 7) % 12] = 0.9;
+        chroma
+    }
+
+    #[test]
+    fn test_detect_key_major() {
+        let key = detect_key(&triad(7, false));
+        assert_eq!(key.root, 7, "Expected G");
+        assert!(!key.minor, "Expected a major key");
+    }
+
+    #[test]
+    fn test_detect_key_minor() {
+        let key = detect_key(&triad(9, true));
+        assert_eq!(key.root, 9, "Expected A");
+        assert!(key.minor, "Expected a minor key");
+        assert_eq!(key_hue(&key), pitch_hue(0), "A minor should share a hue with C major");
+    }
+
+    #[test]
+    fn test_chroma_from_spectrum() {
+        let size = 4096;
+        let rate = 48000.0;
+        let mut spectrum = vec![Complex { re: 0.0f32, im: 0.0f32 }; size / 2 + 1];
+        // 440Hz lands on bin ~37.5, so light up both neighbours.
+        spectrum[37] = Complex { re: 10.0, im: 0.0 };
+        spectrum[38] = Complex { re: 10.0, im: 0.0 };
+        let chroma = chroma_from_spectrum(&spectrum, size, rate);
+        assert_eq!(strongest_pitches(&chroma)[0], 9, "Expected A to be the strongest pitch");
+    }
+}
@@ -1,13 +1,276 @@
-use clap::Parser;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-/// Simple program to greet a person
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+
+use crate::effects::EffectKind;
+#[cfg(feature = "pipewire")]
+use crate::pipewire::AudioSource;
+
+/// Drive Nanoleaf panels from what's on screen and what's playing
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct CliArgs {
-    /// Number of times to greet
-    #[arg(short, long, default_value_t = 15f32)]
-    pub intensity: f32,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Log more, with `-v` for info, `-vv` for debug and `-vvv` for everything,
+    /// overriding `log_level` in the config
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Write the log to this file instead of the terminal
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Options for `run`, which is the default when no command is given
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+impl CliArgs {
+    /// The command to carry out, running the lights if none was given.
+    pub fn command(self) -> Command {
+        self.command.unwrap_or(Command::Run(self.run))
+    }
+
+    /// Whether the lights are being run with the TUI open.
+    #[cfg(feature = "tui")]
+    pub fn tui(&self) -> bool {
+        match &self.command {
+            None => self.run.tui,
+            Some(Command::Run(run)) => run.tui,
+            Some(_) => false,
+        }
+    }
+
+    /// How much leafpipe should log, if given on the command line.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        match self.verbose {
+            0 => None,
+            1 => Some(LevelFilter::Info),
+            2 => Some(LevelFilter::Debug),
+            _ => Some(LevelFilter::Trace),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Drive the lights (the default)
+    Run(RunArgs),
+    /// Find and pair with a controller, pick the output to follow and how strongly the
+    /// lights react, and write it all to the config
+    Setup,
+    /// Ask the controller for an access token and save it to the token file. Hold its
+    /// power button until the lights flash first
+    Pair,
+    /// List the controllers found on the network
+    Discover,
+    /// List the outputs that can be captured
+    #[cfg(feature = "wayland")]
+    Outputs,
+    /// Send a command to the running instance, such as
+    /// `{"command": "scene", "name": "sunset"}`, or print what it's showing if no
+    /// command is given
+    Ctl {
+        command: Option<String>,
+    },
+    /// Time how long rendering frames takes, without any lights attached
+    Bench(BenchArgs),
+    /// Render frames from recorded audio and screenshots on a mock clock, writing what
+    /// would be sent to the panels to a file. The same inputs and config always give
+    /// the same file, to bisect changes to how the lights look
+    Replay(ReplayArgs),
+    /// Show how the panels are laid out and which of them touch
+    Layout,
+    /// Flash panels one after another, to see which panel has which ID
+    Identify {
+        /// Panel IDs as shown by `leafpipe layout`, or `all` to go through every panel
+        /// from left to right
+        #[arg(required = true)]
+        panels: Vec<PanelSelection>,
+    },
+    /// Show known colours on the panels one after another, to check their colour
+    /// calibration
+    Testpattern(TestPatternArgs),
+    /// Show where the config is read from and what it contains
+    Config,
+    /// Share tuned settings with others, or use settings they shared
+    #[command(subcommand)]
+    Preset(PresetCommand),
+    /// Print the OpenAPI spec for the REST API
+    #[cfg(feature = "remote")]
+    Openapi,
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// How strongly the audio drives brightness, overriding `intensity` under
+    /// `[tuning]` in the config
+    #[arg(short, long)]
+    pub intensity: Option<f32>,
+
+    /// The output to capture, overriding `display` in the config
+    #[arg(short, long)]
+    pub display: Option<String>,
+
+    /// Only capture the window with this class or title (Hyprland only)
+    #[cfg(feature = "wayland")]
+    #[arg(short, long)]
+    pub window: Option<String>,
+
+    /// Which effect to drive the lights with
+    #[arg(short, long, value_enum, default_value_t = EffectKind::Screen)]
+    pub effect: EffectKind,
+
+    /// Take the panels over even while the Nanoleaf app is mirroring a screen to them
+    #[arg(long)]
+    pub force: bool,
+
+    /// Where to capture audio from
+    #[cfg(feature = "pipewire")]
+    #[arg(short, long, value_enum, default_value_t = AudioSource::Default)]
+    pub source: AudioSource,
+
+    /// Show the live spectrum in the terminal, with keys to adjust the tuning
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Show an icon in the system tray to pause the lights, pick a scene and set the
+    /// intensity
+    #[cfg(feature = "tray")]
+    #[arg(long)]
+    pub tray: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PresetCommand {
+    /// Write the effect, colour calibration and mapping settings from the config to a
+    /// preset file, as JSON if it ends in `.json` and TOML otherwise
+    Export {
+        path: PathBuf,
+        /// A name for the preset
+        #[arg(long)]
+        name: Option<String>,
+        /// What the preset is for, such as the layout it was tuned on
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// Replace the settings in the config with those from a preset file
+    Import {
+        path: PathBuf,
+    },
+}
 
+/// Panels to flash with `identify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanelSelection {
+    All,
+    Panel(u16),
+}
+
+impl FromStr for PanelSelection {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.eq_ignore_ascii_case("all") {
+            return Ok(PanelSelection::All);
+        }
+        text.parse().map(PanelSelection::Panel).map_err(|_| format!("expected a panel ID or `all`, got {:?}", text))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct TestPatternArgs {
+    /// How many seconds to show each colour for
+    #[arg(long, default_value_t = 3.0)]
+    pub hold: f32,
+
+    /// Also fill the screen with each colour, to compare the panels against
+    #[cfg(feature = "wayland")]
+    #[arg(short, long)]
+    pub fullscreen: bool,
+
+    /// The output to fill with `--fullscreen`, overriding `display` in the config
+    #[cfg(feature = "wayland")]
     #[arg(short, long)]
     pub display: Option<String>,
-}
\ No newline at end of file
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Which effect to render
+    #[arg(short, long, value_enum, default_value_t = EffectKind::Spectrum)]
+    pub effect: EffectKind,
+
+    /// How many panels to render for
+    #[arg(short, long, default_value_t = 24)]
+    pub panels: usize,
+
+    /// How many frames to render
+    #[arg(short, long, default_value_t = 2000)]
+    pub frames: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Audio to play, as mono 32 bit little endian float samples
+    pub audio: PathBuf,
+
+    /// Sample rate of the audio
+    #[arg(short, long, default_value_t = 48000)]
+    pub rate: u32,
+
+    /// The panel layout, as the controller's JSON from `/api/v1/<token>/panelLayout/layout`
+    #[arg(short, long)]
+    pub layout: PathBuf,
+
+    /// Screenshots to show in turn, each for `--hold` frames
+    #[cfg(feature = "screen")]
+    #[arg(short, long)]
+    pub screenshot: Vec<PathBuf>,
+
+    /// How many frames to show each screenshot for
+    #[cfg(feature = "screen")]
+    #[arg(long, default_value_t = 5)]
+    pub hold: usize,
+
+    /// Which effect to render
+    #[arg(short, long, value_enum, default_value_t = EffectKind::Screen)]
+    pub effect: EffectKind,
+
+    /// Where to write the frames, one payload after another
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Also draw the frames into a GIF of the panels, to review or share
+    #[cfg(feature = "record")]
+    #[arg(long)]
+    pub gif: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_command() {
+        let Command::Run(args) = CliArgs::parse_from(["leafpipe", "--effect", "spectrum"]).command() else {
+            panic!("Expected flags alone to run the lights");
+        };
+        assert_eq!(args.effect, EffectKind::Spectrum);
+        assert!(matches!(CliArgs::parse_from(["leafpipe", "run", "-e", "chroma"]).command(), Command::Run(RunArgs { effect: EffectKind::Chroma, .. })));
+        assert!(matches!(CliArgs::parse_from(["leafpipe", "layout"]).command(), Command::Layout));
+        let Command::Identify { panels } = CliArgs::parse_from(["leafpipe", "identify", "12", "all"]).command() else {
+            panic!("Expected identify");
+        };
+        assert_eq!(panels, [PanelSelection::Panel(12), PanelSelection::All]);
+        assert!(CliArgs::try_parse_from(["leafpipe", "identify", "twelve"]).is_err());
+        assert!(CliArgs::try_parse_from(["leafpipe", "--effect", "spectrum", "layout"]).is_err());
+        assert_eq!(CliArgs::parse_from(["leafpipe", "layout", "-vv"]).log_level(), Some(LevelFilter::Debug));
+        assert_eq!(CliArgs::parse_from(["leafpipe"]).log_level(), None);
+    }
+}
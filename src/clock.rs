@@ -0,0 +1,89 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    /// The time on this thread's mock clock, while it has one.
+    static MOCK_NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The current time. Everything that decides what the lights show reads the time from
+/// here rather than the system, so a [`MockClock`] can make replays come out the same
+/// however fast they run.
+pub fn now() -> Instant {
+    MOCK_NOW.with(Cell::get).unwrap_or_else(Instant::now)
+}
+
+/// How long it's been since `since`, by [`now`].
+pub fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+/// Times the frames an effect renders. Its time only ever moves forward, by however
+/// long it's been since the previous frame, except across a [`FrameClock::pause`].
+#[derive(Debug, Default)]
+pub struct FrameClock {
+    last: Option<Instant>,
+    time: Duration,
+}
+
+impl FrameClock {
+    /// Start a new frame, returning the time since the first frame and since the
+    /// previous one.
+    pub fn tick(&mut self) -> (Duration, Duration) {
+        let now = now();
+        let delta = self.last.map_or(Duration::ZERO, |last| now.saturating_duration_since(last));
+        self.last = Some(self.last.map_or(now, |last| last.max(now)));
+        self.time += delta;
+        (self.time, delta)
+    }
+
+    /// Hold the time while the effect isn't being shown, so when it's next rendered it
+    /// picks up where it left off rather than leaping ahead.
+    pub fn pause(&mut self) {
+        self.last = None;
+    }
+}
+
+/// A clock for the current thread that only moves when it's advanced, until it's
+/// dropped.
+pub struct MockClock;
+
+impl MockClock {
+    pub fn start() -> Self {
+        MOCK_NOW.with(|now| now.set(Some(Instant::now())));
+        MockClock
+    }
+
+    pub fn advance(&self, by: Duration) {
+        MOCK_NOW.with(|now| now.set(now.get().map(|now| now + by)));
+    }
+}
+
+impl Drop for MockClock {
+    fn drop(&mut self) {
+        MOCK_NOW.with(|now| now.set(None));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_clock() {
+        let clock = MockClock::start();
+        let mut frames = FrameClock::default();
+        assert_eq!(frames.tick(), (Duration::ZERO, Duration::ZERO));
+        // Frames sent once a second move effects on by a whole second each.
+        for second in 1..=3 {
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(frames.tick(), (Duration::from_secs(second), Duration::from_secs(1)));
+        }
+        // Time spent paused isn't counted.
+        frames.pause();
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(frames.tick(), (Duration::from_secs(3), Duration::ZERO));
+        clock.advance(Duration::from_millis(40));
+        assert_eq!(frames.tick(), (Duration::from_millis(3040), Duration::from_millis(40)));
+    }
+}
@@ -0,0 +1,329 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use colors_transform::Hsl;
+use config::Config;
+
+use crate::cli::{BenchArgs, PanelSelection, PresetCommand, TestPatternArgs};
+use crate::control::{self, ControlCommand};
+use crate::dither::Dither;
+use crate::effects::{new_effect, ScreenColors};
+use crate::layout::Layout;
+use crate::nanoleaf::{self, NanoleafClient, NanoleafEffectPayload, NanoleafLayoutPanelData, NanoleafLayoutResponse};
+use crate::oklab::ColorSpace;
+use crate::preset::{self, Preset};
+use crate::token;
+use crate::tuning::Tuning;
+use crate::vis::BufferManager;
+use crate::{discover_host, Pipeline, LIGHT_INTERVAL};
+
+/**
+ * How long to listen for controllers announcing themselves.
+ */
+#[cfg(feature = "mdns")]
+const DISCOVER_WAIT: Duration = Duration::from_secs(5);
+
+/**
+ * How many times each panel flashes when identifying it.
+ */
+const IDENTIFY_FLASHES: u32 = 3;
+
+/**
+ * How long each flash stays on, and off again, when identifying panels. Held for
+ * longer over HTTP, which can't show frames this quickly.
+ */
+const IDENTIFY_FLASH: Duration = Duration::from_millis(400);
+
+/**
+ * The colours `testpattern` goes through, by name and sRGB value. The skin tones are
+ * the ones on the ColorChecker chart.
+ */
+const TEST_PATTERN: &[(&str, [u8; 3])] = &[
+    ("red", [255, 0, 0]),
+    ("green", [0, 255, 0]),
+    ("blue", [0, 0, 255]),
+    ("white", [255, 255, 255]),
+    ("75% grey", [191, 191, 191]),
+    ("50% grey", [128, 128, 128]),
+    ("25% grey", [64, 64, 64]),
+    ("light skin", [194, 150, 130]),
+    ("dark skin", [115, 82, 68]),
+];
+
+/**
+ * Sample rate of the audio made up for benchmarking.
+ */
+const BENCH_AUDIO_RATE: u32 = 44100;
+
+pub async fn pair(config: &Config) -> Result<(), Box<dyn Error>> {
+    let (host, port) = discover_host(config);
+    let token = nanoleaf::pair(&host, port).await?;
+    let path = token::path(config)?;
+    token::save(&path, &token)?;
+    println!("Paired with the nanoleaf on {}:{}, saved the token to {}", host, port, path.display());
+    Ok(())
+}
+
+/// A controller found announcing itself on the network.
+#[cfg(feature = "mdns")]
+pub struct Controller {
+    pub name: String,
+    pub addresses: Vec<String>,
+    pub port: u16,
+}
+
+/// Listen for controllers announcing themselves on the network.
+#[cfg(feature = "mdns")]
+pub fn find_controllers() -> Result<Vec<Controller>, Box<dyn Error>> {
+    use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+    let mdns = ServiceDaemon::new()?;
+    let receiver = mdns.browse(crate::SERVICE_TYPE)?;
+    let deadline = Instant::now() + DISCOVER_WAIT;
+    let mut found = Vec::new();
+    while let Ok(event) = receiver.recv_deadline(deadline) {
+        if let ServiceEvent::ServiceResolved(info) = event {
+            found.push(Controller {
+                name: info.get_fullname().to_string(),
+                addresses: info.get_addresses().iter().map(|addr| addr.to_string()).collect(),
+                port: info.get_port(),
+            });
+        }
+    }
+    mdns.shutdown()?;
+    Ok(found)
+}
+
+#[cfg(feature = "mdns")]
+pub fn discover() -> Result<(), Box<dyn Error>> {
+    let found = find_controllers()?;
+    for controller in &found {
+        println!("{} on {} port {}", controller.name, controller.addresses.join(", "), controller.port);
+    }
+    if found.is_empty() {
+        return Err("No nanoleaf controllers found".into());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "mdns"))]
+pub fn discover() -> Result<(), Box<dyn Error>> {
+    Err("Built without mDNS support, so can't look for controllers".into())
+}
+
+#[cfg(feature = "wayland")]
+pub fn outputs() -> Result<(), Box<dyn Error>> {
+    use crate::visual::output::OutputPositioning;
+
+    for output in crate::visual::list_outputs()? {
+        let dimensions = &output.dimensions;
+        let scale = output.buffer_scale();
+        let buffer = OutputPositioning { x: 0, y: 0, ..dimensions.clone() }.to_buffer(scale);
+        println!("{}: {}x{} at {},{}, scale {} ({}x{} buffer pixels)", output.name, dimensions.width, dimensions.height, dimensions.x, dimensions.y, scale, buffer.width, buffer.height);
+    }
+    Ok(())
+}
+
+pub fn ctl(command: Option<String>) -> Result<(), Box<dyn Error>> {
+    let mut stream = control::connect()?;
+    let Some(command) = command else {
+        // Nothing to send, so follow the frame reports instead.
+        for line in BufReader::new(stream).lines() {
+            println!("{}", line?);
+        }
+        return Ok(());
+    };
+    // Check the command here, as the running instance can only log a bad one.
+    serde_json::from_str::<ControlCommand>(&command)?;
+    stream.write_all(command.trim().as_bytes())?;
+    stream.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Render frames from made up audio and screen colours as fast as possible, timing
+/// the audio analysis and the effect.
+pub fn bench(args: BenchArgs) -> Result<(), Box<dyn Error>> {
+    let response = NanoleafLayoutResponse {
+        num_panels: args.panels,
+        side_length: 100,
+        position_data: (0..args.panels).map(|index| NanoleafLayoutPanelData {
+            panel_id: index as u16 + 1,
+            x: index * 100,
+            y: 0,
+            shape_type: 2,
+            orientation: 0,
+        }).collect(),
+    };
+    let layout = Layout::new(&response, Default::default())?;
+    let mut pipeline = Pipeline::bare(new_effect(args.effect, Tuning::default().intensity, Default::default(), ColorSpace::Hsl, &[]));
+    let screen_colors = ScreenColors {
+        primary: (0..args.panels).map(|index| Hsl::from(index as f32 * 360.0 / args.panels as f32, 80.0, 50.0)).collect(),
+        accent: vec![None; args.panels],
+    };
+    let mut buffer_manager = BufferManager::default();
+    let mut dither = Dither::new(1.0);
+
+    // A tone sweeping up through the spectrum, with a thump every half second.
+    let chunk = (BENCH_AUDIO_RATE as f32 * LIGHT_INTERVAL.as_secs_f32()) as usize;
+    let mut analysis_time = Duration::ZERO;
+    let mut render_time = Duration::ZERO;
+    for frame in 0..args.frames {
+        let samples: Vec<f32> = (0..chunk).map(|index| {
+            let t = (frame * chunk + index) as f32 / BENCH_AUDIO_RATE as f32;
+            let tone = (t * 2.0 * std::f32::consts::PI * (100.0 + (t * 500.0) % 8000.0)).sin();
+            let thump = if t % 0.5 < 0.05 { (t * 2.0 * std::f32::consts::PI * 60.0).sin() } else { 0.0 };
+            0.3 * tone + 0.7 * thump
+        }).collect();
+        buffer_manager.fill_buffer(&samples, BENCH_AUDIO_RATE);
+
+        let start = Instant::now();
+        let analysis = buffer_manager.fft_interval(LIGHT_INTERVAL, layout.active.len())
+            .map(|audio_data| (audio_data, buffer_manager.chroma()));
+        analysis_time += start.elapsed();
+
+        let start = Instant::now();
+        let frame = pipeline.render(&layout, analysis.as_ref(), &screen_colors, false);
+        layout.encode(&mut dither, &frame.colors);
+        render_time += start.elapsed();
+    }
+
+    let per_frame = |total: Duration| total.as_secs_f64() * 1000.0 / args.frames.max(1) as f64;
+    println!("{} frames of {:?} across {} panels", args.frames, args.effect, args.panels);
+    println!("Audio analysis: {:.3}ms a frame", per_frame(analysis_time));
+    println!("Rendering: {:.3}ms a frame", per_frame(render_time));
+    println!("Budget: {}ms a frame", LIGHT_INTERVAL.as_millis());
+    Ok(())
+}
+
+pub async fn layout(config: &Config) -> Result<(), Box<dyn Error>> {
+    let (host, port) = discover_host(config);
+    let token = token::load(config)?;
+    let response = nanoleaf::get_layout(&token, &host, port).await?;
+    let layout = Layout::new(&response, config.get("panel_mask").unwrap_or_default())?;
+
+    println!("{} panels, side length {}", layout.num_panels, response.side_length);
+    println!("{:>6} {:>6} {:>6} {:>6} {:>9}  touching", "panel", "x", "y", "shape", "rotation");
+    for panel in &layout.panels {
+        let touching = match layout.active.iter().position(|active| active.panel_id == panel.panel_id) {
+            Some(index) => layout.graph.neighbours(index).iter()
+                .map(|neighbour| layout.active[*neighbour].panel_id.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+            None => "(masked)".to_string(),
+        };
+        println!("{:>6} {:>6} {:>6} {:>6} {:>9}  {}", panel.panel_id, panel.x, panel.y, panel.shape_type, panel.orientation, touching);
+    }
+    Ok(())
+}
+
+/// Connect to the configured controller and find out how its panels are laid out,
+/// ignoring the panel mask.
+async fn connect_panels(config: &Config) -> Result<(NanoleafClient, Layout), Box<dyn Error>> {
+    let (host, port) = discover_host(config);
+    let token = token::load(config)?;
+    let nanoleaf = NanoleafClient::connect(token, host, port, config.get("nanoleaf_transport").unwrap_or_default(), false).await?;
+    let layout = Layout::new(&nanoleaf.get_panels().await?, Default::default())?;
+    Ok((nanoleaf, layout))
+}
+
+/// A frame setting every panel to the colour `color` gives for its ID.
+fn fill(layout: &Layout, color: impl Fn(u16) -> [u8; 3]) -> NanoleafEffectPayload {
+    let mut payload = NanoleafEffectPayload::new(layout.num_panels);
+    for panel in &layout.panels {
+        let [r, g, b] = color(panel.panel_id);
+        payload.write_effect(panel.panel_id, r, g, b, 1);
+    }
+    payload
+}
+
+/// Flash the chosen panels white one after another, with every other panel off.
+pub async fn identify(config: &Config, selections: Vec<PanelSelection>) -> Result<(), Box<dyn Error>> {
+    let (mut nanoleaf, layout) = connect_panels(config).await?;
+    let mut panel_ids = Vec::new();
+    for selection in selections {
+        match selection {
+            PanelSelection::All => panel_ids.extend(layout.panels.iter().map(|panel| panel.panel_id)),
+            PanelSelection::Panel(panel_id) if layout.panels.iter().any(|panel| panel.panel_id == panel_id) => panel_ids.push(panel_id),
+            PanelSelection::Panel(panel_id) => {
+                let known: Vec<String> = layout.panels.iter().map(|panel| panel.panel_id.to_string()).collect();
+                return Err(format!("There's no panel {}, the panels are {}", panel_id, known.join(", ")).into());
+            },
+        }
+    }
+
+    for (index, panel_id) in panel_ids.iter().enumerate() {
+        println!("Panel {} ({} of {})", panel_id, index + 1, panel_ids.len());
+        for _ in 0..IDENTIFY_FLASHES {
+            nanoleaf.send_effect(&fill(&layout, |id| if id == *panel_id { [255; 3] } else { [0; 3] }))?;
+            tokio::time::sleep(IDENTIFY_FLASH.max(nanoleaf.frame_interval())).await;
+            nanoleaf.send_effect(&fill(&layout, |_| [0; 3]))?;
+            tokio::time::sleep(IDENTIFY_FLASH.max(nanoleaf.frame_interval())).await;
+        }
+    }
+    Ok(())
+}
+
+/// Show each colour of the test pattern on every panel, and on screen if asked. The
+/// colours are sent as they are, without dithering or gamut mapping.
+pub async fn testpattern(config: &Config, args: TestPatternArgs) -> Result<(), Box<dyn Error>> {
+    let (mut nanoleaf, layout) = connect_panels(config).await?;
+    #[cfg(feature = "wayland")]
+    let mut swatch = if args.fullscreen {
+        let display = args.display.or_else(|| config.get_string("display").ok());
+        Some(crate::visual::swatch::Swatch::open(display.as_deref())?)
+    } else {
+        None
+    };
+
+    for (name, rgb) in TEST_PATTERN {
+        println!("{} ({}, {}, {})", name, rgb[0], rgb[1], rgb[2]);
+        nanoleaf.send_effect(&fill(&layout, |_| *rgb))?;
+        #[cfg(feature = "wayland")]
+        if let Some(swatch) = &mut swatch {
+            if swatch.closed() {
+                break;
+            }
+            swatch.show(*rgb)?;
+        }
+        tokio::time::sleep(Duration::from_secs_f32(args.hold.max(0.0)).max(nanoleaf.frame_interval())).await;
+    }
+    nanoleaf.send_effect(&fill(&layout, |_| [0; 3]))?;
+    Ok(())
+}
+
+pub fn config(config: &Config, config_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    match config_file {
+        Some(path) => println!("Reading config from {}", path.display()),
+        None => println!("No config file in the XDG config directories, reading config.toml from the working directory"),
+    }
+    let mut settings: serde_json::Value = config.clone().try_deserialize()?;
+    if let Some(token) = settings.get_mut("nanoleaf_token") {
+        *token = "<hidden>".into();
+    }
+    println!("{}", serde_json::to_string_pretty(&settings)?);
+    if let Some(settings) = settings.as_object() {
+        for invalid in crate::validate::effect_settings(settings) {
+            println!("Invalid setting {}", invalid);
+        }
+    }
+    Ok(())
+}
+
+pub fn preset(command: PresetCommand, config: &Config, config_file: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    match command {
+        PresetCommand::Export { path, name, description } => {
+            let settings: serde_json::Map<String, serde_json::Value> = config.clone().try_deserialize()?;
+            let preset = Preset::from_settings(&settings, name, description);
+            preset.save(&path)?;
+            println!("Wrote preset to {}", path.display());
+        },
+        PresetCommand::Import { path } => {
+            let config_path = config_file.unwrap_or_else(|| PathBuf::from("config.toml"));
+            let keys = preset::import(&path, &config_path)?;
+            println!("Set {} in {}, the old config is in {}", keys.join(", "), config_path.display(), config_path.with_extension("toml.bak").display());
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crash;
+use crate::log_throttle::warn_throttled;
+use crate::report::{FrameReport, Reporter};
+use crate::tuning::{Tuning, TuningChange};
+
+/**
+ * How long a client's writer may be stuck sending a report before the client is
+ * disconnected.
+ */
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/**
+ * How many reports may be waiting for a client before it's disconnected for falling
+ * behind.
+ */
+const CLIENT_QUEUE: usize = 30;
+
+/// A command sent by a control client, as a line of JSON such as
+/// `{"command": "scene", "name": "sunset"}`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum ControlCommand {
+    /// Show a scene from the config instead of the effect, or go back to the effect if
+    /// no name is given.
+    Scene {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// Change some of the audio [`Tuning`], e.g.
+    /// `{"command": "tune", "intensity": 20, "smoothing": 0.3}`. Settings left out stay
+    /// as they are.
+    Tune(TuningChange),
+    /// Switch to one of the `[profiles]` from the config.
+    Profile {
+        name: String,
+    },
+    /// Stop updating the lights, leaving them as they are.
+    Pause,
+    /// Start updating the lights again after a pause.
+    Resume,
+}
+
+/// What control clients have asked for, as last applied by the render loop.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "remote", derive(utoipa::ToSchema))]
+pub struct ControlState {
+    pub paused: bool,
+    /// The scene showing in place of the effect, if any.
+    pub scene: Option<String>,
+    pub tuning: Tuning,
+    /// The profile last switched to, until the scene or tuning is changed some other
+    /// way.
+    pub profile: Option<String>,
+}
+
+/// A look to switch to in one go, such as from the tray: a scene, or the effect, and
+/// the tuning to drive it with. Settings left out stay as they are.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// Scene to show, or the effect if not given.
+    #[serde(default)]
+    pub scene: Option<String>,
+    #[serde(flatten)]
+    pub tuning: TuningChange,
+}
+
+/// Connect to the control socket of a running instance.
+pub fn connect() -> Result<UnixStream, Box<dyn Error>> {
+    let path = xdg::BaseDirectories::with_prefix("leafpipe")?.find_runtime_file("control.sock")
+        .ok_or("No control socket found, is leafpipe running?")?;
+    Ok(UnixStream::connect(path)?)
+}
+
+/// Pass on a line of JSON from a client as a command. Returns `false` once nothing is
+/// listening for commands any more.
+pub fn send_command(line: &str, commands: &Sender<ControlCommand>) -> bool {
+    if line.trim().is_empty() {
+        return true;
+    }
+    match serde_json::from_str(line) {
+        Ok(command) => commands.send(command).is_ok(),
+        Err(err) => {
+            log::warn!("Ignoring invalid control command {:?}: {}", line, err);
+            true
+        },
+    }
+}
+
+/// Read commands from a client until it disconnects.
+fn read_commands(stream: UnixStream, commands: Sender<ControlCommand>) {
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if !send_command(&line, &commands) {
+            return;
+        }
+    }
+}
+
+/// Write queued reports to a client until it goes away or a write fails. A failed
+/// write may have sent part of a line, so the client is disconnected rather than sent
+/// anything more after it.
+fn write_reports(mut stream: UnixStream, reports: Receiver<Arc<[u8]>>) {
+    for line in reports {
+        if let Err(err) = stream.write_all(&line) {
+            log::debug!("Control client disconnected: {}", err);
+            break;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Where to queue reports for each connected client.
+type Clients = Arc<Mutex<Vec<SyncSender<Arc<[u8]>>>>>;
+
+/// A Unix socket that streams a JSON [`FrameReport`] per line to every connected
+/// client, for external visualisers to mirror the lights, and accepts
+/// [`ControlCommand`]s from them.
+pub struct ControlSocket {
+    path: PathBuf,
+    clients: Clients,
+}
+
+impl ControlSocket {
+    /// Listen on `leafpipe/control.sock` in the user's runtime directory.
+    pub fn bind(commands: Sender<ControlCommand>) -> Result<Self, Box<dyn Error>> {
+        let path = xdg::BaseDirectories::with_prefix("leafpipe")?.place_runtime_file("control.sock")?;
+        // A previous run may not have cleaned up after itself.
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        crash::spawn("control socket", move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)) {
+                            log::warn!("Failed to configure control client: {}", err);
+                            continue;
+                        }
+                        match stream.try_clone() {
+                            Ok(reader) => {
+                                let commands = commands.clone();
+                                crash::spawn("control client", move || read_commands(reader, commands));
+                            },
+                            Err(err) => log::warn!("Failed to read from control client: {}", err),
+                        }
+                        log::debug!("Control client connected");
+                        let (reports_tx, reports) = sync_channel(CLIENT_QUEUE);
+                        accepted.lock().unwrap().push(reports_tx);
+                        crash::spawn("control client writer", move || write_reports(stream, reports));
+                    },
+                    Err(err) => log::warn!("Failed to accept control client: {}", err),
+                }
+            }
+        });
+        log::info!("Listening for control clients on {}", path.display());
+        Ok(ControlSocket { path, clients })
+    }
+}
+
+impl Reporter for ControlSocket {
+    fn wants_report(&self) -> bool {
+        !self.clients.lock().unwrap().is_empty()
+    }
+
+    /// Queue a report for every client, dropping any that have gone away or fallen
+    /// behind. Each client's writer sends it, so a slow client can't hold up the
+    /// render loop.
+    fn report(&mut self, report: &FrameReport) {
+        let mut line = match serde_json::to_vec(report) {
+            Ok(line) => line,
+            Err(err) => {
+                warn_throttled!("Failed to serialize frame report: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let line: Arc<[u8]> = line.into();
+        self.clients.lock().unwrap().retain(|client| match client.try_send(line.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Disconnecting control client that fell behind");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let command: ControlCommand = serde_json::from_str(r#"{"command": "scene", "name": "sunset"}"#).unwrap();
+        assert_eq!(command, ControlCommand::Scene { name: Some("sunset".to_string()) });
+        let command: ControlCommand = serde_json::from_str(r#"{"command": "scene"}"#).unwrap();
+        assert_eq!(command, ControlCommand::Scene { name: None });
+        let command: ControlCommand = serde_json::from_str(r#"{"command": "tune", "intensity": 20}"#).unwrap();
+        assert_eq!(command, ControlCommand::Tune(TuningChange { intensity: Some(20.0), ..TuningChange::default() }));
+        let tuning = Tuning { smoothing: 0.5, ..Tuning::default() };
+        assert_eq!(tuning.with(TuningChange { intensity: Some(20.0), ..TuningChange::default() }), Tuning { intensity: 20.0, ..tuning });
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        let config = config::Config::builder()
+            .set_override("profiles.movie.scene", "dim").unwrap()
+            .set_override("profiles.movie.intensity", 30).unwrap()
+            .build().unwrap();
+        let profiles: std::collections::HashMap<String, Profile> = config.get("profiles").unwrap();
+        assert_eq!(profiles["movie"], Profile {
+            scene: Some("dim".to_string()),
+            tuning: TuningChange { intensity: Some(30.0), ..TuningChange::default() },
+        });
+    }
+}
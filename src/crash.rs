@@ -0,0 +1,192 @@
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::panic::{self, PanicHookInfo};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::control::ControlState;
+use crate::stats;
+
+/**
+ * How many of the most recent log lines go into a crash report.
+ */
+const RECENT_LOG_LINES: usize = 200;
+
+/**
+ * leafpipe's own log lines at this level or above are kept for crash reports, even when
+ * they're too detailed to be shown.
+ */
+const RECENT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+thread_local! {
+    /// The part of leafpipe running on this thread, for threads without a name of their
+    /// own, such as Tokio's workers.
+    static SUBSYSTEM: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+static RECENT_LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+static STATE: OnceLock<Arc<Mutex<ControlState>>> = OnceLock::new();
+
+/// Start a thread named after the subsystem it runs, so a panic on it says where it
+/// happened.
+pub fn spawn<F, T>(subsystem: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::Builder::new().name(subsystem.to_string()).spawn(f).expect("Failed to start a thread")
+}
+
+/// Mark the current thread as running `subsystem`, for work that runs on a thread it
+/// didn't start, such as a Tokio worker.
+pub fn enter(subsystem: &'static str) {
+    SUBSYSTEM.with(|current| current.set(Some(subsystem)));
+}
+
+/// Include what control clients asked for in crash reports.
+pub fn watch_state(state: Arc<Mutex<ControlState>>) {
+    let _ = STATE.set(state);
+}
+
+/// Whether a log line is kept for crash reports.
+fn recorded(metadata: &Metadata) -> bool {
+    metadata.level() <= RECENT_LOG_LEVEL && metadata.target().starts_with("leafpipe")
+}
+
+/// Passes records on to the real logger, keeping the most recent lines for crash reports.
+struct RecordingLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for RecordingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || recorded(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if recorded(record.metadata()) {
+            let line = format!("{} {} {}: {}", chrono::Local::now().format("%H:%M:%S%.3f"), record.level(), record.target(), record.args());
+            let mut recent = RECENT_LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if recent.len() == RECENT_LOG_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(line);
+        }
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install `logger` as the logger, keeping recent lines for crash reports, and the
+/// panic hook that writes them.
+pub fn init(logger: env_logger::Logger) -> Result<(), log::SetLoggerError> {
+    log::set_max_level(logger.filter().max(RECENT_LOG_LEVEL));
+    log::set_boxed_logger(Box::new(RecordingLogger { inner: logger }))?;
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let report = CrashReport::new(info);
+        match report.save() {
+            Ok(path) => log::error!("leafpipe crashed in {}, wrote a crash report to {}", report.subsystem, path.display()),
+            Err(err) => log::error!("leafpipe crashed in {}, and could not write a crash report: {}", report.subsystem, err),
+        }
+    }));
+    Ok(())
+}
+
+/// Everything known about a panic, to attach to a bug report.
+struct CrashReport {
+    subsystem: String,
+    thread: String,
+    message: String,
+    location: String,
+    backtrace: String,
+    /// What control clients had asked for, as JSON, if it could be read.
+    state: Option<String>,
+    stats: String,
+    log: Vec<String>,
+}
+
+impl CrashReport {
+    fn new(info: &PanicHookInfo) -> Self {
+        let thread = thread::current().name().unwrap_or("unnamed").to_string();
+        let subsystem = SUBSYSTEM.with(Cell::get).map(str::to_string).unwrap_or_else(|| thread.clone());
+        let message = info.payload().downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "(no message)".to_string());
+        // The panic may have happened with the state locked, so don't wait for it.
+        let state = STATE.get()
+            .and_then(|state| state.try_lock().ok().map(|state| serde_json::to_string(&*state)))
+            .and_then(Result::ok);
+        CrashReport {
+            subsystem,
+            thread,
+            message,
+            location: info.location().map(ToString::to_string).unwrap_or_default(),
+            backtrace: Backtrace::force_capture().to_string(),
+            state,
+            stats: stats::SESSION.summary().to_string(),
+            log: RECENT_LOG.lock().map(|recent| recent.iter().cloned().collect()).unwrap_or_default(),
+        }
+    }
+
+    /// Write the report to a new file in the state directory.
+    fn save(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let name = format!("crash-{}.txt", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+        let path = xdg::BaseDirectories::with_prefix("leafpipe")?.place_state_file(name)?;
+        fs::write(&path, self.to_string())?;
+        Ok(path)
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "leafpipe {} crashed in {} (thread {})", env!("CARGO_PKG_VERSION"), self.subsystem, self.thread)?;
+        writeln!(f, "{} at {}", self.message, self.location)?;
+        writeln!(f, "\nState: {}", self.state.as_deref().unwrap_or("unavailable"))?;
+        writeln!(f, "\n{}", self.stats)?;
+        writeln!(f, "\nRecent log:")?;
+        for line in &self.log {
+            writeln!(f, "{}", line)?;
+        }
+        write!(f, "\nBacktrace:\n{}", self.backtrace)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let report = CrashReport {
+            subsystem: "screen capture".to_string(),
+            thread: "tokio-runtime-worker".to_string(),
+            message: "index out of bounds".to_string(),
+            location: "src/visual/mod.rs:10:5".to_string(),
+            backtrace: String::new(),
+            state: None,
+            stats: "Ran for 0:00:01".to_string(),
+            log: vec!["12:00:00.000 WARN leafpipe: Dropping a frame".to_string()],
+        };
+        let text = report.to_string();
+        assert!(text.contains("crashed in screen capture (thread tokio-runtime-worker)"));
+        assert!(text.contains("index out of bounds at src/visual/mod.rs:10:5"));
+        assert!(text.contains("State: unavailable"));
+        assert!(text.contains("Recent log:\n12:00:00.000 WARN leafpipe: Dropping a frame\n"));
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::dither::Dither;
+use crate::layout::Layout;
+use crate::nanoleaf::{NanoleafClient, NanoleafEffectPayload, NanoleafLayoutPanelData};
+use crate::log_throttle::warn_throttled;
+use crate::notify;
+use crate::stats::{Stage, SESSION};
+
+/**
+ * How often to check the controller is still answering and in external control mode,
+ * so it can be picked back up after a reboot.
+ */
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/**
+ * Frame rates tried when probing how fast the controller keeps up, below the configured
+ * rate, from fastest to slowest.
+ */
+const PROBE_RATES: &[f32] = &[20.0, 15.0, 10.0, 7.5, 5.0];
+
+/**
+ * How long frames are streamed at each rate while probing.
+ */
+const PROBE_TIME: Duration = Duration::from_secs(1);
+
+/**
+ * How many times the controller's answer is timed, both idle and at each rate. The
+ * median is taken, so one slow answer on a busy network doesn't lower the rate.
+ */
+const PROBE_SAMPLES: u32 = 5;
+
+/**
+ * Name of the file in the state directory the probed frame rate of each controller is
+ * kept in, so it's only probed once.
+ */
+const PROBED_RATES_FILE: &str = "frame_rates.json";
+
+/**
+ * How many times longer than when idle the controller can take to answer while frames
+ * stream to it, before it's taken to be falling behind.
+ */
+const PROBE_SLOWDOWN: u32 = 3;
+
+/**
+ * Answers quicker than this are never taken as falling behind, as the idle time is too
+ * small to compare against.
+ */
+const PROBE_MIN_RESPONSE: Duration = Duration::from_millis(50);
+
+fn default_frames_per_second() -> f32 {
+    15.0
+}
+
+fn default_burst() -> f32 {
+    3.0
+}
+
+fn default_probe() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Most frames a second to send to the device on average.
+    #[serde(default = "default_frames_per_second")]
+    pub frames_per_second: f32,
+    /// How many frames may be sent back to back after a quiet spell.
+    #[serde(default = "default_burst")]
+    pub burst: f32,
+    /// Check how fast the controller keeps up at startup, and send no faster than that.
+    #[serde(default = "default_probe")]
+    pub probe: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            frames_per_second: default_frames_per_second(),
+            burst: default_burst(),
+            probe: default_probe(),
+        }
+    }
+}
+
+fn default_delta_enabled() -> bool {
+    true
+}
+
+fn default_delta_threshold() -> u8 {
+    1
+}
+
+fn default_keyframe_secs() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeltaConfig {
+    /// Only send the panels that changed since the last frame.
+    #[serde(default = "default_delta_enabled")]
+    pub enabled: bool,
+    /// Smallest change in any colour channel worth sending. Above 1, the single steps
+    /// dithering fades with are held back until the change adds up to this.
+    #[serde(default = "default_delta_threshold")]
+    pub threshold: u8,
+    /// Seconds between frames with every panel, which put right any panels left on the
+    /// wrong colour by a lost packet.
+    #[serde(default = "default_keyframe_secs")]
+    pub keyframe_secs: f32,
+}
+
+impl Default for DeltaConfig {
+    fn default() -> Self {
+        DeltaConfig {
+            enabled: default_delta_enabled(),
+            threshold: default_delta_threshold(),
+            keyframe_secs: default_keyframe_secs(),
+        }
+    }
+}
+
+/// The frame rate probed for a controller, and what it was probed with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct ProbedRate {
+    panels: usize,
+    max_rate: f32,
+    rate: f32,
+}
+
+fn probed_rates_path() -> Result<std::path::PathBuf, Box<dyn Error>> {
+    Ok(xdg::BaseDirectories::with_prefix("leafpipe")?.place_state_file(PROBED_RATES_FILE)?)
+}
+
+fn load_probed_rates() -> HashMap<String, ProbedRate> {
+    probed_rates_path().ok()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// The fastest frame rate, up to `max_rate`, the controller at `controller` keeps up
+/// with. Probed the first time, and remembered until the layout or `max_rate` changes.
+pub async fn frame_rate(nanoleaf: &mut NanoleafClient, controller: &str, panels: &[NanoleafLayoutPanelData], max_rate: f32) -> Result<f32, Box<dyn Error>> {
+    let mut rates = load_probed_rates();
+    if let Some(probed) = rates.get(controller).filter(|probed| probed.panels == panels.len() && probed.max_rate == max_rate) {
+        log::debug!("Using the frame rate probed before for {}", controller);
+        return Ok(probed.rate);
+    }
+    if nanoleaf.uses_http() {
+        return Ok(max_rate);
+    }
+    let rate = probe_frame_rate(nanoleaf, panels, max_rate).await?;
+    rates.insert(controller.to_string(), ProbedRate { panels: panels.len(), max_rate, rate });
+    let result = probed_rates_path().and_then(|path| Ok(fs::write(path, serde_json::to_vec(&rates)?)?));
+    if let Err(err) = result {
+        log::warn!("Could not save the probed frame rate: {}", err);
+    }
+    Ok(rate)
+}
+
+/// The middle of `samples`.
+fn median(mut samples: Vec<Duration>) -> Duration {
+    samples.sort();
+    samples[samples.len() / 2]
+}
+
+/// Find the fastest frame rate, up to `max_rate`, that the controller keeps up with.
+/// Controllers with large layouts quietly drop frames sent too fast, and answer their
+/// API slowly while they do, so test frames are streamed at falling rates until its
+/// answers are about as quick as when idle. Frames over HTTP are already paced by the
+/// controller, so aren't probed.
+pub async fn probe_frame_rate(nanoleaf: &mut NanoleafClient, panels: &[NanoleafLayoutPanelData], max_rate: f32) -> Result<f32, Box<dyn Error>> {
+    if nanoleaf.uses_http() {
+        return Ok(max_rate);
+    }
+    let mut idle = Vec::new();
+    for _ in 0..PROBE_SAMPLES {
+        idle.push(nanoleaf.time_response().await?);
+    }
+    let idle = median(idle);
+    let allowed = (idle * PROBE_SLOWDOWN).max(PROBE_MIN_RESPONSE);
+
+    let rates = std::iter::once(max_rate).chain(PROBE_RATES.iter().copied().filter(|rate| *rate < max_rate));
+    let mut slowest = max_rate;
+    for rate in rates {
+        slowest = rate;
+        // Ask once the controller has had a while to fall behind, spread over the rest
+        // of the run.
+        let responses: Vec<_> = (0..PROBE_SAMPLES).map(|sample| {
+            let request = nanoleaf.time_response();
+            let wait = PROBE_TIME / 2 + PROBE_TIME / 2 * sample / PROBE_SAMPLES;
+            tokio::spawn(async move {
+                tokio::time::sleep(wait).await;
+                request.await
+            })
+        }).collect();
+        let mut interval = tokio::time::interval(Duration::from_secs_f32(1.0 / rate));
+        let start = Instant::now();
+        let mut frame = 0u8;
+        while start.elapsed() < PROBE_TIME {
+            interval.tick().await;
+            // Alternate between two dim greys so every frame changes the panels.
+            let level = 30 + (frame % 2) * 20;
+            let mut payload = NanoleafEffectPayload::new(panels.len());
+            for panel in panels {
+                payload.write_effect(panel.panel_id, level, level, level, 1);
+            }
+            // A failed send shows up in the response time too.
+            let _ = nanoleaf.send_effect(&payload);
+            frame = frame.wrapping_add(1);
+        }
+        let mut busy = Vec::new();
+        for response in responses {
+            // An answer that never came counts as the slowest.
+            busy.push(response.await?.unwrap_or_else(|err| {
+                log::debug!("The nanoleaf didn't answer at {} frames a second: {}", rate, err);
+                Duration::MAX
+            }));
+        }
+        let busy = median(busy);
+        if busy <= allowed {
+            return Ok(rate);
+        }
+        log::debug!("The nanoleaf took {}ms to answer at {} frames a second, against {}ms idle", busy.as_millis(), rate, idle.as_millis());
+    }
+    Ok(slowest)
+}
+
+/// Cuts frames down to the panels that changed since the last frame sent.
+struct DeltaEncoder {
+    config: DeltaConfig,
+    /// The colour each panel was last sent.
+    sent: HashMap<u16, [u8; 3]>,
+    last_keyframe: Option<Instant>,
+}
+
+impl DeltaEncoder {
+    fn new(config: DeltaConfig) -> Self {
+        DeltaEncoder {
+            config,
+            sent: HashMap::new(),
+            last_keyframe: None,
+        }
+    }
+
+    /// The part of `payload` worth sending, or `None` if no panel changed enough.
+    /// Every panel is sent if `full` is set or a keyframe is due.
+    fn encode(&mut self, payload: &NanoleafEffectPayload, full: bool, now: Instant) -> Option<NanoleafEffectPayload> {
+        let keyframe_due = self.last_keyframe.is_none_or(|last| now.saturating_duration_since(last).as_secs_f32() >= self.config.keyframe_secs);
+        if full || !self.config.enabled || keyframe_due {
+            self.last_keyframe = Some(now);
+            self.sent.extend(payload.panels().map(|(panel_id, rgb, _)| (panel_id, rgb)));
+            return Some(payload.clone());
+        }
+        let changed: Vec<(u16, [u8; 3], u8)> = payload.panels().filter(|(panel_id, rgb, _)| {
+            self.sent.get(panel_id).is_none_or(|sent| sent.iter().zip(rgb).any(|(a, b)| a.abs_diff(*b) >= self.config.threshold))
+        }).collect();
+        if changed.is_empty() {
+            return None;
+        }
+        let mut delta = NanoleafEffectPayload::new(changed.len());
+        for (panel_id, [r, g, b], transition_time_ds) in changed {
+            delta.write_effect(panel_id, r, g, b, transition_time_ds);
+            self.sent.insert(panel_id, [r, g, b]);
+        }
+        Some(delta)
+    }
+}
+
+/// Turns frames into what's sent to the device: dithered, laid out over the panels and
+/// cut down to the panels that changed.
+pub struct FrameEncoder {
+    layout: Layout,
+    dither: Dither,
+    delta: DeltaEncoder,
+}
+
+impl FrameEncoder {
+    pub fn new(layout: Layout, dither: Dither, delta: DeltaConfig) -> Self {
+        FrameEncoder {
+            layout,
+            dither,
+            delta: DeltaEncoder::new(delta),
+        }
+    }
+
+    /// The payload to send for a frame with a colour for each active panel, or `None`
+    /// if no panel changed enough. Every panel is sent if `full` is set.
+    pub fn encode(&mut self, colors: &[Option<[f32; 3]>], full: bool, now: Instant) -> Option<NanoleafEffectPayload> {
+        let mut payload = NanoleafEffectPayload::new(self.layout.num_panels);
+        self.layout.write_frame(self.dither.quantize(colors), &mut payload);
+        self.delta.encode(&payload, full, now)
+    }
+}
+
+/// A token bucket, refilled at a steady rate up to a burst size, with a token spent on
+/// each frame.
+struct TokenBucket {
+    rate: f32,
+    burst: f32,
+    tokens: f32,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        let burst = config.burst.max(1.0);
+        TokenBucket {
+            rate: config.frames_per_second.max(f32::EPSILON),
+            burst,
+            tokens: burst,
+            refilled: now,
+        }
+    }
+
+    /// Take a token, returning how long to wait before it can be used.
+    fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f32(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Read the controller's brightness setting into `limit`, logging when it changes.
+async fn update_brightness_limit(nanoleaf: &NanoleafClient, limit: Option<&AtomicU8>) {
+    let Some(limit) = limit else {
+        return;
+    };
+    match nanoleaf.brightness().await {
+        Ok(brightness) => {
+            let percent = brightness.percent();
+            if limit.swap(percent, Ordering::Relaxed) != percent {
+                log::info!("The nanoleaf's brightness is now {}%, making up for it", percent);
+            }
+        },
+        Err(err) => log::debug!("Could not read the nanoleaf's brightness: {}", err),
+    }
+}
+
+/// The RGB colour of each active panel, before it's rounded to 8 bits.
+type Frame = Vec<Option<[f32; 3]>>;
+
+/// Sends frames to a device no faster than it can take them. Frames that arrive while
+/// waiting replace the one waiting to go out, so a slow device shows the latest frame
+/// rather than falling behind. Frames are dithered as they go out, so the rounding
+/// error carried from one frame to the next follows what the panels actually showed.
+/// Panels that haven't changed since they were last sent are left out.
+pub struct DeviceOutput {
+    /// The frame waiting to go out, and when it was rendered.
+    frames: watch::Sender<Option<(Instant, Frame)>>,
+}
+
+impl DeviceOutput {
+    /// Hand the client over to a task that sends frames to it. Must be called from
+    /// within the Tokio runtime. Frames that have waited longer than `max_age` for
+    /// anything but the rate limit are dropped, as a newer one is on its way. With
+    /// `brightness_limit`, the controller's brightness setting is kept up to date in it.
+    pub fn start(mut nanoleaf: NanoleafClient, mut encoder: FrameEncoder, config: &RateLimitConfig, max_age: Duration, brightness_limit: Option<Arc<AtomicU8>>) -> Self {
+        let (frames, mut receiver) = watch::channel::<Option<(Instant, Frame)>>(None);
+        let mut bucket = TokenBucket::new(config, Instant::now());
+        tokio::spawn(async move {
+            let mut unreachable = false;
+            // Whether the controller stopped answering, such as while it reboots.
+            let mut away = false;
+            let mut health = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+            health.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                // Send the whole of the last frame again once the controller is back.
+                let resume = tokio::select! {
+                    changed = receiver.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                        false
+                    },
+                    _ = health.tick() => match nanoleaf.ensure_streaming(away).await {
+                        Ok(restored) if restored || away => {
+                            update_brightness_limit(&nanoleaf, brightness_limit.as_deref()).await;
+                            log::info!("The nanoleaf is back, resuming");
+                            SESSION.controller_reconnected();
+                            away = false;
+                            true
+                        },
+                        Ok(_) => {
+                            update_brightness_limit(&nanoleaf, brightness_limit.as_deref()).await;
+                            continue;
+                        },
+                        Err(err) => {
+                            if !away {
+                                log::warn!("The nanoleaf isn't answering, waiting for it to come back: {}", err);
+                                away = true;
+                            }
+                            continue;
+                        },
+                    },
+                };
+                let wait = bucket.take(Instant::now());
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                let Some((rendered, colors)) = receiver.borrow_and_update().clone() else {
+                    continue;
+                };
+                if !resume && rendered.elapsed().saturating_sub(wait) > max_age {
+                    warn_throttled!("Dropping a frame rendered {}ms ago", rendered.elapsed().as_millis());
+                    SESSION.frame_dropped();
+                    continue;
+                }
+                // Display commands over HTTP replace the whole layout.
+                let Some(payload) = encoder.encode(&colors, resume || nanoleaf.uses_http(), Instant::now()) else {
+                    continue;
+                };
+                match nanoleaf.send_effect(&payload) {
+                    Ok(()) => {
+                        SESSION.frame_sent();
+                        SESSION.time(Stage::Send, rendered.elapsed());
+                    },
+                    Err(err) => {
+                        warn_throttled!("Failed to send effect to nanoleaf {:?}", err);
+                        SESSION.send_failed();
+                    },
+                }
+                if nanoleaf.unreachable() != unreachable {
+                    unreachable = !unreachable;
+                    if unreachable {
+                        log::error!("The nanoleaf can't be reached, the lights will stay as they are until it's back");
+                        tokio::task::spawn_blocking(|| notify::notify("leafpipe lost the nanoleaf", "Frames keep failing to reach it, so the lights have stopped. Still retrying."));
+                    } else {
+                        log::info!("The nanoleaf can be reached again");
+                    }
+                }
+            }
+        });
+        DeviceOutput { frames }
+    }
+
+    /// Queue a frame with a colour for each active panel to be sent, replacing any
+    /// frame still waiting.
+    pub fn send(&self, colors: Frame) {
+        self.frames.send_replace(Some((Instant::now(), colors)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nanoleaf::NanoleafLayoutResponse;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&RateLimitConfig { frames_per_second: 10.0, burst: 2.0, probe: false }, start);
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        let wait = bucket.take(start);
+        assert!((wait.as_secs_f32() - 0.1).abs() < 0.001, "Third frame should wait for a token, waited {:?}", wait);
+        // A quiet spell refills the bucket, but no further than the burst size.
+        let later = start + Duration::from_secs(5);
+        assert_eq!(bucket.take(later), Duration::ZERO);
+        assert_eq!(bucket.take(later), Duration::ZERO);
+        assert!(!bucket.take(later).is_zero());
+    }
+
+    #[test]
+    fn test_delta_encoder() {
+        let frame = |colors: &[(u16, [u8; 3])]| {
+            let mut payload = NanoleafEffectPayload::new(colors.len());
+            for (panel_id, [r, g, b]) in colors {
+                payload.write_effect(*panel_id, *r, *g, *b, 1);
+            }
+            payload
+        };
+        let ids = |payload: Option<NanoleafEffectPayload>| payload.map(|payload| payload.panels().map(|(panel_id, _, _)| panel_id).collect::<Vec<_>>());
+        let start = Instant::now();
+        let mut delta = DeltaEncoder::new(DeltaConfig { threshold: 3, ..DeltaConfig::default() });
+
+        assert_eq!(ids(delta.encode(&frame(&[(1, [0, 0, 0]), (2, [0, 0, 0])]), false, start)), Some(vec![1, 2]));
+        // Changes below the threshold aren't sent, nor are frames where nothing changed.
+        assert_eq!(ids(delta.encode(&frame(&[(1, [2, 0, 0]), (2, [0, 50, 0])]), false, start)), Some(vec![2]));
+        assert_eq!(ids(delta.encode(&frame(&[(1, [2, 0, 0]), (2, [0, 50, 0])]), false, start)), None);
+        assert_eq!(ids(delta.encode(&frame(&[(1, [3, 0, 0]), (2, [0, 50, 0])]), false, start)), Some(vec![1]));
+        // Until a keyframe is due.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(ids(delta.encode(&frame(&[(1, [3, 0, 0]), (2, [0, 50, 0])]), false, later)), Some(vec![1, 2]));
+        assert_eq!(ids(delta.encode(&frame(&[(1, [3, 0, 0]), (2, [0, 50, 0])]), true, later)), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_slow_fade_is_sent_smoothly() {
+        let response = NanoleafLayoutResponse {
+            num_panels: 1,
+            side_length: 100,
+            position_data: vec![NanoleafLayoutPanelData { panel_id: 1, x: 50, y: 50, shape_type: 2, orientation: 0 }],
+        };
+        let layout = Layout::new(&response, Default::default()).unwrap();
+        let mut encoder = FrameEncoder::new(layout, Dither::new(1.0), DeltaConfig::default());
+        let start = Instant::now();
+        let mut shown = 0;
+        let mut changes = 0;
+        // A fade from 10 to 13 over most of a second, well within one keyframe.
+        for frame in 0..36 {
+            let wanted = 10.0 + frame as f32 / 12.0;
+            let now = start + Duration::from_millis(frame * 25);
+            if let Some(payload) = encoder.encode(&[Some([wanted, 0.0, 0.0])], false, now) {
+                let (_, [red, _, _], _) = payload.panels().next().unwrap();
+                changes += (red != shown) as u32;
+                shown = red;
+            }
+            assert!((shown as f32 - wanted).abs() < 1.0, "Panel shows {} for {}", shown, wanted);
+        }
+        assert!(changes > 3, "The fade only changed the panel {} times", changes);
+    }
+}
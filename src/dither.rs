@@ -0,0 +1,64 @@
+use colors_transform::{Color, Hsl};
+
+/// Each colour of a frame as RGB, before it's rounded to 8 bits.
+pub fn to_rgb(colors: &[Option<Hsl>]) -> Vec<Option<[f32; 3]>> {
+    colors.iter().map(|color| color.map(|hsl| {
+        let (r, g, b) = hsl.to_rgb().as_tuple();
+        [r, g, b]
+    })).collect()
+}
+
+/// Spreads the rounding error of 8 bit colour over successive frames, so slow fades
+/// at low brightness don't visibly step from one level to the next. Used where frames
+/// go out to the lights, so only frames that are actually sent carry error forward.
+pub struct Dither {
+    /// How much of each frame's rounding error is carried into the next, from 0 (none)
+    /// to 1.
+    strength: f32,
+    errors: Vec<[f32; 3]>,
+}
+
+impl Dither {
+    pub fn new(strength: f32) -> Self {
+        Dither {
+            strength: strength.clamp(0.0, 1.0),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Convert a frame to 8 bit RGB, carrying the rounding error of each panel over to
+    /// the next frame.
+    pub fn quantize(&mut self, colors: &[Option<[f32; 3]>]) -> Vec<Option<[u8; 3]>> {
+        self.errors.resize(colors.len(), [0.0; 3]);
+        colors.iter().zip(self.errors.iter_mut()).map(|(color, error)| {
+            let Some(color) = color else {
+                *error = [0.0; 3];
+                return None;
+            };
+            let mut rgb = [0u8; 3];
+            for (channel, value) in color.iter().enumerate() {
+                let wanted = (value + error[channel]).clamp(0.0, 255.0);
+                rgb[channel] = wanted.round() as u8;
+                error[channel] = (wanted - rgb[channel] as f32) * self.strength;
+            }
+            Some(rgb)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use colors_transform::Rgb;
+
+    #[test]
+    fn test_dither_averages_to_color() {
+        let mut dither = Dither::new(1.0);
+        let color = to_rgb(&[Some(Rgb::from(2.25, 0.0, 0.0).to_hsl())]);
+        let reds: Vec<u8> = (0..4).map(|_| dither.quantize(&color)[0].unwrap()[0]).collect();
+        assert_eq!(reds.iter().map(|red| *red as u32).sum::<u32>(), 9, "Expected {:?} to average 2.25", reds);
+
+        let mut plain = Dither::new(0.0);
+        assert!((0..4).all(|_| plain.quantize(&color)[0].unwrap()[0] == 2));
+    }
+}
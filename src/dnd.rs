@@ -0,0 +1,207 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::control::{ControlCommand, ControlState};
+use crate::crash;
+use crate::log_throttle::warn_throttled;
+
+/**
+ * How often to ask mako which modes it's in, as it can't say when they change.
+ */
+const MAKO_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/**
+ * GNOME's setting for showing notification banners, which do not disturb turns off.
+ */
+const GNOME_SCHEMA: &str = "org.gnome.desktop.notifications";
+const GNOME_KEY: &str = "show-banners";
+
+fn default_mako_mode() -> String {
+    "do-not-disturb".to_string()
+}
+
+/// Where to find out whether do not disturb is on.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DndSource {
+    /// Whichever of the others is running.
+    #[default]
+    Auto,
+    /// SwayNotificationCenter, through `swaync-client`.
+    Swaync,
+    /// mako, through `makoctl`, in the mode named by `mako_mode`.
+    Mako,
+    /// GNOME's notification settings, through `gsettings`.
+    Gnome,
+}
+
+/// Show a calm scene while the desktop's do not disturb mode is on.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DndConfig {
+    pub scene: String,
+    #[serde(default)]
+    pub source: DndSource,
+    /// mako has no do not disturb of its own, only modes set up to act like it.
+    #[serde(default = "default_mako_mode")]
+    pub mako_mode: String,
+}
+
+/// The output of a command, if it ran and succeeded.
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).stderr(Stdio::null()).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Pick the first source that answers, for [`DndSource::Auto`].
+fn detect(config: &DndConfig) -> Option<DndSource> {
+    match config.source {
+        DndSource::Auto => [DndSource::Swaync, DndSource::Mako, DndSource::Gnome].into_iter().find(|source| match source {
+            DndSource::Swaync => run("swaync-client", &["--get-dnd"]).is_some(),
+            DndSource::Mako => run("makoctl", &["mode"]).is_some(),
+            _ => run("gsettings", &["get", GNOME_SCHEMA, GNOME_KEY]).is_some(),
+        }),
+        source => Some(source),
+    }
+}
+
+/// Read a line from `swaync-client --subscribe`, such as
+/// `{"count": 2, "dnd": true, "visible": false, "inhibited": false}`.
+fn parse_swaync(line: &str) -> Option<bool> {
+    let status: serde_json::Value = serde_json::from_str(line).ok()?;
+    status["dnd"].as_bool()
+}
+
+/// Read the value of `show-banners` from `gsettings get`, or a line such as
+/// `show-banners: false` from `gsettings monitor`. Hiding banners is do not disturb.
+fn parse_gsettings(line: &str) -> Option<bool> {
+    let value = line.rsplit(':').next()?.trim();
+    value.parse::<bool>().ok().map(|show_banners| !show_banners)
+}
+
+/// Pass whether do not disturb is on to `on_change` whenever it might have changed,
+/// until it returns `false`.
+fn follow(source: DndSource, mako_mode: &str, mut on_change: impl FnMut(bool) -> bool) -> Result<(), Box<dyn Error>> {
+    let (mut child, parse): (_, fn(&str) -> Option<bool>) = match source {
+        DndSource::Swaync => (Command::new("swaync-client").arg("--subscribe").stdout(Stdio::piped()).spawn()?, parse_swaync),
+        DndSource::Gnome => {
+            if let Some(dnd) = run("gsettings", &["get", GNOME_SCHEMA, GNOME_KEY]).as_deref().and_then(parse_gsettings) {
+                on_change(dnd);
+            }
+            (Command::new("gsettings").args(["monitor", GNOME_SCHEMA, GNOME_KEY]).stdout(Stdio::piped()).spawn()?, parse_gsettings)
+        },
+        _ => {
+            loop {
+                // mako may be restarting, so keep asking.
+                match run("makoctl", &["mode"]) {
+                    Some(modes) => {
+                        if !on_change(modes.lines().any(|mode| mode.trim() == mako_mode)) {
+                            return Ok(());
+                        }
+                    },
+                    None => warn_throttled!("makoctl mode failed, is mako running?"),
+                }
+                thread::sleep(MAKO_POLL_INTERVAL);
+            }
+        },
+    };
+    let stdout = child.stdout.take().ok_or("No output to follow")?;
+    for line in BufReader::new(stdout).lines() {
+        if let Some(dnd) = parse(&line?) {
+            if !on_change(dnd) {
+                break;
+            }
+        }
+    }
+    let _ = child.kill();
+    child.wait()?;
+    Ok(())
+}
+
+/// Shows the scene while do not disturb is on, then puts back whatever was showing
+/// before.
+struct DndSwitcher {
+    scene: String,
+    state: Arc<Mutex<ControlState>>,
+    commands: Sender<ControlCommand>,
+    /// The scene selected before do not disturb came on, while it's on.
+    previous: Option<Option<String>>,
+}
+
+impl DndSwitcher {
+    /// Follow do not disturb turning on or off. Returns `false` once the lights have
+    /// stopped.
+    fn update(&mut self, dnd: bool) -> bool {
+        let command = match (dnd, self.previous.is_some()) {
+            (true, false) => {
+                log::info!("Do not disturb is on, showing {}", self.scene);
+                self.previous = Some(self.state.lock().unwrap().scene.clone());
+                ControlCommand::Scene { name: Some(self.scene.clone()) }
+            },
+            (false, true) => {
+                let previous = self.previous.take().flatten();
+                // A scene picked while do not disturb was on is left showing.
+                if self.state.lock().unwrap().scene.as_deref() != Some(self.scene.as_str()) {
+                    log::info!("Do not disturb is off, leaving the scene picked since");
+                    return true;
+                }
+                log::info!("Do not disturb is off");
+                ControlCommand::Scene { name: previous }
+            },
+            _ => return true,
+        };
+        self.commands.send(command).is_ok()
+    }
+}
+
+/// Switch to `config.scene` while do not disturb is on.
+pub fn watch(config: DndConfig, state: Arc<Mutex<ControlState>>, commands: Sender<ControlCommand>) -> Result<(), Box<dyn Error>> {
+    let source = detect(&config).ok_or("No swaync, mako or GNOME found to ask")?;
+    log::info!("Following do not disturb from {:?}", source);
+    let mut switcher = DndSwitcher { scene: config.scene, state, commands, previous: None };
+    crash::spawn("do not disturb", move || {
+        if let Err(err) = follow(source, &config.mako_mode, |dnd| switcher.update(dnd)) {
+            log::warn!("Stopped following do not disturb: {}", err);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_switches_scene() {
+        assert_eq!(parse_swaync(r#"{"count": 2, "dnd": true, "visible": false, "inhibited": false}"#), Some(true));
+        assert_eq!(parse_swaync("not json"), None);
+        assert_eq!(parse_gsettings("show-banners: false"), Some(true));
+        assert_eq!(parse_gsettings("true\n"), Some(false));
+
+        let (commands, received) = mpsc::channel();
+        let state = Arc::new(Mutex::new(ControlState { scene: Some("sunset".to_string()), ..Default::default() }));
+        let mut switcher = DndSwitcher { scene: "calm".to_string(), state: state.clone(), commands, previous: None };
+        assert!(switcher.update(false));
+        assert!(switcher.update(true));
+        assert!(switcher.update(true));
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), [ControlCommand::Scene { name: Some("calm".to_string()) }]);
+        state.lock().unwrap().scene = Some("calm".to_string());
+        switcher.update(false);
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), [ControlCommand::Scene { name: Some("sunset".to_string()) }]);
+
+        // A scene picked by hand in the meantime is kept.
+        state.lock().unwrap().scene = Some("sunset".to_string());
+        switcher.update(true);
+        state.lock().unwrap().scene = Some("party".to_string());
+        switcher.update(false);
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), [ControlCommand::Scene { name: Some("calm".to_string()) }]);
+    }
+}
@@ -0,0 +1,98 @@
+use serde::Deserialize;
+
+/// How audio with more than one channel is mixed down to the single channel that's
+/// analysed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Downmix {
+    /// The average of every channel.
+    #[default]
+    Mono,
+    /// The average of the left and right channels, ignoring any others.
+    Mid,
+    /// The difference between the left and right channels, which leaves out anything
+    /// panned to the centre, such as vocals, and keeps what's spread across the mix.
+    Side,
+    /// The mid as the audio, with the side analysed separately alongside it to tell
+    /// how wide each band of the mix is.
+    MidSide,
+}
+
+impl Downmix {
+    /// Mix interleaved frames of `channels` samples down into `samples`, returning how
+    /// wide the stereo image is from 0 for mono to 1 for only a difference between left
+    /// and right, if there's a stereo pair. For [`Downmix::MidSide`], the side goes
+    /// into `sides`, which is otherwise left empty.
+    pub fn mix(self, interleaved: &[f32], channels: usize, samples: &mut Vec<f32>, sides: &mut Vec<f32>) -> Option<f32> {
+        samples.clear();
+        sides.clear();
+        let channels = channels.max(1);
+        if channels == 1 {
+            samples.extend_from_slice(interleaved);
+            return None;
+        }
+        let (mut mid_energy, mut side_energy) = (0.0, 0.0);
+        for frame in interleaved.chunks_exact(channels) {
+            let mid = (frame[0] + frame[1]) / 2.0;
+            let side = (frame[0] - frame[1]) / 2.0;
+            mid_energy += mid * mid;
+            side_energy += side * side;
+            samples.push(match self {
+                Downmix::Mono => frame.iter().sum::<f32>() / channels as f32,
+                Downmix::Mid | Downmix::MidSide => mid,
+                Downmix::Side => side,
+            });
+            if self == Downmix::MidSide {
+                sides.push(side);
+            }
+        }
+        let (mid, side) = (f32::sqrt(mid_energy), f32::sqrt(side_energy));
+        (mid + side > 0.0).then(|| side / (mid + side))
+    }
+}
+
+/// Boost the bands towards either end of the layout by up to `amount` times how wide
+/// the mix is, so a wide mix livens up the edge panels. With the side analysed, each
+/// band is boosted by how wide that band is, otherwise all of them by `width`.
+pub fn widen(bands: &mut [f32], sides: Option<&[f32]>, width: f32, amount: f32) {
+    let last = bands.len().saturating_sub(1).max(1) as f32;
+    for (index, band) in bands.iter_mut().enumerate() {
+        let edge = (index as f32 * 2.0 / last - 1.0).abs();
+        let width = match sides.and_then(|sides| sides.get(index)) {
+            Some(side) if *band + side > 0.0 => side / (*band + side),
+            Some(_) => 0.0,
+            None => width,
+        };
+        *band *= 1.0 + amount * width * edge;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mix() {
+        let (mut samples, mut sides) = (Vec::new(), Vec::new());
+        // Identical channels are as narrow as can be.
+        assert_eq!(Downmix::Mono.mix(&[0.5, 0.5, -0.5, -0.5], 2, &mut samples, &mut sides), Some(0.0));
+        assert_eq!(samples, vec![0.5, -0.5]);
+        // Opposite channels cancel out in the mid, leaving only the side.
+        assert_eq!(Downmix::Side.mix(&[0.5, -0.5], 2, &mut samples, &mut sides), Some(1.0));
+        assert_eq!(samples, vec![0.5]);
+        assert_eq!(Downmix::Mid.mix(&[0.5, -0.5], 2, &mut samples, &mut sides), Some(1.0));
+        assert_eq!(samples, vec![0.0]);
+        assert!(sides.is_empty());
+        // Or both, side by side.
+        Downmix::MidSide.mix(&[0.5, 0.1, 0.2, 0.2], 2, &mut samples, &mut sides);
+        assert_eq!((samples, sides), (vec![0.3, 0.2], vec![0.2, 0.0]));
+
+        let mut bands = [1.0, 1.0, 1.0];
+        widen(&mut bands, None, 1.0, 0.5);
+        assert_eq!(bands, [1.5, 1.0, 1.5]);
+        // Only the bands that are wide are boosted.
+        let mut bands = [1.0, 1.0, 1.0];
+        widen(&mut bands, Some(&[1.0, 1.0, 0.0]), 1.0, 0.5);
+        assert_eq!(bands, [1.25, 1.0, 1.0]);
+    }
+}
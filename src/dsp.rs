@@ -0,0 +1,150 @@
+use std::f32::consts::PI;
+
+/**
+ * Cutoff for the microphone high-pass filter. Removes rumble, handling noise and
+ * mains hum that would otherwise swamp the bass bands.
+ */
+const MIC_HIGH_PASS_FREQ: f32 = 120.0;
+
+/**
+ * RMS level the gain control steers towards.
+ */
+const AGC_TARGET_RMS: f32 = 0.1;
+
+/**
+ * Limits on the applied gain, so silence isn't amplified into noise.
+ */
+const AGC_MIN_GAIN: f32 = 0.5;
+const AGC_MAX_GAIN: f32 = 40.0;
+
+/**
+ * Envelope time constants in seconds. The envelope rises quickly so sudden loud
+ * sounds don't clip, and falls slowly so the room doesn't "breathe" between beats.
+ */
+const AGC_ATTACK_SECS: f32 = 0.01;
+const AGC_RELEASE_SECS: f32 = 2.0;
+
+/**
+ * Below this envelope level the input is treated as silence and the gain is held.
+ */
+const AGC_NOISE_FLOOR: f32 = 0.0005;
+
+/// A second order Butterworth high-pass filter.
+pub struct HighPassFilter {
+    cutoff: f32,
+    rate: u32,
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl HighPassFilter {
+    pub fn new(cutoff: f32) -> Self {
+        HighPassFilter {
+            cutoff,
+            rate: 0,
+            b: [0.0; 3],
+            a: [0.0; 2],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn configure(&mut self, rate: u32) {
+        let omega = 2.0 * PI * self.cutoff / rate as f32;
+        let alpha = omega.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos = omega.cos();
+        let a0 = 1.0 + alpha;
+        self.b = [(1.0 + cos) / 2.0 / a0, -(1.0 + cos) / a0, (1.0 + cos) / 2.0 / a0];
+        self.a = [-2.0 * cos / a0, (1.0 - alpha) / a0];
+        self.x = [0.0; 2];
+        self.y = [0.0; 2];
+        self.rate = rate;
+    }
+
+    pub fn process(&mut self, samples: &mut [f32], rate: u32) {
+        if rate != self.rate {
+            self.configure(rate);
+        }
+        for sample in samples.iter_mut() {
+            let input = *sample;
+            let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+                - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+            self.x = [input, self.x[0]];
+            self.y = [output, self.y[0]];
+            *sample = output;
+        }
+    }
+}
+
+/// Tracks the loudness of the input and scales it towards a constant level.
+pub struct AutomaticGainControl {
+    envelope: f32,
+    gain: f32,
+}
+
+impl AutomaticGainControl {
+    pub fn new() -> Self {
+        AutomaticGainControl {
+            envelope: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32], rate: u32) {
+        let attack = 1.0 - f32::exp(-1.0 / (AGC_ATTACK_SECS * rate as f32));
+        let release = 1.0 - f32::exp(-1.0 / (AGC_RELEASE_SECS * rate as f32));
+        for sample in samples.iter_mut() {
+            let level = sample.abs();
+            let coefficient = if level > self.envelope { attack } else { release };
+            self.envelope += (level - self.envelope) * coefficient;
+            if self.envelope > AGC_NOISE_FLOOR {
+                self.gain = (AGC_TARGET_RMS / self.envelope).clamp(AGC_MIN_GAIN, AGC_MAX_GAIN);
+            }
+            *sample = (*sample * self.gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+/// Processing applied to room audio picked up by a microphone before analysis.
+pub struct MicProcessor {
+    high_pass: HighPassFilter,
+    agc: AutomaticGainControl,
+}
+
+impl MicProcessor {
+    pub fn new() -> Self {
+        MicProcessor {
+            high_pass: HighPassFilter::new(MIC_HIGH_PASS_FREQ),
+            agc: AutomaticGainControl::new(),
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32], rate: u32) {
+        self.high_pass.process(samples, rate);
+        self.agc.process(samples, rate);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+        let mut filter = HighPassFilter::new(MIC_HIGH_PASS_FREQ);
+        let mut samples = vec![0.5f32; 48000];
+        filter.process(&mut samples, 48000);
+        assert!(samples.last().unwrap().abs() < 0.001, "DC offset should be filtered out");
+    }
+
+    #[test]
+    fn test_agc_boosts_quiet_input() {
+        let mut agc = AutomaticGainControl::new();
+        let mut samples: Vec<f32> = (0..96000).map(|i| (i as f32 * 0.05).sin() * 0.01).collect();
+        agc.process(&mut samples, 48000);
+        assert!(agc.gain > 5.0, "Quiet input should be amplified, gain was {}", agc.gain);
+        assert!(agc.gain <= AGC_MAX_GAIN);
+    }
+}
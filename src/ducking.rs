@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use colors_transform::Hsl;
+use serde::Deserialize;
+
+use crate::clock;
+use crate::effects::{decay, PostProcess};
+use crate::oklab::ColorSpace;
+
+/**
+ * How far the lights are dimmed while a call is active, as a fraction of the effect's
+ * lightness.
+ */
+const DIM_LIGHTNESS: f32 = 0.3;
+
+/**
+ * How much of the way towards the target ducking level to move per frame (scaled to
+ * how long frames actually take), so the lights ease in and out of a call rather
+ * than snapping.
+ */
+const DUCK_EASING: f32 = 0.15;
+
+/// What to do with the lights while a call (a PipeWire stream with the
+/// "Communication" role) is active.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CallPolicy {
+    /// Carry on as normal.
+    Ignore,
+    /// Keep the effect running, but dimmed.
+    #[default]
+    Dim,
+    /// Hold the colours from when the call started, dimmed, until the call ends.
+    Steady,
+}
+
+pub struct Ducking {
+    policy: CallPolicy,
+    call_active: Arc<AtomicBool>,
+    /// 0.0 is not ducked at all, 1.0 is fully ducked.
+    level: f32,
+    held: Option<Vec<Option<Hsl>>>,
+    color_space: ColorSpace,
+    last_frame: Option<Instant>,
+}
+
+impl Ducking {
+    pub fn new(policy: CallPolicy, call_active: Arc<AtomicBool>, color_space: ColorSpace) -> Self {
+        Ducking {
+            policy,
+            call_active,
+            level: 0.0,
+            held: None,
+            color_space,
+            last_frame: None,
+        }
+    }
+}
+
+impl PostProcess for Ducking {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        if self.policy == CallPolicy::Ignore {
+            return;
+        }
+        let active = self.call_active.load(Ordering::Relaxed);
+        let target = if active { 1.0 } else { 0.0 };
+        let now = clock::now();
+        let delta = self.last_frame.replace(now).map_or(Duration::ZERO, |last| now.saturating_duration_since(last));
+        self.level += (target - self.level) * (1.0 - decay(1.0 - DUCK_EASING, delta));
+        if self.level < 0.01 {
+            self.level = 0.0;
+        }
+
+        if self.policy == CallPolicy::Steady {
+            if active {
+                let held = self.held.get_or_insert_with(|| colors.clone());
+                colors.clone_from(held);
+            } else if self.level == 0.0 && self.held.take().is_some() {
+                log::info!("Call ended, resuming effect");
+            }
+        }
+
+        if self.level == 0.0 {
+            return;
+        }
+        let scale = 1.0 - (1.0 - DIM_LIGHTNESS) * self.level;
+        for hsl in colors.iter_mut().flatten() {
+            *hsl = self.color_space.scale(hsl, scale);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use colors_transform::Hsl;
+
+use crate::chroma::{self, Chroma};
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::slidingwindow::SlidingWindow;
+
+use super::{audio_intensity, decay, Effect, EffectInput};
+
+/**
+ * How much of each new chromagram is blended into the running average each frame, at
+ * the default frame rate. Lower values keep the detected key stable across short passing notes.
+ */
+const CHROMA_SMOOTHING: f32 = 0.15;
+
+/**
+ * How many of the strongest pitch classes are treated as the current chord.
+ */
+const CHORD_SIZE: usize = 3;
+
+/// Colours the wall by the detected musical key, with each panel picking up one of the
+/// notes of the current chord.
+pub struct ChromaEffect {
+    window: SlidingWindow,
+    chroma: Chroma,
+    intensity_modifier: f32,
+}
+
+impl ChromaEffect {
+    pub fn new(intensity_modifier: f32) -> Self {
+        ChromaEffect {
+            window: SlidingWindow::new(64),
+            chroma: [0.0; 12],
+            intensity_modifier,
+        }
+    }
+}
+
+impl Effect for ChromaEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        for (smoothed, new) in self.chroma.iter_mut().zip(input.chroma.iter()) {
+            *smoothed += (new - *smoothed) * (1.0 - decay(1.0 - CHROMA_SMOOTHING, input.delta));
+        }
+        let key = chroma::detect_key(&self.chroma);
+        let key_hue = chroma::key_hue(&key);
+        let chord = chroma::strongest_pitches(&self.chroma);
+        // Weakly correlated keys get washed out, so noise doesn't look like a key change.
+        let saturation = (key.confidence.max(0.0) * 100.0).clamp(30.0, 100.0);
+        log::trace!("Detected key {:?}", key);
+
+        (0..panels.len()).map(|panel_index| {
+            let energy = *input.audio.get(panel_index)?;
+            let (min, max) = self.window.submit_new(energy);
+            let note_hue = chroma::pitch_hue(chord[panel_index % CHORD_SIZE]);
+            // Lean towards the key colour so the wall stays coherent.
+            let offset = ((note_hue - key_hue + 540.0) % 360.0) - 180.0;
+            let hue = (key_hue + offset * 0.3 + 360.0) % 360.0;
+            let intensity = audio_intensity(30.0, energy, min, max, self.intensity_modifier, panel_index);
+            Some(Hsl::from(hue, saturation, intensity))
+        }).collect()
+    }
+
+    fn set_intensity(&mut self, intensity_modifier: f32) {
+        self.intensity_modifier = intensity_modifier;
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        vec![&mut self.window]
+    }
+}
@@ -0,0 +1,115 @@
+use colors_transform::{Color, Hsl, Rgb};
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::oklab::ColorSpace;
+use crate::slidingwindow::SlidingWindow;
+
+use super::peak::PeakHoldConfig;
+use super::{decay, to_rgb, Effect, EffectInput, ScreenEffect, SpectrumEffect};
+
+/**
+ * How much of the way towards the new balance between screen and audio to move per
+ * frame at the default frame rate, so the panels drift from one source to the other rather than jumping.
+ */
+const CROSSFADE_EASING: f32 = 0.03;
+
+/**
+ * Frames of total audio energy to judge how loud the music currently is against.
+ */
+const LOUDNESS_WINDOW: usize = 512;
+
+/// How confident we are that the audio is worth showing, from 0 to 1: how loud it is
+/// compared to the recent past.
+pub(super) fn audio_confidence(energy: f32, (min, max): (f32, f32)) -> f32 {
+    if max <= min {
+        return 0.0;
+    }
+    ((energy - min) / (max - min)).clamp(0.0, 1.0)
+}
+
+/// How confident we are that the screen is worth showing, from 0 to 1: half from how
+/// saturated its colours are, and half from how much their hues vary across panels.
+fn screen_confidence(colors: &[Hsl]) -> f32 {
+    if colors.is_empty() {
+        return 0.0;
+    }
+    let count = colors.len() as f32;
+    let saturation = colors.iter().map(|hsl| hsl.get_saturation() / 100.0).sum::<f32>() / count;
+    // One minus the length of the mean hue vector, which is 0 when every hue matches.
+    let (x, y) = colors.iter()
+        .map(|hsl| hsl.get_hue().to_radians())
+        .fold((0.0, 0.0), |(x, y), hue| (x + hue.cos(), y + hue.sin()));
+    let spread = 1.0 - (x / count).hypot(y / count);
+    (saturation * 0.5 + spread.clamp(0.0, 1.0) * 0.5).clamp(0.0, 1.0)
+}
+
+/// Shows the screen during quiet scenes and the spectrum during loud music, crossfading
+/// between them depending on which source has more to show.
+pub struct CrossfadeEffect {
+    screen: ScreenEffect,
+    spectrum: SpectrumEffect,
+    loudness: SlidingWindow,
+    /// How much of the output comes from the audio, from 0 to 1.
+    audio_share: f32,
+    color_space: ColorSpace,
+}
+
+impl CrossfadeEffect {
+    pub fn new(intensity_modifier: f32, peak_hold: PeakHoldConfig, color_space: ColorSpace) -> Self {
+        CrossfadeEffect {
+            screen: ScreenEffect::new(intensity_modifier),
+            spectrum: SpectrumEffect::new(intensity_modifier, peak_hold),
+            loudness: SlidingWindow::new(LOUDNESS_WINDOW),
+            audio_share: 0.5,
+            color_space,
+        }
+    }
+}
+
+impl Effect for CrossfadeEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        let energy: f32 = input.audio.iter().sum();
+        let audio = audio_confidence(energy, self.loudness.submit_new(energy));
+        let screen = screen_confidence(input.colors);
+        let target = if audio + screen > 0.0 { audio / (audio + screen) } else { 0.5 };
+        self.audio_share += (target - self.audio_share) * (1.0 - decay(1.0 - CROSSFADE_EASING, input.delta));
+
+        let screen_colors = self.screen.render(input, panels);
+        let spectrum_colors = self.spectrum.render(input, panels);
+        screen_colors.into_iter().zip(spectrum_colors).map(|(screen, spectrum)| match (screen, spectrum) {
+            (Some(screen), Some(spectrum)) => {
+                let [r, g, b] = self.color_space.mix(to_rgb(&screen), to_rgb(&spectrum), self.audio_share);
+                Some(Rgb::from(r as f32, g as f32, b as f32).to_hsl())
+            },
+            (screen, spectrum) => screen.or(spectrum),
+        }).collect()
+    }
+
+    fn set_intensity(&mut self, intensity_modifier: f32) {
+        self.screen.set_intensity(intensity_modifier);
+        self.spectrum.set_intensity(intensity_modifier);
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        let mut windows = vec![&mut self.loudness];
+        windows.extend(self.screen.windows());
+        windows.extend(self.spectrum.windows());
+        windows
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_screen_confidence() {
+        let grey = [Hsl::from(0.0, 0.0, 50.0); 4];
+        let same = [Hsl::from(200.0, 100.0, 50.0); 4];
+        let varied = [0.0, 90.0, 180.0, 270.0].map(|hue| Hsl::from(hue, 100.0, 50.0));
+        assert_eq!(screen_confidence(&grey), 0.0);
+        assert!((screen_confidence(&same) - 0.5).abs() < 0.001);
+        assert!(screen_confidence(&varied) > 0.99);
+        assert_eq!(screen_confidence(&[]), 0.0);
+    }
+}
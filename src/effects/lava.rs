@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use colors_transform::{Color, Hsl, Rgb};
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::noise::simplex;
+use crate::oklab::ColorSpace;
+use crate::scene::gradient_at;
+
+use super::{to_rgb, Effect, EffectInput};
+
+/**
+ * How many screen colours to remember, and how often to take one. With both, the
+ * history covers the last minute or so.
+ */
+const HISTORY_LEN: usize = 24;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(3);
+
+/**
+ * How far each panel moves through the history per second, where 1 is the whole
+ * history.
+ */
+const DRIFT: f32 = 0.004;
+
+/**
+ * How far apart the panels at either end of the layout are in the history.
+ */
+const SPREAD: f32 = 0.35;
+
+/**
+ * How far each panel wanders back and forth through the history, on top of drifting,
+ * and how many times a second its direction changes, roughly.
+ */
+const WANDER: f32 = 0.15;
+const WANDER_RATE: f32 = 0.05;
+
+/**
+ * How much each panel's brightness swells and ebbs, and how quickly.
+ */
+const BREATHE: f32 = 0.3;
+const BREATHE_RATE: f32 = 0.08;
+
+/**
+ * Distance between the rows of noise each panel follows, far enough apart that panels
+ * move independently.
+ */
+const NOISE_ROW: f32 = 3.7;
+
+/**
+ * Lightness the colours are kept within, so the panels never blind or go dark.
+ */
+const MIN_LIGHTNESS: f32 = 15.0;
+const MAX_LIGHTNESS: f32 = 50.0;
+
+/**
+ * Colours to drift through until the screen has given some of its own.
+ */
+const LAVA: [[u8; 3]; 3] = [[255, 60, 0], [200, 20, 40], [255, 140, 0]];
+
+/// Keeps a rolling history of the screen's colours, and slowly drifts each panel
+/// through it, wandering back and forth. Follows what's been on screen over the last
+/// minute or so rather than what's there now, and ignores the audio.
+pub struct LavaEffect {
+    history: VecDeque<[u8; 3]>,
+    /// When the last colour was taken, by the effect's time.
+    last_sample: Option<Duration>,
+    color_space: ColorSpace,
+}
+
+impl LavaEffect {
+    pub fn new(color_space: ColorSpace) -> Self {
+        LavaEffect {
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            last_sample: None,
+            color_space,
+        }
+    }
+
+    /// Remember the most saturated colour on screen, every so often. Colours that stay
+    /// on screen longer then take up more of the history.
+    fn sample(&mut self, colors: &[Hsl], now: Duration) {
+        if self.last_sample.is_some_and(|last| now.saturating_sub(last) < SAMPLE_INTERVAL) {
+            return;
+        }
+        let Some(color) = colors.iter().max_by(|a, b| a.get_saturation().total_cmp(&b.get_saturation())) else {
+            return;
+        };
+        self.last_sample = Some(now);
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(to_rgb(color));
+    }
+}
+
+impl Effect for LavaEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        self.sample(input.colors, input.time);
+        let history: Vec<[u8; 3]> = if self.history.is_empty() { LAVA.to_vec() } else { self.history.iter().copied().collect() };
+        let elapsed = input.time.as_secs_f32();
+        let count = panels.len().max(1) as f32;
+        (0..panels.len()).map(|panel_index| {
+            // Each panel wanders and breathes along its own rows of the noise.
+            let row = panel_index as f32 * 2.0 * NOISE_ROW;
+            let position = elapsed * DRIFT
+                + panel_index as f32 / count * SPREAD
+                + simplex(elapsed * WANDER_RATE, row) * WANDER;
+            let [r, g, b] = gradient_at(&history, position.rem_euclid(1.0), true, self.color_space);
+            let color = Rgb::from(r as f32, g as f32, b as f32).to_hsl();
+            let breath = 1.0 + simplex(elapsed * BREATHE_RATE, row + NOISE_ROW) * BREATHE;
+            let lightness = (color.get_lightness() * breath).clamp(MIN_LIGHTNESS, MAX_LIGHTNESS);
+            Some(self.color_space.with_lightness(&color, lightness))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chroma::Chroma;
+    use crate::panel_graph::PanelGraph;
+
+    #[test]
+    fn test_lava_follows_history() {
+        let panels: Vec<NanoleafLayoutPanelData> = (0..4).map(|i| NanoleafLayoutPanelData { panel_id: i, x: i as usize * 100, y: 0, shape_type: 2, orientation: 0 }).collect();
+        let graph = PanelGraph::new(&panels, 100);
+        let chroma: Chroma = [0.0; 12];
+        let input = |colors, secs| EffectInput { audio: &[], beat: false, colors, accents: &[], chroma: &chroma, graph: &graph, time: Duration::from_secs(secs), delta: Duration::ZERO };
+        let mut effect = LavaEffect::new(ColorSpace::Hsl);
+
+        // Lava colours until the screen has shown something.
+        let colors = effect.render(&input(&[], 0), &panels);
+        assert!(colors.iter().flatten().all(|color| color.get_hue() < 40.0 || color.get_hue() > 340.0));
+
+        let blue = [Hsl::from(230.0, 100.0, 50.0), Hsl::from(0.0, 0.0, 50.0)];
+        for secs in 0..3 {
+            effect.render(&input(&blue, secs), &panels);
+        }
+        // Only sampled once per interval, and the greys are passed over.
+        assert_eq!(effect.history.len(), 1);
+        assert_eq!(effect.history[0], to_rgb(&blue[0]));
+
+        // Long after the screen goes dark, the panels still drift through its blues.
+        let colors = effect.render(&input(&[], 63), &panels);
+        for color in colors.iter().flatten() {
+            assert!((color.get_hue() - 230.0).abs() < 1.0);
+            assert!((MIN_LIGHTNESS..=MAX_LIGHTNESS).contains(&color.get_lightness()));
+        }
+    }
+}
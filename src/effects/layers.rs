@@ -0,0 +1,255 @@
+use std::time::Duration;
+
+use colors_transform::{Color, Hsl, Rgb};
+use serde::Deserialize;
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::oklab::ColorSpace;
+use crate::slidingwindow::SlidingWindow;
+
+use super::crossfade::audio_confidence;
+use super::peak::PeakHoldConfig;
+use super::{decay, frames, new_effect, to_rgb, Effect, EffectInput, EffectKind};
+
+/// How a layer is combined with the layers below it.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    /// Covers the layers below, as far as its opacity allows.
+    #[default]
+    Alpha,
+    /// Adds its light to the layers below, only ever brightening them.
+    Add,
+    /// Tints and darkens the layers below, only ever dimming them.
+    Multiply,
+    /// Brightens what's bright below and darkens what's dark, keeping contrast.
+    Overlay,
+}
+
+impl BlendMode {
+    /// Blend a channel of the layer, `top`, over the same channel below it, `base`,
+    /// each from 0 to 1.
+    fn blend(self, base: f32, top: f32) -> f32 {
+        match self {
+            BlendMode::Alpha => top,
+            BlendMode::Add => (base + top).min(1.0),
+            BlendMode::Multiply => base * top,
+            BlendMode::Overlay if base < 0.5 => 2.0 * base * top,
+            BlendMode::Overlay => 1.0 - 2.0 * (1.0 - base) * (1.0 - top),
+        }
+    }
+}
+
+/**
+ * How far back to judge the current levels against: the last second for the bass, and
+ * the last minute for how loud it is overall. Windows hold a value for each frame of
+ * [`RATE_FRAME`](super::RATE_FRAME).
+ */
+const BASS_WINDOW: Duration = Duration::from_secs(1);
+const LOUDNESS_WINDOW: Duration = Duration::from_secs(60);
+
+/**
+ * How much of the beat envelope is left after each frame of [`RATE_FRAME`](super::RATE_FRAME).
+ */
+const BEAT_DECAY: f32 = 0.8;
+
+/// Something about the audio a layer's opacity can follow.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFeature {
+    /// The energy of the lowest band, against the last second or so.
+    Bass,
+    /// Jumps up on each beat, then fades away.
+    Beat,
+    /// The energy across every band, against the last minute or so.
+    Loudness,
+}
+
+/// The audio features layers can follow, each from 0 to 1.
+struct AudioFeatures {
+    bass_window: SlidingWindow,
+    loudness_window: SlidingWindow,
+    bass: f32,
+    beat: f32,
+    loudness: f32,
+}
+
+impl AudioFeatures {
+    fn new() -> Self {
+        AudioFeatures {
+            bass_window: SlidingWindow::new(frames(BASS_WINDOW).round() as usize),
+            loudness_window: SlidingWindow::new(frames(LOUDNESS_WINDOW).round() as usize),
+            bass: 0.0,
+            beat: 0.0,
+            loudness: 0.0,
+        }
+    }
+
+    fn update(&mut self, input: &EffectInput) {
+        let bass = input.audio.first().copied().unwrap_or(0.0);
+        self.bass = audio_confidence(bass, self.bass_window.submit_new(bass));
+        let loudness: f32 = input.audio.iter().sum();
+        self.loudness = audio_confidence(loudness, self.loudness_window.submit_new(loudness));
+        self.beat = if input.beat { 1.0 } else { self.beat * decay(BEAT_DECAY, input.delta) };
+    }
+
+    fn get(&self, feature: AudioFeature) -> f32 {
+        match feature {
+            AudioFeature::Bass => self.bass,
+            AudioFeature::Beat => self.beat,
+            AudioFeature::Loudness => self.loudness,
+        }
+    }
+}
+
+fn default_opacity() -> f32 {
+    1.0
+}
+
+/// An effect drawn as one layer of `--effect layers`, from the bottom up.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LayerConfig {
+    pub effect: EffectKind,
+    #[serde(default)]
+    pub blend: BlendMode,
+    /// How much of the blended layer shows, from 0 to 1.
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    /// Move the opacity with the audio, from `min_opacity` while the feature is at or
+    /// below `threshold`, up to `opacity` as it peaks.
+    #[serde(default)]
+    pub follow: Option<AudioFeature>,
+    #[serde(default)]
+    pub min_opacity: f32,
+    #[serde(default)]
+    pub threshold: f32,
+}
+
+impl LayerConfig {
+    fn new(effect: EffectKind) -> Self {
+        LayerConfig { effect, blend: BlendMode::Alpha, opacity: 1.0, follow: None, min_opacity: 0.0, threshold: 0.0 }
+    }
+
+    /// The layer's opacity, given the current audio features.
+    fn opacity(&self, features: &AudioFeatures) -> f32 {
+        let Some(feature) = self.follow else {
+            return self.opacity;
+        };
+        let threshold = self.threshold.clamp(0.0, 0.99);
+        let level = ((features.get(feature) - threshold) / (1.0 - threshold)).clamp(0.0, 1.0);
+        self.min_opacity + (self.opacity - self.min_opacity) * level
+    }
+}
+
+/// Stacks effects on top of each other, blending each over the ones below. Panels no
+/// layer has lit yet count as black.
+pub struct LayeredEffect {
+    layers: Vec<(LayerConfig, Box<dyn Effect>)>,
+    features: AudioFeatures,
+    color_space: ColorSpace,
+}
+
+impl LayeredEffect {
+    pub fn new(layers: &[LayerConfig], intensity_modifier: f32, peak_hold: PeakHoldConfig, color_space: ColorSpace) -> Self {
+        let mut layers: Vec<LayerConfig> = layers.iter().filter(|layer| {
+            if layer.effect == EffectKind::Layers {
+                log::warn!("Layers can't contain more layers, skipping");
+            }
+            layer.effect != EffectKind::Layers
+        }).cloned().collect();
+        if layers.is_empty() {
+            log::warn!("No [[layers]] configured, showing the screen alone");
+            layers.push(LayerConfig::new(EffectKind::Screen));
+        }
+        LayeredEffect {
+            layers: layers.into_iter().map(|layer| {
+                let effect = new_effect(layer.effect, intensity_modifier, peak_hold.clone(), color_space, &[]);
+                (layer, effect)
+            }).collect(),
+            features: AudioFeatures::new(),
+            color_space,
+        }
+    }
+}
+
+/// Blend `top` over `base` with `mode`, showing `opacity` of the result mixed in
+/// `color_space`.
+fn blend(base: Option<Hsl>, top: Option<Hsl>, mode: BlendMode, opacity: f32, color_space: ColorSpace) -> Option<Hsl> {
+    let Some(top) = top else {
+        return base;
+    };
+    let base_rgb = base.as_ref().map_or([0; 3], to_rgb);
+    let top_rgb = to_rgb(&top);
+    let blended = [0, 1, 2].map(|c| {
+        let (below, above) = (base_rgb[c] as f32 / 255.0, top_rgb[c] as f32 / 255.0);
+        (mode.blend(below, above) * 255.0).round() as u8
+    });
+    let [r, g, b] = color_space.mix(base_rgb, blended, opacity.clamp(0.0, 1.0));
+    Some(Rgb::from(r as f32, g as f32, b as f32).to_hsl())
+}
+
+impl Effect for LayeredEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        self.features.update(input);
+        let mut colors = vec![None; panels.len()];
+        for (layer, effect) in self.layers.iter_mut() {
+            // Layers render even while hidden, so they don't jump when they reappear.
+            let layer_colors = effect.render(input, panels);
+            let opacity = layer.opacity(&self.features);
+            for (color, top) in colors.iter_mut().zip(layer_colors) {
+                *color = blend(*color, top, layer.blend, opacity, self.color_space);
+            }
+        }
+        colors
+    }
+
+    fn set_intensity(&mut self, intensity_modifier: f32) {
+        for (_, effect) in self.layers.iter_mut() {
+            effect.set_intensity(intensity_modifier);
+        }
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        let mut windows = vec![&mut self.features.bass_window, &mut self.features.loudness_window];
+        windows.extend(self.layers.iter_mut().flat_map(|(_, effect)| effect.windows()));
+        windows
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rgb(color: Option<Hsl>) -> [u8; 3] {
+        to_rgb(&color.unwrap())
+    }
+
+    #[test]
+    fn test_blend_layers() {
+        let red = Some(Rgb::from(200.0, 0.0, 0.0).to_hsl());
+        let grey = Some(Rgb::from(128.0, 128.0, 128.0).to_hsl());
+        assert_eq!(blend(red, None, BlendMode::Add, 1.0, ColorSpace::Hsl), red);
+        assert_eq!(blend(None, None, BlendMode::Alpha, 1.0, ColorSpace::Hsl), None);
+        // Panels nothing has lit yet are black underneath.
+        assert_eq!(rgb(blend(None, red, BlendMode::Alpha, 0.5, ColorSpace::Hsl)), [100, 0, 0]);
+        assert_eq!(rgb(blend(red, grey, BlendMode::Add, 1.0, ColorSpace::Hsl)), [255, 128, 128]);
+        assert_eq!(rgb(blend(red, grey, BlendMode::Multiply, 1.0, ColorSpace::Hsl)), [100, 0, 0]);
+        assert_eq!(rgb(blend(red, grey, BlendMode::Multiply, 0.5, ColorSpace::Hsl)), [150, 0, 0]);
+        assert_eq!(rgb(blend(red, grey, BlendMode::Overlay, 1.0, ColorSpace::Hsl)), [200, 0, 0]);
+
+        let layers = [LayerConfig::new(EffectKind::Lava), LayerConfig::new(EffectKind::Layers)];
+        let effect = LayeredEffect::new(&layers, 1.0, Default::default(), ColorSpace::Hsl);
+        assert_eq!(effect.layers.len(), 1);
+
+        // Following the beat, a layer shows fully on the beat, then fades out once the
+        // envelope falls below the threshold.
+        let strobe = LayerConfig { follow: Some(AudioFeature::Beat), threshold: 0.5, min_opacity: 0.1, ..LayerConfig::new(EffectKind::Splat) };
+        let mut features = AudioFeatures::new();
+        features.beat = 1.0;
+        assert_eq!(strobe.opacity(&features), 1.0);
+        features.beat = 0.75;
+        assert!((strobe.opacity(&features) - 0.55).abs() < 0.001);
+        features.beat = 0.3;
+        assert_eq!(strobe.opacity(&features), 0.1);
+    }
+}
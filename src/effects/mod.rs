@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+use colors_transform::{Color, Hsl};
+use serde::Deserialize;
+
+use crate::panel_graph::PanelGraph;
+use crate::chroma::Chroma;
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::oklab::ColorSpace;
+use crate::slidingwindow::SlidingWindow;
+use crate::LIGHT_INTERVAL;
+
+mod chroma;
+mod crossfade;
+mod layers;
+mod lava;
+mod peak;
+mod ripple;
+mod screen;
+mod spectrogram;
+mod spectrum;
+mod splat;
+
+pub use self::chroma::ChromaEffect;
+pub use self::crossfade::CrossfadeEffect;
+pub use self::layers::{LayerConfig, LayeredEffect};
+pub use self::lava::LavaEffect;
+pub use self::peak::PeakHoldConfig;
+pub use self::ripple::RippleEffect;
+pub use self::screen::ScreenEffect;
+pub use self::spectrogram::SpectrogramEffect;
+pub use self::spectrum::SpectrumEffect;
+pub use self::splat::SplatEffect;
+
+/**
+ * The length of frame that effects' per frame rates are given for. Rates are scaled to
+ * the actual time between frames, so effects move at the same speed whatever the send
+ * interval.
+ */
+const RATE_FRAME: Duration = LIGHT_INTERVAL;
+
+/// How many frames of [`RATE_FRAME`] fit into `delta`.
+pub fn frames(delta: Duration) -> f32 {
+    delta.as_secs_f32() / RATE_FRAME.as_secs_f32()
+}
+
+/// How much is left after `delta` of something that keeps `rate` of itself each frame.
+pub fn decay(rate: f32, delta: Duration) -> f32 {
+    rate.powf(frames(delta))
+}
+
+/// The colours picked from the screen for each panel's region, in the same order as
+/// the panels.
+#[derive(Debug, Clone, Default)]
+pub struct ScreenColors {
+    /// The most prominent colour of each region.
+    pub primary: Vec<Hsl>,
+    /// A clearly different colour that also stands out in each region, if there is one.
+    pub accent: Vec<Option<Hsl>>,
+}
+
+/// Everything an effect may draw upon when rendering a single frame.
+pub struct EffectInput<'a> {
+    /// Audio energy per panel, in the same order as the panels.
+    pub audio: &'a [f32],
+    /// Whether this frame starts a beat.
+    pub beat: bool,
+    /// Prominent screen colour per panel, in the same order as the panels.
+    pub colors: &'a [Hsl],
+    /// Accent screen colour per panel, in the same order as the panels.
+    pub accents: &'a [Option<Hsl>],
+    /// Pitch class energy of the current audio interval.
+    pub chroma: &'a Chroma,
+    /// Which panels touch each other, by their index in the panels.
+    pub graph: &'a PanelGraph,
+    /// How long the effect has been rendering, which only ever moves forward.
+    pub time: Duration,
+    /// Time since the previous frame, to animate by rather than counting frames.
+    pub delta: Duration,
+}
+
+pub trait Effect: Send {
+    /// Render a colour for each panel. Panels are sorted left to right, and a `None`
+    /// leaves the panel untouched for this frame.
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>>;
+
+    /// Change how strongly the audio drives the brightness, for effects that use it.
+    fn set_intensity(&mut self, _intensity_modifier: f32) {}
+
+    /// The windows the effect normalizes audio levels against, which are saved between
+    /// runs so it doesn't have to learn them again.
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        Vec::new()
+    }
+}
+
+/// A step applied to the output of the effect before it's sent to the lights, such
+/// as dimming during calls.
+pub trait PostProcess: Send {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>);
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EffectKind {
+    /// Colours from the screen, brightness from the audio.
+    Screen,
+    /// Colours from the musical key and chord, brightness from the audio.
+    Chroma,
+    /// A scrolling spectrogram across the rows and columns of a grid layout.
+    Spectrogram,
+    /// A fixed colour per band, from red for the bass to blue for the treble, with
+    /// brightness from the audio.
+    Spectrum,
+    /// Crossfades between `screen` and `spectrum`, following the screen during quiet
+    /// scenes and the music when it's loud.
+    Auto,
+    /// Rings of colour that spread out across the layout on each beat, from the panel
+    /// with the most bass.
+    Ripple,
+    /// Splats of colour thrown onto the loudest panel on each beat, bleeding into the
+    /// panels around them as they fade.
+    Splat,
+    /// Panels slowly drift through the colours the screen has shown over the last
+    /// minute or so, ignoring the audio.
+    Lava,
+    /// The effects listed under `[[layers]]`, stacked and blended from the bottom up.
+    Layers,
+}
+
+pub fn new_effect(kind: EffectKind, intensity_modifier: f32, peak_hold: PeakHoldConfig, color_space: ColorSpace, layers: &[LayerConfig]) -> Box<dyn Effect> {
+    match kind {
+        EffectKind::Screen => Box::new(ScreenEffect::new(intensity_modifier)),
+        EffectKind::Chroma => Box::new(ChromaEffect::new(intensity_modifier)),
+        EffectKind::Spectrogram => Box::new(SpectrogramEffect::new(peak_hold)),
+        EffectKind::Spectrum => Box::new(SpectrumEffect::new(intensity_modifier, peak_hold)),
+        EffectKind::Auto => Box::new(CrossfadeEffect::new(intensity_modifier, peak_hold, color_space)),
+        EffectKind::Ripple => Box::new(RippleEffect::new()),
+        EffectKind::Splat => Box::new(SplatEffect::new()),
+        EffectKind::Lava => Box::new(LavaEffect::new(color_space)),
+        EffectKind::Layers => Box::new(LayeredEffect::new(layers, intensity_modifier, peak_hold, color_space)),
+    }
+}
+
+/// A colour as whole sRGB channels, for effects that blend or remember colours in the
+/// form the panels are sent. Frames themselves go through [`crate::dither::to_rgb`].
+pub fn to_rgb(hsl: &Hsl) -> [u8; 3] {
+    let (r, g, b) = hsl.to_rgb().as_tuple();
+    [r, g, b].map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+}
+
+/// The brightness curve shared by the audio reactive effects. Panels further along
+/// the layout get a slight boost, as higher frequency bands carry less energy.
+pub fn audio_intensity(base: f32, energy: f32, min: f32, max: f32, intensity_modifier: f32, panel_index: usize) -> f32 {
+    (base + ((energy + min) / max) * intensity_modifier * (panel_index as f32 + 1.0f32).powf(1.05f32)).clamp(5.0, 80.0)
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::clock;
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_hold() -> f32 {
+    0.2
+}
+
+fn default_half_life() -> f32 {
+    0.25
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PeakHoldConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Seconds a band holds its peak before it starts to fall.
+    #[serde(default = "default_hold")]
+    pub hold: f32,
+    /// Seconds for a falling peak to halve.
+    #[serde(default = "default_half_life")]
+    pub half_life: f32,
+}
+
+impl Default for PeakHoldConfig {
+    fn default() -> Self {
+        PeakHoldConfig {
+            enabled: default_enabled(),
+            hold: default_hold(),
+            half_life: default_half_life(),
+        }
+    }
+}
+
+struct Peak {
+    value: f32,
+    /// How long since the peak was last raised.
+    age: Duration,
+}
+
+/// Remembers the recent peak of each band and lets it fall slowly, so short hits stay
+/// visible at the rate the lights update.
+pub struct PeakHold {
+    config: PeakHoldConfig,
+    peaks: Vec<Peak>,
+    last_update: Option<Instant>,
+}
+
+impl PeakHold {
+    pub fn new(config: PeakHoldConfig) -> Self {
+        PeakHold {
+            config,
+            peaks: Vec::new(),
+            last_update: None,
+        }
+    }
+
+    /// Replace each band's energy with its held peak, where that's higher.
+    pub fn apply(&mut self, bands: &mut [f32]) {
+        let now = clock::now();
+        let elapsed = self.last_update.map_or(Duration::ZERO, |last| now - last);
+        self.last_update = Some(now);
+        if self.config.enabled {
+            self.update(bands, elapsed);
+        }
+    }
+
+    fn update(&mut self, bands: &mut [f32], elapsed: Duration) {
+        let hold = Duration::from_secs_f32(self.config.hold.max(0.0));
+        self.peaks.resize_with(bands.len(), || Peak { value: 0.0, age: Duration::ZERO });
+        for (band, peak) in bands.iter_mut().zip(self.peaks.iter_mut()) {
+            peak.age += elapsed;
+            if peak.age > hold {
+                let falling = (peak.age - hold).min(elapsed).as_secs_f32();
+                peak.value *= 0.5f32.powf(falling / self.config.half_life.max(f32::EPSILON));
+            }
+            if *band >= peak.value {
+                peak.value = *band;
+                peak.age = Duration::ZERO;
+            } else {
+                *band = peak.value;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peak_hold() {
+        let mut peak_hold = PeakHold::new(PeakHoldConfig { enabled: true, hold: 0.2, half_life: 0.1 });
+        let interval = Duration::from_millis(100);
+        let held: Vec<f32> = [8.0, 0.0, 0.0, 0.0, 0.0, 5.0].iter().map(|energy| {
+            let mut bands = [*energy];
+            peak_hold.update(&mut bands, interval);
+            bands[0]
+        }).collect();
+        assert_eq!(held, vec![8.0, 8.0, 8.0, 4.0, 2.0, 5.0]);
+    }
+}
@@ -0,0 +1,97 @@
+use colors_transform::Hsl;
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::slidingwindow::SlidingWindow;
+
+use super::{frames, Effect, EffectInput};
+
+/**
+ * Panels a ripple travels outwards each frame, at the default frame rate.
+ */
+const RIPPLE_SPEED: f32 = 0.5;
+
+/**
+ * Frames a ripple lasts before it has faded out completely, at the default frame rate.
+ */
+const RIPPLE_LIFETIME: f32 = 16.0;
+
+/**
+ * How far round the colour wheel each new ripple moves.
+ */
+const RIPPLE_HUE_STEP: f32 = 40.0;
+
+struct Ripple {
+    /// Steps from the ripple's origin to each panel.
+    distances: Vec<Option<usize>>,
+    /// Frames since the ripple started, at the default frame rate.
+    age: f32,
+    hue: f32,
+    strength: f32,
+}
+
+/// Sends rings of colour out across the layout on each beat, starting from the panel
+/// with the most bass.
+pub struct RippleEffect {
+    window: SlidingWindow,
+    ripples: Vec<Ripple>,
+    hue: f32,
+}
+
+impl RippleEffect {
+    pub fn new() -> Self {
+        RippleEffect {
+            window: SlidingWindow::new(64),
+            ripples: Vec::new(),
+            hue: 0.0,
+        }
+    }
+}
+
+impl Effect for RippleEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        // Bands follow the panels, so the bass is in the first quarter of them.
+        let bass_bands = (input.audio.len().min(panels.len()) / 4).max(1);
+        let origin = input.audio.iter().take(bass_bands).enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(panel, energy)| (panel, *energy));
+
+        if let (true, Some((panel, energy))) = (input.beat, origin) {
+            let (min, max) = self.window.submit_new(energy);
+            let strength = if max > min { ((energy - min) / (max - min)).clamp(0.3, 1.0) } else { 1.0 };
+            self.hue = (self.hue + RIPPLE_HUE_STEP) % 360.0;
+            self.ripples.push(Ripple {
+                distances: input.graph.distances(panel),
+                age: 0.0,
+                hue: self.hue,
+                strength,
+            });
+        }
+
+        let mut colors = vec![Hsl::from(self.hue, 100.0, 5.0); panels.len()];
+        let mut brightest = vec![0.0f32; panels.len()];
+        for ripple in &self.ripples {
+            let radius = ripple.age * RIPPLE_SPEED;
+            let fade = 1.0 - ripple.age / RIPPLE_LIFETIME;
+            for (panel, distance) in ripple.distances.iter().enumerate().take(panels.len()) {
+                let Some(distance) = distance else {
+                    continue;
+                };
+                let level = ripple.strength * fade * (1.0 - (*distance as f32 - radius).abs()).max(0.0);
+                if level > brightest[panel] {
+                    brightest[panel] = level;
+                    colors[panel] = Hsl::from(ripple.hue, 100.0, 5.0 + level * 55.0);
+                }
+            }
+        }
+
+        for ripple in self.ripples.iter_mut() {
+            ripple.age += frames(input.delta);
+        }
+        self.ripples.retain(|ripple| ripple.age < RIPPLE_LIFETIME);
+        colors.into_iter().map(Some).collect()
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        vec![&mut self.window]
+    }
+}
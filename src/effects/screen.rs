@@ -0,0 +1,60 @@
+use colors_transform::{Color, Hsl};
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::slidingwindow::SlidingWindow;
+
+use super::{audio_intensity, decay, Effect, EffectInput};
+
+/**
+ * How much of a beat flash is left after each frame, at the default frame rate.
+ */
+const FLASH_DECAY: f32 = 0.5;
+
+/**
+ * Flashes show the accent colour until they've faded below this level.
+ */
+const FLASH_VISIBLE: f32 = 0.2;
+
+/// Takes the prominent colour of the screen region above each panel, and scales its
+/// lightness with the audio energy of the panel's band. On each beat, panels briefly
+/// flash the accent colour of their region.
+pub struct ScreenEffect {
+    window: SlidingWindow,
+    intensity_modifier: f32,
+    flash: f32,
+}
+
+impl ScreenEffect {
+    pub fn new(intensity_modifier: f32) -> Self {
+        ScreenEffect {
+            // Needs to be over a sliding window.
+            window: SlidingWindow::new(64),
+            intensity_modifier,
+            flash: 0.0,
+        }
+    }
+}
+
+impl Effect for ScreenEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        self.flash = if input.beat { 1.0 } else { self.flash * decay(FLASH_DECAY, input.delta) };
+        let flashing = self.flash > FLASH_VISIBLE;
+        (0..panels.len()).map(|panel_index| {
+            let primary = input.colors.get(panel_index)?;
+            let accent = input.accents.get(panel_index).copied().flatten().filter(|_| flashing);
+            let color = accent.as_ref().unwrap_or(primary);
+            let energy = *input.audio.get(panel_index)?;
+            let (min, max) = self.window.submit_new(energy);
+            let intensity = audio_intensity(color.get_lightness() - 10.0, energy, min, max, self.intensity_modifier, panel_index);
+            Some(Hsl::from(color.get_hue(), color.get_saturation(), intensity))
+        }).collect()
+    }
+
+    fn set_intensity(&mut self, intensity_modifier: f32) {
+        self.intensity_modifier = intensity_modifier;
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        vec![&mut self.window]
+    }
+}
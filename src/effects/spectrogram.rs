@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use colors_transform::Hsl;
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::slidingwindow::SlidingWindow;
+
+use super::peak::{PeakHold, PeakHoldConfig};
+use super::{Effect, EffectInput, RATE_FRAME};
+
+/**
+ * Hue of a quiet cell, fading towards 0 (red) as the cell gets louder.
+ */
+const QUIET_HUE: f32 = 240.0;
+
+/// A scrolling spectrogram for grid layouts. Each row of panels is a frequency band
+/// (bass at the bottom). The latest audio is written to the rightmost column, which
+/// scrolls off to the left once a frame at the default frame rate, whatever the send
+/// interval.
+pub struct SpectrogramEffect {
+    window: SlidingWindow,
+    peak_hold: PeakHold,
+    history: VecDeque<Vec<f32>>,
+    /// Time since the history last scrolled.
+    since_scroll: Duration,
+    /// Column and row of each panel, computed from the layout on the first frame.
+    grid: Option<Grid>,
+}
+
+struct Grid {
+    columns: Vec<usize>,
+    rows: Vec<usize>,
+    column_count: usize,
+    row_count: usize,
+}
+
+/// Group coordinates that are within a unit of each other into the same cell, returning
+/// the cell index of each value and the number of cells.
+fn group_positions(values: &[usize]) -> (Vec<usize>, usize) {
+    let mut distinct: Vec<usize> = values.to_vec();
+    distinct.sort();
+    distinct.dedup_by(|a, b| a.abs_diff(*b) <= 1);
+    let cells = values.iter().map(|value| {
+        distinct.iter().position(|d| d.abs_diff(*value) <= 1).unwrap_or(0)
+    }).collect();
+    (cells, distinct.len())
+}
+
+impl Grid {
+    fn new(panels: &[NanoleafLayoutPanelData]) -> Self {
+        let (columns, column_count) = group_positions(&panels.iter().map(|p| p.x).collect::<Vec<_>>());
+        let (rows, row_count) = group_positions(&panels.iter().map(|p| p.y).collect::<Vec<_>>());
+        Grid { columns, rows, column_count, row_count }
+    }
+}
+
+impl SpectrogramEffect {
+    pub fn new(peak_hold: PeakHoldConfig) -> Self {
+        SpectrogramEffect {
+            window: SlidingWindow::new(64),
+            peak_hold: PeakHold::new(peak_hold),
+            history: VecDeque::new(),
+            since_scroll: Duration::ZERO,
+            grid: None,
+        }
+    }
+}
+
+impl Effect for SpectrogramEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        let grid = self.grid.get_or_insert_with(|| Grid::new(panels));
+        if input.audio.is_empty() {
+            return vec![None; panels.len()];
+        }
+
+        // Fold the spectrum down to one band per row.
+        let mut bands: Vec<f32> = (0..grid.row_count).map(|row| {
+            let start = row * input.audio.len() / grid.row_count;
+            let end = ((row + 1) * input.audio.len() / grid.row_count).max(start + 1).min(input.audio.len());
+            input.audio[start..end].iter().sum::<f32>() / (end - start) as f32
+        }).collect();
+        self.peak_hold.apply(&mut bands);
+        let mut range = (0.0f32, 0.0f32);
+        for band in &bands {
+            range = self.window.submit_new(*band);
+        }
+        let (min, max) = range;
+
+        self.since_scroll += input.delta;
+        let scrolls = (self.since_scroll.as_nanos() / RATE_FRAME.as_nanos()) as usize;
+        if scrolls > 0 || self.history.is_empty() {
+            self.since_scroll = Duration::from_nanos((self.since_scroll.as_nanos() % RATE_FRAME.as_nanos()) as u64);
+            // A slow frame scrolls by every column it covers, filled with the latest
+            // audio, but never more than the grid holds.
+            for _ in 0..scrolls.clamp(1, grid.column_count) {
+                self.history.push_front(bands.clone());
+            }
+            self.history.truncate(grid.column_count);
+        } else {
+            self.history[0] = bands;
+        }
+
+        (0..panels.len()).map(|panel_index| {
+            let age = grid.column_count - 1 - grid.columns[panel_index];
+            let energy = *self.history.get(age)?.get(grid.rows[panel_index])?;
+            let level = if max > 0.0 { ((energy - min) / (max - min).max(f32::EPSILON)).clamp(0.0, 1.0) } else { 0.0 };
+            Some(Hsl::from(QUIET_HUE * (1.0 - level), 100.0, 5.0 + level * 55.0))
+        }).collect()
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        vec![&mut self.window]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chroma::Chroma;
+    use crate::panel_graph::PanelGraph;
+
+    #[test]
+    fn test_group_positions() {
+        let (cells, count) = group_positions(&[200, 0, 100, 101, 0, 200]);
+        assert_eq!(count, 3, "Expected three distinct columns");
+        assert_eq!(cells, vec![2, 0, 1, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_scrolls_with_time() {
+        let panels: Vec<NanoleafLayoutPanelData> = (0..4).map(|i| NanoleafLayoutPanelData { panel_id: i, x: i as usize * 100, y: 0, shape_type: 2, orientation: 0 }).collect();
+        let graph = PanelGraph::new(&panels, 100);
+        let chroma: Chroma = [0.0; 12];
+        let input = |audio, delta| EffectInput { audio, beat: false, colors: &[], accents: &[], chroma: &chroma, graph: &graph, time: Duration::ZERO, delta };
+        let mut effect = SpectrogramEffect::new(PeakHoldConfig { enabled: false, ..Default::default() });
+
+        effect.render(&input(&[5.0], RATE_FRAME), &panels);
+        // A frame twice as long scrolls by two columns.
+        effect.render(&input(&[1.0], RATE_FRAME * 2), &panels);
+        assert_eq!(effect.history, [vec![1.0], vec![1.0], vec![5.0]]);
+        // Half a frame only updates the newest column.
+        effect.render(&input(&[2.0], RATE_FRAME / 2), &panels);
+        assert_eq!(effect.history, [vec![2.0], vec![1.0], vec![5.0]]);
+        // A long stall scrolls the whole grid, and no further.
+        effect.render(&input(&[3.0], RATE_FRAME * 100), &panels);
+        assert_eq!(effect.history, vec![vec![3.0]; 4]);
+    }
+}
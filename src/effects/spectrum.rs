@@ -0,0 +1,72 @@
+use colors_transform::Hsl;
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::slidingwindow::SlidingWindow;
+
+use super::peak::{PeakHold, PeakHoldConfig};
+use super::{audio_intensity, Effect, EffectInput};
+
+/**
+ * Hue of the highest band, with the lowest band at 0 (red).
+ */
+const TREBLE_HUE: f32 = 240.0;
+
+/// The hue of a band, running from red for the bass through to blue for the treble.
+fn band_hue(band: usize, band_count: usize) -> f32 {
+    if band_count < 2 {
+        return 0.0;
+    }
+    TREBLE_HUE * band as f32 / (band_count - 1) as f32
+}
+
+/// The classic music visualiser. Each panel has a fixed colour for its band, and its
+/// brightness follows the energy of that band.
+pub struct SpectrumEffect {
+    window: SlidingWindow,
+    peak_hold: PeakHold,
+    intensity_modifier: f32,
+}
+
+impl SpectrumEffect {
+    pub fn new(intensity_modifier: f32, peak_hold: PeakHoldConfig) -> Self {
+        SpectrumEffect {
+            window: SlidingWindow::new(64),
+            peak_hold: PeakHold::new(peak_hold),
+            intensity_modifier,
+        }
+    }
+}
+
+impl Effect for SpectrumEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        let mut bands = input.audio.to_vec();
+        self.peak_hold.apply(&mut bands);
+        (0..panels.len()).map(|panel_index| {
+            let energy = *bands.get(panel_index)?;
+            let (min, max) = self.window.submit_new(energy);
+            let intensity = audio_intensity(10.0, energy, min, max, self.intensity_modifier, panel_index);
+            Some(Hsl::from(band_hue(panel_index, panels.len()), 100.0, intensity))
+        }).collect()
+    }
+
+    fn set_intensity(&mut self, intensity_modifier: f32) {
+        self.intensity_modifier = intensity_modifier;
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        vec![&mut self.window]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::band_hue;
+
+    #[test]
+    fn test_band_hue() {
+        assert_eq!(band_hue(0, 5), 0.0);
+        assert_eq!(band_hue(2, 5), 120.0);
+        assert_eq!(band_hue(4, 5), 240.0);
+        assert_eq!(band_hue(0, 1), 0.0);
+    }
+}
@@ -0,0 +1,123 @@
+use colors_transform::Hsl;
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::slidingwindow::SlidingWindow;
+
+use super::{decay, Effect, EffectInput};
+
+/**
+ * Share of each panel's energy passed on to its neighbours every frame, at the
+ * default frame rate.
+ */
+const DIFFUSION: f32 = 0.3;
+
+/**
+ * How much energy a panel keeps from one frame to the next, at the default frame rate.
+ */
+const DECAY: f32 = 0.85;
+
+/**
+ * Hue step between splats, the golden angle, so consecutive splats never land on
+ * similar colours.
+ */
+const SPLAT_HUE_STEP: f32 = 137.5;
+
+/// Throws a splat of colour onto the loudest panel on each beat, which then bleeds
+/// into the panels around it as it fades.
+pub struct SplatEffect {
+    window: SlidingWindow,
+    energy: Vec<f32>,
+    hues: Vec<f32>,
+    hue: f32,
+}
+
+impl SplatEffect {
+    pub fn new() -> Self {
+        SplatEffect {
+            window: SlidingWindow::new(64),
+            energy: Vec::new(),
+            hues: Vec::new(),
+            hue: 0.0,
+        }
+    }
+
+    /// Spread some of each panel's energy to its neighbours, carrying its hue along to
+    /// any neighbour it outshines.
+    fn diffuse(&mut self, input: &EffectInput) {
+        let diffusion = 1.0 - decay(1.0 - DIFFUSION, input.delta);
+        let mut energy: Vec<f32> = self.energy.iter().map(|energy| energy * (1.0 - diffusion)).collect();
+        let mut hues = self.hues.clone();
+        for (panel, level) in self.energy.iter().enumerate() {
+            let neighbours = input.graph.neighbours(panel);
+            if neighbours.is_empty() {
+                energy[panel] += level * diffusion;
+                continue;
+            }
+            let share = level * diffusion / neighbours.len() as f32;
+            for neighbour in neighbours.iter().filter(|neighbour| **neighbour < self.energy.len()) {
+                if share > self.energy[*neighbour] {
+                    hues[*neighbour] = self.hues[panel];
+                }
+                energy[*neighbour] += share;
+            }
+        }
+        let keep = decay(DECAY, input.delta);
+        self.energy = energy.into_iter().map(|energy| energy * keep).collect();
+        self.hues = hues;
+    }
+}
+
+impl Effect for SplatEffect {
+    fn render(&mut self, input: &EffectInput, panels: &[NanoleafLayoutPanelData]) -> Vec<Option<Hsl>> {
+        self.energy.resize(panels.len(), 0.0);
+        self.hues.resize(panels.len(), 0.0);
+        self.diffuse(input);
+
+        let loudest = input.audio.iter().take(panels.len()).enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(panel, energy)| (panel, *energy));
+        if let (true, Some((panel, energy))) = (input.beat, loudest) {
+            let (min, max) = self.window.submit_new(energy);
+            let strength = if max > min { ((energy - min) / (max - min)).clamp(0.3, 1.0) } else { 1.0 };
+            self.hue = (self.hue + SPLAT_HUE_STEP) % 360.0;
+            self.energy[panel] = (self.energy[panel] + strength).min(1.0);
+            self.hues[panel] = self.hue;
+        }
+
+        self.energy.iter().zip(&self.hues).map(|(energy, hue)| {
+            Some(Hsl::from(*hue, 100.0, 5.0 + energy.min(1.0) * 55.0))
+        }).collect()
+    }
+
+    fn windows(&mut self) -> Vec<&mut SlidingWindow> {
+        vec![&mut self.window]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::effects::RATE_FRAME;
+    use crate::panel_graph::PanelGraph;
+    use crate::chroma::Chroma;
+
+    #[test]
+    fn test_splat_spreads() {
+        let panels: Vec<NanoleafLayoutPanelData> = (0..4).map(|i| NanoleafLayoutPanelData { panel_id: i, x: i as usize * 100, y: 0, shape_type: 2, orientation: 0 }).collect();
+        let graph = PanelGraph::new(&panels, 100);
+        let chroma: Chroma = [0.0; 12];
+        let input = |audio, beat| EffectInput { audio, beat, colors: &[], accents: &[], chroma: &chroma, graph: &graph, time: Duration::ZERO, delta: RATE_FRAME };
+        let mut effect = SplatEffect::new();
+
+        effect.render(&input(&[0.0, 5.0, 0.0, 0.0], true), &panels);
+        assert_eq!(effect.energy, vec![0.0, 1.0, 0.0, 0.0]);
+        effect.render(&input(&[0.0; 4], false), &panels);
+        // The splat bleeds into both neighbours, but hasn't reached the far panel yet.
+        assert!(effect.energy[0] > 0.0 && effect.energy[2] > 0.0);
+        assert_eq!(effect.energy[3], 0.0);
+        assert!(effect.energy[1] < 1.0);
+        assert_eq!(effect.hues[0], effect.hues[1]);
+    }
+}
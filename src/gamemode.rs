@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use zbus::blocking::{Connection, Proxy};
+
+use crate::crash;
+use crate::power::PowerSaver;
+
+/// Watch Feral GameMode on the session bus, telling `power` whenever a game registers
+/// or the last one leaves. Nothing happens if GameMode isn't installed.
+pub fn watch(power: Arc<PowerSaver>) -> Result<(), zbus::Error> {
+    let connection = Connection::session()?;
+    crash::spawn("gamemode", move || {
+        let proxy = match Proxy::new(&connection, "com.feralinteractive.GameMode", "/com/feralinteractive/GameMode", "com.feralinteractive.GameMode") {
+            Ok(proxy) => proxy,
+            Err(err) => {
+                log::warn!("Could not watch GameMode: {}", err);
+                return;
+            }
+        };
+        // Fails when GameMode isn't running, in which case no games are either.
+        power.set_gaming(proxy.get_property::<i32>("ClientCount").unwrap_or(0) > 0);
+        for change in proxy.receive_property_changed::<i32>("ClientCount") {
+            match change.get() {
+                Ok(count) => power.set_gaming(count > 0),
+                Err(err) => log::warn!("Could not read GameMode client count: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
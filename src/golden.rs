@@ -0,0 +1,99 @@
+//! End to end test of the pipeline. Canned audio and screenshots are fed through
+//! audio analysis, screen analysis, the effect and the device output's encoding, and
+//! the payloads a device would be sent are recorded and compared against a golden
+//! file. Refactors of the
+//! pipeline shouldn't change what ends up on the panels without the golden file being
+//! updated alongside them.
+//!
+//! Set `LEAFPIPE_BLESS=1` to write out the golden file after an intended change.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::clock::{self, MockClock};
+use crate::device::{DeltaConfig, FrameEncoder};
+use crate::dither::{self, Dither};
+use crate::effects::{new_effect, EffectKind, PeakHoldConfig};
+use crate::layout::Layout;
+use crate::mask::PanelMask;
+use crate::nanoleaf::NanoleafLayoutResponse;
+use crate::oklab::ColorSpace;
+use crate::replay::{load_audio, load_screenshot};
+use crate::vis::BufferManager;
+use crate::visual::{AnalysisConfig, ScreenAnalysis};
+use crate::{Pipeline, LIGHT_INTERVAL};
+
+const FIXTURES: &str = "samples/pipeline";
+
+/**
+ * Sample rate of the canned audio, which is mono 32 bit float.
+ */
+const AUDIO_RATE: u32 = 22050;
+
+/**
+ * Screenshots shown in turn, each for `FRAMES_PER_SCREENSHOT` frames.
+ */
+const SCREENSHOTS: [&str; 4] = ["samples/colortray.png", "samples/gradientrb.png", "samples/testcard.png", "samples/colortray.png"];
+
+const FRAMES_PER_SCREENSHOT: usize = 5;
+
+/// Run the canned inputs through the pipeline, describing each frame on a line: whether
+/// it started a beat, then the colour of each panel sent to the device, in the order
+/// they were sent. Panels that didn't change enough to be sent are left out.
+fn render_frames() -> String {
+    // Effects animate by the time between frames, so frames are a frame apart however
+    // fast the test runs.
+    let clock = MockClock::start();
+    let response: NanoleafLayoutResponse = serde_json::from_slice(&fs::read(Path::new(FIXTURES).join("layout.json")).unwrap()).unwrap();
+    let layout = Layout::new(&response, PanelMask { exclude: vec![15], color: Some([10, 20, 30]) }).unwrap();
+    let mut screen_analysis = ScreenAnalysis::new(&AnalysisConfig {
+        panel_widths: layout.widths.clone(),
+        gamut: Default::default(),
+        heatmap: Default::default(),
+        hysteresis: Default::default(),
+        max_age: Duration::MAX,
+        downscale: 1,
+    });
+    // Post-processes are left out, so the golden file only follows the analysis and the
+    // effect.
+    let mut pipeline = Pipeline::bare(new_effect(EffectKind::Screen, 1.0, PeakHoldConfig::default(), ColorSpace::Hsl, &[]));
+    let mut buffer_manager = BufferManager::default();
+    let mut device = FrameEncoder::new(layout.clone(), Dither::new(1.0), DeltaConfig::default());
+
+    let audio = load_audio(&Path::new(FIXTURES).join("audio.f32")).unwrap();
+    let chunk = (AUDIO_RATE as f32 * LIGHT_INTERVAL.as_secs_f32()) as usize;
+    let mut output = String::new();
+    for (index, samples) in audio.chunks_exact(chunk).enumerate() {
+        let screenshot = load_screenshot(Path::new(SCREENSHOTS[(index / FRAMES_PER_SCREENSHOT) % SCREENSHOTS.len()])).unwrap();
+        let screen_colors = screen_analysis.analyse(&screenshot);
+        buffer_manager.fill_buffer(samples, AUDIO_RATE);
+        let analysis = buffer_manager.fft_interval(LIGHT_INTERVAL, layout.active.len())
+            .map(|audio_data| (audio_data, buffer_manager.chroma()));
+
+        let frame = pipeline.render(&layout, analysis.as_ref(), &screen_colors, false);
+        let sent = device.encode(&dither::to_rgb(&frame.colors), false, clock::now());
+        output.push_str(if frame.beat { "beat" } else { "-" });
+        for (panel_id, [r, g, b], _) in sent.iter().flat_map(|payload| payload.panels()) {
+            output.push_str(&format!(" {}:#{:02x}{:02x}{:02x}", panel_id, r, g, b));
+        }
+        output.push('\n');
+        clock.advance(LIGHT_INTERVAL);
+    }
+    output
+}
+
+#[test]
+fn test_pipeline_golden() {
+    let golden_path = Path::new(FIXTURES).join("golden.txt");
+    let output = render_frames();
+    if std::env::var_os("LEAFPIPE_BLESS").is_some() {
+        fs::write(&golden_path, &output).unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&golden_path).unwrap();
+    for (frame, (actual, expected)) in output.lines().zip(golden.lines()).enumerate() {
+        assert_eq!(actual, expected, "Frame {} differs from {}", frame, golden_path.display());
+    }
+    assert_eq!(output.lines().count(), golden.lines().count(), "Number of frames differs from {}", golden_path.display());
+}
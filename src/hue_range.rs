@@ -0,0 +1,145 @@
+use colors_transform::{Color, Hsl};
+use serde::Deserialize;
+
+use crate::effects::PostProcess;
+
+/// Hue ranges that are hard to tell apart with common forms of colour blindness.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HuePreset {
+    /// Red-green colour blindness from missing red cones. Greens are confused with
+    /// reds, and deep reds look close to black, so avoids both.
+    Protanopia,
+    /// Red-green colour blindness from missing green cones. Greens are confused with
+    /// reds, which still look bright, so only avoids greens.
+    Deuteranopia,
+    /// Blue-yellow colour blindness. Avoids yellows and cyans.
+    Tritanopia,
+}
+
+impl HuePreset {
+    fn avoid(self) -> &'static [[f32; 2]] {
+        match self {
+            HuePreset::Protanopia => &[[70.0, 170.0], [340.0, 10.0]],
+            HuePreset::Deuteranopia => &[[70.0, 170.0]],
+            HuePreset::Tritanopia => &[[45.0, 75.0], [160.0, 200.0]],
+        }
+    }
+}
+
+/// What to do with hues outside of the allowed range.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HueRangeMode {
+    /// Squeeze the whole colour wheel into the allowed hues, so different colours stay
+    /// different.
+    #[default]
+    Remap,
+    /// Move hues outside the allowed range to the nearest allowed hue.
+    Clamp,
+}
+
+fn default_max() -> f32 {
+    360.0
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HueRangeConfig {
+    #[serde(default)]
+    pub preset: Option<HuePreset>,
+    /// Allowed hues run from `min` up to `max`, wrapping past 360 if `max` is smaller.
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+    /// Ranges of hues, as `[from, to]`, to never show.
+    #[serde(default)]
+    pub avoid: Vec<[f32; 2]>,
+    #[serde(default)]
+    pub mode: HueRangeMode,
+}
+
+/// Whether `hue` lies on the arc running from `from` up to `to`.
+fn on_arc(hue: f32, from: f32, to: f32) -> bool {
+    (hue - from).rem_euclid(360.0) <= (to - from).rem_euclid(360.0)
+}
+
+/// Restricts the hues the lights show, applied to the effect output.
+pub struct HueRange {
+    mode: HueRangeMode,
+    /// Every allowed whole degree, in order around the wheel.
+    allowed: Vec<f32>,
+}
+
+impl HueRange {
+    pub fn new(config: &HueRangeConfig) -> Self {
+        let preset_avoid = config.preset.map(HuePreset::avoid).unwrap_or_default();
+        let full_circle = config.max - config.min >= 360.0;
+        let allowed: Vec<f32> = (0..360).map(|degree| degree as f32).filter(|hue| {
+            (full_circle || on_arc(*hue, config.min, config.max))
+                && !config.avoid.iter().chain(preset_avoid).any(|[from, to]| on_arc(*hue, *from, *to))
+        }).collect();
+        if allowed.is_empty() {
+            log::warn!("hue_range doesn't allow any hues, ignoring it");
+        }
+        HueRange {
+            mode: config.mode,
+            allowed,
+        }
+    }
+
+    pub fn map_hue(&self, hue: f32) -> f32 {
+        if self.allowed.is_empty() {
+            return hue;
+        }
+        match self.mode {
+            HueRangeMode::Remap => {
+                let index = (hue.rem_euclid(360.0) / 360.0 * self.allowed.len() as f32) as usize;
+                self.allowed[index.min(self.allowed.len() - 1)]
+            },
+            HueRangeMode::Clamp => {
+                let distance = |allowed: f32| {
+                    let difference = (allowed - hue).rem_euclid(360.0);
+                    difference.min(360.0 - difference)
+                };
+                if self.allowed.contains(&hue.round().rem_euclid(360.0)) {
+                    return hue;
+                }
+                *self.allowed.iter().min_by(|a, b| distance(**a).total_cmp(&distance(**b))).unwrap()
+            },
+        }
+    }
+}
+
+impl PostProcess for HueRange {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        for hsl in colors.iter_mut().flatten() {
+            *hsl = Hsl::from(self.map_hue(hsl.get_hue()), hsl.get_saturation(), hsl.get_lightness());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(min: f32, max: f32, avoid: Vec<[f32; 2]>, mode: HueRangeMode) -> HueRangeConfig {
+        HueRangeConfig { preset: None, min, max, avoid, mode }
+    }
+
+    #[test]
+    fn test_remap_into_range() {
+        let range = HueRange::new(&config(200.0, 299.0, vec![], HueRangeMode::Remap));
+        assert_eq!(range.map_hue(0.0), 200.0);
+        assert_eq!(range.map_hue(180.0), 250.0);
+        assert_eq!(range.map_hue(359.0), 299.0);
+    }
+
+    #[test]
+    fn test_clamp_avoids_wrapping_range() {
+        let range = HueRange::new(&config(0.0, 360.0, vec![[330.0, 30.0]], HueRangeMode::Clamp));
+        assert_eq!(range.map_hue(100.0), 100.0);
+        assert_eq!(range.map_hue(20.0), 31.0);
+        assert_eq!(range.map_hue(340.0), 329.0);
+    }
+}
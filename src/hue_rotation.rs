@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use colors_transform::{Color, Hsl};
+use serde::Deserialize;
+
+use crate::clock;
+use crate::effects::PostProcess;
+
+/**
+ * Smallest change in hue or saturation that counts as the colours changing.
+ */
+const CHANGE_THRESHOLD: f32 = 2.0;
+
+fn default_degrees_per_minute() -> f32 {
+    30.0
+}
+
+fn default_stale_secs() -> f32 {
+    60.0
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HueRotationConfig {
+    /// How quickly to turn around the colour wheel.
+    #[serde(default = "default_degrees_per_minute")]
+    pub degrees_per_minute: f32,
+    /// Only rotate once the colours have stayed the same for `stale_secs`, such as on
+    /// a static desktop, going back to the real colours as soon as they change.
+    #[serde(default)]
+    pub only_when_stale: bool,
+    #[serde(default = "default_stale_secs")]
+    pub stale_secs: f32,
+}
+
+/// Turns every panel's hue steadily around the colour wheel, to keep the lights lively
+/// while what they're showing doesn't change.
+pub struct HueRotation {
+    config: HueRotationConfig,
+    /// The hue and saturation of each panel before rotating, last time they changed.
+    last_colors: Vec<Option<(f32, f32)>>,
+    last_change: Instant,
+    /// How far round the wheel to turn, in degrees.
+    offset: f32,
+    last_frame: Option<Instant>,
+}
+
+impl HueRotation {
+    pub fn new(config: HueRotationConfig) -> Self {
+        HueRotation {
+            config,
+            last_colors: Vec::new(),
+            last_change: clock::now(),
+            offset: 0.0,
+            last_frame: None,
+        }
+    }
+
+    fn rotate(&mut self, colors: &mut [Option<Hsl>], now: Instant) {
+        let mut since = self.last_frame.replace(now).unwrap_or(now);
+        if self.config.only_when_stale {
+            let current: Vec<Option<(f32, f32)>> = colors.iter().map(|hsl| hsl.map(|hsl| (hsl.get_hue(), hsl.get_saturation()))).collect();
+            let changed = current.len() != self.last_colors.len() || current.iter().zip(&self.last_colors).any(|(current, last)| match (current, last) {
+                (Some((hue, saturation)), Some((last_hue, last_saturation))) => {
+                    let hue_distance = (hue - last_hue).rem_euclid(360.0);
+                    hue_distance.min(360.0 - hue_distance) > CHANGE_THRESHOLD || (saturation - last_saturation).abs() > CHANGE_THRESHOLD
+                },
+                (current, last) => current.is_some() != last.is_some(),
+            });
+            if changed {
+                self.last_colors = current;
+                self.last_change = now;
+                self.offset = 0.0;
+                return;
+            }
+            let stale_at = self.last_change + Duration::from_secs_f32(self.config.stale_secs.max(0.0));
+            if now < stale_at {
+                return;
+            }
+            since = since.max(stale_at);
+        }
+
+        let elapsed = now.saturating_duration_since(since).as_secs_f32();
+        self.offset = (self.offset + self.config.degrees_per_minute * elapsed / 60.0).rem_euclid(360.0);
+        for hsl in colors.iter_mut().flatten() {
+            *hsl = Hsl::from((hsl.get_hue() + self.offset).rem_euclid(360.0), hsl.get_saturation(), hsl.get_lightness());
+        }
+    }
+}
+
+impl PostProcess for HueRotation {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        self.rotate(colors, clock::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotate_when_stale() {
+        let mut rotation = HueRotation::new(HueRotationConfig { degrees_per_minute: 60.0, only_when_stale: true, stale_secs: 10.0 });
+        let start = Instant::now();
+        let frame = |hue: f32| vec![Some(Hsl::from(hue, 100.0, 50.0)), None];
+        let hue = |colors: &[Option<Hsl>]| colors[0].unwrap().get_hue();
+
+        let mut colors = frame(100.0);
+        rotation.rotate(&mut colors, start);
+        assert_eq!(hue(&colors), 100.0);
+        // Not stale yet.
+        let mut colors = frame(100.0);
+        rotation.rotate(&mut colors, start + Duration::from_secs(5));
+        assert_eq!(hue(&colors), 100.0);
+        // Stale from 10 seconds in, then turning a degree a second.
+        let mut colors = frame(100.0);
+        rotation.rotate(&mut colors, start + Duration::from_secs(11));
+        let mut colors = frame(100.0);
+        rotation.rotate(&mut colors, start + Duration::from_secs(13));
+        assert!((hue(&colors) - 103.0).abs() < 0.01, "Expected 103, got {}", hue(&colors));
+        // A new colour is shown as it is.
+        let mut colors = frame(200.0);
+        rotation.rotate(&mut colors, start + Duration::from_secs(14));
+        assert_eq!(hue(&colors), 200.0);
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::chroma::Chroma;
+use crate::LIGHT_INTERVAL;
+
+/// How the audio analysed between two frames sent to the lights is combined.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    /// Only use the most recent analysis.
+    #[default]
+    Latest,
+    /// Use the loudest level of each band, so short hits aren't missed between sends.
+    Max,
+    /// Average the levels, smoothing out the lights.
+    Average,
+}
+
+fn default_capture_ms() -> u64 {
+    100
+}
+
+fn default_send_ms() -> u64 {
+    LIGHT_INTERVAL.as_millis() as u64
+}
+
+fn default_max_age_ms() -> u64 {
+    500
+}
+
+fn default_audio_latency_ms() -> u64 {
+    150
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IntervalConfig {
+    /// Milliseconds between screen captures.
+    #[serde(default = "default_capture_ms")]
+    pub capture_ms: u64,
+    /// Milliseconds between audio analyses, defaulting to once per frame sent.
+    #[serde(default)]
+    pub analysis_ms: Option<u64>,
+    /// Milliseconds between frames sent to the lights.
+    #[serde(default = "default_send_ms")]
+    pub send_ms: u64,
+    #[serde(default)]
+    pub aggregate: Aggregate,
+    /// Milliseconds after which captured frames, audio and frames waiting to be sent
+    /// are too old to be worth using, and are dropped.
+    #[serde(default = "default_max_age_ms")]
+    pub max_age_ms: u64,
+    /// Milliseconds of audio to keep queued for analysis. More is dropped, oldest
+    /// first, unless analysis happens less often than this.
+    #[serde(default = "default_audio_latency_ms")]
+    pub audio_latency_ms: u64,
+}
+
+impl Default for IntervalConfig {
+    fn default() -> Self {
+        IntervalConfig {
+            capture_ms: default_capture_ms(),
+            analysis_ms: None,
+            send_ms: default_send_ms(),
+            aggregate: Aggregate::default(),
+            max_age_ms: default_max_age_ms(),
+            audio_latency_ms: default_audio_latency_ms(),
+        }
+    }
+}
+
+impl IntervalConfig {
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    pub fn capture(&self) -> Duration {
+        Duration::from_millis(self.capture_ms.max(1))
+    }
+
+    pub fn send(&self) -> Duration {
+        Duration::from_millis(self.send_ms.max(1))
+    }
+
+    /// How old data can get before it's dropped, which is never less than the time
+    /// between sends, as data normally waits that long to be used.
+    pub fn max_age(&self) -> Duration {
+        Duration::from_millis(self.max_age_ms).max(self.send())
+    }
+
+    pub fn audio_latency(&self) -> Duration {
+        Duration::from_millis(self.audio_latency_ms)
+    }
+
+    /// The time between analyses, which is never longer than the time between sends.
+    pub fn analysis(&self) -> Duration {
+        self.analysis_ms.map_or(self.send(), |analysis_ms| Duration::from_millis(analysis_ms.max(1)).min(self.send()))
+    }
+}
+
+/// Combines the audio analysed since the last frame was sent.
+pub struct AudioAggregator {
+    aggregate: Aggregate,
+    bands: Option<Box<[f32]>>,
+    chroma: Chroma,
+    count: u32,
+}
+
+impl AudioAggregator {
+    pub fn new(aggregate: Aggregate) -> Self {
+        AudioAggregator {
+            aggregate,
+            bands: None,
+            chroma: Chroma::default(),
+            count: 0,
+        }
+    }
+
+    pub fn add(&mut self, bands: Box<[f32]>, chroma: Chroma) {
+        let combine = |total: &mut [f32], values: &[f32], aggregate: Aggregate| {
+            for (total, value) in total.iter_mut().zip(values) {
+                match aggregate {
+                    Aggregate::Max => *total = total.max(*value),
+                    _ => *total += value,
+                }
+            }
+        };
+        match &mut self.bands {
+            Some(total) if self.aggregate != Aggregate::Latest && total.len() == bands.len() => {
+                combine(total, &bands, self.aggregate);
+                combine(&mut self.chroma, &chroma, self.aggregate);
+                self.count += 1;
+            },
+            _ => {
+                self.bands = Some(bands);
+                self.chroma = chroma;
+                self.count = 1;
+            },
+        }
+    }
+
+    /// The combined analysis since the last call, if any audio was analysed.
+    pub fn take(&mut self) -> Option<(Box<[f32]>, Chroma)> {
+        let mut bands = self.bands.take()?;
+        let mut chroma = self.chroma;
+        if self.aggregate == Aggregate::Average {
+            let count = self.count as f32;
+            bands.iter_mut().chain(chroma.iter_mut()).for_each(|value| *value /= count);
+        }
+        Some((bands, chroma))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        let analyse = |aggregate: Aggregate| {
+            let mut aggregator = AudioAggregator::new(aggregate);
+            aggregator.add(Box::new([1.0, 4.0]), [2.0; 12]);
+            aggregator.add(Box::new([3.0, 2.0]), [0.0; 12]);
+            let (bands, chroma) = aggregator.take().unwrap();
+            assert!(aggregator.take().is_none());
+            (bands.to_vec(), chroma[0])
+        };
+        assert_eq!(analyse(Aggregate::Latest), (vec![3.0, 2.0], 0.0));
+        assert_eq!(analyse(Aggregate::Max), (vec![3.0, 4.0], 2.0));
+        assert_eq!(analyse(Aggregate::Average), (vec![2.0, 3.0], 1.0));
+    }
+}
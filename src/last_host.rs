@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/**
+ * How long to wait for the last known nanoleaf to accept a connection before falling
+ * back to discovering it again.
+ */
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Where the nanoleaf was found last time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct LastHost {
+    host: String,
+    port: u16,
+}
+
+fn path() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(xdg::BaseDirectories::with_prefix("leafpipe")?.place_state_file("device.json")?)
+}
+
+/// The nanoleaf's address from last time, if it's still accepting connections.
+pub fn load() -> Option<(String, u16)> {
+    let path = path().ok()?;
+    let last: LastHost = serde_json::from_slice(&fs::read(&path).ok()?).map_err(|err| {
+        log::warn!("Ignoring unreadable last known nanoleaf in {}: {}", path.display(), err);
+    }).ok()?;
+    let reachable = (last.host.as_str(), last.port).to_socket_addrs().ok()?
+        .any(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok());
+    if !reachable {
+        log::info!("Last known nanoleaf on {}:{} isn't answering", last.host, last.port);
+        return None;
+    }
+    Some((last.host, last.port))
+}
+
+/// Remember where the nanoleaf was found, to try first next time.
+pub fn save(host: &str, port: u16) {
+    let last = LastHost { host: host.to_string(), port };
+    let result = path().and_then(|path| Ok(fs::write(path, serde_json::to_vec(&last)?)?));
+    if let Err(err) = result {
+        log::warn!("Could not save the nanoleaf's address: {}", err);
+    }
+}
@@ -0,0 +1,139 @@
+use colors_transform::Hsl;
+
+use crate::dither::{self, Dither};
+use crate::panel_graph::PanelGraph;
+use crate::mask::PanelMask;
+use crate::nanoleaf::{NanoleafEffectPayload, NanoleafLayoutPanelData, NanoleafLayoutResponse};
+use crate::report::PanelReport;
+use crate::shapes::shape;
+
+/// The panels of a layout, ordered left to right. Panels at the same position keep
+/// the order the controller listed them in.
+pub fn sort_panels(panels: &[NanoleafLayoutPanelData]) -> Vec<NanoleafLayoutPanelData> {
+    let mut sorted_panels = panels.to_vec();
+    sorted_panels.sort_by_key(|panel| panel.x);
+    sorted_panels
+}
+
+/// The panels of a layout, and the ones effects draw on once any masked out are
+/// left aside.
+#[derive(Clone)]
+pub struct Layout {
+    /// Number of panels in each frame sent to the controller.
+    pub num_panels: usize,
+    /// Every panel, ordered left to right.
+    pub panels: Vec<NanoleafLayoutPanelData>,
+    /// Panels that aren't masked, ordered left to right.
+    pub active: Vec<NanoleafLayoutPanelData>,
+    /// Which of the active panels touch each other.
+    pub graph: PanelGraph,
+    /// How wide each active panel is from side to side, which sets how much of the
+    /// screen it takes its colour from.
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    pub widths: Vec<f32>,
+    pub mask: PanelMask,
+}
+
+impl Layout {
+    /// Build a layout from the controller's response. Panels listed more than once are
+    /// only used once, and layouts with nothing to draw on are rejected.
+    pub fn new(response: &NanoleafLayoutResponse, mask: PanelMask) -> Result<Self, String> {
+        let mut unique: Vec<NanoleafLayoutPanelData> = Vec::with_capacity(response.position_data.len());
+        for panel in &response.position_data {
+            if unique.iter().any(|seen| seen.panel_id == panel.panel_id) {
+                log::warn!("Panel {} is listed more than once in the layout", panel.panel_id);
+            } else {
+                unique.push(panel.clone());
+            }
+        }
+        if unique.is_empty() {
+            return Err("The layout doesn't have any panels".to_string());
+        }
+        // Each frame starts with the number of panels as two bytes.
+        if unique.len() > u16::MAX as usize {
+            return Err(format!("The layout has {} panels, more than can be sent in a frame", unique.len()));
+        }
+        if unique.len() != response.num_panels {
+            log::warn!("The layout says it has {} panels, but lists {}", response.num_panels, unique.len());
+        }
+        let panels = sort_panels(&unique);
+        let active = mask.active(&panels);
+        if active.is_empty() {
+            return Err("Every panel is excluded by panel_mask".to_string());
+        }
+        // Panels of unknown shape are taken to be as wide as the layout's side length.
+        let widths = active.iter().map(|panel| {
+            shape(panel.shape_type).map_or(response.side_length as f32, |shape| shape.width(panel.orientation)).max(1.0)
+        }).collect();
+        Ok(Layout {
+            num_panels: panels.len(),
+            graph: PanelGraph::new(&active, response.side_length),
+            widths,
+            panels,
+            active,
+            mask,
+        })
+    }
+
+    /// What every panel shows for a frame, taking a colour for each active panel in
+    /// turn and holding the masked panels at the mask colour.
+    pub fn report(&self, colors: impl IntoIterator<Item = Option<[u8; 3]>>) -> Vec<PanelReport> {
+        let mut colors = colors.into_iter();
+        self.panels.iter().map(|panel| {
+            let rgb = if self.mask.excludes(panel.panel_id) {
+                Some(self.mask.color())
+            } else {
+                colors.next().flatten()
+            };
+            PanelReport { panel_id: panel.panel_id, color: rgb }
+        }).collect()
+    }
+
+    /// Write a frame to the payload, as laid out by [`Layout::report`].
+    pub fn write_frame(&self, colors: impl IntoIterator<Item = Option<[u8; 3]>>, payload: &mut NanoleafEffectPayload) -> Vec<PanelReport> {
+        let reports = self.report(colors);
+        for report in &reports {
+            if let Some([r, g, b]) = report.color {
+                payload.write_effect(report.panel_id, r, g, b, 1);
+            }
+        }
+        reports
+    }
+
+    /// Dither a frame into the payload for the panels, returning what each panel was
+    /// set to.
+    pub fn encode(&self, dither: &mut Dither, colors: &[Option<Hsl>]) -> (NanoleafEffectPayload, Vec<PanelReport>) {
+        let mut payload = NanoleafEffectPayload::new(self.num_panels);
+        let reports = self.write_frame(dither.quantize(&dither::to_rgb(colors)), &mut payload);
+        (payload, reports)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn panel(panel_id: u16, x: usize) -> NanoleafLayoutPanelData {
+        NanoleafLayoutPanelData { panel_id, x, y: 0, shape_type: 7, orientation: 0 }
+    }
+
+    #[test]
+    fn test_layout() {
+        let response = NanoleafLayoutResponse {
+            num_panels: 2,
+            side_length: 100,
+            position_data: vec![panel(3, usize::MAX), panel(1, 0), panel(2, 100), panel(1, 200)],
+        };
+        let layout = Layout::new(&response, PanelMask::default()).unwrap();
+        let ids: Vec<u16> = layout.panels.iter().map(|panel| panel.panel_id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(layout.num_panels, 3);
+        let mut payload = NanoleafEffectPayload::new(layout.num_panels);
+        layout.write_frame(std::iter::repeat(Some([1, 2, 3])), &mut payload);
+
+        let empty = NanoleafLayoutResponse { num_panels: 0, side_length: 100, position_data: Vec::new() };
+        assert!(Layout::new(&empty, PanelMask::default()).is_err());
+        let masked = PanelMask { exclude: vec![1, 2, 3], color: None };
+        assert!(Layout::new(&response, masked).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::effects::{decay, Effect, EffectKind, LayerConfig};
+use crate::slidingwindow::{SlidingWindow, WindowState};
+
+/**
+ * How often the learned levels are written out, so a crash or power cut loses at most
+ * this much learning.
+ */
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+ * Frames of each band to learn its range from.
+ */
+const BAND_WINDOW: usize = 64;
+
+/**
+ * How far a band's noise floor rises towards its recent minimum each frame. It drops
+ * straight away, so it takes the best part of a minute to learn a noisier input but no
+ * time at all to notice a quieter one.
+ */
+const NOISE_FLOOR_RISE: f32 = 0.005;
+
+/**
+ * Highest a band's noise floor can go, as a fraction of its recent peak. A band that
+ * never drops far below its peaks is holding a note rather than hissing, and is left
+ * alone.
+ */
+const NOISE_FLOOR_MAX_FRACTION: f32 = 0.25;
+
+/// What has been learned about one audio band.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BandState {
+    pub window: WindowState,
+    pub noise_floor: f32,
+}
+
+struct BandLevel {
+    window: SlidingWindow,
+    noise_floor: f32,
+}
+
+/// Learns the range and noise floor of each audio band, and takes the noise floor off
+/// the bands so hiss and hum on a quiet input don't light the panels.
+#[derive(Default)]
+pub struct BandLevels {
+    bands: Vec<BandLevel>,
+    last_frame: Option<Instant>,
+}
+
+impl BandLevels {
+    pub fn apply(&mut self, bands: &mut [f32]) {
+        if bands.is_empty() {
+            return;
+        }
+        if self.bands.len() != bands.len() {
+            self.bands = (0..bands.len()).map(|_| BandLevel { window: SlidingWindow::new(BAND_WINDOW), noise_floor: 0.0 }).collect();
+        }
+        let now = clock::now();
+        let delta = self.last_frame.replace(now).map_or(Duration::ZERO, |last| now.saturating_duration_since(last));
+        let rise = 1.0 - decay(1.0 - NOISE_FLOOR_RISE, delta);
+        for (energy, band) in bands.iter_mut().zip(&mut self.bands) {
+            let (min, max) = band.window.submit_new(*energy);
+            let target = min.min(max * NOISE_FLOOR_MAX_FRACTION);
+            if target < band.noise_floor {
+                band.noise_floor = target;
+            } else {
+                band.noise_floor += (target - band.noise_floor) * rise;
+            }
+            *energy = (*energy - band.noise_floor).max(0.0);
+        }
+    }
+
+    pub fn state(&self) -> Vec<BandState> {
+        self.bands.iter().map(|band| BandState { window: band.window.state(), noise_floor: band.noise_floor }).collect()
+    }
+
+    pub fn restore(&mut self, saved: &[BandState]) {
+        self.bands = saved.iter().map(|state| {
+            let mut window = SlidingWindow::new(BAND_WINDOW);
+            window.restore(&state.window);
+            BandLevel { window, noise_floor: state.noise_floor }
+        }).collect();
+    }
+}
+
+/// Everything saved in the levels file.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct SavedLevels {
+    /// Windows of each effect, by the effect's name.
+    effects: HashMap<String, Vec<WindowState>>,
+    bands: Vec<BandState>,
+}
+
+/// The name an effect's levels are kept under. Layered effects are kept by their layers
+/// as well, as levels learned by one stack of effects mean nothing to another.
+fn key(effect: EffectKind, layers: &[LayerConfig]) -> String {
+    let name = |effect: EffectKind| format!("{:?}", effect).to_lowercase();
+    match effect {
+        EffectKind::Layers => format!("layers({})", layers.iter().map(|layer| name(layer.effect)).collect::<Vec<_>>().join(",")),
+        effect => name(effect),
+    }
+}
+
+/// Keeps the audio levels each effect and band has learned in the user's state
+/// directory, so the lights look right straight away after a restart rather than after
+/// a minute of relearning.
+pub struct LevelStore {
+    path: PathBuf,
+    effect: String,
+    levels: SavedLevels,
+    last_saved: Instant,
+}
+
+impl LevelStore {
+    /// Open `leafpipe/levels.json` in the user's state directory.
+    pub fn open(effect: EffectKind, layers: &[LayerConfig]) -> Result<Self, Box<dyn Error>> {
+        let path = xdg::BaseDirectories::with_prefix("leafpipe")?.place_state_file("levels.json")?;
+        Ok(Self::load(path, effect, layers))
+    }
+
+    fn load(path: PathBuf, effect: EffectKind, layers: &[LayerConfig]) -> Self {
+        let levels = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                log::warn!("Ignoring unreadable levels in {}: {}", path.display(), err);
+                SavedLevels::default()
+            }),
+            Err(_) => SavedLevels::default(),
+        };
+        LevelStore {
+            path,
+            effect: key(effect, layers),
+            levels,
+            last_saved: Instant::now(),
+        }
+    }
+
+    /// Give the effect and bands back the levels they learned last time.
+    pub fn restore(&self, effect: &mut dyn Effect, bands: &mut BandLevels) {
+        bands.restore(&self.levels.bands);
+        let Some(saved) = self.levels.effects.get(&self.effect) else {
+            return;
+        };
+        let mut windows = effect.windows();
+        if windows.len() != saved.len() {
+            return;
+        }
+        for (window, state) in windows.iter_mut().zip(saved) {
+            window.restore(state);
+        }
+        log::debug!("Restored learned levels from {}", self.path.display());
+    }
+
+    /// Write out what has been learned, if it's been a while since the last save.
+    pub fn autosave(&mut self, effect: &mut dyn Effect, bands: &BandLevels) {
+        if self.last_saved.elapsed() < AUTOSAVE_INTERVAL {
+            return;
+        }
+        self.save(effect, bands);
+    }
+
+    /// Write out what has been learned, as when shutting down.
+    pub fn save(&mut self, effect: &mut dyn Effect, bands: &BandLevels) {
+        self.last_saved = Instant::now();
+        if let Err(err) = self.write(effect, bands) {
+            log::warn!("Failed to save learned levels to {}: {}", self.path.display(), err);
+        }
+    }
+
+    fn write(&mut self, effect: &mut dyn Effect, bands: &BandLevels) -> Result<(), Box<dyn Error>> {
+        let windows: Vec<WindowState> = effect.windows().iter().map(|window| window.state()).collect();
+        if !windows.is_empty() {
+            self.levels.effects.insert(self.effect.clone(), windows);
+        }
+        let bands = bands.state();
+        if !bands.is_empty() {
+            self.levels.bands = bands;
+        }
+        // Write alongside and move into place, so a crash mid-write can't leave a
+        // truncated file behind.
+        let partial = self.path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_vec(&self.levels)?)?;
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::effects::new_effect;
+    use crate::oklab::ColorSpace;
+
+    #[test]
+    fn test_levels_round_trip() {
+        let path = std::env::temp_dir().join(format!("leafpipe-levels-{}.json", std::process::id()));
+        let mut effect = new_effect(EffectKind::Spectrum, 15.0, Default::default(), ColorSpace::Hsl, &[]);
+        for value in [0.5, 2.0, 8.0] {
+            effect.windows()[0].submit_new(value);
+        }
+        let mut bands = BandLevels::default();
+        bands.apply(&mut [1.0, 4.0]);
+        LevelStore::load(path.clone(), EffectKind::Spectrum, &[]).write(effect.as_mut(), &bands).unwrap();
+
+        let mut restored = new_effect(EffectKind::Spectrum, 15.0, Default::default(), ColorSpace::Hsl, &[]);
+        let mut restored_bands = BandLevels::default();
+        LevelStore::load(path.clone(), EffectKind::Spectrum, &[]).restore(restored.as_mut(), &mut restored_bands);
+        assert_eq!(restored.windows()[0].state(), effect.windows()[0].state());
+        assert_eq!(restored_bands.state(), bands.state());
+        // Other effects don't pick up levels learned by this one.
+        let mut other = new_effect(EffectKind::Chroma, 15.0, Default::default(), ColorSpace::Hsl, &[]);
+        LevelStore::load(path.clone(), EffectKind::Chroma, &[]).restore(other.as_mut(), &mut BandLevels::default());
+        assert_eq!(other.windows()[0].state().max, 0.0);
+        // Nor do layered effects with different layers.
+        let layers: Vec<LayerConfig> = serde_json::from_str(r#"[{"effect": "screen"}, {"effect": "spectrum"}]"#).unwrap();
+        assert_eq!(key(EffectKind::Layers, &layers), "layers(screen,spectrum)");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_band_noise_floor() {
+        let clock = clock::MockClock::start();
+        let mut levels = BandLevels::default();
+        // A band that hisses steadily between quieter and louder sounds learns to sit
+        // at its hiss, while one holding a note keeps it.
+        let mut bands = [0.0; 2];
+        for frame in 0..2000 {
+            bands = [if frame % 10 == 0 { 4.0 } else { 0.5 }, 3.0];
+            levels.apply(&mut bands);
+            clock.advance(Duration::from_millis(100));
+        }
+        assert!(bands[0] < 0.05, "Hiss should be taken off, was {}", bands[0]);
+        assert!(bands[1] > 2.0, "A held note should be kept, was {}", bands[1]);
+    }
+}
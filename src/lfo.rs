@@ -0,0 +1,158 @@
+use std::f32::consts::PI;
+use std::time::Instant;
+
+use colors_transform::{Color, Hsl};
+use serde::Deserialize;
+
+use crate::clock;
+use crate::effects::PostProcess;
+use crate::oklab::ColorSpace;
+
+/// What an [`LfoConfig`] modulates.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LfoTarget {
+    /// Shift the hue of every panel, by up to `depth` degrees either way.
+    Hue,
+    /// Raise or lower the lightness of every panel, by up to `depth` percent.
+    Brightness,
+    /// A wave of brightness, `depth` percent high, that travels from the left of the
+    /// layout to the right once a period.
+    Sweep,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LfoShape {
+    #[default]
+    Sine,
+    Triangle,
+    /// Rises steadily, then drops straight back.
+    Saw,
+}
+
+impl LfoShape {
+    /// The value of the wave, from -1 to 1, at `phase` through a period.
+    fn value(self, phase: f32) -> f32 {
+        let phase = phase.rem_euclid(1.0);
+        match self {
+            LfoShape::Sine => (phase * 2.0 * PI).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.25).rem_euclid(1.0).min(1.0 - (phase - 0.25).rem_euclid(1.0)),
+            LfoShape::Saw => phase * 2.0 - 1.0,
+        }
+    }
+}
+
+fn default_period_secs() -> f32 {
+    60.0
+}
+
+fn default_depth() -> f32 {
+    10.0
+}
+
+/// A low frequency oscillator, slowly changing part of the effect's output over
+/// seconds to minutes.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LfoConfig {
+    pub target: LfoTarget,
+    #[serde(default)]
+    pub shape: LfoShape,
+    /// Seconds for one full cycle.
+    #[serde(default = "default_period_secs")]
+    pub period_secs: f32,
+    /// How far the target is moved at the peak of the wave.
+    #[serde(default = "default_depth")]
+    pub depth: f32,
+    /// Where in its cycle the oscillator starts, from 0 to 1.
+    #[serde(default)]
+    pub phase: f32,
+}
+
+impl LfoConfig {
+    /// The oscillator's offset, `elapsed` seconds after starting, for a panel at
+    /// `position` across the layout from 0 on the left to 1 on the right.
+    fn offset(&self, elapsed: f32, position: f32) -> f32 {
+        let mut phase = self.phase + elapsed / self.period_secs.max(f32::EPSILON);
+        if self.target == LfoTarget::Sweep {
+            phase -= position;
+        }
+        self.shape.value(phase) * self.depth
+    }
+}
+
+/// Modulates the effect's output with the oscillators configured for it, so the
+/// lights keep slowly changing even while the music doesn't.
+pub struct Modulation {
+    lfos: Vec<LfoConfig>,
+    started: Instant,
+    color_space: ColorSpace,
+}
+
+impl Modulation {
+    pub fn new(lfos: Vec<LfoConfig>, color_space: ColorSpace) -> Self {
+        Modulation {
+            lfos,
+            started: clock::now(),
+            color_space,
+        }
+    }
+
+    fn modulate(&self, colors: &mut [Option<Hsl>], elapsed: f32) {
+        let last = colors.len().saturating_sub(1).max(1) as f32;
+        for (index, hsl) in colors.iter_mut().enumerate() {
+            let Some(hsl) = hsl else {
+                continue;
+            };
+            // Panels the effect turned off stay off.
+            if hsl.get_lightness() <= 0.0 {
+                continue;
+            }
+            let position = index as f32 / last;
+            let (mut hue, mut lightness) = (hsl.get_hue(), hsl.get_lightness());
+            for lfo in &self.lfos {
+                let offset = lfo.offset(elapsed, position);
+                match lfo.target {
+                    LfoTarget::Hue => hue += offset,
+                    LfoTarget::Brightness | LfoTarget::Sweep => lightness += offset,
+                }
+            }
+            *hsl = self.color_space.with_lightness(&Hsl::from(hue.rem_euclid(360.0), hsl.get_saturation(), hsl.get_lightness()), lightness);
+        }
+    }
+}
+
+impl PostProcess for Modulation {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        self.modulate(colors, clock::elapsed(self.started).as_secs_f32());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lfo(target: LfoTarget, shape: LfoShape) -> LfoConfig {
+        LfoConfig { target, shape, period_secs: 60.0, depth: 20.0, phase: 0.0 }
+    }
+
+    #[test]
+    fn test_lfo_modulation() {
+        assert!((LfoShape::Triangle.value(0.25) - 1.0).abs() < 0.001);
+        assert!((LfoShape::Triangle.value(0.75) + 1.0).abs() < 0.001);
+        assert!((LfoShape::Saw.value(0.5)).abs() < 0.001);
+
+        let modulation = Modulation::new(vec![lfo(LfoTarget::Hue, LfoShape::Sine), lfo(LfoTarget::Sweep, LfoShape::Sine)], ColorSpace::Hsl);
+        let mut colors = vec![Some(Hsl::from(350.0, 100.0, 50.0)), None, Some(Hsl::from(100.0, 100.0, 0.0)), Some(Hsl::from(100.0, 100.0, 50.0))];
+        // A quarter of the way through, the hue is at its peak and the sweep has moved
+        // a quarter of the way across.
+        modulation.modulate(&mut colors, 15.0);
+        let first = colors[0].unwrap();
+        assert!((first.get_hue() - 10.0).abs() < 0.01);
+        assert!((first.get_lightness() - 70.0).abs() < 0.01);
+        assert_eq!(colors[1], None);
+        assert_eq!(colors[2].unwrap().get_lightness(), 0.0);
+        // The last panel is a whole period behind the first along the sweep.
+        assert!((colors[3].unwrap().get_lightness() - 70.0).abs() < 0.01);
+    }
+}
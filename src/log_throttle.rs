@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+/**
+ * Shortest time between two messages from the same throttled log line.
+ */
+pub const THROTTLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lets a log line through at most once every [`THROTTLE_INTERVAL`], counting how
+/// many were held back in between.
+pub struct LogThrottle {
+    last: Option<Instant>,
+    suppressed: u32,
+}
+
+impl LogThrottle {
+    pub const fn new() -> Self {
+        LogThrottle {
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Whether the line should be logged at `now`, returning how many times it was held
+    /// back since it was last logged if so.
+    pub fn ready(&mut self, now: Instant) -> Option<u32> {
+        if self.last.is_some_and(|last| now.saturating_duration_since(last) < THROTTLE_INTERVAL) {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+/// Log a warning like `log::warn!`, but at most once every [`THROTTLE_INTERVAL`] from
+/// each place it's used, for failures that can repeat every frame.
+macro_rules! warn_throttled {
+    ($($arg:tt)+) => {{
+        static THROTTLE: std::sync::Mutex<$crate::log_throttle::LogThrottle> = std::sync::Mutex::new($crate::log_throttle::LogThrottle::new());
+        let ready = THROTTLE.lock().unwrap().ready(std::time::Instant::now());
+        match ready {
+            Some(0) => log::warn!($($arg)+),
+            Some(suppressed) => log::warn!("{} ({} more since the last)", format_args!($($arg)+), suppressed),
+            None => {},
+        }
+    }};
+}
+
+pub(crate) use warn_throttled;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_throttle() {
+        let start = Instant::now();
+        let mut throttle = LogThrottle::new();
+        assert_eq!(throttle.ready(start), Some(0));
+        assert_eq!(throttle.ready(start + Duration::from_secs(1)), None);
+        assert_eq!(throttle.ready(start + Duration::from_secs(2)), None);
+        assert_eq!(throttle.ready(start + THROTTLE_INTERVAL), Some(2));
+        assert_eq!(throttle.ready(start + THROTTLE_INTERVAL * 3), Some(0));
+    }
+}
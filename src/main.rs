@@ -1,85 +1,474 @@
-#![feature(test)]
+#![cfg_attr(feature = "bench", feature(test))]
+#[cfg(feature = "bench")]
 extern crate test;
 
 use clap::Parser;
-use colors_transform::{Color, Hsl};
-use nanoleaf::{NanoleafClient, NanoleafEffectPayload, NanoleafLayoutResponse};
-use visual::backend;
-use wayland_client::protocol::wl_output::WlOutput;
-use wayland_client::{Connection, QueueHandle};
-use wayland_client::globals::{registry_queue_init, GlobalListContents};
-use wayland_client::protocol::wl_registry;
+use colors_transform::Hsl;
+use nanoleaf::NanoleafClient;
 use core::panic;
-use std::cmp::Ordering;
-use std::ops::Sub;
-use std::sync::mpsc::{channel, Receiver};
-use std::sync::{Arc, RwLock};
-use std::{thread, time};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU8};
+use std::thread;
 use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use vis::BufferManager;
 use config::{Config, ConfigError};
+#[cfg(feature = "mdns")]
 use mdns_sd::{ServiceDaemon, ServiceEvent};
-use crate::slidingwindow::SlidingWindow;
+use crate::ambient::{AmbientBrightness, AmbientLightConfig};
+use crate::beat::BeatDetector;
+use crate::brightness_limit::BrightnessLimit;
+use crate::chroma::Chroma;
+use crate::cli::{Command, RunArgs};
+use crate::clock::FrameClock;
+use crate::control::{ControlCommand, ControlSocket, ControlState, Profile};
+use crate::device::{DeltaConfig, DeviceOutput, FrameEncoder, RateLimitConfig};
+use crate::osc::OscSender;
+use crate::power::{PowerSaver, StandbyConfig};
+use crate::program::{AmbientProgram, AmbientProgramConfig};
+use crate::report::{FrameReport, Reporter};
+use crate::stats::Stage;
+use crate::sync::{SyncFrame, SyncLeader, SyncMode};
+use crate::transition::Transition;
+use crate::tuning::Tuning;
+use crate::dither::Dither;
+use crate::downmix::Downmix;
+use crate::ducking::{CallPolicy, Ducking};
+use crate::effects::{Effect, EffectInput, LayerConfig, PostProcess, ScreenColors};
+use crate::hue_range::{HueRange, HueRangeConfig};
+use crate::hue_rotation::{HueRotation, HueRotationConfig};
+use crate::intervals::{AudioAggregator, IntervalConfig};
+use crate::lfo::{LfoConfig, Modulation};
+use crate::layout::Layout;
+use crate::levels::{BandLevels, LevelStore};
+use crate::memory::MemoryBudget;
+use crate::noise::{NoiseShimmer, NoiseShimmerConfig};
+use crate::oklab::ColorSpace;
+use crate::parallel_fft::FftConfig;
+use crate::safety::{SafetyConfig, StrobeLimiter};
+use crate::scene::Scenes;
 
+#[cfg(feature = "pipewire")]
 mod audio;
+#[cfg(feature = "pipewire")]
+mod dsp;
+mod ambient;
+mod beat;
+mod brightness_limit;
+mod clock;
+mod control;
+mod crash;
+mod device;
+mod osc;
+mod report;
+mod resample;
+mod dither;
+mod dnd;
+mod downmix;
+mod ducking;
+mod hue_range;
+mod hue_rotation;
+mod intervals;
+#[cfg(feature = "gamemode")]
+mod gamemode;
+#[cfg(feature = "mdns")]
+mod last_host;
+mod layout;
+mod levels;
+mod lfo;
+mod log_throttle;
+mod mask;
+mod memory;
+#[cfg(all(test, feature = "nanoleaf"))]
+mod mock_controller;
+#[cfg(feature = "mpris")]
+mod mpris;
+mod network_audio;
+mod noise;
+mod notify;
+mod oklab;
+mod panel_graph;
+mod parallel_fft;
+mod chroma;
+mod commands;
+mod effects;
+#[cfg(all(test, feature = "screen"))]
+mod golden;
+mod safety;
+mod scene;
+mod setup;
+mod shapes;
+mod simd;
 mod slidingwindow;
+mod stats;
+#[cfg(feature = "pipewire")]
+mod stream_mix;
+mod sync;
+mod token;
+mod transition;
+mod tuning;
+mod validate;
+#[cfg(feature = "tray")]
+mod tray;
+#[cfg(feature = "tui")]
+mod tui;
 mod vis;
 mod nanoleaf;
+mod power;
+mod preset;
+mod program;
+#[cfg(feature = "record")]
+mod recording;
+mod replay;
+#[cfg(feature = "remote")]
+mod remote;
+#[cfg(feature = "remote")]
+mod rest;
+#[cfg(feature = "screen")]
 mod visual;
+#[cfg(feature = "weather")]
+mod weather;
+#[cfg(feature = "wayland")]
+mod workspaces;
+#[cfg(feature = "pipewire")]
 mod pipewire;
 mod cli;
 
+#[cfg(not(feature = "nanoleaf"))]
+compile_error!("leafpipe needs at least one device backend, enable the \"nanoleaf\" feature");
+
 const LIGHT_INTERVAL: Duration = Duration::from_millis(100);
+/**
+ * How long without any audio before falling back to the idle scene.
+ */
+const NO_AUDIO_FALLBACK: Duration = Duration::from_secs(2);
+#[cfg(feature = "mdns")]
 const SERVICE_TYPE: &str = "_nanoleafapi._tcp.local.";
 
-fn update_lights(panels: NanoleafLayoutResponse, nanoleaf: NanoleafClient, buffer_manager: Arc<RwLock<BufferManager>>, color_channel: Receiver<Vec<Hsl>>, intensity_modifier: f32) {
-    // Needs to be over a sliding window.
-    let mut window = SlidingWindow::new(64);
-    let mut color_set: Vec<Hsl> = Vec::new();
-    let mut sorted_panels = panels.position_data.to_vec();
-    sorted_panels.sort_by(|a,b| {
-        let v = a.x as i32 - b.x as i32;
-        if v > 1 {
-            return Ordering::Greater;
-        } else if v < -1 {
-            return Ordering::Less;
-        }
-        Ordering::Equal
-    });
+/// The stages a frame passes through on its way to the lights.
+struct Pipeline {
+    effect: Box<dyn Effect>,
+    /// Scenes shown instead of the effect while idle or when selected.
+    scenes: Scenes,
+    post_processes: Vec<Box<dyn PostProcess>>,
+    reporters: Vec<Box<dyn Reporter>>,
+    beat_detector: BeatDetector,
+    /// Learns the range and noise floor of each audio band.
+    band_levels: BandLevels,
+    /// Where the audio levels learned by the effect and bands are saved between runs.
+    levels: Option<LevelStore>,
+    /// Fades the lights in when starting and out when stopping.
+    transition: Transition,
+    /// How often audio is analysed and frames are sent.
+    intervals: IntervalConfig,
+    /// How much a wide stereo mix boosts the edge panels.
+    stereo_width: f32,
+    /// What control clients have asked for, for them to read back.
+    state: Arc<Mutex<ControlState>>,
+    /// The `[profiles]` control clients can switch to, by name.
+    profiles: std::collections::HashMap<String, Profile>,
+    /// Told whenever commands have changed the state.
+    state_watchers: Vec<Sender<()>>,
+    /// Times the frames the effect renders.
+    frame_clock: FrameClock,
+}
+
+/// A frame rendered by the pipeline, along with the audio it was rendered from.
+struct RenderedFrame {
+    colors: Vec<Option<Hsl>>,
+    bands: Vec<f32>,
+    beat: bool,
+}
+
+impl Pipeline {
+    /// A pipeline that only runs `effect`, with none of the scenes, post processing or
+    /// reporting set up by the config.
+    fn bare(effect: Box<dyn Effect>) -> Self {
+        Pipeline {
+            effect,
+            scenes: Scenes::new(Default::default(), Default::default(), None, ColorSpace::Hsl),
+            post_processes: Vec::new(),
+            reporters: Vec::new(),
+            beat_detector: BeatDetector::new(),
+            band_levels: BandLevels::default(),
+            levels: None,
+            transition: Transition::new(&Default::default(), ColorSpace::Hsl).0,
+            intervals: Default::default(),
+            stereo_width: 0.0,
+            state: Default::default(),
+            profiles: Default::default(),
+            state_watchers: Vec::new(),
+            frame_clock: FrameClock::default(),
+        }
+    }
+
+    /// Save what's been learnt and finish off the reporters, then let whoever asked for
+    /// the lights to stop know they're off.
+    fn stop(&mut self) {
+        if let Some(levels) = &mut self.levels {
+            levels.save(self.effect.as_mut(), &self.band_levels);
+        }
+        for reporter in &mut self.reporters {
+            reporter.finish();
+        }
+        self.transition.done();
+    }
+
+    /// Render a frame from the latest audio analysis and screen colours, showing a scene
+    /// instead if one is selected or `fallback` is set.
+    fn render(&mut self, layout: &Layout, analysis: Option<&(Box<[f32]>, Chroma)>, screen_colors: &ScreenColors, fallback: bool) -> RenderedFrame {
+        let mut bands = analysis.map(|(audio_data, _)| audio_data.to_vec()).unwrap_or_default();
+        self.band_levels.apply(&mut bands);
+        let beat = self.beat_detector.update(&bands);
+        let scene = self.scenes.render(&layout.active, fallback);
+        let mut colors = match (scene, analysis) {
+            (Some(colors), _) => {
+                self.frame_clock.pause();
+                colors
+            },
+            (None, Some((_, chroma))) => {
+                let (time, delta) = self.frame_clock.tick();
+                let input = EffectInput {
+                    audio: &bands,
+                    beat,
+                    colors: &screen_colors.primary,
+                    accents: &screen_colors.accent,
+                    chroma,
+                    graph: &layout.graph,
+                    time,
+                    delta,
+                };
+                self.effect.render(&input, &layout.active)
+            },
+            // Nothing for the effect to draw with, but post-processes such as the
+            // ambient program may still light the panels.
+            (None, None) => {
+                self.frame_clock.pause();
+                vec![None; layout.active.len()]
+            },
+        };
+        for post_process in self.post_processes.iter_mut() {
+            post_process.apply(&mut colors);
+        }
+        RenderedFrame { colors, bands, beat }
+    }
+}
+
+fn update_lights(layout: Layout, output: DeviceOutput, buffer_manager: Arc<RwLock<BufferManager>>, color_channel: Receiver<ScreenColors>, mut pipeline: Pipeline, power: Arc<PowerSaver>, commands: Receiver<ControlCommand>) {
+    crash::enter("lights");
+    let mut screen_colors = ScreenColors::default();
+    let mut idle = false;
+    let mut last_sent = Instant::now();
+    let mut last_audio = Instant::now();
+    let mut paused = false;
+    // Whether the lights were turned off on going into standby.
+    let mut standing_by = false;
+    // The last frame shown, so it can be faded out if stopping while paused.
+    let mut last_colors = Vec::new();
+    let (full_analysis_interval, full_send_interval) = (pipeline.intervals.analysis(), pipeline.intervals.send());
+    let mut audio = AudioAggregator::new(pipeline.intervals.aggregate);
+    let mut side_audio = AudioAggregator::new(pipeline.intervals.aggregate);
+    let mut next_frame = Instant::now();
     loop { 
         let process_start = Instant::now();
+        let (analysis_interval, send_interval) = if power.is_gaming() {
+            (full_analysis_interval * power::GAMING_SLOWDOWN, full_send_interval * power::GAMING_SLOWDOWN)
+        } else {
+            (full_analysis_interval, full_send_interval)
+        };
+        let stopping = pipeline.transition.stopping(process_start);
+        // Lights already dark in standby stay that way when stopping, but otherwise are
+        // woken to fade out.
+        let standby = power.update_standby() && (standing_by || !stopping);
         {
-            if let Ok(v) = color_channel.try_recv() {
-                color_set = v;
+            // Only the newest colours matter if capture got ahead.
+            if let Some(v) = color_channel.try_iter().last() {
+                screen_colors = v;
             } // else, use the previous value.
+            let mut changed = false;
+            for command in commands.try_iter() {
+                changed = true;
+                let mut state = pipeline.state.lock().unwrap();
+                match command {
+                    ControlCommand::Scene { name } => {
+                        pipeline.scenes.select(name);
+                        state.scene = pipeline.scenes.selected().map(str::to_string);
+                        state.profile = None;
+                    },
+                    ControlCommand::Tune(change) => {
+                        state.tuning = state.tuning.with(change);
+                        pipeline.effect.set_intensity(state.tuning.intensity);
+                        buffer_manager.write().unwrap().tune(state.tuning);
+                        state.profile = None;
+                    },
+                    ControlCommand::Profile { name } => match pipeline.profiles.get(&name) {
+                        Some(profile) => {
+                            pipeline.scenes.select(profile.scene.clone());
+                            state.scene = pipeline.scenes.selected().map(str::to_string);
+                            state.tuning = state.tuning.with(profile.tuning);
+                            pipeline.effect.set_intensity(state.tuning.intensity);
+                            buffer_manager.write().unwrap().tune(state.tuning);
+                            log::info!("Switched to the {} profile", name);
+                            state.profile = Some(name);
+                        },
+                        None => log::warn!("No profile named {}", name),
+                    },
+                    ControlCommand::Pause | ControlCommand::Resume => {
+                        paused = command == ControlCommand::Pause;
+                        state.paused = paused;
+                        log::info!("{}", if paused { "Paused" } else { "Resumed" });
+                    },
+                }
+            }
+            if changed {
+                pipeline.state_watchers.retain(|watcher| watcher.send(()).is_ok());
+            }
 
-            if let Some(audio_data) = buffer_manager.write().unwrap().fft_interval(LIGHT_INTERVAL, panels.num_panels) {
-                let mut effect = NanoleafEffectPayload::new(panels.num_panels);
-                for (panel_index, panel) in sorted_panels.iter().enumerate() {
-                    if let Some(color) = color_set.get(panel_index) {
-                        let (min, max) = window.submit_new(audio_data[panel_index]);
-                        let base_int = color.get_lightness() - 10.0;
-                        let intensity = (base_int + ((audio_data[panel_index] + min) / max) * intensity_modifier * (panel_index as f32 + 1.0f32).powf(1.05f32)).clamp(5.0, 80.0);
-                        let hsl = Hsl::from(color.get_hue(), color.get_saturation(), intensity);
-                        let rgb = hsl.to_rgb().as_tuple();
-                        let r = rgb.0.round() as u8;
-                        let g = rgb.1.round() as u8;
-                        let b = rgb.2.round() as u8;
-                        effect.write_effect(panel.panel_id, r, g, b, 1);
+            {
+                let analysis_start = Instant::now();
+                let mut buffer_manager = buffer_manager.write().unwrap();
+                if standby {
+                    // Only listen out for audio starting.
+                    buffer_manager.measure_interval(analysis_interval);
+                } else if let Some(audio_data) = buffer_manager.fft_interval(analysis_interval, layout.active.len()) {
+                    audio.add(audio_data, buffer_manager.chroma());
+                    if let Some(sides) = buffer_manager.side_bands() {
+                        side_audio.add(sides.into(), Chroma::default());
                     }
+                    stats::SESSION.time(Stage::AudioAnalysis, analysis_start.elapsed());
                 }
-                if let Err(err) = nanoleaf.send_effect(&effect) {
-                    log::warn!("Failed to send effect to nanoleaf {:?}", err);
+                power.audio_level(buffer_manager.rms());
+            }
+        }
+        if standby {
+            if !standing_by {
+                output.send(vec![Some([0.0; 3]); layout.active.len()]);
+                standing_by = true;
+            }
+            if stopping {
+                // Nothing to fade out.
+                pipeline.stop();
+                return;
+            }
+            pipeline.frame_clock.pause();
+            thread::sleep(analysis_interval.saturating_sub(process_start.elapsed()));
+            continue;
+        }
+        standing_by = false;
+        // Audio may be analysed several times for each frame sent to the lights.
+        if process_start >= next_frame {
+            next_frame = (next_frame + send_interval).max(process_start);
+            let stereo_width = buffer_manager.read().unwrap().stereo_width();
+            let sides = side_audio.take().map(|(sides, _)| sides);
+            let analysis = audio.take().map(|(mut bands, chroma)| {
+                downmix::widen(&mut bands, sides.as_deref(), stereo_width, pipeline.stereo_width);
+                (bands, chroma)
+            });
+
+            // Audio is still analysed every interval while idle, so we wake up as soon as
+            // anything plays, but the lights are only updated occasionally.
+            if power.is_idle() != idle {
+                idle = !idle;
+                log::info!("{}", if idle { "Nothing happening, saving power" } else { "Activity detected, resuming" });
+            }
+            let skip_frame = !stopping && (paused || (idle && last_sent.elapsed() < power::IDLE_INTERVAL));
+            if paused {
+                pipeline.frame_clock.pause();
+            }
+            if let Some(levels) = &mut pipeline.levels {
+                levels.autosave(pipeline.effect.as_mut(), &pipeline.band_levels);
+            }
+            if analysis.is_some() {
+                last_audio = Instant::now();
+            }
+
+            let render_start = Instant::now();
+            let frame = if skip_frame {
+                None
+            } else if paused {
+                // Fade out what was left showing, rather than lighting the panels up again.
+                Some(RenderedFrame { colors: last_colors.clone(), bands: Vec::new(), beat: false })
+            } else {
+                Some(pipeline.render(&layout, analysis.as_ref(), &screen_colors, idle || last_audio.elapsed() > NO_AUDIO_FALLBACK))
+            };
+
+            if let Some(mut frame) = frame.filter(|frame| frame.colors.iter().any(Option::is_some)) {
+                last_colors.clone_from(&frame.colors);
+                pipeline.transition.apply(&mut frame.colors, process_start);
+                last_sent = Instant::now();
+                let colors = dither::to_rgb(&frame.colors);
+                // Reports show the colours before the device output dithers them.
+                let panel_reports = pipeline.reporters.iter().any(|reporter| reporter.wants_report())
+                    .then(|| layout.report(colors.iter().map(|color| color.map(|rgb| rgb.map(|value| value.round() as u8)))));
+                stats::SESSION.time(Stage::Render, render_start.elapsed());
+                output.send(colors);
+                if let Some(panel_reports) = panel_reports {
+                    let report = FrameReport {
+                        bands: frame.bands,
+                        side_bands: sides.map(Vec::from),
+                        beat: frame.beat,
+                        panels: panel_reports,
+                        audio: buffer_manager.read().unwrap().stats(),
+                    };
+                    for reporter in pipeline.reporters.iter_mut().filter(|reporter| reporter.wants_report()) {
+                        reporter.report(&report);
+                    }
                 }
             }
+            if pipeline.transition.finished(process_start) {
+                // Give the device a moment to show the last, dark frame.
+                thread::sleep(send_interval * 3);
+                pipeline.stop();
+                return;
+            }
         }
-        if LIGHT_INTERVAL.ge(&process_start.elapsed()) {
-            let sleep_duration = LIGHT_INTERVAL.sub(process_start.elapsed());
-            if sleep_duration.ge(&Duration::ZERO) {
-                thread::sleep(sleep_duration);
+        thread::sleep(analysis_interval.saturating_sub(process_start.elapsed()));
+    }
+}
+
+/// Replay frames from a leader instance on our own panels.
+fn follow_lights(layout: Layout, output: DeviceOutput, frames: Receiver<SyncFrame>) {
+    crash::enter("sync follower");
+    for frame in frames {
+        output.send(frame.resample(layout.active.len()).into_iter().map(|color| color.map(|rgb| rgb.map(f32::from))).collect());
+    }
+}
+
+#[cfg(feature = "mdns")]
+fn discover_mdns() -> (String, u16) {
+    if let Some(last) = last_host::load() {
+        log::info!("Using last known nanoleaf");
+        return last;
+    }
+    log::info!("Discovering nanoleaf via mdns");
+    let mdns: ServiceDaemon = ServiceDaemon::new().expect("Failed to create daemon");
+    // Browse for a service type.
+    let receiver = mdns.browse(SERVICE_TYPE).expect("Failed to browse");
+    while let Ok(event) = receiver.recv() {
+        match event {
+            ServiceEvent::ServiceFound(service, extra) => {
+                log::debug!("Discovered service {} {}", service, extra);
+            }
+            ServiceEvent::ServiceResolved(info) => {
+                log::debug!("Resolved service {} {:?}", info.get_fullname(), info.get_addresses());
+                // TODO: Support IPv6. My system doesn't :(
+                let service_ip = info.get_addresses().iter().find(|addr| addr.is_ipv4()).expect("Service found but with no addresses").to_string();
+                mdns.shutdown().unwrap();
+                return (service_ip, info.get_port());
+            }
+            _ => {
+                // Not interested in other events.
             }
         }
     }
+    panic!("Failed to find nanoleaf");
+}
+
+#[cfg(not(feature = "mdns"))]
+fn discover_mdns() -> (String, u16) {
+    panic!("Built without mDNS support, nanoleaf_host must be configured")
 }
 
 fn discover_host(config: &Config) -> (String, u16) {
@@ -90,30 +479,7 @@ fn discover_host(config: &Config) -> (String, u16) {
                 config.get_int("nanoleaf_port").unwrap_or(nanoleaf::DEFAULT_API_PORT.into()).try_into().expect("Provided nanoleaf_port did not fit in range")
             )
         },
-        Err(ConfigError::NotFound(_err)) => {
-            log::info!("Discovering nanoleaf via mdns");
-            let mdns: ServiceDaemon = ServiceDaemon::new().expect("Failed to create daemon");
-            // Browse for a service type.
-            let receiver = mdns.browse(SERVICE_TYPE).expect("Failed to browse");
-            while let Ok(event) = receiver.recv() {
-                match event {
-                    ServiceEvent::ServiceFound(service, extra) => {
-                        log::debug!("Discovered service {} {}", service, extra);
-                    }
-                    ServiceEvent::ServiceResolved(info) => {
-                        log::debug!("Resolved service {} {:?}", info.get_fullname(), info.get_addresses());
-                        // TODO: Support IPv6. My system doesn't :(
-                        let service_ip = info.get_addresses().iter().find(|addr| addr.is_ipv4()).expect("Service found but with no addresses").to_string();
-                        mdns.shutdown().unwrap();
-                        return (service_ip, info.get_port());
-                    }
-                    _ => {
-                        // Not interested in other events.
-                    }
-                }
-            }
-            panic!("Failed to find nanoleaf");
-        }
+        Err(ConfigError::NotFound(_err)) => discover_mdns(),
         Err(err) => {
             log::warn!("Encountered error with config {:?}", err);
             panic!("Unexpected error handling config")
@@ -121,108 +487,381 @@ fn discover_host(config: &Config) -> (String, u16) {
     }
 }
 
-struct AppState;
-
-impl wayland_client::Dispatch<wl_registry::WlRegistry, GlobalListContents> for AppState {
-    fn event(
-        _: &mut AppState,
-        _: &wl_registry::WlRegistry,
-        _: wl_registry::Event,
-        _: &GlobalListContents,
-        _: &Connection,
-        _: &QueueHandle<AppState>,
-    ) {
-    }
-}
-
-
-fn configure_display(pause_duration:time::Duration, panel_count: usize, output_name: Option<String>) -> std::sync::mpsc::Receiver<Vec<Hsl>> {
-    let conn = Connection::connect_to_env().unwrap();
-    let (globals, _) = registry_queue_init::<AppState>(&conn).unwrap();
-    let out: WlOutput = if let Some(output_name_result) = output_name {
-        visual::output::get_wloutput(
-            output_name_result.trim().to_string(),
-            visual::output::get_all_outputs(&globals, &conn),
-        )
-    } else {
-        visual::output::get_all_outputs(&globals, &conn)
-            .first()
-            .unwrap()
-            .wl_output
-            .clone()
-    };
-
-    let mut capturer = backend::setup_capture(&globals,&conn, &out).unwrap();
-    let (tx, rx) = channel();
-
-    thread::spawn(move|| {
-        log::info!("Capturing frames");
-        let mut last_value = 0.0f32;
-        let mut heatmap = vec![vec![vec![vec![0u32; 21]; 21]; 37]; panel_count];
-        loop {
-            let start = Instant::now();
-            let frame_copy = backend::capture_output_frame(
-                &globals,
-                &conn,
-                &out,
-                &mut capturer,
-            ).unwrap();
-            let hsl = visual::prominent_color::determine_prominent_color(frame_copy, &mut heatmap);
-            let value_hash: f32 = hsl.iter().map(|f| f.get_hue() + f.get_lightness() + f.get_saturation()).sum();
-            if value_hash != last_value {
-                tx.send(hsl).unwrap();
-                last_value = value_hash;
-            }
-            if pause_duration.ge(&start.elapsed()) {
-                let sleep_duration = LIGHT_INTERVAL.sub(start.elapsed());
-                if sleep_duration.ge(&Duration::ZERO) {
-                    thread::sleep(sleep_duration);
-                }
-            }
-    
-        }
-    });
-    rx
-}
-
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = cli::CliArgs::parse();
 
     let config_builder = Config::builder().add_source(config::Environment::with_prefix("LP"));
 
-    let config = if let Some(config_file) = xdg::BaseDirectories::with_prefix("leafpipe").unwrap().find_config_file("config.toml") {
-        config_builder.add_source(config::File::from(config_file)).build().unwrap()
+    let config_file = xdg::BaseDirectories::with_prefix("leafpipe").unwrap().find_config_file("config.toml");
+    let config = if let Some(config_file) = &config_file {
+        config_builder.add_source(config::File::from(config_file.clone())).build().unwrap()
     } else {
         config_builder.add_source(config::File::with_name("config.toml")).build().unwrap()
     };
 
-    env_logger::init();
+    let mut logger = env_logger::Builder::from_default_env();
+    let config_level = config.get_string("log_level").ok();
+    let log_level = args.log_level().or_else(|| config_level.as_deref().and_then(|level| level.parse().ok()));
+    if let Some(level) = log_level {
+        // Only for leafpipe itself, as libraries such as wgpu are very chatty.
+        logger.filter_module("leafpipe", level);
+    }
+    #[cfg(feature = "tui")]
+    let log_file = match args.log_file.clone() {
+        // Logging to the terminal would draw over the TUI.
+        None if args.tui() => Some(xdg::BaseDirectories::with_prefix("leafpipe")?.place_state_file("leafpipe.log")?),
+        log_file => log_file,
+    };
+    #[cfg(not(feature = "tui"))]
+    let log_file = args.log_file.clone();
+    if let Some(log_file) = &log_file {
+        logger.target(env_logger::Target::Pipe(Box::new(std::fs::File::create(log_file)?)));
+    }
+    crash::init(logger.build())?;
+    if config_level.is_some() && log_level.is_none() {
+        log::warn!("Ignoring unknown log_level {:?}, expected one of off, error, warn, info, debug or trace", config_level.unwrap_or_default());
+    }
     log::trace!("Logger initialized.");
 
-    let buffer_manager: Arc<RwLock<BufferManager>> = Arc::new(RwLock::new(BufferManager::default()));
+    match args.command() {
+        // Running in the background, nobody may be watching the log.
+        Command::Run(args) => run(args, config, config_file).await
+            .inspect_err(|err| notify::notify("leafpipe stopped", &err.to_string())),
+        Command::Setup => setup::run(&config, config_file).await,
+        Command::Pair => commands::pair(&config).await,
+        Command::Discover => commands::discover(),
+        #[cfg(feature = "wayland")]
+        Command::Outputs => commands::outputs(),
+        Command::Ctl { command } => commands::ctl(command),
+        Command::Bench(args) => commands::bench(args),
+        Command::Replay(args) => replay::replay(args, &config),
+        Command::Layout => commands::layout(&config).await,
+        Command::Identify { panels } => commands::identify(&config, panels).await,
+        Command::Testpattern(args) => commands::testpattern(&config, args).await,
+        Command::Config => commands::config(&config, config_file.as_deref()),
+        Command::Preset(command) => commands::preset(command, &config, config_file),
+        #[cfg(feature = "remote")]
+        Command::Openapi => {
+            println!("{}", rest::spec());
+            Ok(())
+        },
+    }
+}
+
+/// Drive the lights until interrupted.
+#[cfg_attr(not(feature = "tui"), allow(unused_variables))]
+async fn run(args: RunArgs, config: Config, config_file: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let invalid = validate::effect_settings(&config.clone().try_deserialize()?);
+    if !invalid.is_empty() {
+        for invalid in &invalid {
+            log::error!("Invalid setting {}", invalid);
+        }
+        return Err(format!("The config has {} invalid settings, see above", invalid.len()).into());
+    }
+    stats::SESSION.start();
+    let stats_file: Option<PathBuf> = config.get_string("stats_file").ok().map(PathBuf::from);
+    let mut tuning: Tuning = config.get("tuning").unwrap_or_default();
+    if let Some(intensity) = args.intensity {
+        tuning.intensity = intensity;
+    }
+    let intervals: IntervalConfig = config.get("intervals").unwrap_or_default();
+    let mut buffer_manager = BufferManager::default();
+    buffer_manager.tune(tuning);
+    buffer_manager.set_max_age(intervals.max_age());
+    buffer_manager.set_latency_target(intervals.audio_latency());
+    let memory = MemoryBudget::from_config(&config);
+    if let Some(bytes) = memory.audio_queue_bytes() {
+        buffer_manager.set_queue_limit(bytes);
+    }
+    let fft: FftConfig = config.get("fft").unwrap_or_default();
+    buffer_manager.set_overlap(fft.overlap);
+    match fft.pool() {
+        Ok(Some((min_size, pool))) => buffer_manager.set_parallel_fft(min_size, pool),
+        Ok(None) => {},
+        Err(err) => log::warn!("Could not start threads for the FFT, using one thread: {}", err),
+    }
+    let downmix: Downmix = config.get("downmix").unwrap_or_default();
+    if downmix == Downmix::MidSide {
+        buffer_manager.analyse_side();
+    }
+    let buffer_manager: Arc<RwLock<BufferManager>> = Arc::new(RwLock::new(buffer_manager));
     let buffer_manager_lights = buffer_manager.clone();
 
-    let pipewire = crate::pipewire::PipewireContainer::new(buffer_manager).expect("Could not configure pipewire");
+    let sync_mode: SyncMode = config.get("sync_mode").unwrap_or_default();
+    let sync_group = config.get_string("sync_group").unwrap_or(sync::DEFAULT_SYNC_GROUP.to_string());
+    let following = sync_mode == SyncMode::Follower;
+
+    let call_active = Arc::new(AtomicBool::new(false));
+    let network_audio: Option<network_audio::NetworkAudioConfig> = config.get("network_audio").ok();
+    let receiving_network_audio = network_audio.is_some();
+    if let Some(network_audio) = network_audio.filter(|_| !following) {
+        network_audio::start(network_audio, buffer_manager.clone(), downmix).expect("Could not listen for network audio");
+    }
+    #[cfg(feature = "pipewire")]
+    let pipewire = if receiving_network_audio || following {
+        None
+    } else {
+        let audio_applications: Vec<String> = config.get("audio_applications").unwrap_or_default();
+        Some(crate::pipewire::PipewireContainer::new(buffer_manager, args.source, downmix, audio_applications, call_active.clone()).expect("Could not configure pipewire"))
+    };
+    #[cfg(not(feature = "pipewire"))]
+    if !receiving_network_audio && !following {
+        return Err("Built without PipeWire support, so [network_audio] or sync_mode = \"follower\" must be configured to get audio".into());
+    }
 
     let service = discover_host(&config);
     log::info!("Discovered nanoleaf on {}:{}", service.0, service.1);
 
-    let nanoleaf: NanoleafClient = NanoleafClient::connect(
-        config.get_string("nanoleaf_token").expect("Missing nanoleaf_token config"),
-        service.0,
+    let mut nanoleaf: NanoleafClient = NanoleafClient::connect(
+        token::load(&config)?,
+        service.0.clone(),
         service.1,
-    ).await.unwrap();
-
-    // Check we can contact the nanoleaf
-    nanoleaf.get_panels().await.expect("Could not contact nanoleaf lights");
+        config.get("nanoleaf_transport").unwrap_or_default(),
+        args.force,
+    ).await?;
 
-    let panels: nanoleaf::NanoleafLayoutResponse = nanoleaf.get_panels().await.unwrap();
-    let color_rx = configure_display(Duration::from_millis(33), panels.num_panels, args.display);
+    // Check we can contact the nanoleaf, and find out how the panels are laid out.
+    let panels: nanoleaf::NanoleafLayoutResponse = nanoleaf.get_panels().await
+        .map_err(std::io::Error::other)?;
+    #[cfg(feature = "mdns")]
+    last_host::save(&service.0, service.1);
+    let layout = Layout::new(&panels, config.get("panel_mask").unwrap_or_default())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    if let Err(err) = nanoleaf.check_udp(&layout.panels).await {
+        log::error!("The test frame sent over UDP was rejected ({}), check a firewall isn't blocking UDP port 60222 to the nanoleaf", err);
+    }
+    let mut rate_limit: RateLimitConfig = config.get("rate_limit").unwrap_or_default();
+    if rate_limit.probe {
+        let controller = format!("{}:{}", service.0, service.1);
+        match device::frame_rate(&mut nanoleaf, &controller, &layout.panels, rate_limit.frames_per_second).await {
+            Ok(rate) if rate < rate_limit.frames_per_second => {
+                log::warn!("The nanoleaf falls behind above {} frames a second with {} panels, sending no faster than that", rate, layout.num_panels);
+                rate_limit.frames_per_second = rate;
+            },
+            Ok(rate) => log::info!("The nanoleaf keeps up with {} frames a second", rate),
+            Err(err) => log::warn!("Could not probe how fast the nanoleaf keeps up: {}", err),
+        }
+    }
+    let delta: DeltaConfig = config.get("delta").unwrap_or_default();
+    // The controller scales every frame by its own brightness setting, so frames are
+    // brightened to make up for it and the panels show the brightness they were made with.
+    let brightness_limit = config.get_bool("respect_controller_brightness").unwrap_or(true).then(|| Arc::new(AtomicU8::new(100)));
+    let encoder = FrameEncoder::new(layout.clone(), Dither::new(config.get("dither").unwrap_or(1.0)), delta);
+    let output = DeviceOutput::start(nanoleaf, encoder, &rate_limit, intervals.max_age(), brightness_limit.clone());
+    let standby: StandbyConfig = config.get("standby").unwrap_or_default();
+    let power = Arc::new(PowerSaver::new(config.get_bool("power_saver").unwrap_or(true), standby.after()));
+    #[cfg(feature = "gamemode")]
+    if let Err(err) = gamemode::watch(power.clone()) {
+        log::warn!("Could not connect to the session bus to watch GameMode: {}", err);
+    }
+    #[cfg(feature = "mpris")]
+    if standby.enabled {
+        if let Err(err) = mpris::watch(power.clone()) {
+            log::warn!("Could not connect to the session bus to watch media players: {}", err);
+        }
+    }
+    if following {
+        let frames = sync::follow(&sync_group).expect("Could not join sync group");
+        tokio::spawn(async move { follow_lights(layout, output, frames) });
+        tokio::signal::ctrl_c().await?;
+        stats::end_session(stats_file.as_deref());
+        return Ok(());
+    }
+    #[cfg(feature = "screen")]
+    let color_rx = {
+        let snapshot_requested = Arc::new(AtomicBool::new(false));
+        let mut snapshot_signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        let snapshot_flag = snapshot_requested.clone();
+        tokio::spawn(async move {
+            while snapshot_signal.recv().await.is_some() {
+                snapshot_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        });
+        let analysis = visual::AnalysisConfig {
+            panel_widths: layout.widths.clone(),
+            gamut: config.get("output_gamut").unwrap_or_default(),
+            heatmap: visual::heatmap::HeatmapConfig { compact: memory.compact_heatmap, ..config.get("heatmap").unwrap_or_default() },
+            hysteresis: config.get("color_hysteresis").unwrap_or_default(),
+            max_age: intervals.max_age(),
+            downscale: memory.capture_downscale(),
+        };
+        let downscale = analysis.downscale;
+        match config.get::<Vec<String>>("capture_command") {
+            Ok(command) => visual::analyse_frames(
+                move || Ok(Box::new(visual::command::CommandCapture::start(&command, downscale)?) as Box<dyn visual::capture::FrameSource>),
+                intervals.capture(), analysis, snapshot_requested, power.clone(),
+            ),
+            #[cfg(feature = "wayland")]
+            Err(_) => {
+                let capture_region: Option<visual::region::CaptureRegion> = match config.get("capture_region") {
+                    Ok(region) => Some(region),
+                    Err(ConfigError::NotFound(_)) => None,
+                    Err(err) => return Err(format!("Invalid capture_region: {}", err).into()),
+                };
+                visual::configure_display(intervals.capture(), analysis, args.display.or_else(|| config.get_string("display").ok()), args.window, capture_region, snapshot_requested, power.clone())
+            },
+            #[cfg(not(feature = "wayland"))]
+            Err(_) => {
+                log::warn!("Built without Wayland support and no capture_command is configured, the lights won't follow the screen");
+                std::sync::mpsc::channel().1
+            },
+        }
+    };
+    #[cfg(not(feature = "screen"))]
+    let color_rx = std::sync::mpsc::channel().1;
 
-    tokio::spawn(async move { update_lights(panels, nanoleaf, buffer_manager_lights, color_rx, args.intensity) });
-    pipewire.run();
-    pipewire.stop().expect("Failed to stop pipewire");
+    let color_space: ColorSpace = config.get("color_space").unwrap_or_default();
+    let layers: Vec<LayerConfig> = config.get("layers").unwrap_or_default();
+    let mut effect = effects::new_effect(args.effect, tuning.intensity, config.get("peak_hold").unwrap_or_default(), color_space, &layers);
+    let mut band_levels = BandLevels::default();
+    let levels = match LevelStore::open(args.effect, &layers) {
+        Ok(levels) => {
+            levels.restore(effect.as_mut(), &mut band_levels);
+            Some(levels)
+        },
+        Err(err) => {
+            log::warn!("Could not open learned levels, starting from scratch: {}", err);
+            None
+        },
+    };
+    #[cfg_attr(not(feature = "weather"), allow(unused_mut))]
+    let mut live_scenes = std::collections::HashMap::new();
+    #[cfg(feature = "weather")]
+    if let Ok(weather) = config.get::<weather::WeatherConfig>("weather") {
+        live_scenes.insert(weather::WEATHER_SCENE.to_string(), weather::start(weather));
+    }
+    let scenes = Scenes::new(config.get("scenes").unwrap_or_default(), live_scenes, config.get_string("idle_scene").ok(), color_space);
+    let call_policy: CallPolicy = config.get("call_policy").unwrap_or_default();
+    let mut post_processes: Vec<Box<dyn PostProcess>> = Vec::new();
+    let effect_name = format!("{:?}", args.effect).to_lowercase();
+    if let Ok(lfos) = config.get::<Vec<LfoConfig>>(&format!("lfo.{}", effect_name)) {
+        post_processes.push(Box::new(Modulation::new(lfos, color_space)));
+    }
+    if let Ok(program) = config.get::<AmbientProgramConfig>("ambient_program") {
+        post_processes.push(Box::new(AmbientProgram::new(&program, color_space).expect("Invalid start time in ambient_program")));
+    }
+    if let Ok(hue_rotation) = config.get::<HueRotationConfig>("hue_rotation") {
+        post_processes.push(Box::new(HueRotation::new(hue_rotation)));
+    }
+    if let Ok(shimmer) = config.get::<NoiseShimmerConfig>("noise_shimmer") {
+        post_processes.push(Box::new(NoiseShimmer::new(shimmer, &layout.active, color_space)));
+    }
+    if let Ok(hue_range) = config.get::<HueRangeConfig>("hue_range") {
+        post_processes.push(Box::new(HueRange::new(&hue_range)));
+    }
+    post_processes.push(Box::new(Ducking::new(call_policy, call_active, color_space)));
+    if let Ok(ambient_light) = config.get::<AmbientLightConfig>("ambient_light") {
+        match AmbientBrightness::start(ambient_light, color_space) {
+            Ok(ambient) => post_processes.push(Box::new(ambient)),
+            Err(err) => log::warn!("Could not read ambient light: {}", err),
+        }
+    }
+    if let Some(limit) = brightness_limit {
+        post_processes.push(Box::new(BrightnessLimit::new(limit, color_space)));
+    }
+    let safety: SafetyConfig = config.get("safety").unwrap_or_default();
+    if !safety.enabled {
+        log::warn!("Strobe safety limiter is disabled");
+    }
+    post_processes.push(Box::new(StrobeLimiter::new(safety, color_space)));
+    let mut reporters: Vec<Box<dyn Reporter>> = Vec::new();
+    let (command_tx, command_rx) = std::sync::mpsc::channel();
+    let state = Arc::new(Mutex::new(ControlState { tuning, ..Default::default() }));
+    crash::watch_state(state.clone());
+    // Asked for by anything other than Ctrl+C that stops leafpipe.
+    let quit = Arc::new(Notify::new());
+    #[cfg(feature = "tui")]
+    if args.tui {
+        let config_path = config_file.unwrap_or_else(|| PathBuf::from("config.toml"));
+        reporters.push(Box::new(tui::start(tuning, config_path, command_tx.clone(), quit.clone())));
+    }
+    let profiles: std::collections::HashMap<String, Profile> = config.get("profiles").unwrap_or_default();
+    #[cfg_attr(not(feature = "tray"), allow(unused_mut))]
+    let mut state_watchers = Vec::new();
+    #[cfg(feature = "tray")]
+    if args.tray {
+        state_watchers.push(tray::start(profiles.keys().cloned().collect(), state.clone(), command_tx.clone()));
+    }
+    #[cfg(feature = "wayland")]
+    if let Ok(profiles) = config.get::<Vec<workspaces::WorkspaceProfile>>("workspace_profiles") {
+        if let Err(err) = workspaces::watch(profiles, tuning, command_tx.clone()) {
+            log::warn!("Could not follow workspaces: {}", err);
+        }
+    }
+    if let Ok(dnd) = config.get::<dnd::DndConfig>("do_not_disturb") {
+        if let Err(err) = dnd::watch(dnd, state.clone(), command_tx.clone()) {
+            log::warn!("Could not follow do not disturb: {}", err);
+        }
+    }
+    #[cfg(feature = "remote")]
+    if let Ok(remote) = config.get::<remote::RemoteConfig>("remote") {
+        match remote::RemoteControl::bind(remote, state.clone(), command_tx.clone(), profiles.keys().cloned().collect()) {
+            Ok(remote) => reporters.push(Box::new(remote)),
+            Err(err) => log::warn!("Could not listen for remote control: {}", err),
+        }
+    }
+    match ControlSocket::bind(command_tx) {
+        Ok(control) => reporters.push(Box::new(control)),
+        Err(err) => log::warn!("Could not open control socket: {}", err),
+    }
+    if let Ok(osc_target) = config.get_string("osc_target") {
+        reporters.push(Box::new(OscSender::connect(&osc_target).expect("Could not open OSC socket")));
+    }
+    #[cfg(feature = "record")]
+    if let Ok(record) = config.get::<recording::RecordConfig>("record") {
+        match recording::Recording::start(record, &layout) {
+            Ok(recording) => reporters.push(Box::new(recording)),
+            Err(err) => log::warn!("Could not start recording: {}", err),
+        }
+    }
+    if sync_mode == SyncMode::Leader {
+        reporters.push(Box::new(SyncLeader::new(&sync_group).expect("Could not open sync socket")));
+    }
+    let (transition, fade_out) = Transition::new(&config.get("transitions").unwrap_or_default(), color_space);
+    let pipeline = Pipeline {
+        effect,
+        scenes,
+        post_processes,
+        reporters,
+        beat_detector: BeatDetector::new(),
+        band_levels,
+        levels,
+        transition,
+        intervals,
+        stereo_width: config.get("stereo_width").unwrap_or(0.0),
+        state,
+        profiles,
+        state_watchers,
+        frame_clock: FrameClock::default(),
+    };
+    tokio::spawn(async move { update_lights(layout, output, buffer_manager_lights, color_rx, pipeline, power, command_rx) });
+    #[cfg(feature = "pipewire")]
+    if let Some(pipewire) = pipewire {
+        // PipeWire's main loop doesn't return, so fade out and exit from a task instead.
+        tokio::spawn(async move {
+            if shutdown_requested(&quit).await.is_ok() {
+                fade_out.run().await;
+                stats::end_session(stats_file.as_deref());
+                std::process::exit(0);
+            }
+        });
+        crash::enter("audio capture");
+        pipewire.run();
+        pipewire.stop().expect("Failed to stop pipewire");
+        return Ok(());
+    }
+    shutdown_requested(&quit).await?;
+    fade_out.run().await;
+    stats::end_session(stats_file.as_deref());
     Ok(())
 }
 
+/// Wait for Ctrl+C, or for something else such as the TUI to ask to quit.
+async fn shutdown_requested(quit: &Notify) -> std::io::Result<()> {
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = quit.notified() => Ok(()),
+    }
+}
+
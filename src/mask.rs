@@ -0,0 +1,47 @@
+use serde::Deserialize;
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+
+/// Panels to leave out of effects, e.g. ones hidden behind a shelf.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PanelMask {
+    /// IDs of the panels to leave out, as shown in the Nanoleaf app or the debug log.
+    #[serde(default)]
+    pub exclude: Vec<u16>,
+    /// Colour to hold the excluded panels at. They're left dark if not set.
+    pub color: Option<[u8; 3]>,
+}
+
+impl PanelMask {
+    pub fn excludes(&self, panel_id: u16) -> bool {
+        self.exclude.contains(&panel_id)
+    }
+
+    pub fn color(&self) -> [u8; 3] {
+        self.color.unwrap_or([0, 0, 0])
+    }
+
+    /// The panels effects should draw on, keeping their order.
+    pub fn active(&self, panels: &[NanoleafLayoutPanelData]) -> Vec<NanoleafLayoutPanelData> {
+        for panel_id in self.exclude.iter().filter(|id| !panels.iter().any(|panel| panel.panel_id == **id)) {
+            log::warn!("Excluded panel {} isn't in the layout", panel_id);
+        }
+        panels.iter().filter(|panel| !self.excludes(panel.panel_id)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_active_panels() {
+        let panels: Vec<NanoleafLayoutPanelData> = (1..=3)
+            .map(|panel_id| NanoleafLayoutPanelData { panel_id, x: panel_id as usize * 100, y: 0, shape_type: 7, orientation: 0 })
+            .collect();
+        let mask = PanelMask { exclude: vec![2, 9], color: None };
+        let active: Vec<u16> = mask.active(&panels).iter().map(|panel| panel.panel_id).collect();
+        assert_eq!(active, vec![1, 3]);
+        assert_eq!(mask.color(), [0, 0, 0]);
+    }
+}
@@ -0,0 +1,63 @@
+use config::Config;
+use serde::Deserialize;
+
+fn default_capture_downscale() -> u32 {
+    1
+}
+
+/// Limits on how much memory leafpipe uses, for devices like a Raspberry Pi where full
+/// resolution frame copies and heatmaps add up. Nothing is limited by default, and
+/// `low_memory = true` picks [`MemoryBudget::LOW`] in one go.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MemoryBudget {
+    /// Keep one in this many pixels along each row and column of captured frames.
+    #[serde(default = "default_capture_downscale")]
+    pub capture_downscale: u32,
+    /// Count colours in 16 bits rather than 32, which stops counting at 65535 sightings
+    /// of a colour in one panel's region.
+    #[serde(default)]
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    pub compact_heatmap: bool,
+    /// Most KiB of audio to keep queued for analysis, however far behind analysis is.
+    #[serde(default)]
+    pub audio_queue_kib: Option<usize>,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget {
+            capture_downscale: default_capture_downscale(),
+            compact_heatmap: false,
+            audio_queue_kib: None,
+        }
+    }
+}
+
+impl MemoryBudget {
+    /**
+     * The budget `low_memory = true` selects. Frames are a quarter of the size, and the
+     * audio queue holds about a third of a second of 48kHz audio.
+     */
+    pub const LOW: MemoryBudget = MemoryBudget {
+        capture_downscale: 2,
+        compact_heatmap: true,
+        audio_queue_kib: Some(64),
+    };
+
+    /// The budget set by `low_memory`, or else by the `memory` section.
+    pub fn from_config(config: &Config) -> Self {
+        if config.get_bool("low_memory").unwrap_or(false) {
+            return MemoryBudget::LOW;
+        }
+        config.get("memory").unwrap_or_default()
+    }
+
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    pub fn capture_downscale(&self) -> u32 {
+        self.capture_downscale.max(1)
+    }
+
+    pub fn audio_queue_bytes(&self) -> Option<usize> {
+        self.audio_queue_kib.map(|kib| kib * 1024)
+    }
+}
@@ -0,0 +1,327 @@
+//! Tests of [`NanoleafClient`] against a mock controller, which answers the parts of the
+//! HTTP API leafpipe uses and listens for the UDP stream like a real controller does.
+//! Changes to how leafpipe talks to the controller show up here, without needing one on
+//! the network.
+
+use std::fs;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::device;
+use crate::nanoleaf::{self, NanoleafClient, NanoleafEffectPayload, Transport};
+
+const TOKEN: &str = "mock-token";
+
+const LAYOUT: &str = "samples/pipeline/layout.json";
+
+const EXT_CONTROL_EFFECT: &str = "*ExtControl*";
+
+/**
+ * The port controllers listen for the stream on, which can't be changed.
+ */
+const UDP_PORT: u16 = 60222;
+
+/**
+ * How long to wait for something sent to the mock to arrive.
+ */
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/**
+ * Each mock listens on a loopback address of its own, as the stream always goes to
+ * `UDP_PORT` and tests run side by side.
+ */
+static NEXT_ADDRESS: AtomicU8 = AtomicU8::new(1);
+
+/**
+ * How far back frames are counted to tell whether the mock is falling behind.
+ */
+const BUSY_WINDOW: Duration = Duration::from_millis(500);
+
+/**
+ * How long the mock takes to answer while it's falling behind.
+ */
+const BUSY_DELAY: Duration = Duration::from_millis(200);
+
+/// A request the mock was sent.
+#[derive(Debug, Clone, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    body: Value,
+}
+
+struct MockState {
+    /// The effect the controller is showing.
+    select: String,
+    /// Whether the power button has been held, so new pairings are accepted.
+    pairing: bool,
+    requests: Vec<Request>,
+    /// The fastest frame rate the controller keeps up with, if it's limited.
+    keeps_up_with: Option<f32>,
+    /// When each frame streamed to a limited controller arrived.
+    frames: Vec<Instant>,
+}
+
+impl MockState {
+    /// How long to wait before answering, which is slow while frames are streaming in
+    /// faster than the controller keeps up with.
+    fn answer_delay(&self) -> Duration {
+        let Some(rate) = self.keeps_up_with else {
+            return Duration::ZERO;
+        };
+        let recent = self.frames.iter().filter(|arrived| arrived.elapsed() < BUSY_WINDOW).count();
+        // Allow a frame over for where the window falls between frames.
+        if recent as f32 > rate * BUSY_WINDOW.as_secs_f32() + 1.0 {
+            BUSY_DELAY
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+struct MockController {
+    host: String,
+    port: u16,
+    udp: UdpSocket,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockController {
+    async fn start() -> Self {
+        let host = Ipv4Addr::new(127, 60, std::process::id() as u8, NEXT_ADDRESS.fetch_add(1, Ordering::Relaxed)).to_string();
+        let listener = TcpListener::bind((host.as_str(), 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let udp = UdpSocket::bind((host.as_str(), UDP_PORT)).unwrap();
+        udp.set_read_timeout(Some(RECEIVE_TIMEOUT)).unwrap();
+        let state = Arc::new(Mutex::new(MockState {
+            select: "Northern Lights".to_string(),
+            pairing: false,
+            requests: Vec::new(),
+            keeps_up_with: None,
+            frames: Vec::new(),
+        }));
+        let served = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, served.clone()));
+            }
+        });
+        MockController { host, port, udp, state }
+    }
+
+    /// The next frame streamed to the controller.
+    fn receive_frame(&self) -> Vec<u8> {
+        let mut buf = [0; 1024];
+        let len = self.udp.recv(&mut buf).expect("No frame was streamed to the controller");
+        buf[..len].to_vec()
+    }
+
+    /// Fall behind, and answer slowly, while frames stream faster than `rate`. Frames
+    /// are counted rather than kept for `receive_frame`.
+    fn keep_up_with(&self, rate: f32) {
+        self.state.lock().unwrap().keeps_up_with = Some(rate);
+        let (udp, state) = (self.udp.try_clone().unwrap(), self.state.clone());
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while udp.recv(&mut buf).is_ok() {
+                state.lock().unwrap().frames.push(Instant::now());
+            }
+        });
+    }
+
+    fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Wait for a request matching `matches` to arrive, for requests sent in the
+    /// background.
+    async fn wait_for_request(&self, matches: impl Fn(&Request) -> bool) -> Request {
+        let deadline = Instant::now() + RECEIVE_TIMEOUT;
+        loop {
+            if let Some(request) = self.requests().into_iter().find(&matches) {
+                return request;
+            }
+            assert!(Instant::now() < deadline, "The request never arrived, got {:?}", self.requests());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// Answer a single request, then close the connection.
+async fn serve(stream: TcpStream, state: Arc<Mutex<MockState>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut request_line = line.split_whitespace().map(str::to_string);
+    let (method, path) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+    let mut length = 0;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    let delay = state.lock().unwrap().answer_delay();
+    tokio::time::sleep(delay).await;
+    let (status, response) = respond(&mut state.lock().unwrap(), &method, &path, body);
+    let response = response.map(|response| response.to_string()).unwrap_or_default();
+    let reply = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, response.len(), response);
+    reader.into_inner().write_all(reply.as_bytes()).await
+}
+
+/// The status and JSON body a controller would answer with.
+fn respond(state: &mut MockState, method: &str, path: &str, body: Value) -> (&'static str, Option<Value>) {
+    state.requests.push(Request { method: method.to_string(), path: path.to_string(), body: body.clone() });
+    if (method, path) == ("POST", "/api/v1/new") {
+        return match state.pairing {
+            true => ("200 OK", Some(json!({"auth_token": TOKEN}))),
+            false => ("403 Forbidden", None),
+        };
+    }
+    let Some(endpoint) = path.strip_prefix(&format!("/api/v1/{}", TOKEN)) else {
+        return ("401 Unauthorized", None);
+    };
+    match (method, endpoint) {
+        ("GET", "/effects") => ("200 OK", Some(json!({"effectsList": ["Northern Lights"], "select": state.select}))),
+        ("GET", "/effects/select") => ("200 OK", Some(json!(state.select))),
+        ("PUT", "/effects") => {
+            if body["write"]["animType"] == "extControl" {
+                state.select = EXT_CONTROL_EFFECT.to_string();
+            }
+            ("204 No Content", None)
+        },
+        ("GET", "/panelLayout/layout") => ("200 OK", Some(serde_json::from_str(&fs::read_to_string(LAYOUT).unwrap()).unwrap())),
+        ("GET", "/state/on") => ("200 OK", Some(json!({"value": true}))),
+        ("GET", "/state/brightness") => ("200 OK", Some(json!({"value": 100, "max": 100, "min": 0}))),
+        _ => ("404 Not Found", None),
+    }
+}
+
+fn test_payload() -> NanoleafEffectPayload {
+    let mut payload = NanoleafEffectPayload::new(2);
+    payload.write_effect(13, 255, 0, 0, 1);
+    payload.write_effect(11, 0, 0, 255, 1);
+    payload
+}
+
+#[tokio::test]
+async fn test_pair() {
+    let mock = MockController::start().await;
+    let err = nanoleaf::pair(&mock.host, mock.port).await.unwrap_err();
+    assert!(err.to_string().contains("isn't accepting new pairings"), "{}", err);
+
+    mock.state.lock().unwrap().pairing = true;
+    assert_eq!(nanoleaf::pair(&mock.host, mock.port).await.unwrap(), TOKEN);
+}
+
+#[tokio::test]
+async fn test_connect_and_stream() {
+    let mock = MockController::start().await;
+    assert!(NanoleafClient::connect("wrong".to_string(), mock.host.clone(), mock.port, Transport::Udp, false).await.is_err());
+
+    let mut client = NanoleafClient::connect(TOKEN.to_string(), mock.host.clone(), mock.port, Transport::Udp, false).await.unwrap();
+    let ext_control = mock.requests().into_iter().find(|request| request.method == "PUT").expect("External control was never enabled");
+    assert_eq!(ext_control.body["write"], json!({"command": "display", "animType": "extControl", "extControlVersion": "v2"}));
+
+    let layout = client.get_panels().await.unwrap();
+    assert_eq!(layout.num_panels, 5);
+    client.check_udp(&layout.position_data).await.unwrap();
+    let test_frame = mock.receive_frame();
+    assert_eq!(test_frame[..2], [0, 5]);
+
+    let payload = test_payload();
+    client.send_effect(&payload).unwrap();
+    assert_eq!(mock.receive_frame(), payload.bytes());
+    client.time_response().await.unwrap();
+
+    assert!(!client.ensure_streaming(false).await.unwrap());
+    // An effect picked in the app is left alone.
+    mock.state.lock().unwrap().select = "Northern Lights".to_string();
+    assert!(!client.ensure_streaming(false).await.unwrap());
+    assert_eq!(mock.state.lock().unwrap().select, "Northern Lights");
+    // A controller that rebooted, and so left external control, is switched back.
+    assert!(client.ensure_streaming(true).await.unwrap());
+    assert_eq!(mock.state.lock().unwrap().select, EXT_CONTROL_EFFECT);
+}
+
+#[tokio::test]
+async fn test_screen_mirror() {
+    let mock = MockController::start().await;
+    mock.state.lock().unwrap().select = "*Screen Mirror*".to_string();
+    let err = NanoleafClient::connect(TOKEN.to_string(), mock.host.clone(), mock.port, Transport::Udp, false).await.err().unwrap();
+    assert!(err.to_string().contains("--force"), "{}", err);
+    assert_eq!(mock.state.lock().unwrap().select, "*Screen Mirror*");
+
+    let mut client = NanoleafClient::connect(TOKEN.to_string(), mock.host.clone(), mock.port, Transport::Udp, true).await.unwrap();
+    assert_eq!(mock.state.lock().unwrap().select, EXT_CONTROL_EFFECT);
+    mock.state.lock().unwrap().select = "*Screen Mirror*".to_string();
+    assert!(client.ensure_streaming(false).await.unwrap());
+
+    // Unless forced, screen mirroring started while running is left alone, even after
+    // a restart.
+    let mut client = NanoleafClient::connect(TOKEN.to_string(), mock.host.clone(), mock.port, Transport::Udp, false).await.unwrap();
+    mock.state.lock().unwrap().select = "*Screen Mirror*".to_string();
+    assert!(!client.ensure_streaming(false).await.unwrap());
+    assert!(!client.ensure_streaming(true).await.unwrap());
+    assert_eq!(mock.state.lock().unwrap().select, "*Screen Mirror*");
+
+    // An effect that only has mirror in its name isn't screen mirroring.
+    mock.state.lock().unwrap().select = "Mirror Lake".to_string();
+    NanoleafClient::connect(TOKEN.to_string(), mock.host.clone(), mock.port, Transport::Udp, false).await.unwrap();
+    assert_eq!(mock.state.lock().unwrap().select, EXT_CONTROL_EFFECT);
+}
+
+#[tokio::test]
+async fn test_http_transport() {
+    let mock = MockController::start().await;
+    let mut client = NanoleafClient::connect(TOKEN.to_string(), mock.host.clone(), mock.port, Transport::Http, false).await.unwrap();
+    assert!(client.uses_http());
+
+    let payload = test_payload();
+    client.send_effect(&payload).unwrap();
+    let display = mock.wait_for_request(|request| request.body["write"]["animType"] == "static").await;
+    assert_eq!(display.body["write"]["animData"], payload.anim_data(5));
+
+    // A frame sent straight after is dropped, but one held back for the frame interval
+    // is shown, as `identify` does.
+    let mut dark = NanoleafEffectPayload::new(2);
+    dark.write_effect(13, 0, 0, 0, 1);
+    dark.write_effect(11, 0, 0, 0, 1);
+    client.send_effect(&dark).unwrap();
+    tokio::time::sleep(client.frame_interval()).await;
+    let displays = |mock: &MockController| mock.requests().into_iter().filter(|request| request.body["write"]["animType"] == "static").count();
+    assert_eq!(displays(&mock), 1);
+    client.send_effect(&dark).unwrap();
+    mock.wait_for_request(|request| request.body["write"]["animData"] == dark.anim_data(5)).await;
+
+    // Frames sent over HTTP aren't external control, and don't need it.
+    mock.state.lock().unwrap().select = "*Static*".to_string();
+    assert!(!client.ensure_streaming(true).await.unwrap());
+    assert_eq!(mock.state.lock().unwrap().select, "*Static*");
+}
+
+#[tokio::test]
+async fn test_probe_frame_rate() {
+    let mock = MockController::start().await;
+    let mut client = NanoleafClient::connect(TOKEN.to_string(), mock.host.clone(), mock.port, Transport::Udp, false).await.unwrap();
+    let layout = client.get_panels().await.unwrap();
+    mock.keep_up_with(10.0);
+    let rate = device::probe_frame_rate(&mut client, &layout.position_data, 20.0).await.unwrap();
+    assert_eq!(rate, 10.0);
+}
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use zbus::blocking::fdo::DBusProxy;
+use zbus::blocking::{Connection, Proxy};
+
+use crate::crash;
+use crate::power::PowerSaver;
+
+/**
+ * How often to ask media players whether they're playing. Players come and go, so
+ * asking is simpler than keeping track of each one's signals.
+ */
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+/// Whether any media player on the session bus says it's playing.
+fn any_playing(connection: &Connection) -> Result<bool, zbus::Error> {
+    let names = DBusProxy::new(connection)?.list_names()?;
+    Ok(names.iter().filter(|name| name.as_str().starts_with(MPRIS_PREFIX)).any(|name| {
+        Proxy::new(connection, name.as_str(), "/org/mpris/MediaPlayer2", "org.mpris.MediaPlayer2.Player")
+            .and_then(|proxy| proxy.get_property::<String>("PlaybackStatus"))
+            .is_ok_and(|status| status == "Playing")
+    }))
+}
+
+/// Watch media players over MPRIS, telling `power` whenever any of them is playing so
+/// it can wake up from standby, even for video without sound.
+pub fn watch(power: Arc<PowerSaver>) -> Result<(), zbus::Error> {
+    let connection = Connection::session()?;
+    crash::spawn("mpris", move || loop {
+        match any_playing(&connection) {
+            Ok(playing) => power.set_playing(playing),
+            Err(err) => {
+                log::warn!("Could not ask media players whether they're playing: {}", err);
+                return;
+            },
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+    Ok(())
+}
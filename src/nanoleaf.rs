@@ -1,9 +1,48 @@
+#[cfg(feature = "nanoleaf")]
 use std::net::UdpSocket;
+#[cfg(feature = "nanoleaf")]
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+#[cfg(feature = "nanoleaf")]
+use std::sync::Arc;
+#[cfg(feature = "nanoleaf")]
+use std::time::{Duration, Instant};
 use serde::{Serialize,Deserialize};
 
+#[cfg(feature = "nanoleaf")]
+use crate::log_throttle::warn_throttled;
+
+/// How frames are sent to the controller.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Stream over UDP, falling back to HTTP if sends keep failing and trying UDP
+    /// again every minute.
+    #[default]
+    Auto,
+    /// Only ever stream over UDP.
+    Udp,
+    /// Send display commands over HTTP at a low rate, for networks that drop UDP
+    /// without an error.
+    Http,
+}
+
+#[cfg(feature = "nanoleaf")]
 pub struct NanoleafClient {
     socket: UdpSocket,
     base_url: String,
+    transport: Transport,
+    /// UDP sends that have failed in a row.
+    udp_failures: u32,
+    /// When frames fell back to HTTP, so UDP can be tried again later.
+    http_since: Option<Instant>,
+    http: reqwest::Client,
+    http_last_sent: Option<Instant>,
+    /// Whether an HTTP frame is still on its way, so they don't pile up.
+    http_in_flight: Arc<AtomicBool>,
+    /// HTTP frames that have failed in a row.
+    http_failures: Arc<AtomicU32>,
+    /// Whether to take the panels over from the Nanoleaf app's screen mirroring.
+    force: bool,
 }
 
 #[derive(Debug)]
@@ -11,6 +50,14 @@ pub struct NanoleafError {
     msg: String,
 }
 
+impl std::fmt::Display for NanoleafError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl std::error::Error for NanoleafError {}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct NanoleafEffectsResponse {
@@ -18,6 +65,25 @@ struct NanoleafEffectsResponse {
     select: String,
 }
 
+/// The controller's own brightness setting, which it scales every frame by.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NanoleafBrightness {
+    pub value: u32,
+    #[serde(default)]
+    pub min: u32,
+    pub max: u32,
+}
+
+impl NanoleafBrightness {
+    /// How much of its full brightness the controller shows, in percent.
+    pub fn percent(&self) -> u8 {
+        if self.max == 0 {
+            return 100;
+        }
+        (self.value.min(self.max) * 100 / self.max) as u8
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct NanoleafLayoutPanelData {
@@ -25,6 +91,9 @@ pub struct NanoleafLayoutPanelData {
     pub x: usize,
     pub y: usize,
     pub shape_type: u8,
+    /// Rotation of the panel, in degrees.
+    #[serde(rename = "o", default)]
+    pub orientation: u16,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -36,25 +105,92 @@ pub struct NanoleafLayoutResponse {
 }
 
 const EFFECT_SIZE_BYTES: usize = 8;
+#[cfg(feature = "nanoleaf")]
 const UDP_PORT: u16 = 60222;
+/**
+ * UDP sends that must fail in a row before falling back to HTTP.
+ */
+#[cfg(feature = "nanoleaf")]
+const UDP_FAILURE_LIMIT: u32 = 20;
+/**
+ * How long to stay on HTTP after falling back before trying UDP again, in case
+ * whatever was dropping it has gone away.
+ */
+#[cfg(feature = "nanoleaf")]
+const UDP_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/**
+ * Time between frames sent over HTTP, which is much slower than the UDP stream.
+ */
+#[cfg(feature = "nanoleaf")]
+const HTTP_INTERVAL: Duration = Duration::from_millis(500);
+/**
+ * HTTP frames that must fail in a row before the nanoleaf is treated as unreachable.
+ */
+#[cfg(feature = "nanoleaf")]
+const HTTP_FAILURE_LIMIT: u32 = 10;
+/**
+ * How long to wait for the API to answer when checking the controller is still
+ * streaming.
+ */
+#[cfg(feature = "nanoleaf")]
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/**
+ * Longest any request to the API may take, so one that hangs can't hold up the frames
+ * sent after it for good.
+ */
+#[cfg(feature = "nanoleaf")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/**
+ * How long to give the network to reject the UDP test frame sent at startup.
+ */
+#[cfg(feature = "nanoleaf")]
+const UDP_CHECK_WAIT: Duration = Duration::from_millis(250);
 pub const DEFAULT_API_PORT: u16 = 16021;
+/**
+ * The effect the controller reports while in external control mode.
+ */
+#[cfg(feature = "nanoleaf")]
+const EXT_CONTROL_EFFECT: &str = "*ExtControl*";
+/**
+ * The effects the controller reports while the Nanoleaf app mirrors a screen to it,
+ * matched exactly so an effect of the user's own named after a mirror isn't taken for one.
+ */
+#[cfg(feature = "nanoleaf")]
+const SCREEN_MIRROR_EFFECTS: &[&str] = &["*Screen Mirror*"];
+
+/// Whether the controller is mirroring a screen for the Nanoleaf app, such as with the
+/// 4D kit.
+#[cfg(feature = "nanoleaf")]
+fn is_screen_mirror(select: &str) -> bool {
+    SCREEN_MIRROR_EFFECTS.contains(&select)
+}
 
+#[derive(Clone)]
 pub struct NanoleafEffectPayload {
-    pub buf: Vec<u8>,
+    buf: Vec<u8>,
     head: usize,
 }
 
 impl NanoleafEffectPayload {
+    /// A payload with room for up to `panels_to_update` panels.
     pub fn new(panels_to_update: usize) -> Self {
-        let mut buf = vec![0_u8; 2 + (EFFECT_SIZE_BYTES*panels_to_update)];
-        buf[0] = (panels_to_update >> 8).try_into().unwrap();
-        buf[1] = (panels_to_update % 256).try_into().unwrap();
         NanoleafEffectPayload {
             head: 2,
-            buf,
+            buf: vec![0_u8; 2 + (EFFECT_SIZE_BYTES*panels_to_update)],
         }
     }
 
+    /// The frame to send, covering just the panels written so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf[..self.head]
+    }
+
+    /// The panel ID, colour and transition time of each panel written so far.
+    pub fn panels(&self) -> impl Iterator<Item = (u16, [u8; 3], u8)> + '_ {
+        self.buf[2..self.head].chunks_exact(EFFECT_SIZE_BYTES)
+            .map(|panel| (u16::from_be_bytes([panel[0], panel[1]]), [panel[2], panel[3], panel[4]], panel[7]))
+    }
+
     /// Write an effect to the payload to be sent.
     /// `transition_time_cs` is in deciseconds.
     pub fn write_effect(&mut self, panel_id: u16, r: u8, g: u8, b: u8, transition_time_ds: u8) {
@@ -72,16 +208,108 @@ impl NanoleafEffectPayload {
         self.buf[self.head + 6] = 0;
         self.buf[self.head + 7] = transition_time_ds;
         self.head += 8;
+        let panel_count = (self.head - 2) / EFFECT_SIZE_BYTES;
+        self.buf[0] = (panel_count >> 8).try_into().unwrap();
+        self.buf[1] = (panel_count % 256).try_into().unwrap();
+    }
+
+    /// The frame as `animData` for a static display command, fading each panel over
+    /// `transition_time_ds` deciseconds. Only panels written so far are included.
+    pub fn anim_data(&self, transition_time_ds: u16) -> String {
+        let mut anim_data = ((self.head - 2) / EFFECT_SIZE_BYTES).to_string();
+        for (panel_id, [r, g, b], _) in self.panels() {
+            anim_data.push_str(&format!(" {} 1 {} {} {} 0 {}", panel_id, r, g, b, transition_time_ds));
+        }
+        anim_data
+    }
+}
+
+
+#[cfg(feature = "nanoleaf")]
+#[derive(Deserialize, Debug)]
+struct NanoleafPairResponse {
+    auth_token: String,
+}
+
+/// A client for the API that gives up on requests after [`REQUEST_TIMEOUT`].
+#[cfg(feature = "nanoleaf")]
+fn http_client() -> Result<reqwest::Client, NanoleafError> {
+    reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build().map_err(|err| NanoleafError {
+        msg: format!("Failed to create HTTP client {:?}", err),
+    })
+}
+
+#[cfg(feature = "nanoleaf")]
+async fn fetch_layout(base_url: &str) -> Result<NanoleafLayoutResponse, NanoleafError> {
+    http_client()?.get(format!("{base_url}/panelLayout/layout")).send()
+    .await
+    .and_then(|res| res.error_for_status()).map_err(|err| NanoleafError {
+        msg: format!("Failed to contact nanoleaf API {:?}", err),
+    })?.json::<NanoleafLayoutResponse>().await.map_err(|err| NanoleafError {
+        msg: format!("Failed to parse JSON from /panelLayout/layout API {:?}", err),
+    })
+}
+
+/// Fetch the layout of the panels without taking over the controller, which
+/// [`NanoleafClient::connect`] expects to have done.
+#[cfg(feature = "nanoleaf")]
+pub async fn get_layout(access_token: &str, host: &str, http_port: u16) -> Result<NanoleafLayoutResponse, NanoleafError> {
+    fetch_layout(&format!("http://{host}:{http_port}/api/v1/{access_token}")).await
+}
+
+/// Ask the controller for a new access token. This only works for 30 seconds after
+/// the controller's power button has been held until the lights flash.
+#[cfg(feature = "nanoleaf")]
+pub async fn pair(host: &str, http_port: u16) -> Result<String, NanoleafError> {
+    let response = http_client()?.post(format!("http://{host}:{http_port}/api/v1/new"))
+        .send()
+        .await
+        .map_err(|err| NanoleafError {
+            msg: format!("Failed to contact nanoleaf API {:?}", err),
+        })?;
+    if response.status() == reqwest::StatusCode::FORBIDDEN {
+        return Err(NanoleafError {
+            msg: "The controller isn't accepting new pairings, hold its power button until the lights flash and try again".to_string(),
+        });
     }
+    response.error_for_status().map_err(|err| NanoleafError {
+        msg: format!("Failed to pair with nanoleaf {:?}", err),
+    })?.json::<NanoleafPairResponse>().await.map(|pair| pair.auth_token).map_err(|err| NanoleafError {
+        msg: format!("Failed to parse JSON from /new API {:?}", err),
+    })
 }
 
+/// Put the controller into external control mode, where it shows the frames we stream
+/// to it.
+#[cfg(feature = "nanoleaf")]
+async fn enable_ext_control(http: &reqwest::Client, base_url: &str) -> Result<(), NanoleafError> {
+    log::info!("Switching the nanoleaf to external control");
+    let body = serde_json::json!({
+        "write": {
+            "command": "display",
+            "animType": "extControl",
+            "extControlVersion": "v2",
+        }
+    });
+    http.put(format!("{base_url}/effects")).json(&body).timeout(HEALTH_CHECK_TIMEOUT).send()
+        .await
+        .and_then(|res| res.error_for_status()).map_err(|err| NanoleafError {
+            msg: format!("Failed to enable external control {:?}", err),
+        })?;
+    Ok(())
+}
 
+#[cfg(feature = "nanoleaf")]
 impl NanoleafClient {
 
-    pub async fn connect(access_token: String, host: String, http_port: u16) -> Result<Self, NanoleafError> {
+    /// Connect to the controller and switch it to external control. While the Nanoleaf
+    /// app is mirroring a screen to the panels, this is refused unless `force` is set,
+    /// so the two don't fight over them.
+    pub async fn connect(access_token: String, host: String, http_port: u16, transport: Transport, force: bool) -> Result<Self, NanoleafError> {
         let base_url = format!("http://{host}:{http_port}/api/v1/{access_token}", host=host, access_token=access_token);
 
-        let effects_result = reqwest::get(format!("{base_url}/effects"))
+        let http = http_client()?;
+        let effects_result = http.get(format!("{base_url}/effects")).send()
             .await
             .and_then(|res| res.error_for_status()).map_err(|err| NanoleafError {
                 msg: format!("Failed to contact nanoleaf API {:?}", err),
@@ -89,9 +317,16 @@ impl NanoleafClient {
                 msg: format!("Failed to parse JSON from /effects API {:?}", err),
             })?;
 
-        if effects_result.select != "*ExtControl*" {
-            // Make sure we enable ExtControl
-            panic!("Not implemented configuring ExtControl");
+        if is_screen_mirror(&effects_result.select) {
+            if !force {
+                return Err(NanoleafError {
+                    msg: format!("The nanoleaf is mirroring a screen for the Nanoleaf app ({}). Stop screen mirroring in the app, or run with --force to take over", effects_result.select),
+                });
+            }
+            log::warn!("Taking over from the Nanoleaf app's screen mirroring ({})", effects_result.select);
+        }
+        if effects_result.select != EXT_CONTROL_EFFECT {
+            enable_ext_control(&http, &base_url).await?;
         }
 
         // Now bind
@@ -102,6 +337,14 @@ impl NanoleafClient {
                 Ok(NanoleafClient {
                     socket,
                     base_url,
+                    transport,
+                    udp_failures: 0,
+                    http_since: None,
+                    http,
+                    http_last_sent: None,
+                    http_in_flight: Arc::new(AtomicBool::new(false)),
+                    http_failures: Arc::new(AtomicU32::new(0)),
+                    force,
                 })
             },
             Err(e) => {
@@ -113,17 +356,196 @@ impl NanoleafClient {
     }
 
     pub async fn get_panels(&self) -> Result<NanoleafLayoutResponse, NanoleafError> {
-        reqwest::get(format!("{base_url}/panelLayout/layout", base_url=self.base_url))
-        .await
-        .and_then(|res| res.error_for_status()).map_err(|err| NanoleafError {
-            msg: format!("Failed to contact nanoleaf API {:?}", err),
-        })?.json::<NanoleafLayoutResponse>().await.map_err(|err| NanoleafError {
-            msg: format!("Failed to parse JSON from /panelLayout/layout API {:?}", err),
-        })
+        fetch_layout(&self.base_url).await
     }
 
-    pub fn send_effect(&self, payload: &NanoleafEffectPayload)->Result<(), std::io::Error> {
-        self.socket.send(&payload.buf).map(|_| {})
+    /// Send a test frame lighting every panel dim white, and check the network didn't
+    /// reject it, so a blocked UDP port is reported straight away. Firewalls that drop
+    /// packets without replying can't be detected this way.
+    pub async fn check_udp(&mut self, panels: &[NanoleafLayoutPanelData]) -> Result<(), std::io::Error> {
+        if self.transport == Transport::Http {
+            return Ok(());
+        }
+        let mut payload = NanoleafEffectPayload::new(panels.len());
+        for panel in panels {
+            payload.write_effect(panel.panel_id, 40, 40, 40, 1);
+        }
+        self.socket.send(payload.bytes())?;
+        tokio::time::sleep(UDP_CHECK_WAIT).await;
+        // Rejections, such as ICMP port unreachable, are left as a pending socket error.
+        let Some(err) = self.socket.take_error()? else {
+            return Ok(());
+        };
+        if self.transport == Transport::Auto {
+            log::warn!("Falling back to slower HTTP updates");
+            self.udp_failures = UDP_FAILURE_LIMIT;
+            self.http_since = Some(Instant::now());
+        }
+        Err(err)
+    }
+
+    /// Check the controller is still answering. If it's coming back after not
+    /// answering (`away`), such as after it rebooted for a firmware update, it's
+    /// switched back to external control, returning whether it had to be. Otherwise
+    /// an effect picked in the Nanoleaf app is left alone. The app mirroring a screen
+    /// is always left alone unless forced, and taken over if it is. Frames sent over
+    /// HTTP don't need external control.
+    pub async fn ensure_streaming(&mut self, away: bool) -> Result<bool, NanoleafError> {
+        let select = self.http.get(format!("{base_url}/effects/select", base_url=self.base_url)).timeout(HEALTH_CHECK_TIMEOUT).send()
+            .await
+            .and_then(|res| res.error_for_status()).map_err(|err| NanoleafError {
+                msg: format!("Failed to contact nanoleaf API {:?}", err),
+            })?.json::<String>().await.map_err(|err| NanoleafError {
+                msg: format!("Failed to parse JSON from /effects/select API {:?}", err),
+            })?;
+        if select == EXT_CONTROL_EFFECT || self.uses_http() {
+            return Ok(false);
+        }
+        let mirroring = is_screen_mirror(&select);
+        if mirroring && !self.force {
+            warn_throttled!("The Nanoleaf app is mirroring a screen ({}), leaving the panels to it", select);
+            return Ok(false);
+        }
+        if !away && !mirroring {
+            warn_throttled!("The nanoleaf is showing {} from the Nanoleaf app, leaving the panels to it", select);
+            return Ok(false);
+        }
+        enable_ext_control(&self.http, &self.base_url).await?;
+        // Sends that failed while it was away say nothing about the network, so give
+        // UDP another chance.
+        self.udp_failures = 0;
+        self.http_since = None;
+        self.http_failures.store(0, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Read the controller's brightness setting, and the most it can be set to. The API
+    /// has nothing on whether the controller is dimming itself to keep cool.
+    pub async fn brightness(&self) -> Result<NanoleafBrightness, NanoleafError> {
+        self.http.get(format!("{base_url}/state/brightness", base_url=self.base_url)).timeout(HEALTH_CHECK_TIMEOUT).send()
+            .await
+            .and_then(|res| res.error_for_status()).map_err(|err| NanoleafError {
+                msg: format!("Failed to contact nanoleaf API {:?}", err),
+            })?.json::<NanoleafBrightness>().await.map_err(|err| NanoleafError {
+                msg: format!("Failed to parse JSON from /state/brightness API {:?}", err),
+            })
+    }
+
+    /// Time how long the controller takes to answer a request for its state, without
+    /// holding on to the client. A controller struggling to keep up with the frames
+    /// streamed to it is slow to answer.
+    pub fn time_response(&self) -> impl std::future::Future<Output = Result<Duration, NanoleafError>> + Send + 'static {
+        let request = self.http.get(format!("{base_url}/state/on", base_url=self.base_url)).timeout(HEALTH_CHECK_TIMEOUT);
+        async move {
+            let start = Instant::now();
+            request.send()
+                .await
+                .and_then(|res| res.error_for_status()).map_err(|err| NanoleafError {
+                    msg: format!("Failed to contact nanoleaf API {:?}", err),
+                })?;
+            Ok(start.elapsed())
+        }
+    }
+
+    /// Whether frames are going over HTTP, where each one sets the whole layout.
+    pub fn uses_http(&self) -> bool {
+        match self.transport {
+            Transport::Http => true,
+            Transport::Udp => false,
+            Transport::Auto => self.udp_failures >= UDP_FAILURE_LIMIT,
+        }
+    }
+
+    /// Shortest time a frame must be left before the next, as frames sent any sooner
+    /// over HTTP are dropped.
+    pub fn frame_interval(&self) -> Duration {
+        if self.uses_http() {
+            HTTP_INTERVAL
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Whether frames have been failing for long enough that the nanoleaf looks to be
+    /// gone from the network.
+    pub fn unreachable(&self) -> bool {
+        if self.uses_http() {
+            self.http_failures.load(Ordering::Relaxed) >= HTTP_FAILURE_LIMIT
+        } else {
+            self.udp_failures >= UDP_FAILURE_LIMIT
+        }
+    }
+
+    pub fn send_effect(&mut self, payload: &NanoleafEffectPayload)->Result<(), std::io::Error> {
+        if self.http_since.is_some_and(|since| since.elapsed() >= UDP_RETRY_INTERVAL) {
+            log::info!("Trying UDP updates to the nanoleaf again");
+            self.udp_failures = 0;
+            self.http_since = None;
+        }
+        if self.uses_http() {
+            self.send_http(payload);
+            return Ok(());
+        }
+        match self.socket.send(payload.bytes()) {
+            Ok(_) => {
+                self.udp_failures = 0;
+                Ok(())
+            },
+            Err(err) => {
+                self.udp_failures += 1;
+                if self.transport == Transport::Auto && self.udp_failures == UDP_FAILURE_LIMIT {
+                    log::warn!("UDP sends to the nanoleaf keep failing ({}), falling back to slower HTTP updates", err);
+                    self.http_since = Some(Instant::now());
+                }
+                Err(err)
+            },
+        }
+    }
+
+    /// Send a frame as a display command over HTTP, dropping it if the last one was
+    /// too recent or is still being sent.
+    fn send_http(&mut self, payload: &NanoleafEffectPayload) {
+        if self.http_last_sent.is_some_and(|sent| sent.elapsed() < HTTP_INTERVAL) || self.http_in_flight.load(Ordering::Relaxed) {
+            return;
+        }
+        self.http_last_sent = Some(Instant::now());
+        self.http_in_flight.store(true, Ordering::Relaxed);
+        let body = serde_json::json!({
+            "write": {
+                "command": "display",
+                "animType": "static",
+                "animData": payload.anim_data((HTTP_INTERVAL.as_millis() / 100) as u16),
+                "loop": false,
+                "palette": [],
+            }
+        });
+        let request = self.http.put(format!("{base_url}/effects", base_url=self.base_url)).json(&body);
+        let (in_flight, failures) = (self.http_in_flight.clone(), self.http_failures.clone());
+        tokio::spawn(async move {
+            match request.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => failures.store(0, Ordering::Relaxed),
+                Err(err) => {
+                    warn_throttled!("Failed to send effect to nanoleaf over HTTP {:?}", err);
+                    failures.fetch_add(1, Ordering::Relaxed);
+                },
+            }
+            in_flight.store(false, Ordering::Relaxed);
+        });
     }
     
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let mut payload = NanoleafEffectPayload::new(3);
+        payload.write_effect(300, 255, 0, 10, 1);
+        payload.write_effect(7, 1, 2, 3, 1);
+        assert_eq!(payload.anim_data(5), "2 300 1 255 0 10 0 5 7 1 1 2 3 0 5");
+        // Only the panels written are sent, and the count says so.
+        assert_eq!(payload.bytes(), &[0, 2, 1, 44, 255, 0, 10, 0, 0, 1, 0, 7, 1, 2, 3, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,150 @@
+use std::net::UdpSocket;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::crash;
+use crate::downmix::Downmix;
+use crate::log_throttle::warn_throttled;
+use crate::vis::BufferManager;
+
+/**
+ * Large enough for any UDP datagram.
+ */
+const MAX_PACKET_SIZE: usize = 65536;
+
+/**
+ * Size of an RTP header without any CSRCs or extensions.
+ */
+const RTP_HEADER_SIZE: usize = 12;
+
+/// How the PCM audio is packaged in each UDP packet.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkAudioFormat {
+    /// RTP with a 16 bit big endian (L16) payload, as sent by PipeWire and PulseAudio's
+    /// RTP modules.
+    #[default]
+    Rtp,
+    /// Bare 16 bit little endian PCM, e.g. `snapclient --player file` piped into socat.
+    Raw,
+}
+
+fn default_rate() -> u32 {
+    48000
+}
+
+fn default_channels() -> u16 {
+    2
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NetworkAudioConfig {
+    /// Address to listen for packets on, e.g. "0.0.0.0:46000".
+    pub listen: String,
+    #[serde(default)]
+    pub format: NetworkAudioFormat,
+    #[serde(default = "default_rate")]
+    pub rate: u32,
+    #[serde(default = "default_channels")]
+    pub channels: u16,
+}
+
+/// Find the payload of an RTP packet, or `None` if it isn't a valid RTP packet.
+fn rtp_payload(packet: &[u8]) -> Option<&[u8]> {
+    let first = *packet.first()?;
+    if first >> 6 != 2 {
+        return None;
+    }
+    let has_padding = first & 0x20 != 0;
+    let has_extension = first & 0x10 != 0;
+    let csrc_count = (first & 0x0f) as usize;
+
+    let mut start = RTP_HEADER_SIZE + csrc_count * 4;
+    if has_extension {
+        let length = u16::from_be_bytes([*packet.get(start + 2)?, *packet.get(start + 3)?]) as usize;
+        start += 4 + length * 4;
+    }
+    let mut end = packet.len();
+    if has_padding {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+    packet.get(start..end)
+}
+
+/// Decode interleaved 16 bit PCM.
+fn decode_pcm(payload: &[u8], big_endian: bool, samples: &mut Vec<f32>) {
+    samples.clear();
+    samples.extend(payload.chunks_exact(2).map(|bytes| {
+        let bytes = [bytes[0], bytes[1]];
+        let value = if big_endian { i16::from_be_bytes(bytes) } else { i16::from_le_bytes(bytes) };
+        value as f32 / i16::MAX as f32
+    }));
+}
+
+/// Start receiving audio from the network on a new thread, mixing it down with
+/// `downmix` and feeding it to the buffer manager.
+pub fn start(config: NetworkAudioConfig, buffer_manager: Arc<RwLock<BufferManager>>, downmix: Downmix) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(&config.listen)?;
+    log::info!("Receiving {:?} audio on {}", config.format, config.listen);
+    crash::spawn("network audio", move || {
+        let mut packet = vec![0u8; MAX_PACKET_SIZE];
+        let mut interleaved = Vec::new();
+        let (mut samples, mut sides) = (Vec::new(), Vec::new());
+        loop {
+            let size = match socket.recv(&mut packet) {
+                Ok(size) => size,
+                Err(err) => {
+                    warn_throttled!("Failed to receive network audio: {}", err);
+                    continue;
+                }
+            };
+            let packet = &packet[..size];
+            let payload = match config.format {
+                NetworkAudioFormat::Rtp => match rtp_payload(packet) {
+                    Some(payload) => payload,
+                    None => {
+                        log::debug!("Ignoring invalid RTP packet");
+                        continue;
+                    }
+                },
+                NetworkAudioFormat::Raw => packet,
+            };
+            decode_pcm(payload, config.format == NetworkAudioFormat::Rtp, &mut interleaved);
+            let width = downmix.mix(&interleaved, config.channels as usize, &mut samples, &mut sides);
+            if !samples.is_empty() {
+                let mut buffer_manager = buffer_manager.write().unwrap();
+                buffer_manager.fill_buffer(&samples, config.rate);
+                buffer_manager.fill_side(&sides, config.rate);
+                buffer_manager.record_width(width);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rtp_payload() {
+        // Version 2 with padding and one CSRC, followed by two stereo L16 frames and
+        // two bytes of padding.
+        let mut packet = vec![0xa1, 0x0b, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&[0x40, 0x00, 0x40, 0x00, 0xc0, 0x00, 0x00, 0x00]);
+        packet.extend_from_slice(&[0, 2]);
+        let payload = rtp_payload(&packet).unwrap();
+        assert_eq!(payload.len(), 8);
+
+        let mut interleaved = Vec::new();
+        decode_pcm(payload, true, &mut interleaved);
+        assert_eq!(interleaved.len(), 4);
+        let mut samples = Vec::new();
+        Downmix::Mono.mix(&interleaved, 2, &mut samples, &mut Vec::new());
+        assert_eq!(samples.len(), 2);
+        assert!((samples[0] - 0.5).abs() < 0.001);
+        assert!((samples[1] + 0.25).abs() < 0.001);
+        assert!(rtp_payload(&[0x00; 12]).is_none(), "Version 0 isn't RTP");
+    }
+}
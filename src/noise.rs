@@ -0,0 +1,201 @@
+use std::time::Instant;
+
+use colors_transform::{Color, Hsl};
+use serde::Deserialize;
+
+use crate::clock;
+use crate::effects::PostProcess;
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::oklab::ColorSpace;
+
+/**
+ * Skews the plane onto a grid of triangles and back again.
+ */
+const SKEW: f32 = 0.366_025_42;
+const UNSKEW: f32 = 0.211_324_87;
+
+/**
+ * Directions the noise can slope in at each corner of the grid.
+ */
+const GRADIENTS: [(f32, f32); 8] = [
+    (1.0, 0.0), (-1.0, 0.0), (0.0, 1.0), (0.0, -1.0),
+    (0.707, 0.707), (-0.707, 0.707), (0.707, -0.707), (-0.707, -0.707),
+];
+
+/**
+ * Where the second layer of noise is taken from, far enough from the first that they
+ * don't line up.
+ */
+const SECOND_LAYER: (f32, f32) = (31.4, 17.7);
+
+fn gradient(i: i32, j: i32) -> (f32, f32) {
+    let mut hash = (i as u32).wrapping_mul(0x27d4_eb2d) ^ (j as u32).wrapping_mul(0x1656_67b1);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x2c1b_3c6d);
+    hash ^= hash >> 12;
+    GRADIENTS[(hash & 7) as usize]
+}
+
+/// 2D simplex noise, from -1 to 1, changing smoothly over about a unit.
+pub fn simplex(x: f32, y: f32) -> f32 {
+    let skew = (x + y) * SKEW;
+    let (i, j) = ((x + skew).floor(), (y + skew).floor());
+    let unskew = (i + j) * UNSKEW;
+    let (x0, y0) = (x - (i - unskew), y - (j - unskew));
+    // Which of the two triangles in this cell the point is in.
+    let (i1, j1) = if x0 > y0 { (1.0, 0.0) } else { (0.0, 1.0) };
+    let corners = [
+        (x0, y0, 0.0, 0.0),
+        (x0 - i1 + UNSKEW, y0 - j1 + UNSKEW, i1, j1),
+        (x0 - 1.0 + 2.0 * UNSKEW, y0 - 1.0 + 2.0 * UNSKEW, 1.0, 1.0),
+    ];
+    let total: f32 = corners.iter().map(|(dx, dy, di, dj)| {
+        let falloff = 0.5 - dx * dx - dy * dy;
+        if falloff <= 0.0 {
+            return 0.0;
+        }
+        let (gx, gy) = gradient((i + di) as i32, (j + dj) as i32);
+        falloff.powi(4) * (gx * dx + gy * dy)
+    }).sum();
+    (total * 70.0).clamp(-1.0, 1.0)
+}
+
+fn default_scale() -> f32 {
+    1.5
+}
+
+fn default_speed() -> f32 {
+    0.1
+}
+
+/// Slowly moving noise laid over the panels.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NoiseConfig {
+    /// Roughly how many blobs fit across the layout.
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// How far the blobs move per second, in blobs.
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        NoiseConfig { scale: default_scale(), speed: default_speed() }
+    }
+}
+
+impl NoiseConfig {
+    /// The noise from -1 to 1 at each panel, `elapsed` seconds in. Two layers drift in
+    /// different directions, so the blobs change shape rather than just scrolling past.
+    pub fn sample(&self, panels: &[NanoleafLayoutPanelData], elapsed: f32) -> Vec<f32> {
+        let min_x = panels.iter().map(|panel| panel.x).min().unwrap_or(0);
+        let max_x = panels.iter().map(|panel| panel.x).max().unwrap_or(0);
+        let min_y = panels.iter().map(|panel| panel.y).min().unwrap_or(0);
+        let max_y = panels.iter().map(|panel| panel.y).max().unwrap_or(0);
+        // Scaled by the longer side, so blobs stay round on long thin layouts.
+        let size = (max_x - min_x).max(max_y - min_y).max(1) as f32;
+        let moved = elapsed * self.speed;
+        panels.iter().map(|panel| {
+            let x = (panel.x - min_x) as f32 / size * self.scale;
+            let y = (panel.y - min_y) as f32 / size * self.scale;
+            let first = simplex(x + moved, y);
+            let second = simplex(y + SECOND_LAYER.0 - moved * 0.7, x + SECOND_LAYER.1 + moved * 0.4);
+            (first + second) * 0.5
+        }).collect()
+    }
+}
+
+fn default_brightness() -> f32 {
+    15.0
+}
+
+/// Noise laid over the effect, shimmering its brightness and hue.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct NoiseShimmerConfig {
+    #[serde(default)]
+    pub noise: NoiseConfig,
+    /// How far to raise or lower the lightness, in percent.
+    #[serde(default = "default_brightness")]
+    pub brightness: f32,
+    /// How far to shift the hue either way, in degrees.
+    #[serde(default)]
+    pub hue: f32,
+}
+
+/// Shimmers the effect's output with noise across the layout, so neighbouring panels
+/// rise and fall together like light through water.
+pub struct NoiseShimmer {
+    config: NoiseShimmerConfig,
+    panels: Vec<NanoleafLayoutPanelData>,
+    started: Instant,
+    color_space: ColorSpace,
+}
+
+impl NoiseShimmer {
+    pub fn new(config: NoiseShimmerConfig, panels: &[NanoleafLayoutPanelData], color_space: ColorSpace) -> Self {
+        NoiseShimmer {
+            config,
+            panels: panels.to_vec(),
+            started: clock::now(),
+            color_space,
+        }
+    }
+
+    fn shimmer(&self, colors: &mut [Option<Hsl>], elapsed: f32) {
+        let noise = self.config.noise.sample(&self.panels, elapsed);
+        for (hsl, noise) in colors.iter_mut().zip(noise) {
+            let Some(hsl) = hsl else {
+                continue;
+            };
+            // Panels the effect turned off stay off.
+            if hsl.get_lightness() <= 0.0 {
+                continue;
+            }
+            let hue = (hsl.get_hue() + noise * self.config.hue).rem_euclid(360.0);
+            let lightness = hsl.get_lightness() + noise * self.config.brightness;
+            *hsl = self.color_space.with_lightness(&Hsl::from(hue, hsl.get_saturation(), hsl.get_lightness()), lightness);
+        }
+    }
+}
+
+impl PostProcess for NoiseShimmer {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        self.shimmer(colors, clock::elapsed(self.started).as_secs_f32());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_noise() {
+        let samples: Vec<f32> = (0..2000).map(|i| simplex(i as f32 * 0.013, i as f32 * 0.007)).collect();
+        assert!(samples.iter().all(|noise| (-1.0..=1.0).contains(noise)));
+        assert!(samples.windows(2).all(|pair| (pair[1] - pair[0]).abs() < 0.1));
+        assert!(samples.iter().any(|noise| *noise > 0.3) && samples.iter().any(|noise| *noise < -0.3));
+
+        // Panels side by side see similar noise, and the far side of the layout doesn't.
+        let panels: Vec<NanoleafLayoutPanelData> = [0, 10, 1000].into_iter().enumerate()
+            .map(|(i, x)| NanoleafLayoutPanelData { panel_id: i as u16, x, y: 0, shape_type: 2, orientation: 0 })
+            .collect();
+        let config = NoiseConfig { scale: 3.0, speed: 0.1 };
+        let mut near = 0.0;
+        let mut far = 0.0;
+        for second in 0..200 {
+            let noise = config.sample(&panels, second as f32);
+            near += (noise[0] - noise[1]).abs();
+            far += (noise[0] - noise[2]).abs();
+        }
+        assert!(near * 3.0 < far);
+
+        let shimmer = NoiseShimmer::new(NoiseShimmerConfig { noise: config, brightness: 20.0, hue: 0.0 }, &panels, ColorSpace::Hsl);
+        let mut colors = vec![Some(Hsl::from(120.0, 100.0, 50.0)), None, Some(Hsl::from(120.0, 100.0, 0.0))];
+        shimmer.shimmer(&mut colors, 12.0);
+        assert!((30.0..=70.0).contains(&colors[0].unwrap().get_lightness()));
+        assert_eq!(colors[0].unwrap().get_hue(), 120.0);
+        assert_eq!(colors[1], None);
+        assert_eq!(colors[2].unwrap().get_lightness(), 0.0);
+    }
+}
@@ -0,0 +1,18 @@
+/// Show a desktop notification, so that when leafpipe runs in the background someone
+/// finds out why the lights stopped without reading the log. Blocks until the
+/// notification server has been reached.
+#[cfg(feature = "notify")]
+pub fn notify(summary: &str, body: &str) {
+    let result = notify_rust::Notification::new()
+        .appname("leafpipe")
+        .summary(summary)
+        .body(body)
+        .icon("dialog-warning")
+        .show();
+    if let Err(err) = result {
+        log::warn!("Failed to show desktop notification: {}", err);
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+pub fn notify(_summary: &str, _body: &str) {}
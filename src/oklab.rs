@@ -0,0 +1,181 @@
+use colors_transform::{Color, Hsl, Rgb};
+use serde::Deserialize;
+
+/// The colour space used when blending colours together and scaling their brightness.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    /// Scale HSL lightness, and blend in HSL the shorter way around the hue circle.
+    /// Cheap, but blends swing in apparent brightness and dimmed colours shift in
+    /// apparent hue.
+    #[default]
+    Hsl,
+    /// Blend and scale in Oklab, which is perceptually uniform. Blends take the
+    /// straight line between colours.
+    Oklab,
+    /// Like Oklab, but blends travel around the hue circle, so red to blue passes
+    /// through purple at full saturation.
+    Oklch,
+}
+
+fn to_linear(channel: f64) -> f64 {
+    let channel = channel / 255.0;
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn from_linear(channel: f64) -> f64 {
+    let channel = channel.clamp(0.0, 1.0);
+    let encoded = if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    };
+    encoded * 255.0
+}
+
+/// Convert 0-255 sRGB to Oklab `[L, a, b]`.
+fn rgb_to_oklab(rgb: [f64; 3]) -> [f64; 3] {
+    let [r, g, b] = rgb.map(to_linear);
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+/// Convert Oklab `[L, a, b]` to 0-255 sRGB, clipping anything out of gamut.
+fn oklab_to_rgb(lab: [f64; 3]) -> [f64; 3] {
+    let [lightness, a, b] = lab;
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ].map(from_linear)
+}
+
+fn hsl_to_rgb(hsl: &Hsl) -> [f64; 3] {
+    let (r, g, b) = hsl.to_rgb().as_tuple();
+    [r as f64, g as f64, b as f64]
+}
+
+fn rgb_to_hsl(rgb: [f64; 3]) -> Hsl {
+    Rgb::from(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32).to_hsl()
+}
+
+/// Interpolate between two angles in degrees the shorter way around the circle.
+fn lerp_hue(from: f64, to: f64, t: f64) -> f64 {
+    let delta = (to - from + 180.0).rem_euclid(360.0) - 180.0;
+    (from + delta * t).rem_euclid(360.0)
+}
+
+impl ColorSpace {
+    /// Scale the brightness of a colour by `factor`, from 0 (black) through 1
+    /// (unchanged) and above to brighten it.
+    pub fn scale(self, hsl: &Hsl, factor: f32) -> Hsl {
+        match self {
+            ColorSpace::Hsl => Hsl::from(hsl.get_hue(), hsl.get_saturation(), (hsl.get_lightness() * factor).clamp(0.0, 100.0)),
+            // Scaling chroma along with lightness keeps the hue, and keeps the colour in
+            // gamut. Brightening leaves the chroma, as scaling it up would leave the
+            // gamut.
+            ColorSpace::Oklab | ColorSpace::Oklch => {
+                let [lightness, a, b] = rgb_to_oklab(hsl_to_rgb(hsl));
+                let (factor, chroma_factor) = (factor as f64, factor.min(1.0) as f64);
+                rgb_to_hsl(oklab_to_rgb([lightness * factor, a * chroma_factor, b * chroma_factor]))
+            },
+        }
+    }
+
+    /// Brighten or dim a colour to the given HSL lightness, from 0 to 100, by scaling
+    /// it in this colour space. Black has no brightness to scale, so takes the
+    /// lightness directly.
+    pub fn with_lightness(self, hsl: &Hsl, lightness: f32) -> Hsl {
+        let current = hsl.get_lightness();
+        if current <= 0.0 {
+            return Hsl::from(hsl.get_hue(), hsl.get_saturation(), lightness.clamp(0.0, 100.0));
+        }
+        self.scale(hsl, lightness / current)
+    }
+
+    /// Blend from `from` to `to` as `t` goes from 0 to 1.
+    pub fn mix(self, from: [u8; 3], to: [u8; 3], t: f32) -> [u8; 3] {
+        let from_rgb = from.map(|channel| channel as f64);
+        let to_rgb = to.map(|channel| channel as f64);
+        let t = t as f64;
+        let lerp = |a: f64, b: f64| a + (b - a) * t;
+        let rgb = match self {
+            ColorSpace::Hsl => {
+                let (from_hsl, to_hsl) = (rgb_to_hsl(from_rgb), rgb_to_hsl(to_rgb));
+                let [from_hue, from_saturation, from_lightness] = [from_hsl.get_hue(), from_hsl.get_saturation(), from_hsl.get_lightness()].map(f64::from);
+                let [to_hue, to_saturation, to_lightness] = [to_hsl.get_hue(), to_hsl.get_saturation(), to_hsl.get_lightness()].map(f64::from);
+                // Greys have no hue, and black and white no saturation either, so take
+                // them from the other colour rather than sweeping through unrelated ones.
+                let colorless = |saturation: f64, lightness: f64| (saturation <= 0.0, lightness <= 0.0 || lightness >= 100.0);
+                let (from_grey, from_black_or_white) = colorless(from_saturation, from_lightness);
+                let (to_grey, to_black_or_white) = colorless(to_saturation, to_lightness);
+                let from_hue = if from_grey || from_black_or_white { to_hue } else { from_hue };
+                let to_hue = if to_grey || to_black_or_white { from_hue } else { to_hue };
+                let from_saturation = if from_black_or_white { to_saturation } else { from_saturation };
+                let to_saturation = if to_black_or_white { from_saturation } else { to_saturation };
+                let hsl = Hsl::from(
+                    lerp_hue(from_hue, to_hue, t) as f32,
+                    lerp(from_saturation, to_saturation) as f32,
+                    lerp(from_lightness, to_lightness) as f32,
+                );
+                hsl_to_rgb(&hsl)
+            },
+            ColorSpace::Oklab => {
+                let (from_lab, to_lab) = (rgb_to_oklab(from_rgb), rgb_to_oklab(to_rgb));
+                oklab_to_rgb([0, 1, 2].map(|c| lerp(from_lab[c], to_lab[c])))
+            },
+            ColorSpace::Oklch => {
+                let (from_lab, to_lab) = (rgb_to_oklab(from_rgb), rgb_to_oklab(to_rgb));
+                let (from_chroma, to_chroma) = (from_lab[1].hypot(from_lab[2]), to_lab[1].hypot(to_lab[2]));
+                let from_hue = from_lab[2].atan2(from_lab[1]);
+                let mut to_hue = to_lab[2].atan2(to_lab[1]);
+                // Greys have no hue, so take it from the other colour rather than
+                // sweeping through unrelated hues.
+                let from_hue = if from_chroma < 1e-4 { to_hue } else { from_hue };
+                if to_chroma < 1e-4 {
+                    to_hue = from_hue;
+                }
+                // Take the shorter way around.
+                let delta = (to_hue - from_hue + std::f64::consts::PI).rem_euclid(std::f64::consts::TAU) - std::f64::consts::PI;
+                let hue = from_hue + delta * t;
+                let chroma = lerp(from_chroma, to_chroma);
+                oklab_to_rgb([lerp(from_lab[0], to_lab[0]), chroma * hue.cos(), chroma * hue.sin()])
+            },
+        };
+        rgb.map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_oklab() {
+        let white = rgb_to_oklab([255.0, 255.0, 255.0]);
+        assert!((white[0] - 1.0).abs() < 0.001 && white[1].abs() < 0.001 && white[2].abs() < 0.001);
+        for rgb in [[255, 0, 0], [12, 200, 90], [0, 0, 0]] {
+            assert_eq!(ColorSpace::Oklab.mix(rgb, rgb, 0.5), rgb, "Round trip of {:?}", rgb);
+        }
+        // Red to blue stays saturated in Oklch, rather than passing through a dull purple.
+        let purple = ColorSpace::Oklch.mix([255, 0, 0], [0, 0, 255], 0.5);
+        let dull = ColorSpace::Oklab.mix([255, 0, 0], [0, 0, 255], 0.5);
+        assert!(purple[0] as u16 + purple[2] as u16 > dull[0] as u16 + dull[2] as u16);
+        // HSL goes around the hue circle too, rather than through sRGB's dark purple.
+        assert_eq!(ColorSpace::Hsl.mix([255, 0, 0], [0, 0, 255], 0.5), [255, 0, 255]);
+        assert_eq!(ColorSpace::Hsl.mix([128, 128, 128], [0, 0, 255], 0.0), [128, 128, 128]);
+    }
+}
@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::net::UdpSocket;
+
+use crate::report::{FrameReport, Reporter};
+
+/**
+ * Prefix of every OSC address leafpipe sends to.
+ */
+const OSC_PREFIX: &str = "/leafpipe";
+
+enum OscArg {
+    Float(f32),
+    Int(i32),
+}
+
+/// Append an OSC string, null terminated and padded to a multiple of four bytes.
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(value.as_bytes());
+    let padding = 4 - value.len() % 4;
+    buffer.resize(buffer.len() + padding, 0);
+}
+
+fn encode_message(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_string(&mut buffer, address);
+    let type_tags: String = std::iter::once(',').chain(args.iter().map(|arg| match arg {
+        OscArg::Float(_) => 'f',
+        OscArg::Int(_) => 'i',
+    })).collect();
+    write_string(&mut buffer, &type_tags);
+    for arg in args {
+        match arg {
+            OscArg::Float(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+            OscArg::Int(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+    buffer
+}
+
+/// Publishes band energies and beat triggers as Open Sound Control messages over UDP:
+///
+/// - `/leafpipe/bands` with every band as a float, lowest first.
+/// - `/leafpipe/band/<n>` with the energy of band `n`.
+/// - `/leafpipe/beat` with the integer 1 at the start of every beat.
+pub struct OscSender {
+    socket: UdpSocket,
+}
+
+impl OscSender {
+    pub fn connect(target: &str) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(target)?;
+        log::info!("Sending OSC to {}", target);
+        Ok(OscSender { socket })
+    }
+
+    fn send(&self, address: &str, args: &[OscArg]) {
+        if let Err(err) = self.socket.send(&encode_message(address, args)) {
+            log::debug!("Failed to send OSC message {}: {}", address, err);
+        }
+    }
+}
+
+impl Reporter for OscSender {
+    fn report(&mut self, report: &FrameReport) {
+        let bands: Vec<OscArg> = report.bands.iter().map(|band| OscArg::Float(*band)).collect();
+        self.send(&format!("{}/bands", OSC_PREFIX), &bands);
+        for (index, band) in report.bands.iter().enumerate() {
+            self.send(&format!("{}/band/{}", OSC_PREFIX, index), &[OscArg::Float(*band)]);
+        }
+        if report.beat {
+            self.send(&format!("{}/beat", OSC_PREFIX), &[OscArg::Int(1)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_message() {
+        let message = encode_message("/beat", &[OscArg::Int(1), OscArg::Float(0.5)]);
+        assert_eq!(message, vec![
+            b'/', b'b', b'e', b'a', b't', 0, 0, 0,
+            b',', b'i', b'f', 0,
+            0, 0, 0, 1,
+            0x3f, 0, 0, 0,
+        ]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::shapes::shape;
+
+/**
+ * How much further apart two panels may be than expected and still count as touching,
+ * allowing for rounding in the controller's coordinates.
+ */
+const NEIGHBOUR_TOLERANCE: f64 = 1.15;
+
+/// Which panels touch each other, worked out from the positions of their centres, for
+/// effects that spread across the layout rather than along it.
+#[derive(Debug, Clone, Default)]
+pub struct PanelGraph {
+    neighbours: Vec<Vec<usize>>,
+}
+
+impl PanelGraph {
+    /// Panels of known shapes touch when their centres are as far apart as two panels
+    /// sharing an edge would be, so triangles meeting only at a corner don't. Other
+    /// panels touch their nearest neighbour and any others about as close, but never
+    /// anything over two side lengths away when the controller gives a `side_length`,
+    /// which keeps stray panels from joining up with the rest.
+    pub fn new(panels: &[NanoleafLayoutPanelData], side_length: usize) -> Self {
+        let distance = |a: &NanoleafLayoutPanelData, b: &NanoleafLayoutPanelData| {
+            (a.x as f64 - b.x as f64).hypot(a.y as f64 - b.y as f64)
+        };
+        let nearest: Vec<f64> = panels.iter().enumerate().map(|(index, panel)| {
+            panels.iter().enumerate()
+                .filter(|(other_index, _)| *other_index != index)
+                .map(|(_, other)| distance(panel, other))
+                .fold(f64::INFINITY, f64::min)
+        }).collect();
+        let limit = if side_length > 0 { side_length as f64 * 2.0 } else { f64::INFINITY };
+
+        let neighbours = panels.iter().enumerate().map(|(index, panel)| {
+            panels.iter().enumerate().filter(|(other_index, other)| {
+                let apart = distance(panel, other);
+                let expected = match (shape(panel.shape_type), shape(other.shape_type)) {
+                    (Some(a), Some(b)) => (a.apothem() + b.apothem()) as f64,
+                    _ if apart > limit => return false,
+                    _ => nearest[index].max(nearest[*other_index]),
+                };
+                *other_index != index && apart <= expected * NEIGHBOUR_TOLERANCE
+            }).map(|(other_index, _)| other_index).collect()
+        }).collect();
+        PanelGraph { neighbours }
+    }
+
+    /// The panels touching `panel`, by their index in the layout.
+    pub fn neighbours(&self, panel: usize) -> &[usize] {
+        self.neighbours.get(panel).map_or(&[], Vec::as_slice)
+    }
+
+    /// How many steps from panel to touching panel it takes to get from `origin` to
+    /// each panel, or `None` for panels that can't be reached.
+    pub fn distances(&self, origin: usize) -> Vec<Option<usize>> {
+        let mut distances = vec![None; self.neighbours.len()];
+        let Some(distance) = distances.get_mut(origin) else {
+            return distances;
+        };
+        *distance = Some(0);
+        let mut queue = VecDeque::from([origin]);
+        while let Some(panel) = queue.pop_front() {
+            let next = distances[panel].map(|distance| distance + 1);
+            for neighbour in self.neighbours(panel) {
+                if distances[*neighbour].is_none() {
+                    distances[*neighbour] = next;
+                    queue.push_back(*neighbour);
+                }
+            }
+        }
+        distances
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn panel(x: usize, y: usize, shape_type: u8) -> NanoleafLayoutPanelData {
+        NanoleafLayoutPanelData { panel_id: 0, x, y, shape_type, orientation: 0 }
+    }
+
+    #[test]
+    fn test_panel_graph() {
+        // An L of squares, and one far off on its own.
+        let panels = [panel(0, 0, 2), panel(100, 0, 2), panel(200, 0, 2), panel(0, 100, 2), panel(900, 900, 2)];
+        let graph = PanelGraph::new(&panels, 100);
+        assert_eq!(graph.neighbours(0), &[1, 3]);
+        assert_eq!(graph.neighbours(2), &[1]);
+        assert!(graph.neighbours(4).is_empty());
+        assert_eq!(graph.distances(2), vec![Some(2), Some(1), Some(0), Some(3), None]);
+
+        // Shapes of unknown size fall back on the nearest panels, so without a side
+        // length the stray panel is joined to its nearest neighbour.
+        let unknown: Vec<_> = panels.iter().map(|p| panel(p.x, p.y, 1)).collect();
+        assert_eq!(PanelGraph::new(&unknown, 100).neighbours(0), &[1, 3]);
+        assert!(PanelGraph::new(&unknown, 0).neighbours(4).contains(&2));
+    }
+
+    #[test]
+    fn test_triangles() {
+        // A strip of Shapes triangles, alternately pointing up and down. Each shares an
+        // edge with the next, and only a corner with the one after.
+        let height = 134.0 * 3f64.sqrt() / 2.0;
+        let panels: Vec<_> = (0..4).map(|i| {
+            let y = if i % 2 == 0 { height / 3.0 } else { height * 2.0 / 3.0 };
+            panel(100 + i * 67, y.round() as usize, 8)
+        }).collect();
+        let graph = PanelGraph::new(&panels, 134);
+        assert_eq!(graph.neighbours(0), &[1]);
+        assert_eq!(graph.neighbours(1), &[0, 2]);
+    }
+}
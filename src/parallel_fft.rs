@@ -0,0 +1,120 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FftConfig {
+    /// Smallest window, in samples, to split across threads. Smaller windows, and all
+    /// windows if this isn't set, are transformed on the render thread.
+    #[serde(default)]
+    pub parallel_min_size: Option<usize>,
+    /// Threads to split windows across, with 0 for one per core.
+    #[serde(default)]
+    pub threads: usize,
+    /// How much of each analysis window overlaps the one before, from 0 to 0.9.
+    /// Overlapping windows are longer, so catch transients that would otherwise fall
+    /// across the edge between two windows.
+    #[serde(default)]
+    pub overlap: f32,
+}
+
+impl FftConfig {
+    /// The smallest window to split, and the threads to split it across, if enabled.
+    pub fn pool(&self) -> Result<Option<(usize, Arc<ThreadPool>)>, rayon::ThreadPoolBuildError> {
+        let Some(min_size) = self.parallel_min_size else {
+            return Ok(None);
+        };
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .thread_name(|index| format!("fft-{}", index))
+            .build()?;
+        Ok(Some((min_size, Arc::new(pool))))
+    }
+}
+
+/// A forward FFT split across threads with the four step algorithm. The window is laid
+/// out as a matrix, each column is transformed and multiplied by a twiddle factor, and
+/// then each row is transformed, giving the same result as one large FFT.
+pub struct ParallelFft {
+    /// Transforms a column, `n1` long.
+    columns: Arc<dyn Fft<f32>>,
+    /// Transforms a row, `n2` long.
+    rows: Arc<dyn Fft<f32>>,
+    /// The twiddle factor for each element of each column.
+    twiddles: Box<[Complex<f32>]>,
+    pool: Arc<ThreadPool>,
+}
+
+impl ParallelFft {
+    /// An FFT of `size`, which must be a power of two.
+    pub fn new(size: usize, pool: Arc<ThreadPool>) -> Self {
+        let n1 = 1 << (size.trailing_zeros() / 2);
+        let n2 = size / n1;
+        let mut planner = FftPlanner::new();
+        let twiddles = (0..n2).flat_map(|column| (0..n1).map(move |k1| {
+            let angle = -2.0 * PI * (column * k1) as f64 / size as f64;
+            Complex::new(angle.cos() as f32, angle.sin() as f32)
+        })).collect();
+        ParallelFft {
+            columns: planner.plan_fft_forward(n1),
+            rows: planner.plan_fft_forward(n2),
+            twiddles,
+            pool,
+        }
+    }
+
+    pub fn process(&self, data: &mut [Complex<f32>]) {
+        let (n1, n2) = (self.columns.len(), self.rows.len());
+        let mut columns = vec![Complex::default(); data.len()];
+        let mut rows = vec![Complex::default(); data.len()];
+        self.pool.install(|| {
+            let input: &[Complex<f32>] = data;
+            columns.par_chunks_mut(n1).zip(self.twiddles.par_chunks(n1)).enumerate().for_each(|(column, (values, twiddles))| {
+                for (n, value) in values.iter_mut().enumerate() {
+                    *value = input[n * n2 + column];
+                }
+                self.columns.process(values);
+                for (value, twiddle) in values.iter_mut().zip(twiddles) {
+                    *value *= twiddle;
+                }
+            });
+            let columns = &columns;
+            rows.par_chunks_mut(n2).enumerate().for_each(|(k1, values)| {
+                for (column, value) in values.iter_mut().enumerate() {
+                    *value = columns[column * n1 + k1];
+                }
+                self.rows.process(values);
+            });
+        });
+        for (index, value) in rows.into_iter().enumerate() {
+            let (k1, k2) = (index / n2, index % n2);
+            data[k1 + n1 * k2] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches_single_fft() {
+        let size = 2048;
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+        let input: Vec<Complex<f32>> = (0..size).map(|i| Complex::new((i as f32 * 0.37).sin() + (i % 7) as f32 * 0.1, 0.0)).collect();
+
+        let mut expected = input.clone();
+        FftPlanner::new().plan_fft_forward(size).process(&mut expected);
+        let mut actual = input;
+        ParallelFft::new(size, pool).process(&mut actual);
+
+        for (index, (expected, actual)) in expected.iter().zip(&actual).enumerate() {
+            assert!((expected - actual).norm() < 0.01, "Bin {} was {}, expected {}", index, actual, expected);
+        }
+    }
+}
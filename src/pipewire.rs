@@ -1,5 +1,11 @@
-use std::sync::{RwLock, Arc};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use pipewire::prelude::*;
+use pipewire::registry::{Listener, Registry};
 use pipewire::spa::format::{MediaType, MediaSubtype};
 use pipewire::spa::param::audio::AudioInfoRaw;
 use pipewire::spa::pod::Pod;
@@ -8,117 +14,274 @@ use pipewire::properties;
 use pipewire::spa::Direction;
 use pipewire::stream::{StreamFlags, StreamListener};
 use pipewire::stream::Stream;
+use pipewire::types::ObjectType;
 
+use clap::ValueEnum;
+
+use crate::downmix::Downmix;
+use crate::dsp::MicProcessor;
+use crate::stream_mix::StreamMixer;
 use crate::vis::BufferManager;
 
+/**
+ * Media class of nodes that applications play audio through.
+ */
+const APPLICATION_STREAM_CLASS: &str = "Stream/Output/Audio";
+
+/**
+ * Media class of microphones and other recording devices.
+ */
+const MICROPHONE_CLASS: &str = "Audio/Source";
+
+/**
+ * Media role of streams used for voice and video calls.
+ */
+const COMMUNICATION_ROLE: &str = "Communication";
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioSource {
+    /// The default recording source.
+    Default,
+    /// The monitor of the default output, i.e. whatever is playing.
+    Monitor,
+    /// A microphone listening to the room, with filtering and gain control. The first
+    /// one found is used, or the next one plugged in if it goes away.
+    Mic,
+}
+
+struct CaptureStream {
+    _listener: StreamListener<StreamData>,
+    stream: Stream,
+}
+
 pub struct PipewireContainer {
     mainloop: MainLoop,
     _context: Context<MainLoop>,
     _core: Core,
-    _listener: StreamListener<StreamData>,
-    stream: Stream,
+    _registry: (Registry, Listener),
+    /// Capture streams keyed by the node they target, or `None` for the default source.
+    streams: Rc<RefCell<HashMap<Option<u32>, CaptureStream>>>,
 }
 
 #[derive(Default)]
 struct StreamData {
 	configuration: AudioInfoRaw,
     buffer_manager: Arc<RwLock<BufferManager>>,
+    processor: Option<MicProcessor>,
+    downmix: Downmix,
+    scratch: Vec<f32>,
+    /// The side channel, when it's analysed separately.
+    sides: Vec<f32>,
+    /// The mixer shared by every application stream, and the node this one captures.
+    mixer: Option<(Arc<Mutex<StreamMixer>>, u32)>,
+    mixed: Vec<f32>,
 }
 
-impl PipewireContainer {
-    pub fn new(buffer_manager: Arc<RwLock<BufferManager>>) -> Result<Self, pipewire::Error> {
-        pipewire::init();
-        let mainloop = MainLoop::new()?;
-        let context: Context<MainLoop> = Context::new(&mainloop)?;
-        let core = context.connect(None)?;
+/// Does the application that owns a node match one of the configured names. Both the
+/// application name and process binary are compared, ignoring case.
+fn matches_application<D: ReadableDict>(props: &D, applications: &[String]) -> bool {
+    [*pipewire::keys::APP_NAME, *pipewire::keys::APP_PROCESS_BINARY].iter()
+        .filter_map(|key| props.get(key))
+        .any(|value| applications.iter().any(|app| app.eq_ignore_ascii_case(value)))
+}
+
+fn create_capture_stream(core: &Core, buffer_manager: Arc<RwLock<BufferManager>>, source: AudioSource, downmix: Downmix, target: Option<u32>, mixer: Option<Arc<Mutex<StreamMixer>>>) -> Result<CaptureStream, pipewire::Error> {
+    let mut props = properties! {
+        *pipewire::keys::MEDIA_TYPE => "Audio",
+        *pipewire::keys::MEDIA_CATEGORY => "Capture",
+        *pipewire::keys::MEDIA_ROLE => "Music",
+    };
+    if source == AudioSource::Monitor && target.is_none() {
+        props.insert(*pipewire::keys::STREAM_CAPTURE_SINK, "true");
+    }
 
-        let props = properties! {
-            *pipewire::keys::MEDIA_TYPE => "Audio",
-            *pipewire::keys::MEDIA_CATEGORY => "Capture",
-            *pipewire::keys::MEDIA_ROLE => "Music",
+    let stream = Stream::new(
+        core,
+        "audio-capture",
+        props,
+    )?;
+
+    let user_data = StreamData {
+        configuration: Default::default(),
+        buffer_manager,
+        processor: (source == AudioSource::Mic).then(MicProcessor::new),
+        downmix,
+        scratch: Vec::new(),
+        sides: Vec::new(),
+        mixer: mixer.zip(target),
+        mixed: Vec::new(),
+    };
+
+    let listener = stream.add_local_listener_with_user_data(
+        user_data
+    )
+    .param_changed(|_, id, data, param| {
+        let Some(param) = param else {
+            return;
         };
-    
-        let stream = Stream::new(
-            &core,
-            "audio-capture",
-            props,
-        )?;
-    
-        let user_data = StreamData {
-            configuration: Default::default(),
-            buffer_manager,
+        if id != pipewire::spa::param::ParamType::Format.as_raw() {
+            return;
+        }
+
+        let (media_type, media_subtype) =
+        match pipewire::spa::param::format_utils::parse_format(param) {
+            Ok(v) => v,
+            Err(_) => return,
         };
-    
-        let listener = stream.add_local_listener_with_user_data(
-            user_data
-        )
-        .param_changed(|_, id, data, param| {
-            let Some(param) = param else {
+        if media_type != MediaType::Audio
+        || media_subtype != MediaSubtype::Raw
+        {
+            return;
+        }
+        data.configuration.parse(param).expect("Expected to be able to parse audio!");
+    })
+    .process(|_stream, stream_data| {
+        if let Some(mut buffer) = _stream.dequeue_buffer() {
+            // Samples are interleaved, so every channel is in the first data block.
+            let channels = stream_data.configuration.channels() as usize;
+            let Some(channel) = buffer.datas_mut().get_mut(0) else {
                 return;
             };
-            if id != pipewire::spa::param::ParamType::Format.as_raw() {
-                return;
-            }
-    
-            let (media_type, media_subtype) =
-            match pipewire::spa::param::format_utils::parse_format(param) {
-                Ok(v) => v,
-                Err(_) => return,
-            };
-            if media_type != MediaType::Audio 
-            || media_subtype != MediaSubtype::Raw
-            {
-                return;
-            }
-            data.configuration.parse(param).expect("Expected to be able to parse audio!");
-        })
-        .process(|_stream, stream_data| {
-            if let Some(mut buffer) = _stream.dequeue_buffer() {
-                let channels = stream_data.configuration.channels() as usize;
-                for channel_index in 0..channels-1 {
-                    let channel = buffer.datas_mut().get_mut(channel_index).unwrap();
-                    let chunk = channel.chunk(); 
-                    let size = chunk.size() as usize;
-                    let data = channel.data(); 
-                    if let Some(data) = data {
-                        let cast_buffer: &[f32] = unsafe {
-                            std::slice::from_raw_parts(data.as_ptr().cast(), size / std::mem::size_of::<f32>())
-                        };
-                        stream_data.buffer_manager.write().unwrap().fill_buffer(cast_buffer, stream_data.configuration.rate());
-                    }
+            let size = channel.chunk().size() as usize;
+            if let Some(data) = channel.data() {
+                let cast_buffer: &[f32] = unsafe {
+                    std::slice::from_raw_parts(data.as_ptr().cast(), size / std::mem::size_of::<f32>())
+                };
+                let rate = stream_data.configuration.rate();
+                let width = stream_data.downmix.mix(cast_buffer, channels, &mut stream_data.scratch, &mut stream_data.sides);
+                if let Some(processor) = &mut stream_data.processor {
+                    processor.process(&mut stream_data.scratch, rate);
+                }
+                let mut buffer_manager = stream_data.buffer_manager.write().unwrap();
+                match &stream_data.mixer {
+                    Some((mixer, node)) => {
+                        mixer.lock().unwrap().submit(*node, &stream_data.scratch, &mut stream_data.mixed);
+                        if !stream_data.mixed.is_empty() {
+                            buffer_manager.fill_buffer(&stream_data.mixed, rate);
+                        }
+                    },
+                    None => {
+                        buffer_manager.fill_buffer(&stream_data.scratch, rate);
+                        // Only the mid of application streams is mixed together.
+                        buffer_manager.fill_side(&stream_data.sides, rate);
+                    },
                 }
+                buffer_manager.record_width(width);
             }
-        }).register()?;
-    
-        let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-        audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
-        let obj = spa::pod::Object {
-            type_: spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
-            id: spa::param::ParamType::EnumFormat.as_raw(),
-            properties: audio_info.into(),
-        };
-        let values: Vec<u8> = spa::pod::serialize::PodSerializer::serialize(
-            std::io::Cursor::new(Vec::new()),
-            &spa::pod::Value::Object(obj),
-        )
-        .unwrap()
-        .0
-        .into_inner();
-
-        let mut params = [Pod::from_bytes(&values).unwrap()];
-        stream.connect(
-            Direction::Input,
-            None,
-            StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::RT_PROCESS,
-            &mut params,
-        )?;
-
-        Ok(PipewireContainer { 
+        }
+    }).register()?;
+
+    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
+    let obj = spa::pod::Object {
+        type_: spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+        id: spa::param::ParamType::EnumFormat.as_raw(),
+        properties: audio_info.into(),
+    };
+    let values: Vec<u8> = spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(obj),
+    )
+    .unwrap()
+    .0
+    .into_inner();
+
+    let mut params = [Pod::from_bytes(&values).unwrap()];
+    stream.connect(
+        Direction::Input,
+        target,
+        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::RT_PROCESS,
+        &mut params,
+    )?;
+
+    Ok(CaptureStream {
+        _listener: listener,
+        stream,
+    })
+}
+
+impl PipewireContainer {
+    /// Start capturing audio. When `applications` is empty `source` is captured,
+    /// otherwise only streams played by the named applications are captured.
+    /// `call_active` is kept up to date with whether any communication streams exist.
+    /// Stereo audio is mixed down with `downmix`.
+    pub fn new(buffer_manager: Arc<RwLock<BufferManager>>, source: AudioSource, downmix: Downmix, applications: Vec<String>, call_active: Arc<AtomicBool>) -> Result<Self, pipewire::Error> {
+        pipewire::init();
+        let mainloop = MainLoop::new()?;
+        let context: Context<MainLoop> = Context::new(&mainloop)?;
+        let core = context.connect(None)?;
+        let streams: Rc<RefCell<HashMap<Option<u32>, CaptureStream>>> = Rc::new(RefCell::new(HashMap::new()));
+
+        if !applications.is_empty() {
+            log::info!("Only capturing audio from {:?}", applications);
+        } else if source != AudioSource::Mic {
+            streams.borrow_mut().insert(None, create_capture_stream(&core, buffer_manager.clone(), source, downmix, None, None)?);
+        }
+
+        let registry = core.get_registry()?;
+        let added_streams = streams.clone();
+        let removed_streams = streams.clone();
+        let stream_core = core.clone();
+        // Applications playing at once are mixed together before being analysed.
+        let mixer = Arc::new(Mutex::new(StreamMixer::default()));
+        let removed_mixer = mixer.clone();
+        let calls: Rc<RefCell<HashSet<u32>>> = Rc::new(RefCell::new(HashSet::new()));
+        let added_calls = calls.clone();
+        let added_call_active = call_active.clone();
+        let listener = registry.add_listener_local()
+            .global(move |global| {
+                if global.type_ != ObjectType::Node {
+                    return;
+                }
+                let Some(props) = &global.props else {
+                    return;
+                };
+                if props.get(*pipewire::keys::MEDIA_ROLE) == Some(COMMUNICATION_ROLE) {
+                    log::info!("Call started on node {}", global.id);
+                    added_calls.borrow_mut().insert(global.id);
+                    added_call_active.store(true, Ordering::Relaxed);
+                }
+                let class = props.get(*pipewire::keys::MEDIA_CLASS);
+                let wanted = if !applications.is_empty() {
+                    class == Some(APPLICATION_STREAM_CLASS) && matches_application(props, &applications)
+                } else {
+                    // The microphone isn't the default source for everyone, so one is
+                    // picked out by its class.
+                    source == AudioSource::Mic && class == Some(MICROPHONE_CLASS) && added_streams.borrow().is_empty()
+                };
+                if !wanted {
+                    return;
+                }
+                let name = props.get(*pipewire::keys::APP_NAME).or(props.get(*pipewire::keys::NODE_NAME));
+                log::info!("Capturing audio from node {} ({:?})", global.id, name);
+                let mixer = (!applications.is_empty()).then(|| mixer.clone());
+                match create_capture_stream(&stream_core, buffer_manager.clone(), source, downmix, Some(global.id), mixer) {
+                    Ok(stream) => {
+                        added_streams.borrow_mut().insert(Some(global.id), stream);
+                    },
+                    Err(err) => log::warn!("Failed to capture node {}: {:?}", global.id, err),
+                }
+            })
+            .global_remove(move |id| {
+                let mut calls = calls.borrow_mut();
+                if calls.remove(&id) {
+                    log::info!("Call ended on node {}", id);
+                    call_active.store(!calls.is_empty(), Ordering::Relaxed);
+                }
+                if let Some(capture) = removed_streams.borrow_mut().remove(&Some(id)) {
+                    log::info!("Stopped capturing audio from node {}", id);
+                    removed_mixer.lock().unwrap().remove(id);
+                    let _ = capture.stream.disconnect();
+                }
+            })
+            .register();
+
+        Ok(PipewireContainer {
             mainloop,
             _context: context,
             _core: core,
-            _listener: listener,
-            stream,
+            _registry: (registry, listener),
+            streams,
         })
     }
 
@@ -128,6 +291,9 @@ impl PipewireContainer {
     }
 
     pub fn stop(&self) -> Result<(), pipewire::Error> {
-        self.stream.disconnect()
+        for capture in self.streams.borrow().values() {
+            capture.stream.disconnect()?;
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,157 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/**
+ * How long the screen and audio must be unchanged before slowing down.
+ */
+const IDLE_AFTER: Duration = Duration::from_secs(30);
+
+/**
+ * How often to capture the screen and update the lights while idle.
+ */
+pub const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * How often to capture the screen while a game is running, to leave it the CPU.
+ */
+#[cfg_attr(not(feature = "screen"), allow(dead_code))]
+pub const GAMING_INTERVAL: Duration = Duration::from_millis(500);
+
+/**
+ * How many times longer audio analysis and frames sent to the lights wait while a
+ * game is running.
+ */
+pub const GAMING_SLOWDOWN: u32 = 2;
+
+/**
+ * Audio quieter than this RMS level counts as silence.
+ */
+const SILENCE_RMS: f32 = 0.001;
+
+fn default_standby_secs() -> f32 {
+    300.0
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StandbyConfig {
+    /// Stop capturing, analysing and sending frames once nothing has happened for
+    /// `after_secs`, until audio starts or a media player starts playing.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_standby_secs")]
+    pub after_secs: f32,
+}
+
+impl Default for StandbyConfig {
+    fn default() -> Self {
+        StandbyConfig {
+            enabled: false,
+            after_secs: default_standby_secs(),
+        }
+    }
+}
+
+impl StandbyConfig {
+    /// How long nothing must happen before going into standby, if ever.
+    pub fn after(&self) -> Option<Duration> {
+        self.enabled.then(|| Duration::from_secs_f32(self.after_secs.max(0.0)))
+    }
+}
+
+/// Tracks whether anything is happening on screen or in the audio, so capture and
+/// updates can drop to `IDLE_INTERVAL` while nothing is, and resume as soon as
+/// something changes. Also knows when a game is running, so capture can slow down to
+/// `GAMING_INTERVAL`, and analysis and sending by `GAMING_SLOWDOWN`, to keep out of
+/// its way. After longer without anything happening,
+/// goes into standby, where only audio and media players are watched for a reason to
+/// wake up.
+pub struct PowerSaver {
+    enabled: bool,
+    last_screen_change: Mutex<Instant>,
+    last_sound: Mutex<Instant>,
+    gaming: AtomicBool,
+    standby_after: Option<Duration>,
+    standby: AtomicBool,
+    /// Whether a media player says it's playing.
+    playing: AtomicBool,
+}
+
+impl PowerSaver {
+    pub fn new(enabled: bool, standby_after: Option<Duration>) -> Self {
+        PowerSaver {
+            enabled,
+            last_screen_change: Mutex::new(Instant::now()),
+            last_sound: Mutex::new(Instant::now()),
+            gaming: AtomicBool::new(false),
+            standby_after,
+            standby: AtomicBool::new(false),
+            playing: AtomicBool::new(false),
+        }
+    }
+
+    /// Record that the colours picked from the screen have changed.
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    pub fn screen_changed(&self) {
+        *self.last_screen_change.lock().unwrap() = Instant::now();
+    }
+
+    /// Record the level of the latest audio.
+    pub fn audio_level(&self, rms: f32) {
+        if rms > SILENCE_RMS {
+            *self.last_sound.lock().unwrap() = Instant::now();
+        }
+    }
+
+    /// Record whether a game has asked for performance mode.
+    #[cfg_attr(not(feature = "gamemode"), allow(dead_code))]
+    pub fn set_gaming(&self, gaming: bool) {
+        if self.gaming.swap(gaming, Ordering::Relaxed) != gaming {
+            log::info!("{}", if gaming { "A game is running, capturing and updating the lights less often" } else { "No games running, back to full quality" });
+        }
+    }
+
+    /// Record whether any media player is playing.
+    #[cfg_attr(not(feature = "mpris"), allow(dead_code))]
+    pub fn set_playing(&self, playing: bool) {
+        if self.playing.swap(playing, Ordering::Relaxed) != playing {
+            log::debug!("{}", if playing { "A media player started playing" } else { "No media players playing" });
+        }
+    }
+
+    /// Work out whether to be in standby, which is only left once audio starts or a
+    /// media player plays, as the screen isn't captured while in it.
+    pub fn update_standby(&self) -> bool {
+        let Some(after) = self.standby_after else {
+            return false;
+        };
+        let standby = !self.playing.load(Ordering::Relaxed)
+            && self.last_screen_change.lock().unwrap().elapsed() >= after
+            && self.last_sound.lock().unwrap().elapsed() >= after;
+        if self.standby.swap(standby, Ordering::Relaxed) != standby {
+            log::info!("{}", if standby { "Nothing happening for a while, going into standby" } else { "Waking up from standby" });
+            if !standby {
+                // Give capture a chance to see what's on screen before nodding off again.
+                *self.last_screen_change.lock().unwrap() = Instant::now();
+            }
+        }
+        standby
+    }
+
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Relaxed)
+    }
+
+    pub fn is_gaming(&self) -> bool {
+        self.gaming.load(Ordering::Relaxed)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.enabled
+            && self.last_screen_change.lock().unwrap().elapsed() >= IDLE_AFTER
+            && self.last_sound.lock().unwrap().elapsed() >= IDLE_AFTER
+    }
+}
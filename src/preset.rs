@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::validate;
+
+/**
+ * The preset format this build writes. Presets from newer versions are refused rather
+ * than half understood.
+ */
+pub const PRESET_VERSION: u32 = 1;
+
+/**
+ * Config keys that shape how the effects respond.
+ */
+const EFFECT_KEYS: &[&str] = &["tuning", "peak_hold", "hue_range", "hue_rotation", "noise_shimmer", "lfo", "layers", "transitions", "stereo_width", "scenes", "idle_scene"];
+/**
+ * Config keys that calibrate colours for a particular screen and set of panels.
+ */
+const CALIBRATION_KEYS: &[&str] = &["color_space", "output_gamut", "dither", "heatmap", "color_hysteresis"];
+/**
+ * Config keys that map the screen onto the panels.
+ */
+const MAPPING_KEYS: &[&str] = &["panel_mask", "capture_region"];
+
+/// A tuned setup to share with others, bundling the parts of a config that don't
+/// depend on the machine or network it runs on. Tokens, addresses and the strobe
+/// safety limits are never included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub effect: Map<String, Value>,
+    #[serde(default)]
+    pub calibration: Map<String, Value>,
+    #[serde(default)]
+    pub mapping: Map<String, Value>,
+}
+
+impl Preset {
+    /// Gather a preset from the settings in a config.
+    pub fn from_settings(settings: &Map<String, Value>, name: Option<String>, description: Option<String>) -> Self {
+        let section = |keys: &[&str]| -> Map<String, Value> {
+            keys.iter().filter_map(|key| settings.get(*key).map(|value| (key.to_string(), value.clone()))).collect()
+        };
+        Preset {
+            version: PRESET_VERSION,
+            name,
+            description,
+            effect: section(EFFECT_KEYS),
+            calibration: section(CALIBRATION_KEYS),
+            mapping: section(MAPPING_KEYS),
+        }
+    }
+
+    /// Read a preset from a TOML or JSON file, going by its extension.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let preset: Preset = config::Config::builder().add_source(config::File::from(path)).build()?.try_deserialize()?;
+        if preset.version > PRESET_VERSION {
+            return Err(format!("{} is a version {} preset, but this leafpipe only understands up to version {}", path.display(), preset.version, PRESET_VERSION).into());
+        }
+        for (section, allowed, values) in [("effect", EFFECT_KEYS, &preset.effect), ("calibration", CALIBRATION_KEYS, &preset.calibration), ("mapping", MAPPING_KEYS, &preset.mapping)] {
+            if let Some(key) = values.keys().find(|key| !allowed.contains(&key.as_str())) {
+                return Err(format!("{}: {}.{} can't be set by a preset, only {}", path.display(), section, key, allowed.join(", ")).into());
+            }
+        }
+        let invalid = validate::effect_settings(&preset.effect);
+        if !invalid.is_empty() {
+            let invalid: Vec<String> = invalid.iter().map(|invalid| format!("effect.{}", invalid)).collect();
+            return Err(format!("{} has invalid settings:\n{}", path.display(), invalid.join("\n")).into());
+        }
+        Ok(preset)
+    }
+
+    /// Write the preset as JSON if `path` ends in `.json`, otherwise as TOML.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let text = if path.extension().is_some_and(|extension| extension == "json") {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml_edit::ser::to_string_pretty(self)?
+        };
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Every setting in the preset, whichever section it's in.
+    fn settings(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.effect.iter().chain(&self.calibration).chain(&self.mapping)
+    }
+
+    /// Set everything in the preset in a config, replacing what was there. Returns the
+    /// keys that were set.
+    pub fn apply(&self, document: &mut toml_edit::Document) -> Result<Vec<String>, Box<dyn Error>> {
+        let settings: Map<String, Value> = self.settings().map(|(key, value)| (key.clone(), value.clone())).collect();
+        let values = toml_edit::ser::to_document(&settings)?;
+        for (key, item) in values.iter() {
+            document[key] = item.clone();
+        }
+        Ok(settings.into_iter().map(|(key, _)| key).collect())
+    }
+}
+
+/// Import a preset into the config at `config_path`, keeping a copy of the old one
+/// alongside it.
+pub fn import(path: &Path, config_path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let preset = Preset::load(path)?;
+    let text = match fs::read_to_string(config_path) {
+        Ok(text) => {
+            fs::write(config_path.with_extension("toml.bak"), &text)?;
+            text
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut document: toml_edit::Document = text.parse()?;
+    let keys = preset.apply(&mut document)?;
+    fs::write(config_path, document.to_string())?;
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_preset_round_trip() {
+        let settings = json!({
+            "nanoleaf_token": "secret",
+            "safety": {"enabled": false},
+            "tuning": {"intensity": 20.0},
+            "color_space": "oklab",
+            "panel_mask": {"exclude": [123]},
+        });
+        let preset = Preset::from_settings(settings.as_object().unwrap(), Some("Living room".to_string()), None);
+        assert_eq!(preset.effect.keys().collect::<Vec<_>>(), ["tuning"]);
+        assert_eq!(preset.calibration.keys().collect::<Vec<_>>(), ["color_space"]);
+        assert_eq!(preset.mapping.keys().collect::<Vec<_>>(), ["panel_mask"]);
+
+        let path = std::env::temp_dir().join(format!("leafpipe-preset-{}.toml", std::process::id()));
+        preset.save(&path).unwrap();
+        let loaded = Preset::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, preset);
+
+        let mut document: toml_edit::Document = "nanoleaf_token = \"mine\"\n\n[tuning]\nintensity = 15.0\nsmoothing = 0.5\n".parse().unwrap();
+        loaded.apply(&mut document).unwrap();
+        let config: Value = config::Config::builder()
+            .add_source(config::File::from_str(&document.to_string(), config::FileFormat::Toml))
+            .build().unwrap().try_deserialize().unwrap();
+        assert_eq!(config["nanoleaf_token"], "mine");
+        assert_eq!(config["tuning"], json!({"intensity": 20.0}));
+        assert_eq!(config["color_space"], "oklab");
+        assert_eq!(config["panel_mask"], json!({"exclude": [123]}));
+    }
+}
@@ -0,0 +1,136 @@
+use chrono::{Local, NaiveTime};
+use colors_transform::{Color, Hsl, Rgb};
+use serde::Deserialize;
+
+use crate::effects::PostProcess;
+use crate::oklab::ColorSpace;
+use crate::scene::gradient_at;
+
+/**
+ * Colours a sunrise passes through, from night to full daylight.
+ */
+const SUNRISE: [[u8; 3]; 5] = [[0, 0, 0], [80, 0, 0], [255, 60, 0], [255, 150, 60], [255, 210, 160]];
+
+/**
+ * Colours a wind down passes through, from a warm evening light to dark.
+ */
+const WIND_DOWN: [[u8; 3]; 4] = [[255, 170, 90], [255, 100, 20], [120, 20, 0], [0, 0, 0]];
+
+fn default_duration() -> u32 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RampConfig {
+    /// Local time to start at, as "HH:MM".
+    pub start: String,
+    /// Minutes to take getting from the first colour to the last.
+    #[serde(default = "default_duration")]
+    pub duration: u32,
+    /// Minutes to stay at the last colour once the ramp is done.
+    #[serde(default)]
+    pub hold: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AmbientProgramConfig {
+    /// A wake up light, rising from dark through red and orange to daylight.
+    pub sunrise: Option<RampConfig>,
+    /// An evening light, fading from warm white through amber to dark.
+    pub wind_down: Option<RampConfig>,
+}
+
+struct Ramp {
+    start: NaiveTime,
+    duration: f32,
+    hold: f32,
+    colors: &'static [[u8; 3]],
+}
+
+impl Ramp {
+    fn new(config: &RampConfig, colors: &'static [[u8; 3]]) -> Result<Self, chrono::ParseError> {
+        Ok(Ramp {
+            start: NaiveTime::parse_from_str(&config.start, "%H:%M")?,
+            duration: config.duration.max(1) as f32 * 60.0,
+            hold: config.hold as f32 * 60.0,
+            colors,
+        })
+    }
+
+    /// The colour of the ramp at `time`, if it's running.
+    fn color_at(&self, time: NaiveTime, color_space: ColorSpace) -> Option<[u8; 3]> {
+        // Ramps may run past midnight.
+        let since_start = (time - self.start).num_seconds().rem_euclid(24 * 60 * 60) as f32;
+        if since_start > self.duration + self.hold {
+            return None;
+        }
+        Some(gradient_at(self.colors, since_start / self.duration, false, color_space))
+    }
+}
+
+/// Slow lighting programs that run at set times of day. Effects are blended on top of
+/// the program rather than replacing it, so the room still brightens for a sunrise
+/// while music is playing.
+pub struct AmbientProgram {
+    ramps: Vec<Ramp>,
+    color_space: ColorSpace,
+}
+
+impl AmbientProgram {
+    pub fn new(config: &AmbientProgramConfig, color_space: ColorSpace) -> Result<Self, chrono::ParseError> {
+        let mut ramps = Vec::new();
+        if let Some(sunrise) = &config.sunrise {
+            ramps.push(Ramp::new(sunrise, &SUNRISE)?);
+        }
+        if let Some(wind_down) = &config.wind_down {
+            ramps.push(Ramp::new(wind_down, &WIND_DOWN)?);
+        }
+        Ok(AmbientProgram { ramps, color_space })
+    }
+
+    fn color_at(&self, time: NaiveTime) -> Option<[u8; 3]> {
+        self.ramps.iter().find_map(|ramp| ramp.color_at(time, self.color_space))
+    }
+}
+
+/// Blend two colours like two lights shining on the same spot, so the result is
+/// at least as bright as either.
+fn screen_blend(a: [u8; 3], b: [u8; 3]) -> [u8; 3] {
+    [0, 1, 2].map(|c| 255 - ((255 - a[c] as u16) * (255 - b[c] as u16) / 255) as u8)
+}
+
+impl PostProcess for AmbientProgram {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        let Some(base) = self.color_at(Local::now().time()) else {
+            return;
+        };
+        for color in colors.iter_mut() {
+            let rgb = match color {
+                Some(hsl) => {
+                    let (r, g, b) = hsl.to_rgb().as_tuple();
+                    screen_blend(base, [r.round() as u8, g.round() as u8, b.round() as u8])
+                },
+                None => base,
+            };
+            *color = Some(Rgb::from(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32).to_hsl());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ramp_color_at() {
+        let config = RampConfig { start: "23:30".to_string(), duration: 60, hold: 10 };
+        let ramp = Ramp::new(&config, &SUNRISE).unwrap();
+        let at = |time: &str| ramp.color_at(NaiveTime::parse_from_str(time, "%H:%M").unwrap(), ColorSpace::Hsl);
+        assert_eq!(at("23:00"), None);
+        assert_eq!(at("23:30"), Some(SUNRISE[0]));
+        assert_eq!(at("00:00"), Some(SUNRISE[2]), "Halfway through, past midnight");
+        assert_eq!(at("00:35"), Some(SUNRISE[4]), "Holding the last colour");
+        assert_eq!(at("00:45"), None);
+        assert_eq!(screen_blend([255, 0, 0], [0, 0, 255]), [255, 0, 255]);
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::clock;
+use crate::crash;
+use crate::layout::Layout;
+use crate::report::{FrameReport, PanelReport, Reporter};
+use crate::shapes::shape;
+use crate::LIGHT_INTERVAL;
+
+fn default_seconds() -> f32 {
+    10.0
+}
+
+pub fn default_width() -> u32 {
+    320
+}
+
+/**
+ * Colour behind the panels.
+ */
+const BACKGROUND: Rgba<u8> = Rgba([16, 16, 16, 255]);
+
+/**
+ * Colour of panels that haven't been set to anything yet.
+ */
+const UNSET: [u8; 3] = [48, 48, 48];
+
+/**
+ * How much of each panel is drawn, leaving a gap so neighbours can be told apart.
+ */
+const PANEL_SCALE: f32 = 0.9;
+
+/**
+ * Space around the panels, in pixels.
+ */
+const MARGIN: f32 = 8.0;
+
+/**
+ * How hard to work on each frame's palette, from 1 for the best to 30 for the fastest.
+ * Panels are flat colours, so there isn't much to lose.
+ */
+const GIF_SPEED: i32 = 10;
+
+/// Record what the panels show for the first `seconds` after starting to a GIF.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_seconds")]
+    pub seconds: f32,
+    /// Width of the GIF in pixels. The height follows from the layout.
+    #[serde(default = "default_width")]
+    pub width: u32,
+}
+
+/// Draws each panel at its place in the layout, in the colour it was last set to.
+struct PanelCanvas {
+    width: u32,
+    height: u32,
+    /// Each panel's ID and corners, in pixels.
+    panels: Vec<(u16, Vec<(f32, f32)>)>,
+    colors: HashMap<u16, [u8; 3]>,
+}
+
+impl PanelCanvas {
+    /// Fit the layout into an image `width` pixels wide. Panels of unknown shape, such
+    /// as controllers, aren't drawn.
+    fn new(layout: &Layout, width: u32) -> Self {
+        let outlines: Vec<(u16, Vec<(f32, f32)>)> = layout.panels.iter().filter_map(|panel| {
+            let corners = shape(panel.shape_type)?.corners(panel.orientation).into_iter()
+                .map(|(x, y)| (panel.x as f32 + x * PANEL_SCALE, panel.y as f32 + y * PANEL_SCALE))
+                .collect();
+            Some((panel.panel_id, corners))
+        }).collect();
+        let (min_x, max_x, min_y, max_y) = outlines.iter().flat_map(|(_, corners)| corners).fold(
+            (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+            |(min_x, max_x, min_y, max_y), (x, y)| (min_x.min(*x), max_x.max(*x), min_y.min(*y), max_y.max(*y)),
+        );
+        let scale = (width as f32 - 2.0 * MARGIN).max(1.0) / (max_x - min_x).max(1.0);
+        let height = ((max_y - min_y).max(0.0) * scale + 2.0 * MARGIN).ceil() as u32;
+        // The layout's y axis points up, and the image's points down.
+        let panels = outlines.into_iter().map(|(panel_id, corners)| {
+            let corners = corners.into_iter().map(|(x, y)| (MARGIN + (x - min_x) * scale, MARGIN + (max_y - y) * scale)).collect();
+            (panel_id, corners)
+        }).collect();
+        PanelCanvas { width: width.max(1), height, panels, colors: HashMap::new() }
+    }
+
+    fn draw(&mut self, panels: &[PanelReport]) -> RgbaImage {
+        for panel in panels {
+            if let Some(color) = panel.color {
+                self.colors.insert(panel.panel_id, color);
+            }
+        }
+        let mut image = RgbaImage::from_pixel(self.width, self.height, BACKGROUND);
+        for (panel_id, corners) in &self.panels {
+            let [r, g, b] = self.colors.get(panel_id).copied().unwrap_or(UNSET);
+            fill_polygon(&mut image, corners, Rgba([r, g, b, 255]));
+        }
+        image
+    }
+}
+
+/// Fill a convex polygon, colouring the pixels whose centres are inside it.
+fn fill_polygon(image: &mut RgbaImage, corners: &[(f32, f32)], color: Rgba<u8>) {
+    let (min_x, max_x, min_y, max_y) = corners.iter().fold(
+        (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+        |(min_x, max_x, min_y, max_y), (x, y)| (min_x.min(*x), max_x.max(*x), min_y.min(*y), max_y.max(*y)),
+    );
+    for y in (min_y.max(0.0) as u32)..(max_y.max(0.0).ceil() as u32).min(image.height()) {
+        for x in (min_x.max(0.0) as u32)..(max_x.max(0.0).ceil() as u32).min(image.width()) {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            // Inside if it's on the same side of every edge.
+            let sides: Vec<f32> = corners.iter().zip(corners.iter().cycle().skip(1))
+                .map(|((x0, y0), (x1, y1))| (x1 - x0) * (py - y0) - (y1 - y0) * (px - x0))
+                .collect();
+            if sides.iter().all(|side| *side >= 0.0) || sides.iter().all(|side| *side <= 0.0) {
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+/// Writes frames of the panels to a GIF, each shown until the next one.
+pub struct GifRecorder {
+    canvas: PanelCanvas,
+    encoder: GifEncoder<BufWriter<File>>,
+    /// The last frame drawn and when it was shown, written once it's known how long it
+    /// stayed up.
+    pending: Option<(RgbaImage, Instant)>,
+    frames: usize,
+}
+
+impl GifRecorder {
+    pub fn create(path: &Path, layout: &Layout, width: u32) -> Result<Self, Box<dyn Error>> {
+        let mut encoder = GifEncoder::new_with_speed(BufWriter::new(File::create(path)?), GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+        Ok(GifRecorder {
+            canvas: PanelCanvas::new(layout, width),
+            encoder,
+            pending: None,
+            frames: 0,
+        })
+    }
+
+    /// Add the frame shown at `at`.
+    pub fn record(&mut self, panels: &[PanelReport], at: Instant) -> Result<(), Box<dyn Error>> {
+        let image = self.canvas.draw(panels);
+        if let Some((previous, shown)) = self.pending.replace((image, at)) {
+            self.write(previous, at.saturating_duration_since(shown))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, image: RgbaImage, shown_for: Duration) -> Result<(), Box<dyn Error>> {
+        self.encoder.encode_frame(Frame::from_parts(image, 0, 0, Delay::from_saturating_duration(shown_for)))?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Write out the last frame, shown for `last_for`, and finish the file. Returns how
+    /// many frames were written.
+    pub fn finish(mut self, last_for: Duration) -> Result<usize, Box<dyn Error>> {
+        if let Some((image, _)) = self.pending.take() {
+            self.write(image, last_for)?;
+        }
+        Ok(self.frames)
+    }
+}
+
+/// Records the start of a run to a GIF, alongside sending it to the lights. Frames are
+/// encoded on a thread of their own, so the lights aren't held up.
+pub struct Recording {
+    frames: Option<Sender<(Vec<PanelReport>, Instant)>>,
+    encoder: Option<JoinHandle<()>>,
+}
+
+impl Recording {
+    pub fn start(config: RecordConfig, layout: &Layout) -> Result<Self, Box<dyn Error>> {
+        let mut recorder = GifRecorder::create(&config.path, layout, config.width)?;
+        let length = Duration::from_secs_f32(config.seconds.max(0.0));
+        log::info!("Recording the first {}s to {}", config.seconds, config.path.display());
+        let (frames_tx, frames) = mpsc::channel::<(Vec<PanelReport>, Instant)>();
+        let encoder = crash::spawn("recording", move || {
+            let mut started = None;
+            for (panels, at) in frames {
+                if at.saturating_duration_since(*started.get_or_insert(at)) > length {
+                    break;
+                }
+                if let Err(err) = recorder.record(&panels, at) {
+                    log::warn!("Stopped recording to {}: {}", config.path.display(), err);
+                    return;
+                }
+            }
+            match recorder.finish(LIGHT_INTERVAL) {
+                Ok(frames) => log::info!("Recorded {} frames to {}", frames, config.path.display()),
+                Err(err) => log::warn!("Could not finish recording to {}: {}", config.path.display(), err),
+            }
+        });
+        Ok(Recording { frames: Some(frames_tx), encoder: Some(encoder) })
+    }
+}
+
+impl Reporter for Recording {
+    fn wants_report(&self) -> bool {
+        self.frames.is_some()
+    }
+
+    fn report(&mut self, report: &FrameReport) {
+        let sent = self.frames.as_ref().is_some_and(|frames| frames.send((report.panels.clone(), clock::now())).is_ok());
+        if !sent {
+            // The recording has finished.
+            self.frames = None;
+        }
+    }
+
+    fn finish(&mut self) {
+        // Stopping early ends the recording where it got to.
+        self.frames = None;
+        if let Some(encoder) = self.encoder.take() {
+            let _ = encoder.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nanoleaf::{NanoleafLayoutPanelData, NanoleafLayoutResponse};
+
+    #[test]
+    fn test_draws_panels_in_place() {
+        let square = |panel_id, x, y| NanoleafLayoutPanelData { panel_id, x, y, shape_type: 2, orientation: 0 };
+        let response = NanoleafLayoutResponse {
+            num_panels: 3,
+            side_length: 100,
+            position_data: vec![square(1, 50, 50), square(2, 150, 50), square(3, 150, 150)],
+        };
+        let layout = Layout::new(&response, Default::default()).unwrap();
+        let mut canvas = PanelCanvas::new(&layout, 216);
+        assert_eq!((canvas.width, canvas.height), (216, 216));
+
+        let image = canvas.draw(&[
+            PanelReport { panel_id: 1, color: Some([255, 0, 0]) },
+            PanelReport { panel_id: 2, color: Some([0, 0, 255]) },
+        ]);
+        // Panel 3 is above panel 2, and the bottom left is empty.
+        assert_eq!(image.get_pixel(58, 158).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(158, 158).0, [0, 0, 255, 255]);
+        assert_eq!(image.get_pixel(158, 58).0, [48, 48, 48, 255]);
+        assert_eq!(image.get_pixel(58, 58), &BACKGROUND);
+        assert_eq!(image.get_pixel(108, 158), &BACKGROUND);
+
+        // Panels keep their colour until they're set again.
+        let image = canvas.draw(&[PanelReport { panel_id: 1, color: None }]);
+        assert_eq!(image.get_pixel(58, 158).0, [255, 0, 0, 255]);
+    }
+}
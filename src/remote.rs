@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use ring::rand::SystemRandom;
+use serde::Deserialize;
+
+use crate::control::{send_command, ControlCommand, ControlState};
+use crate::crash;
+use crate::log_throttle::warn_throttled;
+use crate::report::{FrameReport, Reporter};
+use crate::rest;
+
+/**
+ * How often each client checks for reports to send while waiting for it to send
+ * something.
+ */
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/**
+ * How many reports may be waiting for a client before it's disconnected for falling
+ * behind.
+ */
+const CLIENT_QUEUE: usize = 30;
+/**
+ * Longest line a client may send, so it can't make us buffer without end.
+ */
+const MAX_LINE: usize = 4096;
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 46200))
+}
+
+fn default_rest_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 46201))
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RemoteConfig {
+    /// Where to listen for remote control clients.
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// Where to serve the REST API, for phone shortcuts and widgets.
+    #[serde(default = "default_rest_listen")]
+    pub rest_listen: SocketAddr,
+    /// The token clients must send before anything else. One is generated and kept in
+    /// the state directory if not set.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Encrypt connections with a self-signed certificate, which clients can check
+    /// against the fingerprint in the pairing code.
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+}
+
+/// The first line a remote client sends, e.g. `{"token": "..."}`.
+#[derive(Deserialize)]
+struct Auth {
+    token: String,
+}
+
+fn state_file(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(xdg::BaseDirectories::with_prefix("leafpipe")?.place_state_file(name)?)
+}
+
+/// Write a file only we can read.
+fn write_private(path: &PathBuf, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The token from last time, or a new one.
+fn load_token() -> Result<String, Box<dyn Error>> {
+    let path = state_file("remote_token")?;
+    if let Ok(token) = fs::read_to_string(&path) {
+        return Ok(token.trim().to_string());
+    }
+    let bytes: [u8; 16] = ring::rand::generate(&SystemRandom::new()).map_err(|_| "Could not generate a token")?.expose();
+    let token = hex(&bytes);
+    write_private(&path, token.as_bytes())?;
+    Ok(token)
+}
+
+/// The certificate and key from last time, or new ones. Keeping them means paired
+/// clients don't need pairing again.
+fn load_certificate() -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let (cert_path, key_path) = (state_file("remote_cert.der")?, state_file("remote_key.der")?);
+    if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+        return Ok((cert, key));
+    }
+    log::info!("Generating a certificate for remote control");
+    let certificate = rcgen::generate_simple_self_signed(vec!["leafpipe".to_string()])?;
+    let (cert, key) = (certificate.serialize_der()?, certificate.serialize_private_key_der());
+    write_private(&key_path, &key)?;
+    fs::write(&cert_path, &cert)?;
+    Ok((cert, key))
+}
+
+/// Whether a client sent the right token, taking the same time however much of it
+/// matches.
+pub fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The address other devices on the network can reach us on, for the pairing code.
+fn lan_address(listen: SocketAddr) -> SocketAddr {
+    if !listen.ip().is_unspecified() {
+        return listen;
+    }
+    // Connecting a UDP socket sends nothing, but picks the interface that routes out.
+    let ip = UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+        socket.connect("192.0.2.1:9")?;
+        socket.local_addr()
+    }).map_or(IpAddr::from([127, 0, 0, 1]), |addr| addr.ip());
+    SocketAddr::new(ip, listen.port())
+}
+
+/// Print a QR code holding everything a client needs to connect.
+fn print_pairing_code(address: SocketAddr, rest_port: u16, token: &str, fingerprint: Option<&str>) {
+    let mut url = format!("leafpipe://{}?token={}&rest={}", address, token, rest_port);
+    if let Some(fingerprint) = fingerprint {
+        url.push_str(&format!("&fingerprint={}", fingerprint));
+    }
+    match QrCode::new(&url) {
+        Ok(code) => println!("{}", code.render::<Dense1x2>().quiet_zone(true).build()),
+        Err(err) => log::warn!("Could not make a pairing code: {}", err),
+    }
+    println!("Scan to control leafpipe remotely, or connect to {}", url);
+}
+
+/// Talk to a remote client until it disconnects: checking its token, then passing on
+/// its commands and sending it reports.
+fn serve<S: Read + Write>(mut stream: S, token: &str, commands: Sender<ControlCommand>, reports: Receiver<Arc<[u8]>>) {
+    let mut authenticated = false;
+    let mut pending = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(read) => pending.extend_from_slice(&buffer[..read]),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {},
+            Err(err) => {
+                log::debug!("Remote client disconnected: {}", err);
+                return;
+            },
+        }
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if authenticated {
+                if !send_command(&line, &commands) {
+                    return;
+                }
+                continue;
+            }
+            match serde_json::from_str::<Auth>(&line) {
+                Ok(auth) if token_matches(&auth.token, token) => {
+                    log::info!("Remote client connected");
+                    authenticated = true;
+                },
+                _ => {
+                    log::warn!("Rejecting remote client with the wrong token");
+                    let _ = stream.write_all(b"{\"error\":\"unauthorized\"}\n");
+                    return;
+                },
+            }
+        }
+        if pending.len() > MAX_LINE {
+            log::warn!("Disconnecting remote client that sent too long a line");
+            return;
+        }
+        loop {
+            match reports.try_recv() {
+                Ok(line) => {
+                    if authenticated && stream.write_all(&line).is_err() {
+                        return;
+                    }
+                },
+                Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+/// Set up a newly accepted connection and serve it.
+fn accept(stream: TcpStream, token: &str, tls: Option<&Arc<rustls::ServerConfig>>, commands: Sender<ControlCommand>, reports: Receiver<Arc<[u8]>>) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_nodelay(true)?;
+    match tls {
+        Some(tls) => serve(rustls::StreamOwned::new(rustls::ServerConnection::new(tls.clone())?, stream), token, commands, reports),
+        None => serve(stream, token, commands, reports),
+    }
+    Ok(())
+}
+
+/// Where to queue reports for each connected client.
+type Clients = Arc<Mutex<Vec<SyncSender<Arc<[u8]>>>>>;
+
+/// Serves the same reports and commands as the control socket to other devices on
+/// the network, such as a phone, once they've sent the right token.
+pub struct RemoteControl {
+    clients: Clients,
+}
+
+impl RemoteControl {
+    /// Listen for clients, and serve the REST API. Must be called from within the Tokio
+    /// runtime.
+    pub fn bind(config: RemoteConfig, state: Arc<Mutex<ControlState>>, commands: Sender<ControlCommand>, profiles: Vec<String>) -> Result<Self, Box<dyn Error>> {
+        let token: Arc<str> = match config.token {
+            Some(token) => token.into(),
+            None => load_token()?.into(),
+        };
+        let (tls, fingerprint) = if config.tls {
+            let (cert, key) = load_certificate()?;
+            let fingerprint = hex(ring::digest::digest(&ring::digest::SHA256, &cert).as_ref());
+            let tls = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(vec![rustls::Certificate(cert)], rustls::PrivateKey(key))?;
+            (Some(Arc::new(tls)), Some(fingerprint))
+        } else {
+            log::warn!("Remote control is unencrypted, so the token can be read by anyone on the network");
+            (None, None)
+        };
+        rest::start(config.rest_listen, token.clone(), tls.clone(), state, commands.clone(), profiles)?;
+        let listener = TcpListener::bind(config.listen)?;
+        let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        let listen_token = token.clone();
+        crash::spawn("remote control", move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::warn!("Failed to accept remote client: {}", err);
+                        continue;
+                    },
+                };
+                let (reports_tx, reports) = sync_channel(CLIENT_QUEUE);
+                accepted.lock().unwrap().push(reports_tx);
+                let (token, tls, commands) = (listen_token.clone(), tls.clone(), commands.clone());
+                crash::spawn("remote client", move || {
+                    if let Err(err) = accept(stream, &token, tls.as_ref(), commands, reports) {
+                        log::warn!("Failed to set up remote client: {}", err);
+                    }
+                });
+            }
+        });
+        log::info!("Listening for remote control clients on {}", config.listen);
+        print_pairing_code(lan_address(config.listen), config.rest_listen.port(), &token, fingerprint.as_deref());
+        Ok(RemoteControl { clients })
+    }
+}
+
+impl Reporter for RemoteControl {
+    fn wants_report(&self) -> bool {
+        !self.clients.lock().unwrap().is_empty()
+    }
+
+    /// Queue a report for every client, dropping any that have gone away or fallen
+    /// behind.
+    fn report(&mut self, report: &FrameReport) {
+        let mut line = match serde_json::to_vec(report) {
+            Ok(line) => line,
+            Err(err) => {
+                warn_throttled!("Failed to serialize frame report: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let line: Arc<[u8]> = line.into();
+        self.clients.lock().unwrap().retain(|client| match client.try_send(line.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Disconnecting remote client that fell behind");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_requires_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (commands_tx, commands) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let (commands_tx, (reports_tx, reports)) = (commands_tx.clone(), sync_channel::<Arc<[u8]>>(1));
+                thread::spawn(move || {
+                    accept(stream.unwrap(), "secret", None, commands_tx, reports).unwrap();
+                    drop(reports_tx);
+                });
+            }
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"{\"token\": \"wrong\"}\n{\"command\": \"pause\"}\n").unwrap();
+        let mut response = String::new();
+        BufReader::new(&client).read_line(&mut response).unwrap();
+        assert_eq!(response, "{\"error\":\"unauthorized\"}\n");
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"{\"token\": \"secret\"}\n{\"command\": \"pause\"}\n").unwrap();
+        assert_eq!(commands.recv_timeout(Duration::from_secs(5)).unwrap(), ControlCommand::Pause);
+    }
+}
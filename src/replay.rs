@@ -0,0 +1,204 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use config::Config;
+
+use crate::cli::ReplayArgs;
+use crate::clock::{self, MockClock};
+use crate::dither::Dither;
+use crate::effects::{new_effect, LayerConfig, PostProcess, ScreenColors};
+use crate::hue_range::{HueRange, HueRangeConfig};
+use crate::hue_rotation::{HueRotation, HueRotationConfig};
+use crate::intervals::IntervalConfig;
+use crate::layout::Layout;
+use crate::lfo::{LfoConfig, Modulation};
+use crate::nanoleaf::NanoleafLayoutResponse;
+use crate::noise::{NoiseShimmer, NoiseShimmerConfig};
+use crate::oklab::ColorSpace;
+#[cfg(feature = "record")]
+use crate::recording::{self, GifRecorder};
+use crate::parallel_fft::FftConfig;
+use crate::safety::{SafetyConfig, StrobeLimiter};
+use crate::transition::Transition;
+use crate::tuning::Tuning;
+use crate::vis::BufferManager;
+#[cfg(feature = "screen")]
+use crate::visual::{capture::{FrameCopy, FrameTransform}, AnalysisConfig, ScreenAnalysis};
+use crate::{Pipeline, LIGHT_INTERVAL};
+
+/// Read mono 32 bit little endian float samples.
+pub fn load_audio(path: &Path) -> Result<Vec<f32>, Box<dyn Error>> {
+    Ok(fs::read(path)?
+        .chunks_exact(4)
+        .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+        .collect())
+}
+
+/// Read an image as though it had been captured from the screen.
+#[cfg(feature = "screen")]
+pub fn load_screenshot(path: &Path) -> Result<FrameCopy, Box<dyn Error>> {
+    let image = image::open(path)?;
+    Ok(FrameCopy {
+        width: image.width(),
+        height: image.height(),
+        stride: image.width() * 4,
+        color_type: image::ColorType::Rgba8,
+        data: image.to_rgba8().into_raw(),
+        transform: FrameTransform::Normal,
+        y_invert: false,
+        damage: None,
+        captured: clock::now(),
+    })
+}
+
+/// Render every frame of the recorded audio and write them to `args.output`.
+pub fn replay(args: ReplayArgs, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut output = BufWriter::new(File::create(&args.output)?);
+    let frames = render(&args, config, &mut output)?;
+    output.flush()?;
+    println!("Wrote {} frames to {}", frames, args.output.display());
+    Ok(())
+}
+
+/// The post-processes that only depend on the frames passing through them, and the
+/// time. The ambient program, ambient light and call ducking follow the world outside,
+/// so are left out.
+fn post_processes(config: &Config, args: &ReplayArgs, layout: &Layout) -> Vec<Box<dyn PostProcess>> {
+    let mut post_processes: Vec<Box<dyn PostProcess>> = Vec::new();
+    let color_space: ColorSpace = config.get("color_space").unwrap_or_default();
+    let effect_name = format!("{:?}", args.effect).to_lowercase();
+    if let Ok(lfos) = config.get::<Vec<LfoConfig>>(&format!("lfo.{}", effect_name)) {
+        post_processes.push(Box::new(Modulation::new(lfos, color_space)));
+    }
+    if let Ok(hue_rotation) = config.get::<HueRotationConfig>("hue_rotation") {
+        post_processes.push(Box::new(HueRotation::new(hue_rotation)));
+    }
+    if let Ok(shimmer) = config.get::<NoiseShimmerConfig>("noise_shimmer") {
+        post_processes.push(Box::new(NoiseShimmer::new(shimmer, &layout.active, color_space)));
+    }
+    if let Ok(hue_range) = config.get::<HueRangeConfig>("hue_range") {
+        post_processes.push(Box::new(HueRange::new(&hue_range)));
+    }
+    let safety: SafetyConfig = config.get("safety").unwrap_or_default();
+    post_processes.push(Box::new(StrobeLimiter::new(safety, color_space)));
+    post_processes
+}
+
+/// Run the recorded audio through the pipeline a frame at a time, moving the mock clock
+/// on by a frame in between, and write each frame's payload to `output`. Learned audio
+/// levels aren't restored, so every run starts from the same place.
+fn render(args: &ReplayArgs, config: &Config, output: &mut impl Write) -> Result<usize, Box<dyn Error>> {
+    let clock = MockClock::start();
+    let response: NanoleafLayoutResponse = serde_json::from_slice(&fs::read(&args.layout)?)?;
+    let layout = Layout::new(&response, config.get("panel_mask").unwrap_or_default())?;
+    let color_space: ColorSpace = config.get("color_space").unwrap_or_default();
+    let tuning: Tuning = config.get("tuning").unwrap_or_default();
+    let intervals: IntervalConfig = config.get("intervals").unwrap_or_default();
+
+    let mut pipeline = Pipeline::bare(new_effect(args.effect, tuning.intensity, config.get("peak_hold").unwrap_or_default(), color_space, &config.get::<Vec<LayerConfig>>("layers").unwrap_or_default()));
+    pipeline.post_processes = post_processes(config, args, &layout);
+    pipeline.transition = Transition::new(&config.get("transitions").unwrap_or_default(), color_space).0;
+    let mut dither = Dither::new(config.get("dither").unwrap_or(1.0));
+    let mut buffer_manager = BufferManager::default();
+    buffer_manager.tune(tuning);
+    buffer_manager.set_max_age(intervals.max_age());
+    buffer_manager.set_latency_target(intervals.audio_latency());
+    buffer_manager.set_overlap(config.get::<FftConfig>("fft").unwrap_or_default().overlap);
+
+    #[cfg(feature = "screen")]
+    let screenshots = args.screenshot.iter().map(|path| load_screenshot(path)).collect::<Result<Vec<_>, _>>()?;
+    #[cfg(feature = "screen")]
+    let mut screen_analysis = ScreenAnalysis::new(&AnalysisConfig {
+        panel_widths: layout.widths.clone(),
+        gamut: config.get("output_gamut").unwrap_or_default(),
+        heatmap: config.get("heatmap").unwrap_or_default(),
+        hysteresis: config.get("color_hysteresis").unwrap_or_default(),
+        max_age: intervals.max_age(),
+        downscale: 1,
+    });
+    #[cfg_attr(not(feature = "screen"), allow(unused_mut))]
+    let mut screen_colors = ScreenColors::default();
+    #[cfg(feature = "record")]
+    let mut gif = args.gif.as_ref().map(|path| GifRecorder::create(path, &layout, recording::default_width())).transpose()?;
+
+    let audio = load_audio(&args.audio)?;
+    let chunk = (args.rate as f32 * LIGHT_INTERVAL.as_secs_f32()) as usize;
+    let mut frames = 0;
+    for (index, samples) in audio.chunks_exact(chunk.max(1)).enumerate() {
+        #[cfg(feature = "screen")]
+        if !screenshots.is_empty() {
+            screen_colors = screen_analysis.analyse(&screenshots[(index / args.hold.max(1)) % screenshots.len()]);
+        }
+        buffer_manager.fill_buffer(samples, args.rate);
+        let analysis = buffer_manager.fft_interval(LIGHT_INTERVAL, layout.active.len())
+            .map(|audio_data| (audio_data, buffer_manager.chroma()));
+
+        let mut frame = pipeline.render(&layout, analysis.as_ref(), &screen_colors, false);
+        pipeline.transition.apply(&mut frame.colors, clock::now());
+        #[cfg_attr(not(feature = "record"), allow(unused_variables))]
+        let (payload, panel_reports) = layout.encode(&mut dither, &frame.colors);
+        output.write_all(payload.bytes())?;
+        #[cfg(feature = "record")]
+        if let Some(gif) = &mut gif {
+            gif.record(&panel_reports, clock::now())?;
+        }
+        frames = index + 1;
+        clock.advance(LIGHT_INTERVAL);
+    }
+    #[cfg(feature = "record")]
+    if let Some(gif) = gif {
+        gif.finish(LIGHT_INTERVAL)?;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use config::{File, FileFormat};
+
+    use super::*;
+    use crate::effects::EffectKind;
+
+    const CONFIG: &str = r#"
+        [[lfo.spectrum]]
+        target = "hue"
+        period_secs = 2
+        depth = 40
+
+        [hue_rotation]
+        degrees_per_minute = 600
+
+        [transitions]
+        fade_in_secs = 1
+    "#;
+
+    #[test]
+    fn test_replay_is_reproducible() {
+        let config = Config::builder().add_source(File::from_str(CONFIG, FileFormat::Toml)).build().unwrap();
+        let args = ReplayArgs {
+            audio: PathBuf::from("samples/pipeline/audio.f32"),
+            rate: 22050,
+            layout: PathBuf::from("samples/pipeline/layout.json"),
+            #[cfg(feature = "screen")]
+            screenshot: vec![PathBuf::from("samples/colortray.png"), PathBuf::from("samples/testcard.png")],
+            #[cfg(feature = "screen")]
+            hold: 3,
+            effect: EffectKind::Spectrum,
+            output: PathBuf::new(),
+            #[cfg(feature = "record")]
+            gif: None,
+        };
+        let mut first = Vec::new();
+        let frames = render(&args, &config, &mut first).unwrap();
+        assert!(frames > 0);
+        // Give the wall clock a chance to move on, which shouldn't change anything.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut second = Vec::new();
+        render(&args, &config, &mut second).unwrap();
+        assert_eq!(first, second);
+    }
+}
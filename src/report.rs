@@ -0,0 +1,52 @@
+use serde::Serialize;
+
+/// The colour sent to a single panel.
+#[derive(Serialize, Debug, Clone)]
+pub struct PanelReport {
+    pub panel_id: u16,
+    /// RGB colour, or `None` if the panel was left untouched this frame.
+    pub color: Option<[u8; 3]>,
+}
+
+/// How well audio is keeping up, to help track down the lights lagging the music.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct AudioStats {
+    /// Buffers dropped because more audio was queued than the latency target.
+    pub dropped: u64,
+    /// Buffers dropped because they'd waited too long to be analysed.
+    pub stale: u64,
+    /// Reads that came up short of a whole interval of audio.
+    pub underruns: u64,
+    /// How long the oldest audio in the last analysis had been waiting, in
+    /// milliseconds.
+    pub latency_ms: f32,
+}
+
+/// What the lights did for a single frame.
+#[derive(Serialize, Debug, Clone)]
+pub struct FrameReport {
+    /// Audio energy per band, lowest frequencies first.
+    pub bands: Vec<f32>,
+    /// Audio energy per band of the side channel, when `downmix = "midside"` analyses
+    /// it separately.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub side_bands: Option<Vec<f32>>,
+    /// Whether this frame starts a beat.
+    pub beat: bool,
+    /// Panels sorted left to right.
+    pub panels: Vec<PanelReport>,
+    pub audio: AudioStats,
+}
+
+/// Something outside of leafpipe that mirrors what the lights are doing.
+pub trait Reporter: Send {
+    /// Whether anyone is listening, so building the report can be skipped.
+    fn wants_report(&self) -> bool {
+        true
+    }
+
+    fn report(&mut self, report: &FrameReport);
+
+    /// Wrap up before leafpipe exits, once the last frame has been reported.
+    fn finish(&mut self) {}
+}
@@ -0,0 +1,62 @@
+use std::error::Error;
+
+use rubato::{FftFixedIn, Resampler};
+
+/**
+ * Roughly how many samples are converted at a time. Audio is held back until a whole
+ * chunk has arrived, adding about this much latency (around 20ms at 48kHz).
+ */
+const CHUNK_SIZE: usize = 1024;
+
+/// Converts mono audio from one sample rate to another.
+pub struct RateConverter {
+    from: u32,
+    resampler: FftFixedIn<f32>,
+    /// Samples waiting for a whole chunk.
+    pending: Vec<f32>,
+}
+
+impl RateConverter {
+    pub fn new(from: u32, to: u32) -> Result<Self, Box<dyn Error>> {
+        Ok(RateConverter {
+            from,
+            resampler: FftFixedIn::new(from as usize, to as usize, CHUNK_SIZE, 2, 1)?,
+            pending: Vec::new(),
+        })
+    }
+
+    /// The rate audio is converted from.
+    pub fn from(&self) -> u32 {
+        self.from
+    }
+
+    /// Convert `samples`, returning however much audio is ready. Samples left over are
+    /// converted along with the next call's.
+    pub fn convert(&mut self, samples: &[f32]) -> Result<Vec<f32>, Box<dyn Error>> {
+        self.pending.extend_from_slice(samples);
+        let mut converted = Vec::new();
+        while self.pending.len() >= self.resampler.input_frames_next() {
+            let chunk: Vec<f32> = self.pending.drain(..self.resampler.input_frames_next()).collect();
+            let mut output = self.resampler.process(&[chunk], None)?;
+            converted.append(&mut output[0]);
+        }
+        Ok(converted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let mut converter = RateConverter::new(44100, 48000).unwrap();
+        let tone: Vec<f32> = (0..44100).map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / 44100.0).sin()).collect();
+        let mut converted = Vec::new();
+        for chunk in tone.chunks(500) {
+            converted.extend(converter.convert(chunk).unwrap());
+        }
+        // All but the last partial chunk comes out, at the new rate.
+        assert!(converted.len() > 46000 && converted.len() <= 48000, "Expected about 48000 samples, got {}", converted.len());
+    }
+}
@@ -0,0 +1,295 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::control::{ControlCommand, ControlState};
+use crate::remote::token_matches;
+use crate::tuning::{Tuning, TuningChange};
+
+/**
+ * Largest request body accepted, which is plenty for any of the requests.
+ */
+const MAX_BODY: usize = 4096;
+
+/// Which scene to show, or `null` to go back to the effect.
+#[derive(Deserialize, ToSchema)]
+struct SceneRequest {
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// Which of the `[profiles]` to switch to.
+#[derive(Deserialize, ToSchema)]
+struct ProfileRequest {
+    name: String,
+}
+
+/// How strongly the audio drives the brightness of the panels.
+#[derive(Deserialize, ToSchema)]
+struct IntensityRequest {
+    intensity: f32,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Adds the bearer token to the spec.
+struct TokenAuth;
+
+impl Modify for TokenAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("token", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "leafpipe", description = "Remote control for leafpipe. Every request needs the pairing token as a bearer token."),
+    paths(status, pause, resume, scene, profile, intensity),
+    components(schemas(ControlState, Tuning, SceneRequest, ProfileRequest, IntensityRequest, ErrorResponse)),
+    modifiers(&TokenAuth),
+    security(("token" = [])),
+)]
+struct ApiDoc;
+
+/// The OpenAPI spec for the REST API, as served on `/openapi.json`.
+pub fn spec() -> String {
+    ApiDoc::openapi().to_pretty_json().expect("OpenAPI spec is always serializable")
+}
+
+/// Answers REST requests from phone shortcuts and widgets.
+struct Api {
+    token: Arc<str>,
+    state: Arc<Mutex<ControlState>>,
+    commands: Sender<ControlCommand>,
+    /// Names of the `[profiles]` that can be switched to.
+    profiles: Vec<String>,
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    let body = serde_json::to_vec(body).expect("Responses are always serializable");
+    Response::builder().status(status).header(header::CONTENT_TYPE, "application/json").body(body.into()).unwrap()
+}
+
+fn error(status: StatusCode, error: &str) -> Response<Body> {
+    json(status, &ErrorResponse { error: error.to_string() })
+}
+
+fn no_content() -> Response<Body> {
+    Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+}
+
+impl Api {
+    fn send(&self, command: ControlCommand) -> Response<Body> {
+        match self.commands.send(command) {
+            Ok(()) => no_content(),
+            Err(_) => error(StatusCode::SERVICE_UNAVAILABLE, "leafpipe is stopping"),
+        }
+    }
+}
+
+/// What the lights are doing.
+#[utoipa::path(get, path = "/status", responses(
+    (status = 200, description = "The current state", body = ControlState),
+    (status = 401, description = "Missing or wrong token", body = ErrorResponse),
+))]
+fn status(api: &Api) -> Response<Body> {
+    json(StatusCode::OK, &*api.state.lock().unwrap())
+}
+
+/// Stop updating the lights, leaving them as they are.
+#[utoipa::path(post, path = "/pause", responses(
+    (status = 204, description = "Paused"),
+    (status = 401, description = "Missing or wrong token", body = ErrorResponse),
+))]
+fn pause(api: &Api) -> Response<Body> {
+    api.send(ControlCommand::Pause)
+}
+
+/// Start updating the lights again.
+#[utoipa::path(post, path = "/resume", responses(
+    (status = 204, description = "Resumed"),
+    (status = 401, description = "Missing or wrong token", body = ErrorResponse),
+))]
+fn resume(api: &Api) -> Response<Body> {
+    api.send(ControlCommand::Resume)
+}
+
+/// Show one of the scenes from the config instead of the effect.
+#[utoipa::path(put, path = "/scene", request_body = SceneRequest, responses(
+    (status = 204, description = "Switched scene"),
+    (status = 400, description = "Invalid request", body = ErrorResponse),
+    (status = 401, description = "Missing or wrong token", body = ErrorResponse),
+))]
+fn scene(api: &Api, request: SceneRequest) -> Response<Body> {
+    api.send(ControlCommand::Scene { name: request.name })
+}
+
+/// Switch to one of the profiles from the config, changing the scene and tuning together.
+#[utoipa::path(put, path = "/profile", request_body = ProfileRequest, responses(
+    (status = 204, description = "Switched profile"),
+    (status = 400, description = "Invalid request", body = ErrorResponse),
+    (status = 401, description = "Missing or wrong token", body = ErrorResponse),
+    (status = 404, description = "No profile with that name", body = ErrorResponse),
+))]
+fn profile(api: &Api, request: ProfileRequest) -> Response<Body> {
+    if !api.profiles.contains(&request.name) {
+        return error(StatusCode::NOT_FOUND, &format!("No profile named {}", request.name));
+    }
+    api.send(ControlCommand::Profile { name: request.name })
+}
+
+/// Change how strongly the audio drives the lights, keeping the rest of the tuning.
+#[utoipa::path(put, path = "/intensity", request_body = IntensityRequest, responses(
+    (status = 204, description = "Changed the intensity"),
+    (status = 400, description = "Invalid request", body = ErrorResponse),
+    (status = 401, description = "Missing or wrong token", body = ErrorResponse),
+))]
+fn intensity(api: &Api, request: IntensityRequest) -> Response<Body> {
+    api.send(ControlCommand::Tune(TuningChange { intensity: Some(request.intensity), ..TuningChange::default() }))
+}
+
+/// Read a JSON request body, refusing anything too big.
+async fn read_json<T: for<'de> Deserialize<'de>>(mut body: Body) -> Result<T, Response<Body>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| error(StatusCode::BAD_REQUEST, &err.to_string()))?;
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > MAX_BODY {
+            return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "Request is too large"));
+        }
+    }
+    serde_json::from_slice(&bytes).map_err(|err| error(StatusCode::BAD_REQUEST, &err.to_string()))
+}
+
+async fn handle(api: Arc<Api>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.method() == Method::GET && request.uri().path() == "/openapi.json" {
+        return Ok(Response::builder().header(header::CONTENT_TYPE, "application/json").body(spec().into()).unwrap());
+    }
+    let authorized = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token_matches(token, &api.token));
+    if !authorized {
+        return Ok(error(StatusCode::UNAUTHORIZED, "Missing or wrong token"));
+    }
+    let (method, path) = (request.method().clone(), request.uri().path().to_string());
+    let response = match (method, path.as_str()) {
+        (Method::GET, "/status") => status(&api),
+        (Method::POST, "/pause") => pause(&api),
+        (Method::POST, "/resume") => resume(&api),
+        (Method::PUT, "/scene") => match read_json(request.into_body()).await {
+            Ok(request) => scene(&api, request),
+            Err(response) => response,
+        },
+        (Method::PUT, "/profile") => match read_json(request.into_body()).await {
+            Ok(request) => profile(&api, request),
+            Err(response) => response,
+        },
+        (Method::PUT, "/intensity") => match read_json(request.into_body()).await {
+            Ok(request) => intensity(&api, request),
+            Err(response) => response,
+        },
+        _ => error(StatusCode::NOT_FOUND, "No such endpoint"),
+    };
+    Ok(response)
+}
+
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + 'static>(stream: S, api: Arc<Api>) {
+    let service = service_fn(move |request| handle(api.clone(), request));
+    if let Err(err) = Http::new().http1_only(true).serve_connection(stream, service).await {
+        log::debug!("REST client disconnected: {}", err);
+    }
+}
+
+/// Serve the REST API on `listen`, over TLS if `tls` is given. Must be called from
+/// within the Tokio runtime.
+pub fn start(listen: SocketAddr, token: Arc<str>, tls: Option<Arc<rustls::ServerConfig>>, state: Arc<Mutex<ControlState>>, commands: Sender<ControlCommand>, profiles: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let listener = std::net::TcpListener::bind(listen)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let api = Arc::new(Api { token, state, commands, profiles });
+    let acceptor = tls.map(TlsAcceptor::from);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    log::warn!("Failed to accept REST client: {}", err);
+                    continue;
+                },
+            };
+            let (api, acceptor) = (api.clone(), acceptor.clone());
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(stream, api).await,
+                        Err(err) => log::debug!("REST client failed TLS handshake: {}", err),
+                    },
+                    None => serve_connection(stream, api).await,
+                }
+            });
+        }
+    });
+    log::info!("Serving the REST API on {}", listen);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests() {
+        let (commands, received) = channel();
+        let state = Arc::new(Mutex::new(ControlState { paused: true, ..Default::default() }));
+        let api = Arc::new(Api { token: "secret".into(), state, commands, profiles: vec!["movie".to_string()] });
+        let request = |method: Method, path: &str, token: &str, body: &str| {
+            Request::builder().method(method).uri(path).header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::from(body.to_string())).unwrap()
+        };
+
+        let response = handle(api.clone(), request(Method::POST, "/pause", "wrong", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(received.try_recv().is_err());
+
+        let response = handle(api.clone(), request(Method::GET, "/status", "secret", "")).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(status["paused"], true);
+
+        let response = handle(api.clone(), request(Method::PUT, "/intensity", "secret", r#"{"intensity": 20}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(received.try_recv().unwrap(), ControlCommand::Tune(TuningChange { intensity: Some(20.0), ..TuningChange::default() }));
+
+        let response = handle(api.clone(), request(Method::PUT, "/profile", "secret", r#"{"name": "movie"}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(received.try_recv().unwrap(), ControlCommand::Profile { name: "movie".to_string() });
+        let response = handle(api.clone(), request(Method::PUT, "/profile", "secret", r#"{"name": "party"}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(received.try_recv().is_err());
+
+        let response = handle(api.clone(), request(Method::PUT, "/scene", "secret", r#"{"name": null}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(received.try_recv().unwrap(), ControlCommand::Scene { name: None });
+
+        assert!(spec().contains("\"/intensity\""));
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use colors_transform::{Color, Hsl};
+use serde::Deserialize;
+
+use crate::clock;
+use crate::effects::PostProcess;
+use crate::oklab::ColorSpace;
+
+/**
+ * A swing in lightness at least this large, there and back, counts as a flash.
+ */
+const FLASH_THRESHOLD: f32 = 20.0;
+
+/**
+ * Window over which flashes are counted.
+ */
+const FLASH_WINDOW: Duration = Duration::from_secs(1);
+
+/**
+ * Movements smaller than this don't change the direction a panel is moving in.
+ */
+const DIRECTION_DEADBAND: f32 = 0.5;
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_brightness_change() -> f32 {
+    150.0
+}
+
+fn default_max_flashes() -> usize {
+    3
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SafetyConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Fastest a panel's lightness may change, in lightness percent per second.
+    #[serde(default = "default_max_brightness_change")]
+    pub max_brightness_change: f32,
+    /// Most flashes a panel may make in a second. Three or fewer is the usual
+    /// guidance for avoiding photosensitive seizures.
+    #[serde(default = "default_max_flashes")]
+    pub max_flashes: usize,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            enabled: default_enabled(),
+            max_brightness_change: default_max_brightness_change(),
+            max_flashes: default_max_flashes(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PanelState {
+    lightness: Option<f32>,
+    /// -1, 0 or 1 for whether the panel is getting darker or brighter.
+    direction: f32,
+    /// Lightness when the panel last changed direction.
+    turned_at: f32,
+    flashes: VecDeque<Duration>,
+}
+
+/// Limits how fast and how often panels can change brightness, so effects can't
+/// strobe. Applied last, so nothing can undo it.
+pub struct StrobeLimiter {
+    config: SafetyConfig,
+    panels: Vec<PanelState>,
+    started: Instant,
+    last_applied: Option<Duration>,
+    color_space: ColorSpace,
+}
+
+impl StrobeLimiter {
+    pub fn new(config: SafetyConfig, color_space: ColorSpace) -> Self {
+        StrobeLimiter {
+            config,
+            panels: Vec::new(),
+            started: clock::now(),
+            last_applied: None,
+            color_space,
+        }
+    }
+
+    /// Limit the colours of a frame shown `now` after the limiter was created.
+    fn limit(&mut self, colors: &mut [Option<Hsl>], now: Duration) {
+        let elapsed = self.last_applied.map_or(Duration::ZERO, |last| now.saturating_sub(last)).min(FLASH_WINDOW);
+        self.last_applied = Some(now);
+        self.panels.resize_with(colors.len(), PanelState::default);
+
+        for (hsl, state) in colors.iter_mut().zip(self.panels.iter_mut()) {
+            let Some(hsl) = hsl else {
+                continue;
+            };
+            let Some(previous) = state.lightness else {
+                state.lightness = Some(hsl.get_lightness());
+                state.turned_at = hsl.get_lightness();
+                continue;
+            };
+            while state.flashes.front().is_some_and(|flash| now.saturating_sub(*flash) > FLASH_WINDOW) {
+                state.flashes.pop_front();
+            }
+
+            // Once out of flashes, hold the panel until the oldest one is forgotten.
+            let max_step = if state.flashes.len() >= self.config.max_flashes {
+                0.0
+            } else {
+                self.config.max_brightness_change * elapsed.as_secs_f32()
+            };
+            let lightness = previous + (hsl.get_lightness() - previous).clamp(-max_step, max_step);
+
+            let movement = lightness - previous;
+            if movement.abs() > DIRECTION_DEADBAND {
+                let direction = movement.signum();
+                if direction != state.direction {
+                    if state.direction != 0.0 && (previous - state.turned_at).abs() >= FLASH_THRESHOLD {
+                        state.flashes.push_back(now);
+                    }
+                    state.direction = direction;
+                    state.turned_at = previous;
+                }
+            }
+
+            state.lightness = Some(lightness);
+            *hsl = self.color_space.with_lightness(hsl, lightness);
+        }
+    }
+}
+
+impl PostProcess for StrobeLimiter {
+    fn apply(&mut self, colors: &mut Vec<Option<Hsl>>) {
+        if self.config.enabled {
+            let now = clock::elapsed(self.started);
+            self.limit(colors, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run a 5Hz strobe between dark and bright through the limiter.
+    fn strobe(config: SafetyConfig) -> Vec<f32> {
+        let mut limiter = StrobeLimiter::new(config, ColorSpace::Hsl);
+        (0..30).map(|frame| {
+            let target = if frame % 2 == 0 { 5.0 } else { 80.0 };
+            let mut colors = vec![Some(Hsl::from(0.0, 100.0, target))];
+            limiter.limit(&mut colors, Duration::from_millis(frame * 100));
+            colors[0].unwrap().get_lightness()
+        }).collect()
+    }
+
+    #[test]
+    fn test_brightness_change_is_limited() {
+        let lightness = strobe(SafetyConfig::default());
+        for pair in lightness.windows(2) {
+            assert!((pair[1] - pair[0]).abs() <= 15.0 + 0.001, "Changed too fast: {:?}", pair);
+        }
+    }
+
+    #[test]
+    fn test_flashes_are_limited() {
+        let lightness = strobe(SafetyConfig { max_brightness_change: 10000.0, ..SafetyConfig::default() });
+        let swings: Vec<bool> = lightness.windows(2).map(|pair| (pair[1] - pair[0]).abs() >= FLASH_THRESHOLD).collect();
+        for second in swings.windows(10) {
+            assert!(second.iter().filter(|swing| **swing).count() <= 4, "Too many flashes: {:?}", lightness);
+        }
+        assert!(swings.iter().filter(|swing| **swing).count() > 4, "Should resume once the window passes");
+    }
+}
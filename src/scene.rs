@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use colors_transform::{Color, Hsl, Rgb};
+use serde::Deserialize;
+
+use crate::clock;
+use crate::nanoleaf::NanoleafLayoutPanelData;
+use crate::noise::NoiseConfig;
+use crate::oklab::ColorSpace;
+
+/// A static or slowly moving arrangement of colours, defined in the config.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SceneConfig {
+    /// Colours spread evenly from the leftmost panel to the rightmost.
+    #[serde(default)]
+    pub gradient: Vec<[u8; 3]>,
+    /// Colours for specific panels, keyed by panel ID, taking priority over the gradient.
+    #[serde(default)]
+    pub panels: HashMap<String, [u8; 3]>,
+    /// How many times a minute the gradient scrolls all the way across the panels.
+    /// The gradient wraps around while scrolling, so the last colour blends back into
+    /// the first.
+    #[serde(default)]
+    pub drift: f32,
+    /// Place each panel along the gradient by noise at its position in the layout,
+    /// rather than from left to right, so blobs of colour wander across the panels.
+    #[serde(default)]
+    pub noise: Option<NoiseConfig>,
+}
+
+/// Blend between the colours of a gradient, with `position` running from 0 to 1. When
+/// `wrap` is set, positions past the last colour blend back into the first.
+pub fn gradient_at(gradient: &[[u8; 3]], position: f32, wrap: bool, color_space: ColorSpace) -> [u8; 3] {
+    if gradient.len() == 1 {
+        return gradient[0];
+    }
+    let segments = if wrap { gradient.len() } else { gradient.len() - 1 };
+    let scaled = position.clamp(0.0, 1.0) * segments as f32;
+    let index = (scaled.floor() as usize).min(segments - 1);
+    let t = scaled - index as f32;
+    color_space.mix(gradient[index], gradient[(index + 1) % gradient.len()], t)
+}
+
+impl SceneConfig {
+    /// Render the scene `elapsed` after it was selected.
+    pub fn render(&self, panels: &[NanoleafLayoutPanelData], elapsed: Duration, color_space: ColorSpace) -> Vec<Option<Hsl>> {
+        let min_x = panels.iter().map(|panel| panel.x).min().unwrap_or(0);
+        let max_x = panels.iter().map(|panel| panel.x).max().unwrap_or(0);
+        let width = (max_x - min_x).max(1) as f32;
+        let offset = elapsed.as_secs_f32() * self.drift / 60.0;
+        let wrap = self.drift != 0.0;
+        let noise = self.noise.map(|noise| noise.sample(panels, elapsed.as_secs_f32()));
+
+        panels.iter().enumerate().map(|(index, panel)| {
+            let rgb = match self.panels.get(&panel.panel_id.to_string()) {
+                Some(rgb) => *rgb,
+                None if self.gradient.is_empty() => return None,
+                None => {
+                    let mut position = match &noise {
+                        Some(noise) => noise[index] * 0.5 + 0.5,
+                        None => (panel.x - min_x) as f32 / width,
+                    };
+                    if wrap {
+                        position = (position + offset).rem_euclid(1.0);
+                    }
+                    gradient_at(&self.gradient, position, wrap, color_space)
+                },
+            };
+            Some(Rgb::from(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32).to_hsl())
+        }).collect()
+    }
+}
+
+/// A scene kept up to date from outside the config, such as by the weather.
+pub type LiveScene = Arc<Mutex<SceneConfig>>;
+
+/// The scenes defined in the config, and which of them should be showing.
+pub struct Scenes {
+    scenes: HashMap<String, SceneConfig>,
+    /// Scenes that change while running, by name.
+    live: HashMap<String, LiveScene>,
+    /// Scene shown while idle or without audio.
+    idle: Option<String>,
+    /// Scene selected by a control client, shown until deselected.
+    selected: Option<String>,
+    /// The scene last rendered, and when it was first rendered, so its animation
+    /// starts from the beginning each time it's shown.
+    showing: Option<(String, Instant)>,
+    color_space: ColorSpace,
+}
+
+impl Scenes {
+    pub fn new(scenes: HashMap<String, SceneConfig>, live: HashMap<String, LiveScene>, idle: Option<String>, color_space: ColorSpace) -> Self {
+        let idle = idle.filter(|name| {
+            let exists = scenes.contains_key(name) || live.contains_key(name);
+            if !exists {
+                log::warn!("Idle scene {} isn't defined in scenes", name);
+            }
+            exists
+        });
+        Scenes {
+            scenes,
+            live,
+            idle,
+            selected: None,
+            showing: None,
+            color_space,
+        }
+    }
+
+    /// Show a scene in place of the effect, or return to the effect with `None`.
+    pub fn select(&mut self, name: Option<String>) {
+        match name {
+            Some(name) if !self.scenes.contains_key(&name) && !self.live.contains_key(&name) => log::warn!("No scene named {}", name),
+            Some(name) => {
+                log::info!("Showing scene {}", name);
+                self.selected = Some(name);
+            },
+            None => {
+                log::info!("Returning to the effect");
+                self.selected = None;
+            },
+        }
+    }
+
+    /// The scene selected by a control client, if any.
+    pub fn selected(&self) -> Option<&str> {
+        self.selected.as_deref()
+    }
+
+    /// Render the scene that should be shown instead of the effect, if any. A selected
+    /// scene always wins, otherwise the idle scene is shown when `idle` is set.
+    pub fn render(&mut self, panels: &[NanoleafLayoutPanelData], idle: bool) -> Option<Vec<Option<Hsl>>> {
+        let Some(name) = self.selected.as_ref().or(self.idle.as_ref().filter(|_| idle)) else {
+            self.showing = None;
+            return None;
+        };
+        let started = match &self.showing {
+            Some((showing, started)) if showing == name => *started,
+            _ => {
+                let started = clock::now();
+                self.showing = Some((name.clone(), started));
+                started
+            },
+        };
+        let elapsed = clock::elapsed(started);
+        match self.scenes.get(name) {
+            Some(scene) => Some(scene.render(panels, elapsed, self.color_space)),
+            None => self.live.get(name).map(|scene| scene.lock().unwrap().render(panels, elapsed, self.color_space)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gradient_at() {
+        let gradient = [[255, 0, 0], [0, 0, 255]];
+        assert_eq!(gradient_at(&gradient, 0.0, false, ColorSpace::Hsl), [255, 0, 0]);
+        // Half way from red to blue is magenta, going the short way around the hues.
+        assert_eq!(gradient_at(&gradient, 0.5, false, ColorSpace::Hsl), [255, 0, 255]);
+        assert_eq!(gradient_at(&gradient, 1.0, false, ColorSpace::Hsl), [0, 0, 255]);
+        // Wrapped, the second half of the gradient fades back to red.
+        assert_eq!(gradient_at(&gradient, 0.5, true, ColorSpace::Hsl), [0, 0, 255]);
+        assert_eq!(gradient_at(&gradient, 0.75, true, ColorSpace::Hsl), [255, 0, 255]);
+    }
+}
@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+#[cfg(any(feature = "wayland", feature = "pipewire"))]
+use std::time::{Duration, Instant};
+
+use colors_transform::Hsl;
+use config::Config;
+
+use crate::dither::Dither;
+use crate::layout::Layout;
+#[cfg(feature = "wayland")]
+use crate::memory::MemoryBudget;
+use crate::nanoleaf::{self, NanoleafClient};
+use crate::token;
+use crate::tuning::Tuning;
+#[cfg(feature = "wayland")]
+use crate::visual::heatmap::HeatmapConfig;
+#[cfg(any(feature = "wayland", feature = "pipewire"))]
+use crate::LIGHT_INTERVAL;
+
+/**
+ * How long each output or intensity is shown on the panels before asking about it.
+ */
+#[cfg(any(feature = "wayland", feature = "pipewire"))]
+const PREVIEW_TIME: Duration = Duration::from_secs(5);
+
+/**
+ * How much the intensity changes each time it's turned up or down while calibrating.
+ */
+#[cfg(feature = "pipewire")]
+const INTENSITY_STEP: f32 = 5.0;
+
+/// What the wizard found out, to be written to the config.
+struct Settings {
+    host: String,
+    port: u16,
+    /// Where the pairing token was saved.
+    token_file: PathBuf,
+    /// The output to capture, if there's a choice.
+    display: Option<String>,
+    intensity: f32,
+}
+
+/// Ask a question and wait for the answer.
+fn ask(question: &str) -> io::Result<String> {
+    print!("{} ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Setup was cancelled"));
+    }
+    Ok(answer.trim().to_string())
+}
+
+/// Ask a yes or no question, going with `default` if nothing is entered.
+fn confirm(question: &str, default: bool) -> io::Result<bool> {
+    let answer = ask(&format!("{} [{}]", question, if default { "Y/n" } else { "y/N" }))?;
+    Ok(match answer.to_lowercase().as_str() {
+        "" => default,
+        answer => answer.starts_with('y'),
+    })
+}
+
+/// Find the controller to drive, from those on the network or by asking for its address.
+fn choose_controller() -> Result<(String, u16), Box<dyn Error>> {
+    #[cfg(feature = "mdns")]
+    {
+        println!("Looking for controllers on the network...");
+        let found: Vec<_> = crate::commands::find_controllers()?.into_iter()
+            .filter_map(|controller| Some((controller.name, controller.addresses.first()?.clone(), controller.port)))
+            .collect();
+        for (index, (name, address, _)) in found.iter().enumerate() {
+            println!("  {}) {} on {}", index + 1, name, address);
+        }
+        if !found.is_empty() {
+            let answer = ask("Which controller? Enter its number, or the address of another one:")?;
+            match answer.parse::<usize>().ok().and_then(|number| found.get(number.checked_sub(1)?)) {
+                Some((_, address, port)) => return Ok((address.clone(), *port)),
+                None if !answer.is_empty() => return Ok((answer, nanoleaf::DEFAULT_API_PORT)),
+                None => {},
+            }
+        } else {
+            println!("No controllers found, check it's on the same network as this computer.");
+        }
+    }
+    loop {
+        let host = ask("Address of the controller:")?;
+        if !host.is_empty() {
+            return Ok((host, nanoleaf::DEFAULT_API_PORT));
+        }
+    }
+}
+
+/// Walk through holding the power button and ask the controller for a token.
+async fn pair(host: &str, port: u16) -> Result<String, Box<dyn Error>> {
+    println!("Hold the power button on the controller for 5-7 seconds, until the lights flash.");
+    loop {
+        ask("Then press Enter to pair.")?;
+        match nanoleaf::pair(host, port).await {
+            Ok(token) => return Ok(token),
+            Err(err) => {
+                println!("{}", err);
+                if !confirm("Try again?", true)? {
+                    return Err(err.into());
+                }
+            },
+        }
+    }
+}
+
+/// Send a colour for each panel.
+fn show(nanoleaf: &mut NanoleafClient, layout: &Layout, dither: &mut Dither, colors: &[Option<Hsl>]) -> io::Result<()> {
+    let (payload, _) = layout.encode(dither, colors);
+    nanoleaf.send_effect(&payload)
+}
+
+/// Mirror an output on the panels for a few seconds, mapped as it would be when running.
+#[cfg(feature = "wayland")]
+async fn preview_output(name: &str, nanoleaf: &mut NanoleafClient, layout: &Layout, analysis: &crate::visual::AnalysisConfig) -> Result<(), Box<dyn Error>> {
+    let mut source = crate::visual::connect(Some(name), None, None, analysis.downscale)?;
+    let mut screen = crate::visual::ScreenAnalysis::new(analysis);
+    let mut dither = Dither::new(1.0);
+    let deadline = Instant::now() + PREVIEW_TIME;
+    while Instant::now() < deadline {
+        let frame = source.capture_frame()?;
+        let colors: Vec<Option<Hsl>> = screen.analyse(&frame).primary.into_iter().map(Some).collect();
+        show(nanoleaf, layout, &mut dither, &colors)?;
+        tokio::time::sleep(LIGHT_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Show each output on the panels in turn until one is picked. Returns `None` when
+/// there's only one output, as it's captured anyway.
+#[cfg(feature = "wayland")]
+async fn choose_output(nanoleaf: &mut NanoleafClient, layout: &Layout, config: &Config) -> Result<Option<String>, Box<dyn Error>> {
+    let outputs = crate::visual::list_outputs()?;
+    if outputs.len() < 2 {
+        return Ok(None);
+    }
+    let memory = MemoryBudget::from_config(config);
+    let analysis = crate::visual::AnalysisConfig {
+        panel_widths: layout.widths.clone(),
+        gamut: config.get("output_gamut").unwrap_or_default(),
+        heatmap: HeatmapConfig { compact: memory.compact_heatmap, ..config.get("heatmap").unwrap_or_default() },
+        hysteresis: config.get("color_hysteresis").unwrap_or_default(),
+        max_age: crate::intervals::IntervalConfig::default().max_age(),
+        downscale: memory.capture_downscale(),
+    };
+    println!("Mirroring each output on the panels in turn, say yes to the one they should follow.");
+    for output in &outputs {
+        println!("Showing {}...", output.name);
+        if let Err(err) = preview_output(&output.name, nanoleaf, layout, &analysis).await {
+            println!("Could not capture {}: {}", output.name, err);
+            continue;
+        }
+        if confirm(&format!("Follow {}?", output.name), false)? {
+            return Ok(Some(output.name.clone()));
+        }
+    }
+    println!("No output picked, the first one will be captured.");
+    Ok(None)
+}
+
+#[cfg(not(feature = "wayland"))]
+async fn choose_output(_nanoleaf: &mut NanoleafClient, _layout: &Layout, _config: &Config) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(None)
+}
+
+/// Play the default effect along to whatever is playing, turning the intensity up or
+/// down until it looks right.
+#[cfg(feature = "pipewire")]
+async fn calibrate_intensity(nanoleaf: &mut NanoleafClient, layout: &Layout) -> Result<f32, Box<dyn Error>> {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, RwLock};
+
+    use crate::effects::{new_effect, EffectKind, ScreenColors};
+    use crate::oklab::ColorSpace;
+    use crate::vis::BufferManager;
+    use crate::Pipeline;
+
+    let buffer_manager = Arc::new(RwLock::new(BufferManager::default()));
+    let capture = buffer_manager.clone();
+    // PipeWire's main loop doesn't return, so it's left running until setup exits.
+    crate::crash::spawn("audio capture", move || {
+        match crate::pipewire::PipewireContainer::new(capture, crate::pipewire::AudioSource::Monitor, Default::default(), Vec::new(), Arc::new(AtomicBool::new(false))) {
+            Ok(pipewire) => pipewire.run(),
+            Err(err) => log::error!("Could not capture audio: {}", err),
+        }
+    });
+    ask("Play some music you'd normally listen to, then press Enter.")?;
+
+    let mut intensity = Tuning::default().intensity;
+    let mut pipeline = Pipeline::bare(new_effect(EffectKind::Screen, intensity, Default::default(), ColorSpace::Hsl, &[]));
+    let mut dither = Dither::new(1.0);
+    let panels = layout.active.len();
+    let screen_colors = ScreenColors {
+        primary: (0..panels).map(|index| Hsl::from(index as f32 * 360.0 / panels as f32, 80.0, 50.0)).collect(),
+        accent: vec![None; panels],
+    };
+    loop {
+        println!("Showing intensity {}...", intensity);
+        pipeline.effect.set_intensity(intensity);
+        let deadline = Instant::now() + PREVIEW_TIME;
+        while Instant::now() < deadline {
+            let analysis = {
+                let mut buffer_manager = buffer_manager.write().unwrap();
+                buffer_manager.fft_interval(LIGHT_INTERVAL, panels).map(|audio_data| (audio_data, buffer_manager.chroma()))
+            };
+            let frame = pipeline.render(layout, analysis.as_ref(), &screen_colors, false);
+            show(nanoleaf, layout, &mut dither, &frame.colors)?;
+            tokio::time::sleep(LIGHT_INTERVAL).await;
+        }
+        match ask("Brighter (b), dimmer (d), or press Enter if it looks right:")?.to_lowercase().as_str() {
+            "b" => intensity = (intensity + INTENSITY_STEP).min(100.0),
+            "d" => intensity = (intensity - INTENSITY_STEP).max(INTENSITY_STEP),
+            _ => return Ok(intensity),
+        }
+    }
+}
+
+#[cfg(not(feature = "pipewire"))]
+async fn calibrate_intensity(_nanoleaf: &mut NanoleafClient, _layout: &Layout) -> Result<f32, Box<dyn Error>> {
+    println!("Built without PipeWire support, so keeping the default intensity.");
+    Ok(Tuning::default().intensity)
+}
+
+/// Write what the wizard found out into the config at `path`, keeping everything else
+/// in it and a copy of the old one alongside it.
+fn write_config(path: &Path, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => {
+            fs::write(path.with_extension("toml.bak"), &text)?;
+            text
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut document: toml_edit::Document = text.parse()?;
+    document["nanoleaf_host"] = toml_edit::value(settings.host.as_str());
+    if settings.port != nanoleaf::DEFAULT_API_PORT {
+        document["nanoleaf_port"] = toml_edit::value(i64::from(settings.port));
+    }
+    // The token is kept out of the config, in a file only we can read.
+    document.remove("nanoleaf_token");
+    document["nanoleaf_token_file"] = toml_edit::value(settings.token_file.to_string_lossy().as_ref());
+    if let Some(display) = &settings.display {
+        document["display"] = toml_edit::value(display.as_str());
+    }
+    let tuning = document.entry("tuning").or_insert(toml_edit::table()).as_table_mut().ok_or("tuning in the config isn't a table")?;
+    // Go through the shortest decimal form, so 0.1 isn't written as 0.10000000149.
+    tuning["intensity"] = toml_edit::value(settings.intensity.to_string().parse::<f64>()?);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, document.to_string())?;
+    Ok(())
+}
+
+/// Walk through finding and pairing with a controller, picking the output to follow
+/// and how strongly the lights react, then write it all to the config.
+pub async fn run(config: &Config, config_file: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let configured = config.get_string("nanoleaf_host").ok().zip(token::load(config).ok());
+    let (host, port, token) = match configured {
+        Some((host, token)) if confirm(&format!("Keep using the controller on {}?", host), true)? => {
+            let port = config.get_int("nanoleaf_port").ok().and_then(|port| port.try_into().ok()).unwrap_or(nanoleaf::DEFAULT_API_PORT);
+            (host, port, token)
+        },
+        _ => {
+            let (host, port) = choose_controller()?;
+            let token = pair(&host, port).await?;
+            println!("Paired with the controller on {}.", host);
+            (host, port, token)
+        },
+    };
+
+    let token_file = token::path(config)?;
+    token::save(&token_file, &token)?;
+    let mut nanoleaf = NanoleafClient::connect(token, host.clone(), port, config.get("nanoleaf_transport").unwrap_or_default(), false).await?;
+    let panels = nanoleaf.get_panels().await?;
+    let layout = Layout::new(&panels, config.get("panel_mask").unwrap_or_default())?;
+    println!("Found {} panels.", layout.num_panels);
+
+    let display = choose_output(&mut nanoleaf, &layout, config).await?;
+    let intensity = calibrate_intensity(&mut nanoleaf, &layout).await?;
+    show(&mut nanoleaf, &layout, &mut Dither::new(1.0), &vec![Some(Hsl::from(0.0, 0.0, 0.0)); layout.active.len()])?;
+
+    let path = match config_file {
+        Some(path) => path,
+        None => xdg::BaseDirectories::with_prefix("leafpipe")?.place_config_file("config.toml")?,
+    };
+    write_config(&path, &Settings { host, port, token_file, display, intensity })?;
+    println!("Wrote {}, run `leafpipe` to start the lights.", path.display());
+    Ok(())
+}
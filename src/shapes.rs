@@ -0,0 +1,83 @@
+use std::f32::consts::PI;
+
+/// The outline of a kind of panel, as a regular polygon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shape {
+    pub sides: u32,
+    /// Length of each side, in the units of the layout's coordinates.
+    pub side_length: f32,
+    /// Angle of one of the corners from the x axis when the panel isn't rotated, in
+    /// degrees.
+    pub corner_angle: f32,
+}
+
+/// The shape of a panel by the controller's `shapeType`, or `None` for controllers,
+/// connectors, lines and anything else that isn't a panel of known size.
+pub fn shape(shape_type: u8) -> Option<Shape> {
+    let (sides, side_length, corner_angle) = match shape_type {
+        // Aurora triangles, pointing up.
+        0 => (3, 150.0, 90.0),
+        // Canvas squares, including the control squares.
+        2..=4 => (4, 100.0, 45.0),
+        // Shapes hexagons, flat along the top.
+        7 => (6, 67.0, 0.0),
+        // Shapes triangles and mini triangles, pointing up.
+        8 => (3, 134.0, 90.0),
+        9 => (3, 67.0, 90.0),
+        // Elements hexagons.
+        14 => (6, 134.0, 0.0),
+        _ => return None,
+    };
+    Some(Shape { sides, side_length, corner_angle })
+}
+
+impl Shape {
+    /// Distance from the centre to the middle of each side.
+    pub fn apothem(&self) -> f32 {
+        self.side_length / (2.0 * (PI / self.sides as f32).tan())
+    }
+
+    /// Distance from the centre to each corner.
+    fn radius(&self) -> f32 {
+        self.side_length / (2.0 * (PI / self.sides as f32).sin())
+    }
+
+    /// How wide the panel is from side to side once rotated by the layout's
+    /// `orientation`, in degrees.
+    pub fn width(&self, orientation: u16) -> f32 {
+        let start = (self.corner_angle + orientation as f32).to_radians();
+        let (left, right) = (0..self.sides)
+            .map(|corner| (start + corner as f32 * 2.0 * PI / self.sides as f32).cos())
+            .fold((0.0f32, 0.0f32), |(left, right), x| (left.min(x), right.max(x)));
+        self.radius() * (right - left)
+    }
+
+    /// Where each corner is relative to the centre once rotated by the layout's
+    /// `orientation`, in degrees, going anticlockwise.
+    #[cfg_attr(not(feature = "record"), allow(dead_code))]
+    pub fn corners(&self, orientation: u16) -> Vec<(f32, f32)> {
+        let start = (self.corner_angle + orientation as f32).to_radians();
+        (0..self.sides).map(|corner| {
+            let angle = start + corner as f32 * 2.0 * PI / self.sides as f32;
+            (self.radius() * angle.cos(), self.radius() * angle.sin())
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_widths() {
+        let square = shape(2).unwrap();
+        assert!((square.width(0) - 100.0).abs() < 0.01);
+        assert!((square.width(45) - 141.42).abs() < 0.01);
+        // A triangle is as wide as its base, and narrower on its side.
+        let triangle = shape(8).unwrap();
+        assert!((triangle.width(0) - 134.0).abs() < 0.01);
+        assert!((triangle.width(90) - 116.05).abs() < 0.01);
+        assert!((shape(9).unwrap().width(60) - 67.0).abs() < 0.01);
+        assert!(shape(12).is_none());
+    }
+}
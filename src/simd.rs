@@ -0,0 +1,206 @@
+use rustfft::num_complex::Complex;
+
+/**
+ * How many values the portable loops work on at a time, enough to fill the widest
+ * vector registers with `f32`s once the compiler vectorises them.
+ */
+#[cfg_attr(not(feature = "screen"), allow(dead_code))]
+const LANES: usize = 8;
+
+/// Convert 8-bit RGB pixels into hue in degrees, and saturation and lightness in
+/// percent, giving exactly what `colors_transform` does but without allocating for
+/// every pixel. `hsl` must be at least as long as `rgb`.
+///
+/// Uses NEON on ARM, since leafpipe often runs on a small board next to the panels,
+/// and AVX2 on x86 CPUs that have it. Either is only used once the CPU is known to
+/// support it.
+#[cfg_attr(not(feature = "screen"), allow(dead_code))]
+pub fn rgb_to_hsl(rgb: &[[u8; 3]], hsl: &mut [[f32; 3]]) {
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        // SAFETY: the CPU has just been checked for NEON.
+        return unsafe { neon::rgb_to_hsl(rgb, hsl) };
+    }
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("avx2") {
+        // SAFETY: the CPU has just been checked for AVX2.
+        return unsafe { rgb_to_hsl_avx2(rgb, hsl) };
+    }
+    rgb_to_hsl_portable(rgb, hsl)
+}
+
+/// The magnitude of each value in an FFT's spectrum.
+pub fn magnitudes(spectrum: &[Complex<f32>]) -> Vec<f32> {
+    let mut magnitudes = vec![0.0; spectrum.len()];
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        // SAFETY: the CPU has just been checked for NEON.
+        unsafe { neon::magnitudes(spectrum, &mut magnitudes) };
+        return magnitudes;
+    }
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("avx2") {
+        // SAFETY: the CPU has just been checked for AVX2.
+        unsafe { magnitudes_avx2(spectrum, &mut magnitudes) };
+        return magnitudes;
+    }
+    magnitudes_portable(spectrum, &mut magnitudes);
+    magnitudes
+}
+
+/// The portable loop, compiled again with AVX2 so it's vectorised eight wide.
+#[cfg(target_arch = "x86_64")]
+#[cfg_attr(not(feature = "screen"), allow(dead_code))]
+#[target_feature(enable = "avx2")]
+unsafe fn rgb_to_hsl_avx2(rgb: &[[u8; 3]], hsl: &mut [[f32; 3]]) {
+    rgb_to_hsl_portable(rgb, hsl)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn magnitudes_avx2(spectrum: &[Complex<f32>], magnitudes: &mut [f32]) {
+    magnitudes_portable(spectrum, magnitudes)
+}
+
+/// Converts `LANES` pixels at a time, a channel at a time and without branches, so the
+/// compiler can vectorise it for whatever CPU it's built for.
+#[cfg_attr(not(feature = "screen"), allow(dead_code))]
+#[inline(always)]
+fn rgb_to_hsl_portable(rgb: &[[u8; 3]], hsl: &mut [[f32; 3]]) {
+    for (rgb, hsl) in rgb.chunks(LANES).zip(hsl.chunks_mut(LANES)) {
+        let mut channels = [[0.0f32; LANES]; 3];
+        for (lane, pixel) in rgb.iter().enumerate() {
+            for (channel, value) in channels.iter_mut().zip(pixel) {
+                channel[lane] = *value as f32 / 255.0;
+            }
+        }
+        let [r, g, b] = channels;
+        let mut converted = [[0.0f32; 3]; LANES];
+        for (lane, out) in converted.iter_mut().enumerate() {
+            let (r, g, b) = (r[lane], g[lane], b[lane]);
+            let max = r.max(g).max(b);
+            let min = r.min(g).min(b);
+            let lightness = (max + min) / 2.0;
+            let delta = max - min;
+            let saturation = if lightness > 0.5 { delta / (2.0 - max - min) } else { delta / (max + min) };
+            let hue = if r == max {
+                (g - b) / delta + if g < b { 6.0 } else { 0.0 }
+            } else if g == max {
+                (b - r) / delta + 2.0
+            } else {
+                (r - g) / delta + 4.0
+            };
+            // Greys have no hue or saturation, and divided by zero above.
+            let grey = max == min;
+            *out = [if grey { 0.0 } else { hue * 60.0 }, if grey { 0.0 } else { saturation * 100.0 }, lightness * 100.0];
+        }
+        let count = rgb.len().min(hsl.len());
+        hsl[..count].copy_from_slice(&converted[..count]);
+    }
+}
+
+#[inline(always)]
+fn magnitudes_portable(spectrum: &[Complex<f32>], magnitudes: &mut [f32]) {
+    for (Complex { re, im }, magnitude) in spectrum.iter().zip(magnitudes.iter_mut()) {
+        *magnitude = f32::sqrt(re * re + im * im);
+    }
+}
+
+/// The same loops written with NEON intrinsics, four values at a time, leaving any
+/// remainder to the portable loops.
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use rustfft::num_complex::Complex;
+
+    /// Load one channel of four pixels, scaled to 0..1.
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn load_channel(rgb: &[[u8; 3]], channel: usize) -> float32x4_t {
+        let values = [rgb[0][channel] as f32, rgb[1][channel] as f32, rgb[2][channel] as f32, rgb[3][channel] as f32];
+        vdivq_f32(vld1q_f32(values.as_ptr()), vdupq_n_f32(255.0))
+    }
+
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    #[target_feature(enable = "neon")]
+    pub unsafe fn rgb_to_hsl(rgb: &[[u8; 3]], hsl: &mut [[f32; 3]]) {
+        let whole = rgb.len().min(hsl.len()) / 4 * 4;
+        let (zero, half, two, four, six) = (vdupq_n_f32(0.0), vdupq_n_f32(0.5), vdupq_n_f32(2.0), vdupq_n_f32(4.0), vdupq_n_f32(6.0));
+        for start in (0..whole).step_by(4) {
+            let pixels = &rgb[start..start + 4];
+            let (r, g, b) = (load_channel(pixels, 0), load_channel(pixels, 1), load_channel(pixels, 2));
+            let max = vmaxq_f32(vmaxq_f32(r, g), b);
+            let min = vminq_f32(vminq_f32(r, g), b);
+            let lightness = vdivq_f32(vaddq_f32(max, min), two);
+            let delta = vsubq_f32(max, min);
+            let saturation = vbslq_f32(
+                vcgtq_f32(lightness, half),
+                vdivq_f32(delta, vsubq_f32(vsubq_f32(two, max), min)),
+                vdivq_f32(delta, vaddq_f32(max, min)),
+            );
+            let red_hue = vaddq_f32(vdivq_f32(vsubq_f32(g, b), delta), vbslq_f32(vcltq_f32(g, b), six, zero));
+            let green_hue = vaddq_f32(vdivq_f32(vsubq_f32(b, r), delta), two);
+            let blue_hue = vaddq_f32(vdivq_f32(vsubq_f32(r, g), delta), four);
+            let hue = vbslq_f32(vceqq_f32(r, max), red_hue, vbslq_f32(vceqq_f32(g, max), green_hue, blue_hue));
+            let grey = vceqq_f32(max, min);
+
+            let mut channels = [[0.0f32; 4]; 3];
+            vst1q_f32(channels[0].as_mut_ptr(), vbslq_f32(grey, zero, vmulq_f32(hue, vdupq_n_f32(60.0))));
+            vst1q_f32(channels[1].as_mut_ptr(), vbslq_f32(grey, zero, vmulq_f32(saturation, vdupq_n_f32(100.0))));
+            vst1q_f32(channels[2].as_mut_ptr(), vmulq_f32(lightness, vdupq_n_f32(100.0)));
+            for (lane, out) in hsl[start..start + 4].iter_mut().enumerate() {
+                *out = [channels[0][lane], channels[1][lane], channels[2][lane]];
+            }
+        }
+        super::rgb_to_hsl_portable(&rgb[whole..], &mut hsl[whole..]);
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn magnitudes(spectrum: &[Complex<f32>], magnitudes: &mut [f32]) {
+        let whole = spectrum.len().min(magnitudes.len()) / 4 * 4;
+        for start in (0..whole).step_by(4) {
+            // Complex is laid out as re then im, so this splits four of them into their
+            // real and imaginary parts.
+            let values = vld2q_f32(spectrum.as_ptr().add(start) as *const f32);
+            let squared = vaddq_f32(vmulq_f32(values.0, values.0), vmulq_f32(values.1, values.1));
+            vst1q_f32(magnitudes.as_mut_ptr().add(start), vsqrtq_f32(squared));
+        }
+        super::magnitudes_portable(&spectrum[whole..], &mut magnitudes[whole..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use colors_transform::{Color, Hsl, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn test_matches_colors_transform() {
+        let steps: Vec<u8> = (0..=255u8).step_by(15).chain([1, 127, 128, 254]).collect();
+        let mut rgb = Vec::new();
+        for r in &steps {
+            for g in &steps {
+                rgb.extend(steps.iter().map(|b| [*r, *g, *b]));
+            }
+        }
+        let mut fast = vec![[0.0; 3]; rgb.len()];
+        let mut portable = vec![[0.0; 3]; rgb.len()];
+        rgb_to_hsl(&rgb, &mut fast);
+        rgb_to_hsl_portable(&rgb, &mut portable);
+        for ((pixel, fast), portable) in rgb.iter().zip(&fast).zip(&portable) {
+            let expected = Rgb::from(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32).to_hsl();
+            let expected = (expected.get_hue(), expected.get_saturation(), expected.get_lightness());
+            for hsl in [fast, portable] {
+                let hsl = Hsl::from(hsl[0], hsl[1], hsl[2]);
+                assert_eq!((hsl.get_hue(), hsl.get_saturation(), hsl.get_lightness()), expected, "{:?}", pixel);
+            }
+        }
+
+        let spectrum: Vec<Complex<f32>> = (0..11).map(|index| Complex { re: index as f32 * 0.3, im: -(index as f32) }).collect();
+        let expected: Vec<f32> = spectrum.iter().map(|value| value.norm_sqr().sqrt()).collect();
+        assert_eq!(magnitudes(&spectrum), expected);
+    }
+}
@@ -1,3 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// What a [`SlidingWindow`] has learned, so it can pick up where it left off.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WindowState {
+    pub min: f32,
+    pub max: f32,
+    pub recorded: Vec<f32>,
+}
+
 pub struct SlidingWindow {
     recorded_intensites: Vec<f32>,
     min: f32,
@@ -54,5 +64,19 @@ impl SlidingWindow {
            self.max,
         )
     }
-    
+
+    pub fn state(&self) -> WindowState {
+        WindowState {
+            min: self.min,
+            max: self.max,
+            recorded: self.recorded_intensites.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, state: &WindowState) {
+        self.min = state.min;
+        self.max = state.max;
+        self.recorded_intensites = state.recorded.iter().copied().take(self.limit).collect();
+        self.updates = 0;
+    }
 }
\ No newline at end of file
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// A step frames go through, timed for the session summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Finding the colours of each panel's region of a captured frame.
+    ScreenAnalysis,
+    /// Turning an interval of audio into bands.
+    AudioAnalysis,
+    /// Rendering and encoding a frame for the panels.
+    Render,
+    /// From a frame being rendered to it leaving for the controller, including time
+    /// spent waiting on the rate limit.
+    Send,
+}
+
+impl Stage {
+    const ALL: [Stage; 4] = [Stage::ScreenAnalysis, Stage::AudioAnalysis, Stage::Render, Stage::Send];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::ScreenAnalysis => "screen_analysis",
+            Stage::AudioAnalysis => "audio_analysis",
+            Stage::Render => "render",
+            Stage::Send => "send",
+        }
+    }
+}
+
+/// Total time spent in a stage, and how many times it ran.
+struct Timing {
+    total_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Timing {
+    const fn new() -> Self {
+        Timing { total_micros: AtomicU64::new(0), count: AtomicU64::new(0) }
+    }
+
+    fn average(&self) -> Option<Duration> {
+        let count = self.count.load(Ordering::Relaxed);
+        (count > 0).then(|| Duration::from_micros(self.total_micros.load(Ordering::Relaxed) / count))
+    }
+}
+
+/// Counters for this run of the lights, updated from whichever thread does the work
+/// and summed up on exit.
+pub struct SessionStats {
+    started: Mutex<Option<Instant>>,
+    frames_sent: AtomicU64,
+    send_failures: AtomicU64,
+    /// Frames dropped for waiting too long to be sent.
+    frames_dropped: AtomicU64,
+    /// Times the controller stopped answering and came back.
+    controller_reconnects: AtomicU64,
+    /// Times screen capture failed and was set up again.
+    capture_restarts: AtomicU64,
+    timings: [Timing; Stage::ALL.len()],
+}
+
+/**
+ * The stats for this run. There's only ever one session in a process.
+ */
+pub static SESSION: SessionStats = SessionStats::new();
+
+impl SessionStats {
+    const fn new() -> Self {
+        SessionStats {
+            started: Mutex::new(None),
+            frames_sent: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            controller_reconnects: AtomicU64::new(0),
+            capture_restarts: AtomicU64::new(0),
+            timings: [Timing::new(), Timing::new(), Timing::new(), Timing::new()],
+        }
+    }
+
+    /// Start timing the session.
+    pub fn start(&self) {
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn frame_sent(&self) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn send_failed(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn frame_dropped(&self) {
+        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn controller_reconnected(&self) {
+        self.controller_reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg_attr(not(feature = "screen"), allow(dead_code))]
+    pub fn capture_restarted(&self) {
+        self.capture_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a run of `stage` that took `elapsed`.
+    pub fn time(&self, stage: Stage, elapsed: Duration) {
+        let timing = &self.timings[stage as usize];
+        timing.total_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        timing.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sum up the session so far.
+    pub fn summary(&self) -> Summary {
+        let runtime = self.started.lock().unwrap().map(|started| started.elapsed()).unwrap_or_default();
+        Summary {
+            ended: chrono::Local::now().to_rfc3339(),
+            runtime_secs: runtime.as_secs(),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            controller_reconnects: self.controller_reconnects.load(Ordering::Relaxed),
+            capture_restarts: self.capture_restarts.load(Ordering::Relaxed),
+            average_ms: Stage::ALL.iter()
+                .filter_map(|stage| Some((stage.name(), self.timings[*stage as usize].average()?.as_secs_f64() * 1000.0)))
+                .collect(),
+        }
+    }
+}
+
+/// What happened over a session, for anyone looking into the lights misbehaving now
+/// and then.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Summary {
+    /// When the session ended, in RFC 3339 format.
+    pub ended: String,
+    pub runtime_secs: u64,
+    pub frames_sent: u64,
+    pub send_failures: u64,
+    pub frames_dropped: u64,
+    pub controller_reconnects: u64,
+    pub capture_restarts: u64,
+    /// Average time each stage took, in milliseconds. Stages that never ran are left out.
+    pub average_ms: BTreeMap<&'static str, f64>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (hours, minutes, seconds) = (self.runtime_secs / 3600, self.runtime_secs / 60 % 60, self.runtime_secs % 60);
+        writeln!(f, "Ran for {}:{:02}:{:02}", hours, minutes, seconds)?;
+        writeln!(f, "Frames sent: {}, failed: {}, dropped: {}", self.frames_sent, self.send_failures, self.frames_dropped)?;
+        writeln!(f, "Controller reconnects: {}, capture restarts: {}", self.controller_reconnects, self.capture_restarts)?;
+        let averages: Vec<String> = self.average_ms.iter().map(|(stage, ms)| format!("{} {:.2}ms", stage, ms)).collect();
+        write!(f, "Average time per stage: {}", if averages.is_empty() { "none recorded".to_string() } else { averages.join(", ") })
+    }
+}
+
+impl Summary {
+    /// Add the summary to the end of a file, as a line of JSON.
+    pub fn append(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Log a summary of the session, and add it to `stats_file` if one is configured.
+pub fn end_session(stats_file: Option<&Path>) {
+    let summary = SESSION.summary();
+    log::info!("{}", summary);
+    if let Some(path) = stats_file {
+        if let Err(err) = summary.append(path) {
+            log::warn!("Could not add the session to {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let stats = SessionStats::new();
+        stats.start();
+        stats.frame_sent();
+        stats.frame_sent();
+        stats.send_failed();
+        stats.time(Stage::Render, Duration::from_millis(2));
+        stats.time(Stage::Render, Duration::from_millis(4));
+        let summary = stats.summary();
+        assert_eq!((summary.frames_sent, summary.send_failures, summary.frames_dropped), (2, 1, 0));
+        assert_eq!(summary.average_ms, BTreeMap::from([("render", 3.0)]));
+        assert!(summary.to_string().contains("render 3.00ms"));
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::{HashMap, VecDeque};
+
+/**
+ * Most samples a stream can get ahead of the others before the mix goes out without
+ * them, about 50ms at 44.1kHz. A paused player stops sending audio, and shouldn't hold
+ * up the ones still playing.
+ */
+const MAX_BACKLOG: usize = 2048;
+
+/// Mixes the audio of several streams into one, sample for sample, so two players
+/// playing at once are heard together rather than one after the other.
+#[derive(Default)]
+pub struct StreamMixer {
+    pending: HashMap<u32, VecDeque<f32>>,
+}
+
+impl StreamMixer {
+    /// Forget a stream that's gone, so the others aren't held up waiting for it.
+    pub fn remove(&mut self, stream: u32) {
+        self.pending.remove(&stream);
+    }
+
+    /// Queue samples from `stream`, and put into `mixed` whatever every stream has
+    /// now sent, summed together. Leaves `mixed` empty while waiting for the others.
+    pub fn submit(&mut self, stream: u32, samples: &[f32], mixed: &mut Vec<f32>) {
+        mixed.clear();
+        self.pending.entry(stream).or_default().extend(samples);
+        let longest = self.pending.values().map(VecDeque::len).max().unwrap_or(0);
+        let ready = if longest > MAX_BACKLOG {
+            // Streams that have fallen silent are left out until they send again.
+            self.pending.retain(|_, queue| !queue.is_empty());
+            longest
+        } else {
+            self.pending.values().map(VecDeque::len).min().unwrap_or(0)
+        };
+        mixed.resize(ready, 0.0);
+        for queue in self.pending.values_mut() {
+            let take = ready.min(queue.len());
+            for (mixed, sample) in mixed.iter_mut().zip(queue.drain(..take)) {
+                *mixed += sample;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mixes_streams() {
+        let mut mixer = StreamMixer::default();
+        let mut mixed = Vec::new();
+        mixer.submit(1, &[0.1; 4], &mut mixed);
+        assert_eq!(mixed, [0.1; 4]);
+
+        // Once a second stream plays, the two are summed rather than queued one after
+        // the other.
+        mixer.submit(2, &[0.2; 4], &mut mixed);
+        assert!(mixed.is_empty());
+        mixer.submit(1, &[0.1; 4], &mut mixed);
+        assert!(mixed.iter().all(|sample| (sample - 0.3).abs() < 1e-6) && mixed.len() == 4);
+
+        // A stream that stops sending only holds the others up for so long.
+        let mut total = 0;
+        for _ in 0..(MAX_BACKLOG / 4 + 1) {
+            mixer.submit(1, &[0.1; 4], &mut mixed);
+            total += mixed.len();
+        }
+        assert_eq!(total, MAX_BACKLOG + 4);
+        mixer.submit(1, &[0.1; 4], &mut mixed);
+        assert_eq!(mixed, [0.1; 4]);
+    }
+}
@@ -0,0 +1,149 @@
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc::{channel, Receiver};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crash;
+use crate::log_throttle::warn_throttled;
+use crate::report::{FrameReport, Reporter};
+
+/**
+ * Multicast group used when `sync_group` isn't configured.
+ */
+pub const DEFAULT_SYNC_GROUP: &str = "239.255.76.80:46100";
+
+/**
+ * Multicast TTL for sync frames. One hop keeps them on the local network.
+ */
+const SYNC_TTL: u32 = 1;
+
+/**
+ * If a frame's sequence number is this far behind the last one seen, assume the
+ * leader restarted rather than the frame arriving late.
+ */
+const SEQUENCE_RESET_GAP: u32 = 100;
+
+/// How this instance takes part in keeping lights in several rooms in sync.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// Run on our own.
+    #[default]
+    Off,
+    /// Analyse audio and video, and broadcast every frame to followers.
+    Leader,
+    /// Don't analyse anything, just replay the leader's frames on our own panels.
+    Follower,
+}
+
+/// A frame of colours, ordered left to right across the leader's panels.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncFrame {
+    pub sequence: u32,
+    pub colors: Vec<Option<[u8; 3]>>,
+}
+
+impl SyncFrame {
+    /// Stretch the leader's colours over `panel_count` panels, so layouts with a
+    /// different number of panels still sweep across the same colours.
+    pub fn resample(&self, panel_count: usize) -> Vec<Option<[u8; 3]>> {
+        if self.colors.is_empty() {
+            return vec![None; panel_count];
+        }
+        (0..panel_count).map(|index| self.colors[index * self.colors.len() / panel_count]).collect()
+    }
+}
+
+/// Broadcasts every frame to the multicast group.
+pub struct SyncLeader {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    sequence: u32,
+}
+
+impl SyncLeader {
+    pub fn new(group: &str) -> Result<Self, Box<dyn Error>> {
+        let group: SocketAddrV4 = group.parse()?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_multicast_ttl_v4(SYNC_TTL)?;
+        log::info!("Leading followers on {}", group);
+        Ok(SyncLeader { socket, group, sequence: 0 })
+    }
+}
+
+impl Reporter for SyncLeader {
+    fn report(&mut self, report: &FrameReport) {
+        self.sequence = self.sequence.wrapping_add(1);
+        let frame = SyncFrame {
+            sequence: self.sequence,
+            colors: report.panels.iter().map(|panel| panel.color).collect(),
+        };
+        match serde_json::to_vec(&frame) {
+            Ok(packet) => {
+                if let Err(err) = self.socket.send_to(&packet, self.group) {
+                    log::debug!("Failed to send sync frame: {}", err);
+                }
+            },
+            Err(err) => warn_throttled!("Failed to serialize sync frame: {}", err),
+        }
+    }
+}
+
+/// Join the multicast group and receive the leader's frames on a new thread,
+/// dropping any that arrive out of order.
+pub fn follow(group: &str) -> Result<Receiver<SyncFrame>, Box<dyn Error>> {
+    let group: SocketAddrV4 = group.parse()?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+    socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    log::info!("Following the leader on {}", group);
+
+    let (tx, rx) = channel();
+    crash::spawn("sync follower", move || {
+        let mut packet = vec![0u8; 65536];
+        let mut last_sequence: Option<u32> = None;
+        loop {
+            let size = match socket.recv(&mut packet) {
+                Ok(size) => size,
+                Err(err) => {
+                    warn_throttled!("Failed to receive sync frame: {}", err);
+                    continue;
+                }
+            };
+            let frame: SyncFrame = match serde_json::from_slice(&packet[..size]) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    log::debug!("Ignoring invalid sync frame: {}", err);
+                    continue;
+                }
+            };
+            if let Some(last) = last_sequence {
+                // Anything a little behind the last frame arrived late, anything further
+                // behind means the leader started counting again.
+                if last.wrapping_sub(frame.sequence) < SEQUENCE_RESET_GAP {
+                    continue;
+                }
+            }
+            last_sequence = Some(frame.sequence);
+            if tx.send(frame).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resample() {
+        let frame = SyncFrame {
+            sequence: 1,
+            colors: vec![Some([255, 0, 0]), None, Some([0, 0, 255])],
+        };
+        assert_eq!(frame.resample(6), vec![Some([255, 0, 0]), Some([255, 0, 0]), None, None, Some([0, 0, 255]), Some([0, 0, 255])]);
+        assert_eq!(frame.resample(1), vec![Some([255, 0, 0])]);
+    }
+}
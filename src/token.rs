@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use config::Config;
+
+/**
+ * Name of the file in the state directory the pairing token is kept in, unless
+ * `nanoleaf_token_file` says otherwise.
+ */
+const TOKEN_FILE: &str = "nanoleaf_token";
+
+/// Where the pairing token is kept: `nanoleaf_token_file`, or the state directory.
+pub fn path(config: &Config) -> Result<PathBuf, Box<dyn Error>> {
+    match config.get_string("nanoleaf_token_file") {
+        Ok(path) => Ok(PathBuf::from(path)),
+        Err(_) => Ok(xdg::BaseDirectories::with_prefix("leafpipe")?.place_state_file(TOKEN_FILE)?),
+    }
+}
+
+/// The pairing token, from `nanoleaf_token` if it's set in the config or `LP_NANOLEAF_TOKEN`,
+/// or else from the token file.
+pub fn load(config: &Config) -> Result<String, Box<dyn Error>> {
+    if let Ok(token) = config.get_string("nanoleaf_token") {
+        return Ok(token);
+    }
+    let path = path(config)?;
+    read(&path).map_err(|err| format!("No nanoleaf_token configured and could not read {} ({}), run `leafpipe pair` to get one", path.display(), err).into())
+}
+
+fn read(path: &Path) -> Result<String, Box<dyn Error>> {
+    let token = fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err("the file is empty".into());
+    }
+    if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+        log::warn!("{} can be read by other users, run `chmod 600` on it", path.display());
+    }
+    Ok(token)
+}
+
+/// Keep the pairing token in a file only we can read.
+pub fn save(path: &Path, token: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // The mode only applies to new files, so tighten up one left from before.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(token.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_file() {
+        let path = std::env::temp_dir().join(format!("leafpipe-token-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(read(&path).is_err());
+
+        save(&path, "secret").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let config = Config::builder().set_override("nanoleaf_token_file", path.to_str().unwrap()).unwrap().build().unwrap();
+        assert_eq!(load(&config).unwrap(), "secret");
+
+        // A token in the config wins over the file.
+        let config = Config::builder().set_override("nanoleaf_token", "inline").unwrap().build().unwrap();
+        assert_eq!(load(&config).unwrap(), "inline");
+        fs::remove_file(&path).unwrap();
+    }
+}
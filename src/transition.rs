@@ -0,0 +1,160 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use colors_transform::Hsl;
+use serde::Deserialize;
+
+use crate::oklab::ColorSpace;
+
+fn default_fade_secs() -> f32 {
+    1.5
+}
+
+/**
+ * Longest to wait for the lights to fade out when stopping, in case the render loop
+ * has stopped responding.
+ */
+const FADE_OUT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TransitionConfig {
+    /// Seconds to fade in from off to the first frames.
+    #[serde(default = "default_fade_secs")]
+    pub fade_in_secs: f32,
+    /// Seconds to fade out to off when stopping.
+    #[serde(default = "default_fade_secs")]
+    pub fade_out_secs: f32,
+}
+
+impl Default for TransitionConfig {
+    fn default() -> Self {
+        TransitionConfig {
+            fade_in_secs: default_fade_secs(),
+            fade_out_secs: default_fade_secs(),
+        }
+    }
+}
+
+/// Fades the lights in from off when they start, and out to off when asked to stop
+/// through the [`FadeOut`] handle, rather than snapping on and off.
+pub struct Transition {
+    fade_in: f32,
+    fade_out: f32,
+    color_space: ColorSpace,
+    /// When the first frame was shown.
+    started: Option<Instant>,
+    /// When stopping began, and who to tell once the lights are off.
+    stopping: Option<(Instant, Sender<()>)>,
+    requests: Receiver<Sender<()>>,
+}
+
+/// Asks a [`Transition`] to fade the lights out.
+pub struct FadeOut {
+    requests: Sender<Sender<()>>,
+}
+
+impl FadeOut {
+    /// Fade the lights out, returning once they're off.
+    pub async fn run(self) {
+        let (done_tx, done_rx) = channel();
+        if self.requests.send(done_tx).is_err() {
+            return;
+        }
+        let _ = tokio::task::spawn_blocking(move || done_rx.recv_timeout(FADE_OUT_TIMEOUT)).await;
+    }
+}
+
+impl Transition {
+    pub fn new(config: &TransitionConfig, color_space: ColorSpace) -> (Self, FadeOut) {
+        let (requests_tx, requests) = channel();
+        let transition = Transition {
+            fade_in: config.fade_in_secs.max(0.0),
+            fade_out: config.fade_out_secs.max(0.0),
+            color_space,
+            started: None,
+            stopping: None,
+            requests,
+        };
+        (transition, FadeOut { requests: requests_tx })
+    }
+
+    /// Whether the lights are fading out.
+    pub fn stopping(&mut self, now: Instant) -> bool {
+        if self.stopping.is_none() {
+            if let Ok(done) = self.requests.try_recv() {
+                log::info!("Fading out");
+                self.stopping = Some((now, done));
+            }
+        }
+        self.stopping.is_some()
+    }
+
+    /// How bright the lights should be at `now`, from 0 for off to 1.
+    fn level(&mut self, now: Instant) -> f32 {
+        let progress = |since: Instant, duration: f32| {
+            if duration <= 0.0 {
+                1.0
+            } else {
+                (now.saturating_duration_since(since).as_secs_f32() / duration).min(1.0)
+            }
+        };
+        let started = *self.started.get_or_insert(now);
+        let fade_in = progress(started, self.fade_in);
+        let fade_out = self.stopping.as_ref().map_or(0.0, |(since, _)| progress(*since, self.fade_out));
+        fade_in.min(1.0 - fade_out)
+    }
+
+    /// Dim a frame about to be shown at `now` to match the fades.
+    pub fn apply(&mut self, colors: &mut [Option<Hsl>], now: Instant) {
+        let level = self.level(now);
+        if level >= 1.0 {
+            return;
+        }
+        for hsl in colors.iter_mut().flatten() {
+            *hsl = self.color_space.scale(hsl, level);
+        }
+    }
+
+    /// Whether the lights have finished fading out.
+    pub fn finished(&self, now: Instant) -> bool {
+        self.stopping.as_ref().is_some_and(|(since, _)| now.saturating_duration_since(*since).as_secs_f32() >= self.fade_out)
+    }
+
+    /// Let whoever asked for the fade out know the lights are off.
+    pub fn done(&mut self) {
+        if let Some((_, done)) = self.stopping.take() {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use colors_transform::Color;
+
+    use super::*;
+
+    #[test]
+    fn test_fades() {
+        let (mut transition, fade_out) = Transition::new(&TransitionConfig { fade_in_secs: 2.0, fade_out_secs: 1.0 }, ColorSpace::Hsl);
+        let start = Instant::now();
+        let lightness = |transition: &mut Transition, at: Duration| {
+            let mut colors = vec![Some(Hsl::from(0.0, 100.0, 50.0))];
+            transition.apply(&mut colors, start + at);
+            colors[0].unwrap().get_lightness()
+        };
+        assert_eq!(lightness(&mut transition, Duration::ZERO), 0.0);
+        assert_eq!(lightness(&mut transition, Duration::from_secs(1)), 25.0);
+        assert_eq!(lightness(&mut transition, Duration::from_secs(5)), 50.0);
+
+        let (done_tx, done_rx) = channel();
+        fade_out.requests.send(done_tx).unwrap();
+        assert!(transition.stopping(start + Duration::from_secs(10)));
+        assert_eq!(lightness(&mut transition, Duration::from_millis(10500)), 25.0);
+        assert!(!transition.finished(start + Duration::from_millis(10500)));
+        assert_eq!(lightness(&mut transition, Duration::from_secs(11)), 0.0);
+        assert!(transition.finished(start + Duration::from_secs(11)));
+        transition.done();
+        assert!(done_rx.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+use ksni::menu::{CheckmarkItem, MenuItem, RadioGroup, RadioItem, SubMenu};
+
+use crate::control::{ControlCommand, ControlState};
+use crate::crash;
+use crate::tuning::TuningChange;
+
+/**
+ * Intensities offered in the tray menu, from gentle to wild.
+ */
+const INTENSITY_PRESETS: [f32; 5] = [5.0, 10.0, 15.0, 25.0, 40.0];
+
+/// A StatusNotifierItem tray icon, for pausing the lights, picking a profile and
+/// setting the intensity without a terminal. Everything is sent on as a
+/// [`ControlCommand`], as if from the control socket, and the menu shows the state the
+/// lights are actually in, however it was changed.
+struct LeafpipeTray {
+    commands: Sender<ControlCommand>,
+    /// Names of the profiles in the config, in the order shown.
+    profiles: Vec<String>,
+    state: Arc<Mutex<ControlState>>,
+}
+
+impl LeafpipeTray {
+    fn send(&self, command: ControlCommand) {
+        if self.commands.send(command).is_err() {
+            log::warn!("The lights have stopped, ignoring tray menu");
+        }
+    }
+
+    fn paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+}
+
+impl ksni::Tray for LeafpipeTray {
+    fn id(&self) -> String {
+        "leafpipe".into()
+    }
+
+    fn title(&self) -> String {
+        "leafpipe".into()
+    }
+
+    fn icon_name(&self) -> String {
+        if self.paused() { "media-playback-pause" } else { "applications-multimedia" }.into()
+    }
+
+    fn status(&self) -> ksni::Status {
+        if self.paused() { ksni::Status::Passive } else { ksni::Status::Active }
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        let state = self.state.lock().unwrap().clone();
+        // Nothing is picked while the scene or tuning has been changed some other way.
+        let selected_profile = state.profile.as_ref()
+            .and_then(|profile| self.profiles.iter().position(|name| name == profile))
+            .unwrap_or(usize::MAX);
+        let selected_intensity = INTENSITY_PRESETS.iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| (*a - state.tuning.intensity).abs().total_cmp(&(*b - state.tuning.intensity).abs()))
+            .map_or(0, |(index, _)| index);
+        vec![
+            CheckmarkItem {
+                label: "Paused".into(),
+                checked: state.paused,
+                activate: Box::new(|tray: &mut Self| {
+                    tray.send(if tray.paused() { ControlCommand::Resume } else { ControlCommand::Pause });
+                }),
+                ..Default::default()
+            }.into(),
+            MenuItem::Separator,
+            SubMenu {
+                label: "Profile".into(),
+                submenu: vec![RadioGroup {
+                    selected: selected_profile,
+                    select: Box::new(|tray: &mut Self, index| {
+                        tray.send(ControlCommand::Profile { name: tray.profiles[index].clone() });
+                    }),
+                    options: self.profiles.iter()
+                        .map(|label| RadioItem { label: label.clone(), ..Default::default() })
+                        .collect(),
+                }.into()],
+                enabled: !self.profiles.is_empty(),
+                ..Default::default()
+            }.into(),
+            SubMenu {
+                label: "Intensity".into(),
+                submenu: vec![RadioGroup {
+                    selected: selected_intensity,
+                    select: Box::new(|tray: &mut Self, index| {
+                        tray.send(ControlCommand::Tune(TuningChange { intensity: Some(INTENSITY_PRESETS[index]), ..TuningChange::default() }));
+                    }),
+                    options: INTENSITY_PRESETS.iter()
+                        .map(|intensity| RadioItem { label: intensity.to_string(), ..Default::default() })
+                        .collect(),
+                }.into()],
+                ..Default::default()
+            }.into(),
+        ]
+    }
+}
+
+/// Show the tray icon on a thread of its own, sending whatever is picked from its menu
+/// to `commands`. Returns where to say the state has changed, so the menu can follow.
+pub fn start(mut profiles: Vec<String>, state: Arc<Mutex<ControlState>>, commands: Sender<ControlCommand>) -> Sender<()> {
+    profiles.sort();
+    let service = ksni::TrayService::new(LeafpipeTray {
+        commands,
+        profiles,
+        state,
+    });
+    let handle = service.handle();
+    service.spawn();
+    let (changed, changes) = channel();
+    crash::spawn("tray", move || {
+        for () in changes {
+            handle.update(|_| {});
+        }
+    });
+    changed
+}
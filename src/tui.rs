@@ -0,0 +1,255 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, Stdout};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::ExecutableCommand;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Bar, BarChart, BarGroup, Block, Borders, Paragraph};
+use ratatui::{Frame, Terminal};
+use tokio::sync::Notify;
+
+use crate::control::ControlCommand;
+use crate::crash;
+use crate::report::{AudioStats, FrameReport, Reporter};
+use crate::tuning::Tuning;
+
+/**
+ * How long to wait for a key press before drawing the latest frame.
+ */
+const REDRAW_INTERVAL: Duration = Duration::from_millis(50);
+
+/**
+ * Closest the floor and ceiling frequencies may get to each other, in Hz.
+ */
+const MIN_FREQ_RANGE: f32 = 100.0;
+
+/// One of the settings in [`Tuning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TuningParam {
+    Intensity,
+    Smoothing,
+    FloorFreq,
+    CeilingFreq,
+}
+
+impl TuningParam {
+    pub const ALL: [TuningParam; 4] = [TuningParam::Intensity, TuningParam::Smoothing, TuningParam::FloorFreq, TuningParam::CeilingFreq];
+
+    /// The name of the setting in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TuningParam::Intensity => "intensity",
+            TuningParam::Smoothing => "smoothing",
+            TuningParam::FloorFreq => "floor_freq",
+            TuningParam::CeilingFreq => "ceiling_freq",
+        }
+    }
+}
+
+impl Tuning {
+    pub fn get(&self, param: TuningParam) -> f32 {
+        match param {
+            TuningParam::Intensity => self.intensity,
+            TuningParam::Smoothing => self.smoothing,
+            TuningParam::FloorFreq => self.floor_freq,
+            TuningParam::CeilingFreq => self.ceiling_freq,
+        }
+    }
+
+    /// Move a setting a step up or down, keeping it within sensible limits.
+    pub fn nudge(&mut self, param: TuningParam, up: bool) {
+        let direction = if up { 1.0 } else { -1.0 };
+        match param {
+            TuningParam::Intensity => self.intensity = (self.intensity + direction).clamp(0.0, 100.0),
+            TuningParam::Smoothing => self.smoothing = ((self.smoothing + direction * 0.05) * 100.0).round().clamp(0.0, 95.0) / 100.0,
+            TuningParam::FloorFreq => self.floor_freq = (self.floor_freq + direction * 10.0).clamp(20.0, self.ceiling_freq - MIN_FREQ_RANGE),
+            TuningParam::CeilingFreq => self.ceiling_freq = (self.ceiling_freq + direction * 500.0).clamp(self.floor_freq + MIN_FREQ_RANGE, 20000.0),
+        }
+    }
+}
+
+/// Hands frames to the TUI to draw, dropping them if it falls behind.
+pub struct TuiReporter {
+    frames: SyncSender<FrameReport>,
+}
+
+impl Reporter for TuiReporter {
+    fn report(&mut self, report: &FrameReport) {
+        let _ = self.frames.try_send(report.clone());
+    }
+}
+
+/// Puts the terminal back how it was, even if the TUI thread panics.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = io::stdout().execute(LeaveAlternateScreen);
+    }
+}
+
+struct TuiState {
+    tuning: Tuning,
+    selected: usize,
+    bands: Vec<f32>,
+    audio: AudioStats,
+    /// Shown at the bottom, such as whether saving worked.
+    status: String,
+}
+
+/// Write the tuning to the `[tuning]` table of the config, keeping everything else in
+/// the file as it was.
+fn save(tuning: &Tuning, config_path: &Path) -> Result<(), Box<dyn Error>> {
+    let text = match fs::read_to_string(config_path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut document: toml_edit::Document = text.parse()?;
+    let table = document.entry("tuning").or_insert(toml_edit::table()).as_table_mut().ok_or("tuning in the config isn't a table")?;
+    for param in TuningParam::ALL {
+        // Go through the shortest decimal form, so 0.1 isn't written as 0.10000000149.
+        let value: f64 = tuning.get(param).to_string().parse()?;
+        table[param.name()] = toml_edit::value(value);
+    }
+    fs::write(config_path, document.to_string())?;
+    Ok(())
+}
+
+fn draw(frame: &mut Frame, state: &TuiState) {
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(6), Constraint::Length(TuningParam::ALL.len() as u16 + 4)])
+        .split(frame.size());
+
+    let spectrum = areas[0];
+    let band_count = state.bands.len().max(1) as u16;
+    let bar_width = (spectrum.width.saturating_sub(2) / band_count).saturating_sub(1).max(1);
+    let bars: Vec<Bar> = state.bands.iter().map(|band| Bar::default().value((band.max(0.0) * 100.0) as u64).text_value(String::new())).collect();
+    frame.render_widget(
+        BarChart::default()
+            .block(Block::default().title(format!(
+                "Spectrum: {:.0}ms behind, {} buffers dropped, {} stale, {} short reads",
+                state.audio.latency_ms, state.audio.dropped, state.audio.stale, state.audio.underruns,
+            )).borders(Borders::ALL))
+            .data(BarGroup::default().bars(&bars))
+            .bar_width(bar_width)
+            .bar_gap(1),
+        spectrum,
+    );
+
+    let mut lines: Vec<Line> = TuningParam::ALL.iter().enumerate().map(|(index, param)| {
+        let text = format!("{:>14}  {}", param.name(), state.tuning.get(*param));
+        if index == state.selected {
+            Line::from(Span::styled(text, Style::default().add_modifier(Modifier::REVERSED)))
+        } else {
+            Line::from(text)
+        }
+    }).collect();
+    lines.push(Line::from(""));
+    lines.push(Line::from(state.status.as_str()));
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::default().title("Tuning: ↑↓ select, ←→ adjust, s save, q save and quit, Ctrl-C quit").borders(Borders::ALL)),
+        areas[1],
+    );
+}
+
+fn run(mut state: TuiState, config_path: PathBuf, frames: Receiver<FrameReport>, commands: Sender<ControlCommand>) -> Result<(), Box<dyn Error>> {
+    enable_raw_mode()?;
+    let _guard = TerminalGuard;
+    io::stdout().execute(EnterAlternateScreen)?;
+    let mut terminal: Terminal<CrosstermBackend<Stdout>> = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    loop {
+        if let Some(frame) = frames.try_iter().last() {
+            state.bands = frame.bands;
+            state.audio = frame.audio;
+        }
+        terminal.draw(|frame| draw(frame, &state))?;
+        if !event::poll(REDRAW_INTERVAL)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let param = TuningParam::ALL[state.selected];
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            KeyCode::Up => state.selected = state.selected.checked_sub(1).unwrap_or(TuningParam::ALL.len() - 1),
+            KeyCode::Down => state.selected = (state.selected + 1) % TuningParam::ALL.len(),
+            KeyCode::Left | KeyCode::Right | KeyCode::Char('-') | KeyCode::Char('+') => {
+                state.tuning.nudge(param, matches!(key.code, KeyCode::Right | KeyCode::Char('+')));
+                if commands.send(ControlCommand::Tune(state.tuning.into())).is_err() {
+                    return Ok(());
+                }
+            },
+            KeyCode::Char('s') | KeyCode::Char('q') => {
+                state.status = match save(&state.tuning, &config_path) {
+                    Ok(()) => format!("Saved to {}", config_path.display()),
+                    Err(err) => format!("Could not save to {}: {}", config_path.display(), err),
+                };
+                if key.code == KeyCode::Char('q') {
+                    log::info!("{}", state.status);
+                    return Ok(());
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Take over the terminal to show the live spectrum, with keys to adjust the `tuning`
+/// and write it back to the config at `config_path`. Quitting the TUI notifies `quit`,
+/// so leafpipe stops as it does for Ctrl+C.
+pub fn start(tuning: Tuning, config_path: PathBuf, commands: Sender<ControlCommand>, quit: Arc<Notify>) -> TuiReporter {
+    let (frames_tx, frames_rx) = sync_channel(4);
+    let state = TuiState {
+        tuning,
+        selected: 0,
+        bands: Vec::new(),
+        audio: AudioStats::default(),
+        status: String::new(),
+    };
+    crash::spawn("tui", move || {
+        if let Err(err) = run(state, config_path, frames_rx, commands) {
+            log::error!("TUI failed: {}", err);
+        }
+        quit.notify_one();
+    });
+    TuiReporter { frames: frames_tx }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nudge() {
+        let mut tuning = Tuning::default();
+        tuning.nudge(TuningParam::Intensity, true);
+        assert_eq!(tuning.intensity, 16.0);
+        tuning.nudge(TuningParam::Smoothing, false);
+        assert_eq!(tuning.smoothing, 0.0);
+        (0..30).for_each(|_| tuning.nudge(TuningParam::Smoothing, true));
+        assert_eq!(tuning.smoothing, 0.95);
+
+        // The floor can't pass the ceiling.
+        tuning.ceiling_freq = 300.0;
+        (0..30).for_each(|_| tuning.nudge(TuningParam::FloorFreq, true));
+        assert_eq!(tuning.floor_freq, 200.0);
+        tuning.nudge(TuningParam::CeilingFreq, false);
+        assert_eq!(tuning.ceiling_freq, 300.0);
+    }
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+fn default_intensity() -> f32 {
+    15.0
+}
+
+fn default_floor_freq() -> f32 {
+    100.0
+}
+
+fn default_ceiling_freq() -> f32 {
+    15000.0
+}
+
+/// Settings for how audio turns into brightness, which can be changed while running
+/// from the TUI or the control socket.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "remote", derive(utoipa::ToSchema))]
+pub struct Tuning {
+    /// How strongly the audio drives the brightness of the panels.
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    /// How much of the previous frame to keep in each band, from 0 for none up to 0.95,
+    /// which calms down twitchy music.
+    #[serde(default)]
+    pub smoothing: f32,
+    /// Lowest frequency shown across the panels, in Hz.
+    #[serde(default = "default_floor_freq")]
+    pub floor_freq: f32,
+    /// Highest frequency shown across the panels, in Hz.
+    #[serde(default = "default_ceiling_freq")]
+    pub ceiling_freq: f32,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            intensity: default_intensity(),
+            smoothing: 0.0,
+            floor_freq: default_floor_freq(),
+            ceiling_freq: default_ceiling_freq(),
+        }
+    }
+}
+
+/// A change to some of the [`Tuning`] settings, leaving the others as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "remote", derive(utoipa::ToSchema))]
+pub struct TuningChange {
+    #[serde(default)]
+    pub intensity: Option<f32>,
+    #[serde(default)]
+    pub smoothing: Option<f32>,
+    #[serde(default)]
+    pub floor_freq: Option<f32>,
+    #[serde(default)]
+    pub ceiling_freq: Option<f32>,
+}
+
+impl From<Tuning> for TuningChange {
+    /// A change to every setting.
+    fn from(tuning: Tuning) -> Self {
+        TuningChange {
+            intensity: Some(tuning.intensity),
+            smoothing: Some(tuning.smoothing),
+            floor_freq: Some(tuning.floor_freq),
+            ceiling_freq: Some(tuning.ceiling_freq),
+        }
+    }
+}
+
+impl Tuning {
+    /// The tuning with the settings in `change` replaced.
+    pub fn with(self, change: TuningChange) -> Self {
+        Tuning {
+            intensity: change.intensity.unwrap_or(self.intensity),
+            smoothing: change.smoothing.unwrap_or(self.smoothing),
+            floor_freq: change.floor_freq.unwrap_or(self.floor_freq),
+            ceiling_freq: change.ceiling_freq.unwrap_or(self.ceiling_freq),
+        }
+    }
+}
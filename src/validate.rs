@@ -0,0 +1,295 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+use crate::effects::EffectKind;
+
+/// What a setting may be set to.
+enum Accepts {
+    /// A number from the first to the second, inclusive.
+    Number(f64, f64),
+    Bool,
+    /// One of these names.
+    OneOf(&'static [&'static str]),
+    /// A list of `[from, to]` pairs, each a number in the range.
+    Pairs(f64, f64),
+    /// The name of an effect.
+    Effect,
+}
+
+/// A setting an effect understands, and what it may be set to.
+struct Param {
+    name: &'static str,
+    accepts: Accepts,
+}
+
+const fn number(name: &'static str, min: f64, max: f64) -> Param {
+    Param { name, accepts: Accepts::Number(min, max) }
+}
+
+const TUNING: &[Param] = &[
+    number("intensity", 0.0, 100.0),
+    number("smoothing", 0.0, 0.95),
+    number("floor_freq", 20.0, 20000.0),
+    number("ceiling_freq", 20.0, 20000.0),
+];
+const PEAK_HOLD: &[Param] = &[
+    Param { name: "enabled", accepts: Accepts::Bool },
+    number("hold", 0.0, 60.0),
+    number("half_life", 0.0, 60.0),
+];
+const HUE_RANGE: &[Param] = &[
+    Param { name: "preset", accepts: Accepts::OneOf(&["protanopia", "deuteranopia", "tritanopia"]) },
+    number("min", 0.0, 360.0),
+    number("max", 0.0, 360.0),
+    Param { name: "avoid", accepts: Accepts::Pairs(0.0, 360.0) },
+    Param { name: "mode", accepts: Accepts::OneOf(&["remap", "clamp"]) },
+];
+const HUE_ROTATION: &[Param] = &[
+    number("degrees_per_minute", -3600.0, 3600.0),
+    Param { name: "only_when_stale", accepts: Accepts::Bool },
+    number("stale_secs", 0.0, 86400.0),
+];
+const TRANSITIONS: &[Param] = &[
+    number("fade_in_secs", 0.0, 60.0),
+    number("fade_out_secs", 0.0, 60.0),
+];
+const LFO: &[Param] = &[
+    Param { name: "target", accepts: Accepts::OneOf(&["hue", "brightness", "sweep"]) },
+    Param { name: "shape", accepts: Accepts::OneOf(&["sine", "triangle", "saw"]) },
+    number("period_secs", 0.1, 86400.0),
+    number("depth", 0.0, 360.0),
+    number("phase", 0.0, 1.0),
+];
+const LAYER: &[Param] = &[
+    Param { name: "effect", accepts: Accepts::Effect },
+    Param { name: "blend", accepts: Accepts::OneOf(&["alpha", "add", "multiply", "overlay"]) },
+    number("opacity", 0.0, 1.0),
+    Param { name: "follow", accepts: Accepts::OneOf(&["bass", "beat", "loudness"]) },
+    number("min_opacity", 0.0, 1.0),
+    number("threshold", 0.0, 1.0),
+];
+
+/**
+ * The tables of effect settings that are checked, and the parameters each may contain.
+ */
+const SECTIONS: &[(&str, &[Param])] = &[
+    ("tuning", TUNING),
+    ("peak_hold", PEAK_HOLD),
+    ("hue_range", HUE_RANGE),
+    ("hue_rotation", HUE_ROTATION),
+    ("transitions", TRANSITIONS),
+];
+
+/// A setting that can't be used, and where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invalid {
+    /// Where the setting is, such as `lfo.spectrum[0].depth`.
+    pub path: String,
+    pub problem: String,
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.problem)
+    }
+}
+
+fn effect_names() -> Vec<String> {
+    EffectKind::value_variants().iter().filter_map(|kind| Some(kind.to_possible_value()?.get_name().to_string())).collect()
+}
+
+/// A setting as a number. Settings from the environment arrive as text.
+fn as_number(value: &Value) -> Option<f64> {
+    value.as_f64().or_else(|| value.as_str()?.parse().ok())
+}
+
+/// Why `value` isn't something `accepts` allows, if it isn't.
+fn check(value: &Value, accepts: &Accepts) -> Option<String> {
+    match accepts {
+        Accepts::Number(min, max) => match as_number(value) {
+            Some(number) if number < *min || number > *max => Some(format!("{} is out of range, expected {} to {}", number, min, max)),
+            Some(_) => None,
+            None => Some(format!("expected a number from {} to {}, got {}", min, max, value)),
+        },
+        Accepts::Bool => match value {
+            Value::Bool(_) => None,
+            Value::String(text) if text == "true" || text == "false" => None,
+            _ => Some(format!("expected true or false, got {}", value)),
+        },
+        Accepts::OneOf(names) => match value.as_str() {
+            Some(name) if names.contains(&name) => None,
+            _ => Some(format!("expected one of {}, got {}", names.join(", "), value)),
+        },
+        Accepts::Pairs(min, max) => {
+            let Some(pairs) = value.as_array() else {
+                return Some(format!("expected a list of [from, to] pairs, got {}", value));
+            };
+            pairs.iter().enumerate().find_map(|(index, pair)| {
+                let valid = pair.as_array().is_some_and(|pair| pair.len() == 2 && pair.iter().all(|value| as_number(value).is_some_and(|number| (*min..=*max).contains(&number))));
+                (!valid).then(|| format!("[{}] is {}, expected [from, to] with each from {} to {}", index, pair, min, max))
+            })
+        },
+        Accepts::Effect => match value.as_str() {
+            Some(name) if EffectKind::from_str(name, true).is_ok() => None,
+            _ => Some(format!("expected one of {}, got {}", effect_names().join(", "), value)),
+        },
+    }
+}
+
+/// Check every setting in a table against the parameters it may contain.
+fn check_table(path: &str, table: &Value, params: &[Param], invalid: &mut Vec<Invalid>) {
+    let Some(table) = table.as_object() else {
+        invalid.push(Invalid { path: path.to_string(), problem: format!("expected a table, got {}", table) });
+        return;
+    };
+    for (key, value) in table {
+        let path = format!("{}.{}", path, key);
+        let problem = match params.iter().find(|param| param.name == key) {
+            Some(param) => check(value, &param.accepts),
+            None => {
+                let names: Vec<&str> = params.iter().map(|param| param.name).collect();
+                Some(format!("unknown setting, expected one of {}", names.join(", ")))
+            },
+        };
+        if let Some(problem) = problem {
+            invalid.push(Invalid { path, problem });
+        }
+    }
+}
+
+/// Find effect settings that are misspelt or out of range, which would otherwise be
+/// ignored or only cause trouble once the lights are running.
+pub fn effect_settings(settings: &Map<String, Value>) -> Vec<Invalid> {
+    let mut invalid = Vec::new();
+    for (name, params) in SECTIONS {
+        if let Some(table) = settings.get(*name) {
+            check_table(name, table, params, &mut invalid);
+        }
+    }
+    if let Some(tuning) = settings.get("tuning") {
+        let (floor, ceiling) = (tuning.get("floor_freq").and_then(as_number), tuning.get("ceiling_freq").and_then(as_number));
+        if let (Some(floor), Some(ceiling)) = (floor, ceiling) {
+            if floor >= ceiling {
+                invalid.push(Invalid { path: "tuning.floor_freq".to_string(), problem: format!("{} must be below ceiling_freq ({})", floor, ceiling) });
+            }
+        }
+    }
+    if let Some(width) = settings.get("stereo_width") {
+        if let Some(problem) = check(width, &Accepts::Number(0.0, 4.0)) {
+            invalid.push(Invalid { path: "stereo_width".to_string(), problem });
+        }
+    }
+    if let Some(lfos) = settings.get("lfo").and_then(Value::as_object) {
+        for (effect, lfos) in lfos {
+            let path = format!("lfo.{}", effect);
+            if EffectKind::from_str(effect, true).is_err() {
+                invalid.push(Invalid { path, problem: format!("unknown effect, expected one of {}", effect_names().join(", ")) });
+                continue;
+            }
+            match lfos.as_array() {
+                Some(lfos) => {
+                    for (index, lfo) in lfos.iter().enumerate() {
+                        check_table(&format!("{}[{}]", path, index), lfo, LFO, &mut invalid);
+                    }
+                },
+                None => invalid.push(Invalid { path, problem: format!("expected a list of oscillators, got {}", lfos) }),
+            }
+        }
+    }
+    if let Some(layers) = settings.get("layers") {
+        match layers.as_array() {
+            Some(layers) => {
+                for (index, layer) in layers.iter().enumerate() {
+                    check_table(&format!("layers[{}]", index), layer, LAYER, &mut invalid);
+                }
+            },
+            None => invalid.push(Invalid { path: "layers".to_string(), problem: format!("expected a list of layers, got {}", layers) }),
+        }
+    }
+    invalid
+}
+
+#[cfg(test)]
+mod test {
+    use serde::de::{self, Deserialize, Deserializer, Error, Visitor};
+    use serde_json::json;
+
+    use super::*;
+    use crate::effects::{LayerConfig, PeakHoldConfig};
+    use crate::hue_range::HueRangeConfig;
+    use crate::hue_rotation::HueRotationConfig;
+    use crate::lfo::LfoConfig;
+    use crate::transition::TransitionConfig;
+    use crate::tuning::Tuning;
+
+    /// Catches the field names a struct hands to its deserializer, without reading anything.
+    struct FieldNames(&'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for &mut FieldNames {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, Self::Error> {
+            self.0 = fields;
+            Err(Error::custom("only after the field names"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    fn sorted_fields<'de, T: Deserialize<'de>>() -> Vec<&'static str> {
+        let mut names = FieldNames(&[]);
+        let _ = T::deserialize(&mut names);
+        let mut fields = names.0.to_vec();
+        fields.sort();
+        fields
+    }
+
+    fn sorted_params(params: &[Param]) -> Vec<&'static str> {
+        let mut names: Vec<&str> = params.iter().map(|param| param.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_params_match_configs() {
+        // A setting added to a config but not here would be reported as unknown, and
+        // one left here after it's gone would be silently ignored.
+        assert_eq!(sorted_params(TUNING), sorted_fields::<Tuning>());
+        assert_eq!(sorted_params(PEAK_HOLD), sorted_fields::<PeakHoldConfig>());
+        assert_eq!(sorted_params(HUE_RANGE), sorted_fields::<HueRangeConfig>());
+        assert_eq!(sorted_params(HUE_ROTATION), sorted_fields::<HueRotationConfig>());
+        assert_eq!(sorted_params(TRANSITIONS), sorted_fields::<TransitionConfig>());
+        assert_eq!(sorted_params(LFO), sorted_fields::<LfoConfig>());
+        assert_eq!(sorted_params(LAYER), sorted_fields::<LayerConfig>());
+    }
+
+    #[test]
+    fn test_effect_settings() {
+        let settings = json!({
+            "tuning": {"intensity": "20", "smoothing": 1.5, "intensty": 10},
+            "hue_range": {"mode": "wrap", "avoid": [[70, 170], [400]]},
+            "lfo": {"spectrum": [{"target": "hue", "phase": 2}], "rainbow": []},
+            "layers": [{"effect": "screen"}, {"effect": "strobe", "blend": "add", "opacity": 0.5}],
+            "nanoleaf_token": "anything",
+        });
+        let invalid: Vec<String> = effect_settings(settings.as_object().unwrap()).iter().map(Invalid::to_string).collect();
+        assert_eq!(invalid, [
+            "tuning.intensty: unknown setting, expected one of intensity, smoothing, floor_freq, ceiling_freq",
+            "tuning.smoothing: 1.5 is out of range, expected 0 to 0.95",
+            "hue_range.avoid: [1] is [400], expected [from, to] with each from 0 to 360",
+            "hue_range.mode: expected one of remap, clamp, got \"wrap\"",
+            "lfo.rainbow: unknown effect, expected one of screen, chroma, spectrogram, spectrum, auto, ripple, splat, lava, layers",
+            "lfo.spectrum[0].phase: 2 is out of range, expected 0 to 1",
+            "layers[1].effect: expected one of screen, chroma, spectrogram, spectrum, auto, ripple, splat, lava, layers, got \"strobe\"",
+        ]);
+    }
+}
@@ -1,14 +1,21 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::collections::{VecDeque, HashMap};
+use std::sync::Arc;
 
 use enterpolation::{linear::Linear, Curve};
-use rustfft::{FftDirection, Fft};
-use rustfft::algorithm::Radix4;
+use realfft::{RealFftPlanner, RealToComplex};
 use rustfft::num_complex::Complex;
+use rayon::ThreadPool;
+
+use crate::chroma::{self, Chroma};
+use crate::clock;
+use crate::parallel_fft::ParallelFft;
+use crate::report::AudioStats;
+use crate::simd;
+use crate::log_throttle::warn_throttled;
+use crate::resample::RateConverter;
+use crate::tuning::Tuning;
 
-const BUFFER_TARGET: usize = 3;
-const CEILING_FREQ: f32 = 15000.0;
-const FLOOR_FREQ: f32 = 100.0;
 const SCALE: f32 = 8.0;
 const POWER_FREQ: f32 = 1.02;
 
@@ -16,6 +23,8 @@ struct AudioBuffer {
 	data: Box<[f32]>,
 	position: usize,
 	rate: f32,
+	/// when the buffer arrived
+	received: Instant,
 }
 
 impl AudioBuffer {
@@ -36,8 +45,15 @@ impl AudioBuffer {
 	}
 }
 
+enum FftAlgorithm {
+	/// a real to complex FFT, which only works out the first half of the spectrum
+	Single(Arc<dyn RealToComplex<f32>>),
+	/// split across threads, for large windows
+	Parallel(ParallelFft),
+}
+
 struct FftCache {
-	algorithm: Radix4<f32>,
+	algorithm: FftAlgorithm,
 	window: Box<[f32]>,
 	scaling_factor: f32,
 }
@@ -47,6 +63,44 @@ pub(crate) struct BufferManager {
 	buffers: VecDeque<AudioBuffer>,
 	/// key is the power to raise 2 to for the radix size
 	ffts: HashMap<u8, FftCache>,
+	/// pitch class energy of the most recently analysed interval
+	chroma: Chroma,
+	/// root mean square level of the most recently analysed interval
+	rms: f32,
+	/// frequency range and smoothing, the intensity is left to the effects
+	tuning: Tuning,
+	/// bands of the previous interval, for smoothing
+	previous: Box<[f32]>,
+	/// buffers older than this are dropped rather than analysed
+	max_age: Option<Duration>,
+	/// the smallest window to split across threads, and the threads to split it across
+	parallel: Option<(usize, Arc<ThreadPool>)>,
+	/// fraction of each window that overlaps the one before
+	overlap: f32,
+	/// the most recently analysed samples, to start the next overlapping window with
+	history: Vec<f32>,
+	/// dropped buffers, short reads and latency so far
+	stats: AudioStats,
+	/// how much audio to keep queued up for the render thread
+	latency_target: Duration,
+	/// the most bytes of audio to keep queued, however long the render thread takes
+	queue_limit: Option<usize>,
+	/// when the render thread last read audio, and how often it reads on average
+	last_read: Option<Instant>,
+	read_interval: Duration,
+	/// the rate audio is analysed at, which is the rate of the first buffer
+	rate: Option<u32>,
+	/// converts audio to `rate` if the stream changes rate part way through
+	converter: Option<RateConverter>,
+	/// a rate that couldn't be converted, so audio at it is analysed as it is instead of
+	/// trying again with every buffer
+	unconvertible: Option<u32>,
+	/// how wide the stereo image of recent audio is, from 0 for mono to 1
+	width: f32,
+	/// analyses the side channel of mid/side audio separately, when asked to
+	side: Option<Box<BufferManager>>,
+	/// bands of the side channel from the last analysis
+	side_bands: Option<Box<[f32]>>,
 }
 
 struct BufferSlice {
@@ -56,38 +110,104 @@ struct BufferSlice {
 
 impl BufferManager {
 	fn take_next(&mut self, interval: Duration) -> BufferSlice {
+		if let Some(max_age) = self.max_age {
+			let stale = self.buffers.iter().take_while(|buffer| clock::elapsed(buffer.received) > max_age).count();
+			if stale > 0 {
+				log::debug!("Dropping {} stale audio buffers", stale);
+				self.stats.stale += stale as u64;
+				self.buffers.drain(0..stale);
+				self.history.clear();
+			}
+		}
+
+		let now = clock::now();
+		if let Some(last_read) = self.last_read.replace(now) {
+			let since = now.duration_since(last_read);
+			self.read_interval = self.read_interval.mul_f32(0.9) + since.mul_f32(0.1);
+		}
+
+		// the next sample to read was played out this long before its buffer arrived
+		if let Some(oldest) = self.buffers.front() {
+			let waiting = (oldest.data.len() - oldest.position) as f32 / oldest.rate;
+			self.stats.latency_ms = (clock::elapsed(oldest.received).as_secs_f32() + waiting) * 1000.0;
+		}
+
 		let mut values = Vec::new();
 		let mut buffers_taken = 0;
-		let mut rate = 0.0;
+		// the rate of each buffer weighted by how much of it was read, and how much was
+		let mut weighted_rate = 0.0;
+		let mut total_elapsed = 0.0;
 		let mut remaining_interval = interval;
-		let interval = interval.as_secs_f32();
+		let mut filled = false;
 
 		for buffer in &mut self.buffers {
 			let buffer_rate = buffer.rate;
 			let (slice, elapsed) = buffer.read(remaining_interval);
 
-			rate += buffer_rate * elapsed.as_secs_f32() / interval;
+			weighted_rate += buffer_rate * elapsed.as_secs_f32();
+			total_elapsed += elapsed.as_secs_f32();
 
 			values.extend_from_slice(slice);
 			remaining_interval = remaining_interval.saturating_sub(elapsed);
 
-			// why not is_zero?: because floating point imprecision and rounding
-			if remaining_interval.as_millis() < 1 {
+			// why not is_zero?: because floating point imprecision and rounding. what's
+			// left can be too short for even one more sample, but a fixed cut off like
+			// a millisecond would leave whole samples of the next buffer unread
+			if remaining_interval.as_secs_f32() * buffer_rate < 1.0 {
+				filled = true;
 				break;
 			}
 
 			buffers_taken += 1;
 		}
 
-		// to account for any remaining time, scale up the existing rate
-		let total_elapsed = interval - remaining_interval.as_secs_f32();
-		rate /= total_elapsed / interval;
+		if !values.is_empty() && !filled {
+			self.stats.underruns += 1;
+		}
+
+		// the average rate of what was read. summing what was read rather than taking
+		// what's left from the interval avoids cancelling out most of the precision
+		// when very little was read
+		let rate = if total_elapsed > 0.0 { weighted_rate / total_elapsed } else { 0.0 };
 
 		self.buffers.drain(0..buffers_taken);
 
 		BufferSlice { values, rate }
 	}
 
+	/// Extend newly read samples back into those analysed last time, so each window
+	/// overlaps the one before and short transients always land well inside one of
+	/// them. The newest samples are kept when trimming to a power of two.
+	fn overlapped(&mut self, values: Vec<f32>) -> Vec<f32> {
+		if self.overlap <= 0.0 {
+			return values;
+		}
+		// new samples make up the hop between windows, which is 1 - overlap of a window
+		let window = (values.len() as f32 / (1.0 - self.overlap)).round() as usize;
+		let from_history = (window - values.len()).min(self.history.len());
+		let mut overlapped = self.history.split_off(self.history.len() - from_history);
+		overlapped.extend(values);
+		self.history = overlapped.clone();
+
+		let size = 2_usize.pow(f32::log2(overlapped.len() as f32).floor() as u32);
+		overlapped.split_off(overlapped.len() - size)
+	}
+
+	/// measure the level of the next interval of audio without analysing it, to notice
+	/// audio starting while in standby
+	pub fn measure_interval(&mut self, interval: Duration) {
+		if let Some(side) = &mut self.side {
+			side.measure_interval(interval);
+		}
+		let BufferSlice { values, .. } = self.take_next(interval);
+		self.history.clear();
+		self.rms = if values.is_empty() {
+			0.0
+		} else {
+			f32::sqrt(values.iter().map(|v| v * v).sum::<f32>() / values.len() as f32)
+		};
+	}
+
 	// TODO: would be nice to have constant_q and/or variable_q intervals
 
 	pub fn fft_interval(
@@ -95,34 +215,59 @@ impl BufferManager {
 		interval: Duration,
 		out_size: usize,
 	) -> Option<Box<[f32]>> {
+		self.side_bands = self.side.as_mut().and_then(|side| side.fft_interval(interval, out_size));
 		let BufferSlice { values, rate } = self.take_next(interval);
 
 		if values.len() < 2 {
+			self.rms = 0.0;
+			self.history.clear();
 			return None;
 		}
+		self.rms = f32::sqrt(values.iter().map(|v| v * v).sum::<f32>() / values.len() as f32);
+		let values = self.overlapped(values);
 
 		let power_of_2 = f32::log2(values.len() as f32).floor() as u32;
 		let size = 2_u32.pow(power_of_2) as usize;
 
+		let parallel = &self.parallel;
 		let fft = self.ffts.entry(power_of_2 as u8).or_insert_with(|| {
+			let algorithm = match parallel {
+				Some((min_size, pool)) if size >= *min_size => FftAlgorithm::Parallel(ParallelFft::new(size, pool.clone())),
+				_ => FftAlgorithm::Single(RealFftPlanner::new().plan_fft_forward(size)),
+			};
 			FftCache {
-				algorithm: Radix4::new(size, FftDirection::Forward),
+				algorithm,
 				window: apodize::hamming_iter(size).map(|v| v as f32).collect(),
 				scaling_factor: (size as f32).sqrt(),
 			}
 		});
 
-		let mut truncated_data = values[0..size].iter()
+		let windowed = values[0..size].iter()
 			.cloned()
 			.zip(fft.window.iter())
-			.map(|(val, scale)| Complex { re: val * scale, im: 0.0 })
-			.collect::<Vec<_>>();
-
-		fft.algorithm.process(truncated_data.as_mut_slice());
+			.map(|(val, scale)| val * scale);
+
+		// only the first half of the spectrum, up to and including the Nyquist frequency
+		let spectrum = match &fft.algorithm {
+			FftAlgorithm::Single(algorithm) => {
+				let mut input = windowed.collect::<Vec<_>>();
+				let mut spectrum = algorithm.make_output_vec();
+				algorithm.process(&mut input, &mut spectrum).expect("FFT buffers are sized by the plan");
+				spectrum
+			},
+			FftAlgorithm::Parallel(algorithm) => {
+				let mut data = windowed.map(|re| Complex { re, im: 0.0 }).collect::<Vec<_>>();
+				algorithm.process(&mut data);
+				data.truncate(size / 2 + 1);
+				data
+			},
+		};
+
+		self.chroma = chroma::chroma_from_spectrum(&spectrum, size, rate);
 
 		// NOTE: taking anything > rate/2 results in Hermitian symmetry
-		let max_frequency_ratio = CEILING_FREQ / rate;
-		let min_frequency_ratio = FLOOR_FREQ / rate;
+		let max_frequency_ratio = self.tuning.ceiling_freq / rate;
+		let min_frequency_ratio = self.tuning.floor_freq / rate;
 		let max_index = usize::min(size, (size as f32 * max_frequency_ratio) as usize);
 		let min_index = (size as f32 * min_frequency_ratio) as usize;
 
@@ -141,33 +286,297 @@ impl BufferManager {
 			[0.0].into_iter().chain(power_data).collect()
 		}
 
-		Some(Linear::builder()
-			.elements(&truncated_data[range])
-			.knots(power_range(POWER_FREQ, count).as_ref())
-			.build()
-			.unwrap()
-			.take(out_size)
-			.map(|Complex { re, im }| {
-				let power = f32::sqrt(re * re + im * im);
-				let value = power / fft.scaling_factor;
-				let log_scale = f32::log10(1.0 + value);
-				
-				log_scale * SCALE
-			})
-			.collect::<Box<_>>())
+		// above rate/2 the spectrum mirrors the first half, so fill those bins back in
+		let bins = range
+			.map(|index| spectrum.get(index).copied().unwrap_or_else(|| spectrum[size - index].conj()))
+			.collect::<Vec<_>>();
+
+		let scaling_factor = fft.scaling_factor;
+		let level = |power: f32| {
+			let value = power / scaling_factor;
+			let log_scale = f32::log10(1.0 + value);
+
+			log_scale * SCALE
+		};
+
+		let magnitudes = simd::magnitudes(&bins);
+		let mut bands = if out_size == 1 {
+			// a lone band covers the whole range, rather than just its lowest frequency
+			let total = magnitudes.into_iter().map(level).sum::<f32>();
+			Box::new([total / count as f32]) as Box<[f32]>
+		} else {
+			// interpolate between magnitudes, as neighbouring bins out of phase would
+			// otherwise cancel each other out
+			Linear::builder()
+				.elements(&magnitudes)
+				.knots(power_range(POWER_FREQ, count).as_ref())
+				.build()
+				.unwrap()
+				.take(out_size)
+				.map(level)
+				.collect::<Box<_>>()
+		};
+
+		if self.previous.len() == bands.len() {
+			let smoothing = self.tuning.smoothing.clamp(0.0, 0.95);
+			for (band, previous) in bands.iter_mut().zip(self.previous.iter()) {
+				*band = previous * smoothing + *band * (1.0 - smoothing);
+			}
+		}
+		self.previous = bands.clone();
+
+		Some(bands)
+	}
+
+	/// change the frequency range and smoothing of the analysis
+	pub fn tune(&mut self, tuning: Tuning) {
+		self.tuning = tuning;
+		if let Some(side) = &mut self.side {
+			side.tune(tuning);
+		}
+	}
+
+	/// analyse the side channel of mid/side audio alongside the audio, with the same
+	/// settings, so call this once everything else is set up
+	pub fn analyse_side(&mut self) {
+		self.side = Some(Box::new(BufferManager {
+			tuning: self.tuning,
+			max_age: self.max_age,
+			parallel: self.parallel.clone(),
+			overlap: self.overlap,
+			latency_target: self.latency_target,
+			queue_limit: self.queue_limit,
+			..BufferManager::default()
+		}));
+	}
+
+	/// queue audio of the side channel, if it's being analysed
+	pub fn fill_side(&mut self, buffer: &[f32], rate: u32) {
+		if let Some(side) = &mut self.side {
+			side.fill_buffer(buffer, rate);
+		}
+	}
+
+	/// bands of the side channel from the last analysis, if it's being analysed
+	pub fn side_bands(&self) -> Option<&[f32]> {
+		self.side_bands.as_deref()
+	}
+
+	pub fn chroma(&self) -> Chroma {
+		self.chroma
+	}
+
+	pub fn rms(&self) -> f32 {
+		self.rms
+	}
+
+	pub fn stats(&self) -> AudioStats {
+		self.stats
+	}
+
+	/// record how wide the stereo image of the audio just added was, if it was stereo
+	pub fn record_width(&mut self, width: Option<f32>) {
+		let width = width.unwrap_or(0.0);
+		self.width = self.width * 0.8 + width * 0.2;
+	}
+
+	pub fn stereo_width(&self) -> f32 {
+		self.width
+	}
+
+	/// Never keep more than `bytes` of audio queued, even when reads are far enough
+	/// apart to need more, so a slow render thread can't eat the memory of a small
+	/// device.
+	pub fn set_queue_limit(&mut self, bytes: usize) {
+		self.queue_limit = Some(bytes);
+	}
+
+	/// Drop audio that has waited longer than `max_age` to be analysed, such as when
+	/// the machine is too busy to keep up, so the lights don't lag behind the music.
+	pub fn set_max_age(&mut self, max_age: Duration) {
+		self.max_age = Some(max_age);
+	}
+
+	/// Keep about `latency_target` of audio queued, or enough to last between reads if
+	/// the render thread reads less often than that, dropping the oldest audio beyond it.
+	pub fn set_latency_target(&mut self, latency_target: Duration) {
+		self.latency_target = latency_target;
+	}
+
+	/// Overlap each analysis window with the last by `overlap`, from 0 for none to 0.9.
+	pub fn set_overlap(&mut self, overlap: f32) {
+		self.overlap = overlap.clamp(0.0, 0.9);
+		self.history.clear();
+	}
+
+	/// Split windows of at least `min_size` samples across the threads of `pool`,
+	/// which keeps long intervals and high sample rates from holding up the lights.
+	pub fn set_parallel_fft(&mut self, min_size: usize, pool: Arc<ThreadPool>) {
+		self.parallel = Some((min_size, pool));
+		self.ffts.clear();
 	}
 
 	pub fn fill_buffer(&mut self, buffer: &[f32], rate: u32) {
-		if self.buffers.len() >= BUFFER_TARGET {
-			// render thread is behind (or not drawing)
-			// pause as to not waste resources copying data
+		let Some((data, rate)) = self.convert(buffer, rate) else {
+			return;
+		};
+		if data.is_empty() {
 			return;
 		}
-
 		self.buffers.push_back(AudioBuffer {
 			position: 0,
 			rate: rate as f32,
-			data: Vec::from(buffer).into_boxed_slice(),
+			data,
+			received: clock::now(),
 		});
+
+		// the render thread is behind (or not drawing), so drop the oldest audio rather
+		// than let the lights fall behind the music, keeping enough queued to last
+		// until the next read
+		let limit = self.latency_target.max(self.read_interval * 2).as_secs_f32();
+		let byte_limit = self.queue_limit.unwrap_or(usize::MAX);
+		while self.buffers.len() > 1 && (self.queued() > limit || self.queued_bytes() > byte_limit) {
+			self.buffers.pop_front();
+			self.stats.dropped += 1;
+		}
+	}
+
+	/// resample audio that isn't at the rate analysis runs at, such as after the stream
+	/// is renegotiated, so frequencies keep landing in the right bands. Returns the
+	/// audio along with the rate it's now at.
+	fn convert(&mut self, buffer: &[f32], rate: u32) -> Option<(Box<[f32]>, u32)> {
+		let target = *self.rate.get_or_insert(rate);
+		if rate == target || self.unconvertible == Some(rate) {
+			self.converter = None;
+			return Some((Box::from(buffer), rate));
+		}
+		if self.converter.as_ref().map(RateConverter::from) != Some(rate) {
+			log::info!("Audio changed from {}Hz to {}Hz, resampling", target, rate);
+			match RateConverter::new(rate, target) {
+				Ok(converter) => self.converter = Some(converter),
+				Err(err) => {
+					log::warn!("Could not resample audio from {}Hz, analysing it as it is: {}", rate, err);
+					self.unconvertible = Some(rate);
+					self.converter = None;
+					return Some((Box::from(buffer), rate));
+				},
+			}
+		}
+		match self.converter.as_mut()?.convert(buffer) {
+			Ok(converted) => Some((converted.into_boxed_slice(), target)),
+			Err(err) => {
+				warn_throttled!("Could not resample audio: {}", err);
+				None
+			},
+		}
+	}
+
+	/// memory held by the queued audio, including what has already been read from the
+	/// buffer at the front
+	fn queued_bytes(&self) -> usize {
+		self.buffers.iter().map(|buffer| std::mem::size_of_val(&*buffer.data)).sum()
+	}
+
+	/// seconds of audio waiting to be read
+	fn queued(&self) -> f32 {
+		self.buffers.iter().map(|buffer| (buffer.data.len() - buffer.position) as f32 / buffer.rate).sum()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use proptest::collection::vec;
+	use proptest::prelude::*;
+
+	use super::*;
+
+	#[test]
+	fn test_single_band() {
+		let rate = 22050;
+		// a low and a high tone, which a lone band should both pick up
+		let tone = |frequency: f32| (0..rate).map(move |i| (i as f32 * frequency * std::f32::consts::TAU / rate as f32).sin());
+		let low: Vec<f32> = tone(200.0).collect();
+		let both: Vec<f32> = tone(200.0).zip(tone(8000.0)).map(|(a, b)| (a + b) / 2.0).collect();
+
+		let level = |samples: &[f32]| {
+			let mut buffer_manager = BufferManager::default();
+			buffer_manager.fill_buffer(samples, rate as u32);
+			buffer_manager.fft_interval(Duration::from_millis(100), 1).unwrap()
+		};
+		let low = level(&low);
+		let both = level(&both);
+		assert_eq!(low.len(), 1);
+		assert!(low[0] > 0.0);
+		assert!(both[0] > low[0] * 0.5, "The treble tone should count towards the band");
+	}
+
+	#[test]
+	fn test_overlapping_windows() {
+		let mut buffer_manager = BufferManager::default();
+		buffer_manager.set_overlap(0.5);
+		let first = buffer_manager.overlapped((0..100).map(|i| i as f32).collect());
+		assert_eq!(first.len(), 64);
+		assert_eq!(first.last(), Some(&99.0));
+		// half of the next window is the end of the last one
+		let second = buffer_manager.overlapped((100..200).map(|i| i as f32).collect());
+		assert_eq!(second.len(), 128);
+		assert_eq!((second[0], second[127]), (72.0, 199.0));
+	}
+
+	#[test]
+	fn test_queues_up_to_latency_target() {
+		let mut buffer_manager = BufferManager::default();
+		buffer_manager.set_latency_target(Duration::from_millis(50));
+		for _ in 0..10 {
+			buffer_manager.fill_buffer(&[0.5; 441], 22050);
+		}
+		// 20ms buffers, so only two fit in 50ms
+		assert_eq!(buffer_manager.buffers.len(), 2);
+		assert_eq!(buffer_manager.stats().dropped, 8);
+	}
+
+	#[test]
+	fn test_drops_stale_buffers() {
+		let mut buffer_manager = BufferManager::default();
+		buffer_manager.set_max_age(Duration::from_millis(500));
+		buffer_manager.fill_buffer(&[0.5; 4410], 22050);
+		buffer_manager.buffers[0].received -= Duration::from_secs(1);
+		assert!(buffer_manager.fft_interval(Duration::from_millis(100), 1).is_none());
+		assert!(buffer_manager.buffers.is_empty());
+	}
+
+	proptest! {
+		#[test]
+		fn prop_take_next_reads_the_interval(buffers in vec((8000u32..96000, 1usize..4800), 1..8), interval_ms in 1u64..500) {
+			let mut buffer_manager = BufferManager::default();
+			for (index, (rate, len)) in buffers.iter().enumerate() {
+				// each sample holds the index of its buffer, to tell where it was read from
+				buffer_manager.buffers.push_back(AudioBuffer {
+					data: vec![index as f32; *len].into(),
+					position: 0,
+					rate: *rate as f32,
+					received: Instant::now(),
+				});
+			}
+			let interval = Duration::from_millis(interval_ms);
+			let BufferSlice { values, rate } = buffer_manager.take_next(interval);
+			prop_assume!(!values.is_empty());
+
+			let read = |index: usize| values.iter().filter(|value| **value as usize == index).count();
+			let used: Vec<f32> = buffers.iter().enumerate().filter(|(index, _)| read(*index) > 0).map(|(_, (rate, _))| *rate as f32).collect();
+			let (min, max) = used.iter().fold((f32::MAX, 0.0f32), |(min, max), rate| (min.min(*rate), max.max(*rate)));
+			prop_assert!(rate >= min * 0.999 && rate <= max * 1.001, "rate {} outside {}..{}", rate, min, max);
+
+			let consumed: f32 = buffers.iter().enumerate().map(|(index, (rate, _))| read(index) as f32 / *rate as f32).sum();
+			let queued: f32 = buffers.iter().map(|(rate, len)| *len as f32 / *rate as f32).sum();
+			let interval = interval.as_secs_f32();
+			if queued >= interval {
+				// short by less than a sample, which may be of the next buffer along
+				let sample = 1.0 / buffers.iter().map(|(rate, _)| *rate as f32).fold(f32::MAX, f32::min);
+				prop_assert!(consumed <= interval + 1e-6 && consumed >= interval - sample - 1e-6, "read {}s of {}s", consumed, interval);
+			} else {
+				prop_assert_eq!(values.len(), buffers.iter().map(|(_, len)| len).sum::<usize>());
+			}
+		}
 	}
 }
@@ -1,15 +1,16 @@
 use std::{
     error::Error,
-    ffi::CStr,
     fs::File,
     os::fd::{OwnedFd, AsRawFd, AsFd},
     sync::atomic::{AtomicBool, Ordering},
-    time::{SystemTime, UNIX_EPOCH}, io::{Read, Seek},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}, io::{ErrorKind, Read, Seek, SeekFrom},
 };
 
 use nix::{
     fcntl,
+    poll::{self, PollFd, PollFlags},
     sys::{mman, stat, memfd},
+    time::{self, ClockId},
     unistd,
 };
 
@@ -19,13 +20,20 @@ use wayland_client::{
     delegate_noop,
     globals::GlobalList,
     protocol::{
-        wl_buffer::WlBuffer, wl_output::WlOutput, wl_shm, wl_shm::Format, wl_shm::WlShm,
+        wl_buffer::WlBuffer, wl_output::Transform, wl_output::WlOutput, wl_shm, wl_shm::Format, wl_shm::WlShm,
         wl_shm_pool::WlShmPool,
     },
-    Connection, Dispatch, QueueHandle,
+    Connection, Dispatch, EventQueue, QueueHandle,
     WEnum::Value,
+    backend::WaylandError,
 };
 
+use crate::log_throttle::warn_throttled;
+use crate::visual::capture::{DamageRect, FrameCopy, FrameSource, FrameTransform};
+use crate::visual::hyprland;
+use crate::visual::output::{OutputInfo, OutputPositioning};
+use crate::visual::region::CaptureRegion;
+
 use wayland_protocols_wlr::screencopy::v1::client::{
     zwlr_screencopy_frame_v1, zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
     zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
@@ -35,6 +43,8 @@ struct CaptureFrameState {
     formats: Vec<FrameFormat>,
     state: Option<FrameState>,
     buffer_done: AtomicBool,
+    y_invert: bool,
+    damage: Vec<DamageRect>,
 }
 
 impl Dispatch<ZwlrScreencopyFrameV1, ()> for CaptureFrameState {
@@ -65,21 +75,26 @@ impl Dispatch<ZwlrScreencopyFrameV1, ()> for CaptureFrameState {
                     log::warn!("Received Buffer event with unidentified format");
                 }
             }
-            zwlr_screencopy_frame_v1::Event::Flags { .. } => {
+            zwlr_screencopy_frame_v1::Event::Flags { flags } => {
                 log::debug!("Received Flags event");
+                if let Value(flags) = flags {
+                    frame.y_invert = flags.contains(zwlr_screencopy_frame_v1::Flags::YInvert);
+                }
             }
-            zwlr_screencopy_frame_v1::Event::Ready { .. } => {
+            zwlr_screencopy_frame_v1::Event::Ready { tv_sec_hi, tv_sec_lo, tv_nsec } => {
                 // If the frame is successfully copied, a “flags” and a “ready” events are sent. Otherwise, a “failed” event is sent.
                 // This is useful when we call .copy on the frame object.
                 log::debug!("Received Ready event");
-                frame.state.replace(FrameState::Finished);
+                let presented = Duration::new((u64::from(tv_sec_hi) << 32) | u64::from(tv_sec_lo), tv_nsec);
+                frame.state.replace(FrameState::Finished(monotonic_instant(presented)));
             }
             zwlr_screencopy_frame_v1::Event::Failed => {
                 log::debug!("Received Failed event");
                 frame.state.replace(FrameState::Failed);
             }
-            zwlr_screencopy_frame_v1::Event::Damage { .. } => {
+            zwlr_screencopy_frame_v1::Event::Damage { x, y, width, height } => {
                 log::debug!("Received Damage event");
+                frame.damage.push(DamageRect { x, y, width, height });
             }
             zwlr_screencopy_frame_v1::Event::LinuxDmabuf { .. } => {
                 log::debug!("Received LinuxDmaBuf event");
@@ -115,24 +130,184 @@ pub struct FrameFormat {
 enum FrameState {
     /// Compositor returned a failed event on calling `frame.copy`.
     Failed,
-    /// Compositor sent a Ready event on calling `frame.copy`.
-    Finished,
+    /// Compositor sent a Ready event on calling `frame.copy`, with when the frame was
+    /// shown.
+    Finished(Instant),
 }
 
-/// The copied frame comprising of the FrameFormat, ColorType (Rgba8), and a memory backed shm
-/// file that holds the image data in it.
-#[derive(Debug)]
-pub struct FrameCopy {
-    pub width: u32,
-    pub height: u32,
-    pub frame_color_type: ColorType,
-    pub data: Vec<u8>,
+/// The `Instant` of a time on the monotonic clock, such as the compositor stamps frames
+/// with.
+fn monotonic_instant(timestamp: Duration) -> Instant {
+    let now = Instant::now();
+    match time::clock_gettime(ClockId::CLOCK_MONOTONIC) {
+        Ok(monotonic) => now.checked_sub(Duration::from(monotonic).saturating_sub(timestamp)).unwrap_or(now),
+        Err(_) => now,
+    }
+}
+
+/**
+ * How often to look up the geometry of a followed window.
+ */
+const WINDOW_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * How long to wait for the screen to change before handing back the previous frame
+ * again, so a still screen doesn't look like an unresponsive compositor.
+ */
+const DAMAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl DamageRect {
+    /// The rectangle in a frame that keeps one in `factor` pixels along each row and
+    /// column, grown to cover every kept pixel it touches.
+    fn downscaled(self, factor: u32) -> Self {
+        let (x, y) = (self.x / factor, self.y / factor);
+        DamageRect {
+            x,
+            y,
+            width: (self.x + self.width).div_ceil(factor) - x,
+            height: (self.y + self.height).div_ceil(factor) - y,
+        }
+    }
+}
+
+impl From<Transform> for FrameTransform {
+    fn from(transform: Transform) -> Self {
+        match transform {
+            Transform::_90 => FrameTransform::Rotate90,
+            Transform::_180 => FrameTransform::Rotate180,
+            Transform::_270 => FrameTransform::Rotate270,
+            Transform::Flipped => FrameTransform::Flipped,
+            Transform::Flipped90 => FrameTransform::Flipped90,
+            Transform::Flipped180 => FrameTransform::Flipped180,
+            Transform::Flipped270 => FrameTransform::Flipped270,
+            _ => FrameTransform::Normal,
+        }
+    }
+}
+
+/// Captures a single output using wlr-screencopy, optionally limited to the region
+/// covered by a window and / or a configured crop.
+pub struct WaylandCapture {
+    globals: GlobalList,
+    conn: Connection,
+    output: WlOutput,
+    output_name: String,
+    output_size: OutputPositioning,
+    transform: Transform,
+    capturer: FrameCapturer,
+    /// Class or title of a window to follow instead of capturing the whole output.
+    window: Option<String>,
+    crop: Option<CaptureRegion>,
+    region: Option<OutputPositioning>,
+    last_window_check: Option<Instant>,
+    /// Keep one in this many pixels along each row and column of every frame.
+    downscale: u32,
+}
+
+impl WaylandCapture {
+    pub fn new(globals: GlobalList, conn: Connection, output: OutputInfo, window: Option<String>, crop: Option<CaptureRegion>, downscale: u32) -> Result<Self, Box<dyn Error>> {
+        let output_size = OutputPositioning {
+            x: 0,
+            y: 0,
+            width: output.dimensions.width,
+            height: output.dimensions.height,
+        };
+        let scale = output.buffer_scale();
+        let region = crop.map(|crop| crop.within(&output_size));
+        if let Some(region) = &region {
+            log::info!("Capturing region {:?} of {}, {:?} in buffer pixels at scale {}", region, output.name, region.to_buffer(scale), scale);
+        }
+        if downscale > 1 {
+            log::info!("Keeping one in {} pixels of each row and column of captured frames", downscale);
+        }
+        let capturer = setup_capture(&globals, &conn, &output.wl_output, region.as_ref(), downscale)?;
+        Ok(WaylandCapture {
+            globals,
+            conn,
+            output: output.wl_output,
+            output_name: output.name,
+            output_size,
+            transform: output.transform,
+            capturer,
+            window,
+            crop,
+            region,
+            last_window_check: None,
+            downscale,
+        })
+    }
+
+    /// Look up where the followed window currently is, and recreate the capture
+    /// buffer if it has moved or resized.
+    fn refresh_window_region(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(window) = &self.window else {
+            return Ok(());
+        };
+        if self.last_window_check.is_some_and(|checked| checked.elapsed() < WINDOW_REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        self.last_window_check = Some(Instant::now());
+        let window_region = match hyprland::window_region(window, &self.output_name) {
+            Ok(region) => region,
+            Err(err) => {
+                warn_throttled!("Could not find window geometry: {}", err);
+                None
+            }
+        };
+        let region = match (&window_region, &self.crop) {
+            (Some(window_region), Some(crop)) => Some(crop.within(window_region)),
+            (None, Some(crop)) => Some(crop.within(&self.output_size)),
+            (window_region, None) => window_region.clone(),
+        };
+        if region == self.region {
+            return Ok(());
+        }
+        match &window_region {
+            Some(window_region) => log::info!("Following window \"{}\" at {:?}", window, window_region),
+            None => log::info!("Window \"{}\" not visible on {}, capturing the whole output", window, self.output_name),
+        }
+        self.capturer.buffer.destroy();
+        self.capturer.screencopy_manager.destroy();
+        self.capturer = setup_capture(&self.globals, &self.conn, &self.output, region.as_ref(), self.downscale)?;
+        self.region = region;
+        Ok(())
+    }
+}
+
+impl FrameSource for WaylandCapture {
+    fn capture_frame(&mut self) -> Result<FrameCopy, Box<dyn Error>> {
+        self.refresh_window_region()?;
+        let mut frame = capture_output_frame(&self.conn, &self.output, self.region.as_ref(), &mut self.capturer)?;
+        frame.transform = self.transform.into();
+        Ok(frame)
+    }
+}
+
+/// Request a frame of the whole output, or just `region` of it.
+fn request_frame(
+    screencopy_manager: &ZwlrScreencopyManagerV1,
+    output: &WlOutput,
+    region: Option<&OutputPositioning>,
+    qh: &QueueHandle<CaptureFrameState>,
+) -> ZwlrScreencopyFrameV1 {
+    match region {
+        Some(region) => screencopy_manager.capture_output_region(0, output, region.x, region.y, region.width, region.height, qh, ()),
+        None => screencopy_manager.capture_output(0, output, qh, ()),
+    }
 }
 
 pub struct FrameCapturer {
     pub buffer: wayland_client::protocol::wl_buffer::WlBuffer,
     pub frame_format: FrameFormat,
     pub mem_file: File,
+    /// Kept for the life of the capture, as the compositor tracks damage per manager.
+    pub screencopy_manager: ZwlrScreencopyManagerV1,
+    /// Whether `buffer` holds the previous frame, so only damage needs copying.
+    pub primed: bool,
+    /// Whether the frame in `buffer` is stored bottom to top.
+    pub y_invert: bool,
+    /// Keep one in this many pixels along each row and column when reading frames.
+    pub downscale: u32,
 }
 
 
@@ -140,11 +315,15 @@ pub fn setup_capture(
     globals: &GlobalList,
     conn: &Connection,
     output: &WlOutput,
+    region: Option<&OutputPositioning>,
+    downscale: u32,
 ) -> Result<FrameCapturer, Box<dyn Error>> {
     let mut state = CaptureFrameState {
         formats: Vec::new(),
         state: None,
         buffer_done: AtomicBool::new(false),
+        y_invert: false,
+        damage: Vec::new(),
     };
     let mut event_queue = conn.new_event_queue::<CaptureFrameState>();
     let qh = event_queue.handle();
@@ -159,7 +338,7 @@ pub fn setup_capture(
     };
 
     // Capture output.
-    screencopy_manager.capture_output(0, output, &qh, ());
+    request_frame(&screencopy_manager, output, region, &qh);
 
     while !state.buffer_done.load(Ordering::SeqCst) {
         event_queue.blocking_dispatch(&mut state)?;
@@ -199,35 +378,116 @@ pub fn setup_capture(
         buffer,
         frame_format,
         mem_file,
+        screencopy_manager,
+        primed: false,
+        y_invert: false,
+        downscale: downscale.max(1),
     })
 }
 
+/// Dispatch events on `event_queue`, waiting at most `timeout` for them to arrive.
+/// Returns whether any events were dispatched.
+fn dispatch_timeout(
+    event_queue: &mut EventQueue<CaptureFrameState>,
+    state: &mut CaptureFrameState,
+    timeout: Duration,
+) -> Result<bool, Box<dyn Error>> {
+    if event_queue.dispatch_pending(state)? > 0 {
+        return Ok(true);
+    }
+    event_queue.flush()?;
+    if let Some(guard) = event_queue.prepare_read() {
+        let fd = guard.connection_fd();
+        let mut fds = [PollFd::new(&fd, PollFlags::POLLIN)];
+        if poll::poll(&mut fds, timeout.as_millis() as i32)? == 0 {
+            return Ok(false);
+        }
+        match guard.read() {
+            Ok(_) => {}
+            Err(WaylandError::Io(err)) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(event_queue.dispatch_pending(state)? > 0)
+}
+
+/// Read the frame last copied into the capture buffer, which was on screen at
+/// `captured`.
+fn read_frame(capturer: &mut FrameCapturer, damage: Option<Vec<DamageRect>>, captured: Instant) -> Result<FrameCopy, Box<dyn Error>> {
+    let factor = capturer.downscale;
+    let (mut width, mut height, mut stride) = (capturer.frame_format.width, capturer.frame_format.height, capturer.frame_format.stride);
+    let mut data: Vec<u8> = vec![];
+    if factor > 1 {
+        data = read_downscaled(&mut capturer.mem_file, &capturer.frame_format, factor)?;
+        (width, height, stride) = (width.div_ceil(factor), height.div_ceil(factor), width.div_ceil(factor) * 4);
+    } else {
+        capturer.mem_file.read_to_end(&mut data)?;
+    }
+    capturer.mem_file.rewind()?;
+    let damage = damage.map(|damage| damage.into_iter().map(|rect| rect.downscaled(factor)).collect());
+    let color_type = match capturer.frame_format.format {
+        wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888 => {
+            // Swap out b with r as these formats are in little endian notation.
+            for chunk in data.chunks_exact_mut(4) {
+                chunk.swap(0, 2);
+            }
+            ColorType::Rgba8
+        }
+        wl_shm::Format::Xbgr8888 => ColorType::Rgba8,
+        unsupported_format => {
+            panic!("Unsupported buffer format: {:?}", unsupported_format);
+        }
+    };
+    Ok(FrameCopy {
+        width,
+        height,
+        stride,
+        color_type,
+        data,
+        transform: FrameTransform::Normal,
+        y_invert: capturer.y_invert,
+        damage,
+        captured,
+    })
+}
+
+/// Read one in `factor` pixels along each row and column of a frame, without ever
+/// holding the whole frame, so a smaller copy is all that's kept.
+fn read_downscaled(file: &mut (impl Read + Seek), format: &FrameFormat, factor: u32) -> std::io::Result<Vec<u8>> {
+    let width = format.width.div_ceil(factor) as usize;
+    let mut row = vec![0; format.stride as usize];
+    let mut data = Vec::with_capacity(width * format.height.div_ceil(factor) as usize * 4);
+    for y in (0..format.height).step_by(factor as usize) {
+        file.seek(SeekFrom::Start(y as u64 * format.stride as u64))?;
+        file.read_exact(&mut row)?;
+        data.extend(row.chunks_exact(4).step_by(factor as usize).take(width).flatten());
+    }
+    Ok(data)
+}
+
 /// Get a FrameCopy instance with screenshot pixel data for any wl_output object.
+///
+/// Once the capture buffer holds a frame, this waits for the screen to change and
+/// reports which parts did. If nothing changes for a while, the previous frame is
+/// returned again with no damage.
 pub fn capture_output_frame(
-    globals: &GlobalList,
     conn: &Connection,
     output: &WlOutput,
+    region: Option<&OutputPositioning>,
     capturer: &mut FrameCapturer,
 ) -> Result<FrameCopy, Box<dyn Error>> {
     let mut state = CaptureFrameState {
         formats: Vec::new(),
         state: None,
         buffer_done: AtomicBool::new(false),
+        y_invert: false,
+        damage: Vec::new(),
     };
     let mut event_queue = conn.new_event_queue::<CaptureFrameState>();
     let qh = event_queue.handle();
 
-    // Instantiating screencopy manager.
-    let screencopy_manager = match globals.bind::<ZwlrScreencopyManagerV1, _, _>(&qh, 3..=3, ()) {
-        Ok(x) => x,
-        Err(e) => {
-            log::error!("Failed to create screencopy manager. Does your compositor implement ZwlrScreencopy?");
-            panic!("{:#?}", e);
-        }
-    };
-
     // Capture output.
-    let frame: ZwlrScreencopyFrameV1 = screencopy_manager.capture_output(0, output, &qh, ());
+    let frame: ZwlrScreencopyFrameV1 = request_frame(&capturer.screencopy_manager, output, region, &qh);
 
     log::debug!("Waiting for buffer");
     while !state.buffer_done.load(Ordering::SeqCst) {
@@ -236,57 +496,55 @@ pub fn capture_output_frame(
     log::debug!("Buffer done");
 
     // Copy the pixel data advertised by the compositor into the buffer we just created.
-    frame.copy(&capturer.buffer);
+    // Once the buffer holds a frame, wait for something to change and only hear about
+    // what did.
+    let primed = capturer.primed;
+    if primed {
+        frame.copy_with_damage(&capturer.buffer);
+    } else {
+        frame.copy(&capturer.buffer);
+    }
 
     // On copy the Ready / Failed events are fired by the frame object, so here we check for them.
+    let started = Instant::now();
     loop {
-        // Basically reads, if frame state is not None then...
-        if let Some(state) = state.state {
-            match state {
-                FrameState::Failed => {
-                    log::error!("Frame copy failed");
-                }
-                FrameState::Finished => {
-                    // Create a writeable memory map backed by a mem_file.
-                    let mut data: Vec<u8> = vec![];
-                    capturer.mem_file.read_to_end(&mut data).unwrap();
-                    capturer.mem_file.rewind().unwrap();
-                    let frame_color_type = match capturer.frame_format.format {
-                        wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888 => {
-                            // Swap out b with r as these formats are in little endian notation.
-                            for chunk in data.chunks_exact_mut(4) {
-                                chunk.swap(0, 2);
-                            }
-                            ColorType::Rgba8
-                        }
-                        wl_shm::Format::Xbgr8888 => ColorType::Rgba8,
-                        unsupported_format => {
-                            panic!("Unsupported buffer format: {:?}", unsupported_format);
-                        }
-                    };
-                    return Ok(FrameCopy {
-                        width: capturer.frame_format.width,
-                        height: capturer.frame_format.height,
-                        frame_color_type,
-                        data,
-                    });
-                }
+        match state.state {
+            Some(FrameState::Failed) => {
+                frame.destroy();
+                capturer.primed = false;
+                return Err("Frame copy failed".into());
+            }
+            Some(FrameState::Finished(captured)) => {
+                frame.destroy();
+                capturer.primed = true;
+                capturer.y_invert = state.y_invert;
+                let damage = primed.then(|| std::mem::take(&mut state.damage));
+                return read_frame(capturer, damage, captured);
             }
+            None if primed && started.elapsed() >= DAMAGE_TIMEOUT => {
+                // Nothing has changed, so the buffer still holds what's on screen now.
+                frame.destroy();
+                return read_frame(capturer, Some(Vec::new()), Instant::now());
+            }
+            None => {}
+        }
+        if primed {
+            dispatch_timeout(&mut event_queue, &mut state, DAMAGE_TIMEOUT.saturating_sub(started.elapsed()))?;
+        } else {
+            event_queue.blocking_dispatch(&mut state)?;
         }
-        event_queue.blocking_dispatch(&mut state)?;
     }
 }
 
 /// Return a RawFd to a shm file. We use memfd create on linux and shm_open for BSD support.
-/// You don't need to mess around with this function, it is only used by
-/// capture_output_frame.
-fn create_shm_fd() -> std::io::Result<OwnedFd> {
+/// Used for the capture buffer, and for the test pattern window.
+pub fn create_shm_fd() -> std::io::Result<OwnedFd> {
     // Only try memfd on linux and freebsd.
     #[cfg(any(target_os = "linux", target_os = "freebsd"))]
     loop {
         // Create a file that closes on succesful execution and seal it's operations.
         match memfd::memfd_create(
-            CStr::from_bytes_with_nul(b"pxlha\0").unwrap(),
+            c"leafpipe",
             memfd::MemFdCreateFlag::MFD_CLOEXEC | memfd::MemFdCreateFlag::MFD_ALLOW_SEALING,
         ) {
             Ok(fd) => {
@@ -310,7 +568,7 @@ fn create_shm_fd() -> std::io::Result<OwnedFd> {
     // Fallback to using shm_open.
     let sys_time = SystemTime::now();
     let mut mem_file_handle = format!(
-        "/pxlha-{}",
+        "/leafpipe-{}",
         sys_time.duration_since(UNIX_EPOCH).unwrap().subsec_nanos()
     );
     loop {
@@ -338,7 +596,7 @@ fn create_shm_fd() -> std::io::Result<OwnedFd> {
             Err(nix::errno::Errno::EEXIST) => {
                 // If a file with that handle exists then change the handle
                 mem_file_handle = format!(
-                    "/pxlha-{}",
+                    "/leafpipe-{}",
                     sys_time.duration_since(UNIX_EPOCH).unwrap().subsec_nanos()
                 );
                 continue;
@@ -348,3 +606,29 @@ fn create_shm_fd() -> std::io::Result<OwnedFd> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_read_downscaled() {
+        // Three rows of three pixels, each pixel filled with its index, and rows padded
+        // to 16 bytes.
+        let mut frame = vec![];
+        for y in 0..3u8 {
+            for x in 0..3u8 {
+                frame.extend([y * 3 + x; 4]);
+            }
+            frame.extend([0xff; 4]);
+        }
+        let format = FrameFormat { format: Format::Xrgb8888, width: 3, height: 3, stride: 16 };
+        let data = read_downscaled(&mut Cursor::new(frame), &format, 2).unwrap();
+        assert_eq!(data.chunks_exact(4).map(|pixel| pixel[0]).collect::<Vec<_>>(), vec![0, 2, 6, 8]);
+
+        let damage = DamageRect { x: 1, y: 0, width: 2, height: 1 }.downscaled(2);
+        assert_eq!(damage, DamageRect { x: 0, y: 0, width: 2, height: 1 });
+    }
+}
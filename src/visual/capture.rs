@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::time::Instant;
+
+use image::ColorType;
+
+/// How a captured frame is turned to show it the way it's displayed, as for a rotated
+/// or flipped monitor. Numbered as Wayland numbers output transforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameTransform {
+    #[default]
+    Normal,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+impl From<FrameTransform> for u32 {
+    fn from(transform: FrameTransform) -> u32 {
+        transform as u32
+    }
+}
+
+impl FrameTransform {
+    /// Map a pixel of a frame to where it is displayed. Returns the logical position
+    /// along with the logical width and height.
+    pub fn logical_position(self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let (max_x, max_y) = (width.saturating_sub(1), height.saturating_sub(1));
+        match self {
+            FrameTransform::Normal => (x, y, width, height),
+            FrameTransform::Rotate90 => (y, max_x - x, height, width),
+            FrameTransform::Rotate180 => (max_x - x, max_y - y, width, height),
+            FrameTransform::Rotate270 => (max_y - y, x, height, width),
+            FrameTransform::Flipped => (max_x - x, y, width, height),
+            FrameTransform::Flipped90 => (y, x, height, width),
+            FrameTransform::Flipped180 => (x, max_y - y, width, height),
+            FrameTransform::Flipped270 => (max_y - y, max_x - x, height, width),
+        }
+    }
+}
+
+/// A rectangle of a frame that changed since the previous frame, in buffer coordinates.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DamageRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A copied frame. `data` holds `height` rows of `stride` bytes, of which the first
+/// `width` pixels of each row are image data in the given `color_type`.
+#[derive(Debug)]
+pub struct FrameCopy {
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row, which may include padding after the last pixel.
+    pub stride: u32,
+    pub color_type: ColorType,
+    pub data: Vec<u8>,
+    /// How the output displays this buffer, applied before mapping pixels to panels.
+    pub transform: FrameTransform,
+    /// Whether the rows of the buffer are stored bottom to top.
+    pub y_invert: bool,
+    /// The parts of the buffer that changed since the previous frame from the same
+    /// source, or `None` if the whole frame should be treated as new.
+    pub damage: Option<Vec<DamageRect>>,
+    /// When the frame was taken from the screen.
+    pub captured: Instant,
+}
+
+/// A source of screen frames to analyse. Each capture mechanism implements this, so
+/// the analysis thread doesn't need to know how frames are obtained.
+pub trait FrameSource: Send {
+    /// Block until the next frame is available and return a copy of it.
+    fn capture_frame(&mut self) -> Result<FrameCopy, Box<dyn Error>>;
+}
@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::Instant;
+
+use image::ColorType;
+
+use crate::visual::capture::{FrameCopy, FrameSource, FrameTransform};
+
+/// Captures frames from a command that writes the screen to its output as a stream of
+/// binary PPM images, such as ffmpeg with `-f image2pipe -c:v ppm -`. This covers X11
+/// (`-f x11grab`) and macOS (`-f avfoundation`), or anything else ffmpeg can read.
+pub struct CommandCapture {
+    child: Child,
+    frames: BufReader<ChildStdout>,
+    /// Keep one in this many pixels along each row and column of every frame.
+    downscale: u32,
+}
+
+impl CommandCapture {
+    pub fn start(command: &[String], downscale: u32) -> Result<Self, Box<dyn Error>> {
+        let (program, args) = command.split_first().ok_or("capture_command is empty")?;
+        let mut child = Command::new(program).args(args).stdin(Stdio::null()).stdout(Stdio::piped()).spawn()
+            .map_err(|err| format!("Could not run {}: {}", program, err))?;
+        let stdout = child.stdout.take().ok_or("No output to read frames from")?;
+        log::info!("Capturing frames from {}", program);
+        Ok(CommandCapture { child, frames: BufReader::new(stdout), downscale: downscale.max(1) })
+    }
+}
+
+impl Drop for CommandCapture {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl FrameSource for CommandCapture {
+    fn capture_frame(&mut self) -> Result<FrameCopy, Box<dyn Error>> {
+        read_ppm(&mut self.frames, self.downscale)
+    }
+}
+
+/// Read the next number in a PPM header, skipping whitespace and comments before it.
+fn read_header_number(reader: &mut impl BufRead) -> Result<u32, Box<dyn Error>> {
+    let mut digits = String::new();
+    let mut byte = [0];
+    loop {
+        reader.read_exact(&mut byte)?;
+        match byte[0] {
+            b'#' if digits.is_empty() => {
+                reader.read_line(&mut String::new())?;
+            },
+            byte if byte.is_ascii_digit() => digits.push(byte as char),
+            byte if byte.is_ascii_whitespace() && digits.is_empty() => {},
+            // The single whitespace after the last number is the end of the header.
+            byte if byte.is_ascii_whitespace() => return Ok(digits.parse()?),
+            byte => return Err(format!("Unexpected {:?} in PPM header", byte as char).into()),
+        }
+    }
+}
+
+/// Read one binary PPM (P6) image with 8 bits a channel, keeping one in `factor`
+/// pixels along each row and column.
+fn read_ppm(reader: &mut impl BufRead, factor: u32) -> Result<FrameCopy, Box<dyn Error>> {
+    let mut magic = [0; 2];
+    reader.read_exact(&mut magic)?;
+    if &magic != b"P6" {
+        return Err("Frames must be binary PPM images (P6)".into());
+    }
+    let width = read_header_number(reader)?;
+    let height = read_header_number(reader)?;
+    let max_value = read_header_number(reader)?;
+    if max_value > 255 {
+        return Err("Frames must have 8 bits a channel".into());
+    }
+    let mut row = vec![0; width as usize * 3];
+    let (kept_width, kept_height) = (width.div_ceil(factor), height.div_ceil(factor));
+    let mut data = Vec::with_capacity(kept_width as usize * kept_height as usize * 3);
+    for y in 0..height {
+        reader.read_exact(&mut row)?;
+        if y % factor == 0 {
+            data.extend(row.chunks_exact(3).step_by(factor as usize).flatten());
+        }
+    }
+    Ok(FrameCopy {
+        width: kept_width,
+        height: kept_height,
+        stride: kept_width * 3,
+        color_type: ColorType::Rgb8,
+        data,
+        transform: FrameTransform::Normal,
+        y_invert: false,
+        damage: None,
+        captured: Instant::now(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_reads_frames() {
+        let mut stream = b"P6\n# from ffmpeg\n2 1\n255\n".to_vec();
+        stream.extend([255, 0, 0, 0, 0, 255]);
+        stream.extend(b"P6 1 1 255 ");
+        stream.extend([9, 8, 7]);
+        let mut reader = Cursor::new(stream);
+        let frame = read_ppm(&mut reader, 1).unwrap();
+        assert_eq!((frame.width, frame.height), (2, 1));
+        assert_eq!(frame.pixel(1, 0), Some([0, 0, 255]));
+        assert_eq!(read_ppm(&mut reader, 1).unwrap().pixel(0, 0), Some([9, 8, 7]));
+        assert!(read_ppm(&mut reader, 1).is_err());
+        assert!(read_ppm(&mut Cursor::new(b"P3 1 1 255 ".to_vec()), 1).is_err());
+
+        // Three rows of three pixels, each filled with its index, downscaled by two.
+        let mut stream = b"P6 3 3 255\n".to_vec();
+        stream.extend((0..9).flat_map(|index| [index; 3]));
+        let frame = read_ppm(&mut Cursor::new(stream), 2).unwrap();
+        assert_eq!((frame.width, frame.height), (2, 2));
+        assert_eq!(frame.data.chunks_exact(3).map(|pixel| pixel[0]).collect::<Vec<_>>(), [0, 2, 6, 8]);
+    }
+}
@@ -0,0 +1,103 @@
+use serde::Deserialize;
+
+/// The colour gamut an output shows, and so what captured pixel values mean. Wide
+/// gamut monitors with a colour profile applied by the compositor give values in their
+/// own gamut rather than sRGB.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Gamut {
+    #[default]
+    Srgb,
+    DisplayP3,
+    Bt2020,
+}
+
+impl Gamut {
+    /// The matrix taking linear light in this gamut to linear sRGB, row by row, or
+    /// `None` for sRGB itself.
+    fn to_srgb(self) -> Option<[[f32; 3]; 3]> {
+        match self {
+            Gamut::Srgb => None,
+            Gamut::DisplayP3 => Some([
+                [1.2249401, -0.2249404, 0.0],
+                [-0.0420569, 1.0420571, 0.0],
+                [-0.0196376, -0.0786361, 1.0982735],
+            ]),
+            Gamut::Bt2020 => Some([
+                [1.660491, -0.5876411, -0.0728499],
+                [-0.1245505, 1.1328999, -0.0083494],
+                [-0.0181508, -0.1005789, 1.1187297],
+            ]),
+        }
+    }
+}
+
+fn srgb_decode(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn srgb_encode(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts captured pixels from an output's gamut into sRGB, clipping colours sRGB
+/// can't show. Wide gamut outputs are assumed to use the sRGB transfer curve, as
+/// Display P3 does.
+pub struct GamutConversion {
+    matrix: [[f32; 3]; 3],
+    /// Linear light for each 8-bit channel value.
+    decode: [f32; 256],
+}
+
+impl GamutConversion {
+    /// A conversion from `gamut`, or `None` if pixels are already sRGB.
+    pub fn new(gamut: Gamut) -> Option<Self> {
+        let matrix = gamut.to_srgb()?;
+        let mut decode = [0.0; 256];
+        for (value, linear) in decode.iter_mut().enumerate() {
+            *linear = srgb_decode(value as f32 / 255.0);
+        }
+        Some(GamutConversion { matrix, decode })
+    }
+
+    /// The matrix taking linear light in the output's gamut to linear sRGB.
+    #[cfg(feature = "gpu")]
+    pub fn matrix(&self) -> [[f32; 3]; 3] {
+        self.matrix
+    }
+
+    pub fn convert(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let linear = rgb.map(|channel| self.decode[channel as usize]);
+        self.matrix.map(|row| {
+            let value = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+            (srgb_encode(value.clamp(0.0, 1.0)) * 255.0).round() as u8
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        assert!(GamutConversion::new(Gamut::Srgb).is_none());
+        let p3 = GamutConversion::new(Gamut::DisplayP3).unwrap();
+        // Greys are the same in every gamut.
+        assert_eq!(p3.convert([128, 128, 128]), [128, 128, 128]);
+        assert_eq!(p3.convert([255, 255, 255]), [255, 255, 255]);
+        // P3 red is redder than sRGB can show, so is clipped.
+        assert_eq!(p3.convert([255, 0, 0]), [255, 0, 0]);
+        // Less saturated colours come out more saturated in sRGB terms.
+        let [r, g, b] = p3.convert([200, 100, 50]);
+        assert!(r > 200 && g < 100 && b < 50, "Expected a more saturated colour, got {:?}", [r, g, b]);
+    }
+}
@@ -0,0 +1,239 @@
+use std::error::Error;
+use std::sync::mpsc::channel;
+
+use image::ColorType;
+
+use crate::visual::capture::FrameCopy;
+use crate::visual::gamut::GamutConversion;
+use crate::visual::heatmap::Heatmap;
+use crate::visual::prominent_color::{PanelLayout, LIGHTNESS_MAX, LIGHTNESS_MIN, SATURATION_MIN, SKIP_PIXEL};
+
+/**
+ * Pixels counted by each workgroup of the shader, along each axis.
+ */
+const WORKGROUP_SIZE: u32 = 8;
+
+/// The frame texture, kept between frames as long as the frame size doesn't change.
+struct FrameTexture {
+    width: u32,
+    height: u32,
+    texture: wgpu::Texture,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Counts the colours of frames into a [`Heatmap`] with a compute shader, leaving the
+/// CPU free on large or high refresh rate outputs. Frames are uploaded from the shared
+/// memory buffer they were captured into, so capture still copies each frame once;
+/// only the counting moves to the GPU.
+pub struct GpuCounter {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    params: wgpu::Buffer,
+    /// Where each panel's region ends, see [`PanelLayout::ends`].
+    ends: wgpu::Buffer,
+    counts: wgpu::Buffer,
+    readback: wgpu::Buffer,
+    frame: Option<FrameTexture>,
+}
+
+impl GpuCounter {
+    /// Set up a counter for heatmaps shaped like `heatmap`.
+    pub fn new(heatmap: &Heatmap) -> Result<Self, Box<dyn Error>> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::LowPower,
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        })).ok_or("No GPU adapter available")?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: Some("leafpipe"),
+            required_features: wgpu::Features::empty(),
+            required_limits: wgpu::Limits::downlevel_defaults(),
+        }, None))?;
+        log::info!("Counting screen colours on {}", adapter.get_info().name);
+
+        let source = format!(
+            "const LIGHTNESS_MIN: f32 = {:?};\nconst LIGHTNESS_MAX: f32 = {:?};\nconst SATURATION_MIN: f32 = {:?};\n{}",
+            LIGHTNESS_MIN, LIGHTNESS_MAX, SATURATION_MIN, include_str!("heatmap.wgsl"),
+        );
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("heatmap"),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("heatmap"),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+        });
+
+        let counts_size = (heatmap.panel_count() * heatmap.buckets_per_panel() * 4) as u64;
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("heatmap params"),
+            size: 32 * 4,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let ends = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("heatmap panel ends"),
+            size: (heatmap.panel_count().max(1) * 4) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let counts = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("heatmap counts"),
+            size: counts_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("heatmap readback"),
+            size: counts_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Ok(GpuCounter {
+            device,
+            queue,
+            pipeline,
+            params,
+            ends,
+            counts,
+            readback,
+            frame: None,
+        })
+    }
+
+    /// Make sure there's a frame texture of the given size.
+    fn prepare_texture(&mut self, width: u32, height: u32) {
+        if self.frame.as_ref().is_some_and(|frame| frame.width == width && frame.height == height) {
+            return;
+        }
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("frame"),
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("heatmap"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&view) },
+                wgpu::BindGroupEntry { binding: 1, resource: self.params.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: self.counts.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 3, resource: self.ends.as_entire_binding() },
+            ],
+        });
+        self.frame = Some(FrameTexture { width, height, texture, bind_group });
+    }
+
+    /// Replace the counts in `heatmap` with the colours of `frame_copy`, shared out
+    /// between panels by `layout`, after converting them into sRGB with `gamut`.
+    pub fn count(&mut self, frame_copy: &FrameCopy, layout: &PanelLayout, heatmap: &mut Heatmap, gamut: Option<&GamutConversion>) -> Result<(), Box<dyn Error>> {
+        if frame_copy.color_type != ColorType::Rgba8 {
+            return Err(format!("Cannot count {:?} frames on the GPU", frame_copy.color_type).into());
+        }
+        let (left, top, right, bottom) = layout.bounds;
+        let steps = heatmap.steps();
+        let sizes = heatmap.sizes();
+        let params = [
+            layout.width, layout.height, left, top, right, bottom,
+            layout.panel_count() as u32, SKIP_PIXEL as u32 + 1, u32::from(layout.transform), frame_copy.y_invert as u32,
+            steps[0], steps[1], steps[2], sizes[0] as u32, sizes[1] as u32, sizes[2] as u32,
+        ];
+        let mut params: Vec<u8> = params.iter().flat_map(|value| value.to_ne_bytes()).collect();
+        // Each row of the matrix is padded out to a vec4, followed by whether to use it.
+        let matrix = gamut.map_or([[0.0; 3]; 3], GamutConversion::matrix);
+        params.extend(matrix.iter().flat_map(|row| [row[0], row[1], row[2], 0.0]).flat_map(f32::to_ne_bytes));
+        params.extend([gamut.is_some() as u32, 0, 0, 0].iter().flat_map(|value| value.to_ne_bytes()));
+        self.queue.write_buffer(&self.params, 0, &params);
+        let ends: Vec<u8> = layout.ends.iter().flat_map(|end| end.to_ne_bytes()).collect();
+        self.queue.write_buffer(&self.ends, 0, &ends);
+
+        let (width, height) = (frame_copy.width, frame_copy.height);
+        self.prepare_texture(width, height);
+        let frame = self.frame.as_ref().unwrap();
+        let size = wgpu::Extent3d { width, height, depth_or_array_layers: 1 };
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &frame.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &frame_copy.data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(frame_copy.stride),
+                rows_per_image: Some(height),
+            },
+            size,
+        );
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("heatmap") });
+        encoder.clear_buffer(&self.counts, 0, None);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: Some("heatmap"), timestamp_writes: None });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &frame.bind_group, &[]);
+            let columns = width.div_ceil(SKIP_PIXEL as u32 + 1);
+            pass.dispatch_workgroups(columns.div_ceil(WORKGROUP_SIZE), height.div_ceil(WORKGROUP_SIZE), 1);
+        }
+        encoder.copy_buffer_to_buffer(&self.counts, 0, &self.readback, 0, self.counts.size());
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = self.readback.slice(..);
+        let (tx, rx) = channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = tx.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        rx.recv()??;
+        heatmap.load(slice.get_mapped_range().chunks_exact(4).map(|count| u32::from_ne_bytes([count[0], count[1], count[2], count[3]])));
+        self.readback.unmap();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::visual::heatmap::HeatmapConfig;
+    use crate::visual::prominent_color::FrameHeatmap;
+    use crate::visual::capture::FrameTransform;
+
+    #[test]
+    #[ignore = "needs a GPU, run with --ignored"]
+    fn test_gpu_matches_cpu() {
+        let image = image::open("samples/colortray.png").unwrap();
+        let frame = FrameCopy {
+            width: image.width(),
+            height: image.height(),
+            stride: image.width() * 4,
+            color_type: ColorType::Rgba8,
+            data: image.to_rgba8().into_raw(),
+            transform: FrameTransform::Normal,
+            y_invert: false,
+            damage: None,
+            captured: Instant::now(),
+        };
+        let mut cpu = FrameHeatmap::new(Heatmap::new(4, &HeatmapConfig::default()));
+        cpu.update(&frame);
+
+        let mut heatmap = Heatmap::new(4, &HeatmapConfig::default());
+        let mut gpu = GpuCounter::new(&heatmap).expect("No GPU to count on");
+        gpu.count(&frame, &PanelLayout::new(&frame, &[1.0; 4]), &mut heatmap, None).unwrap();
+        for panel in 0..4 {
+            assert!(heatmap.buckets(panel).eq(cpu.heatmap().buckets(panel)), "Panel {} should match the CPU", panel);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use colors_transform::{Color, Hsl};
+use serde::Deserialize;
+
+fn default_hue_step() -> u32 {
+    10
+}
+
+fn default_saturation_step() -> u32 {
+    5
+}
+
+fn default_lightness_step() -> u32 {
+    5
+}
+
+/// How finely colours are split into buckets when finding the most prominent one.
+/// Coarser buckets are faster, finer buckets flicker less on gradients.
+#[derive(Deserialize, Debug, Clone)]
+pub struct HeatmapConfig {
+    /// Degrees of hue per bucket.
+    #[serde(default = "default_hue_step")]
+    pub hue_step: u32,
+    /// Percent of saturation per bucket.
+    #[serde(default = "default_saturation_step")]
+    pub saturation_step: u32,
+    /// Percent of lightness per bucket.
+    #[serde(default = "default_lightness_step")]
+    pub lightness_step: u32,
+    /// Count colours with a compute shader, which needs the `gpu` build feature.
+    #[serde(default)]
+    pub gpu: bool,
+    /// Count in 16 bits, to save memory. Set by the memory budget rather than here.
+    #[serde(skip)]
+    pub compact: bool,
+}
+
+impl Default for HeatmapConfig {
+    fn default() -> Self {
+        HeatmapConfig {
+            hue_step: default_hue_step(),
+            saturation_step: default_saturation_step(),
+            lightness_step: default_lightness_step(),
+            gpu: false,
+            compact: false,
+        }
+    }
+}
+
+/// A bucket of similar colours in a [`Heatmap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket(usize);
+
+/// The count of every bucket, in 16 bits each when memory is tight. 16 bit counts stop
+/// at `u16::MAX`.
+enum Counts {
+    Wide(Vec<u32>),
+    Compact(Vec<u16>),
+}
+
+impl Counts {
+    fn get(&self, index: usize) -> Option<u32> {
+        match self {
+            Counts::Wide(counts) => counts.get(index).copied(),
+            Counts::Compact(counts) => counts.get(index).map(|count| *count as u32),
+        }
+    }
+
+    fn set(&mut self, index: usize, count: u32) {
+        match self {
+            Counts::Wide(counts) => counts[index] = count,
+            Counts::Compact(counts) => counts[index] = count.min(u16::MAX as u32) as u16,
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    fn len(&self) -> usize {
+        match self {
+            Counts::Wide(counts) => counts.len(),
+            Counts::Compact(counts) => counts.len(),
+        }
+    }
+}
+
+/// How often each bucket of colours has been seen in each panel's region.
+pub struct Heatmap {
+    steps: [u32; 3],
+    /// Number of hue, saturation and lightness buckets.
+    sizes: [usize; 3],
+    panel_count: usize,
+    counts: Counts,
+}
+
+impl Heatmap {
+    pub fn new(panel_count: usize, config: &HeatmapConfig) -> Self {
+        let steps = [config.hue_step.max(1), config.saturation_step.max(1), config.lightness_step.max(1)];
+        let sizes = [360 / steps[0] as usize + 1, 100 / steps[1] as usize + 1, 100 / steps[2] as usize + 1];
+        let len = panel_count * sizes[0] * sizes[1] * sizes[2];
+        Heatmap {
+            steps,
+            sizes,
+            panel_count,
+            counts: if config.compact { Counts::Compact(vec![0; len]) } else { Counts::Wide(vec![0; len]) },
+        }
+    }
+
+    pub fn panel_count(&self) -> usize {
+        self.panel_count
+    }
+
+    /// Degrees of hue, and percent of saturation and lightness, per bucket.
+    #[cfg(feature = "gpu")]
+    pub fn steps(&self) -> [u32; 3] {
+        self.steps
+    }
+
+    /// Number of hue, saturation and lightness buckets.
+    #[cfg(feature = "gpu")]
+    pub fn sizes(&self) -> [usize; 3] {
+        self.sizes
+    }
+
+    pub fn buckets_per_panel(&self) -> usize {
+        self.sizes[0] * self.sizes[1] * self.sizes[2]
+    }
+
+    /// The bucket a colour falls into.
+    pub fn bucket(&self, hsl: &Hsl) -> Bucket {
+        let index = |value: f32, axis: usize| ((value.max(0.0) as usize) / self.steps[axis] as usize).min(self.sizes[axis] - 1);
+        let (h, s, l) = (index(hsl.get_hue(), 0), index(hsl.get_saturation(), 1), index(hsl.get_lightness(), 2));
+        Bucket((h * self.sizes[1] + s) * self.sizes[2] + l)
+    }
+
+    /// The colour at the start of a bucket.
+    pub fn color(&self, bucket: Bucket) -> Hsl {
+        let l = bucket.0 % self.sizes[2];
+        let s = bucket.0 / self.sizes[2] % self.sizes[1];
+        let h = bucket.0 / self.sizes[2] / self.sizes[1];
+        Hsl::from((h as u32 * self.steps[0]) as f32, (s as u32 * self.steps[1]) as f32, (l as u32 * self.steps[2]) as f32)
+    }
+
+    /// Count another sighting of a bucket in a panel's region, returning the new count.
+    pub fn add(&mut self, panel: usize, bucket: Bucket) -> u32 {
+        let index = panel * self.buckets_per_panel() + bucket.0;
+        let count = self.counts.get(index).unwrap_or(0).saturating_add(1);
+        self.counts.set(index, count);
+        self.counts.get(index).unwrap_or(0)
+    }
+
+    /// Forget a sighting of a bucket in a panel's region.
+    pub fn remove(&mut self, panel: usize, bucket: Bucket) {
+        let index = panel * self.buckets_per_panel() + bucket.0;
+        let count = self.counts.get(index).unwrap_or(0).saturating_sub(1);
+        self.counts.set(index, count);
+    }
+
+    /// Replace every count, panel by panel, e.g. with counts made elsewhere.
+    #[cfg(feature = "gpu")]
+    pub fn load(&mut self, counts: impl IntoIterator<Item = u32>) {
+        for (index, loaded) in counts.into_iter().take(self.counts.len()).enumerate() {
+            self.counts.set(index, loaded);
+        }
+    }
+
+    /// Forget everything that has been seen.
+    pub fn clear(&mut self) {
+        match &mut self.counts {
+            Counts::Wide(counts) => counts.fill(0),
+            Counts::Compact(counts) => counts.fill(0),
+        }
+    }
+
+    pub fn count(&self, panel: usize, bucket: Bucket) -> u32 {
+        self.counts.get(panel * self.buckets_per_panel() + bucket.0).unwrap_or(0)
+    }
+
+    /// Every bucket of a panel's region, with its count.
+    pub fn buckets(&self, panel: usize) -> impl Iterator<Item = (Bucket, u32)> + '_ {
+        let per_panel = self.buckets_per_panel();
+        (0..per_panel).map(move |index| (Bucket(index), self.counts.get(panel * per_panel + index).unwrap_or(0)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let mut heatmap = Heatmap::new(2, &HeatmapConfig::default());
+        let bucket = heatmap.bucket(&Hsl::from(243.0, 87.0, 41.0));
+        let color = heatmap.color(bucket);
+        assert_eq!((color.get_hue(), color.get_saturation(), color.get_lightness()), (240.0, 85.0, 40.0));
+        assert_eq!(heatmap.add(1, bucket), 1);
+        assert_eq!(heatmap.add(1, bucket), 2);
+        assert_eq!(heatmap.count(0, bucket), 0);
+        assert_eq!(heatmap.buckets(1).map(|(_, count)| count).sum::<u32>(), 2);
+
+        let coarse = Heatmap::new(1, &HeatmapConfig { hue_step: 30, saturation_step: 25, lightness_step: 25, ..HeatmapConfig::default() });
+        let color = coarse.color(coarse.bucket(&Hsl::from(359.0, 100.0, 100.0)));
+        assert_eq!((color.get_hue(), color.get_saturation(), color.get_lightness()), (330.0, 100.0, 100.0));
+    }
+
+    #[test]
+    fn test_compact_counts_saturate() {
+        let mut heatmap = Heatmap::new(1, &HeatmapConfig { compact: true, ..HeatmapConfig::default() });
+        let bucket = heatmap.bucket(&Hsl::from(120.0, 50.0, 50.0));
+        for _ in 0..u16::MAX {
+            heatmap.add(0, bucket);
+        }
+        assert_eq!(heatmap.add(0, bucket), u16::MAX as u32);
+        heatmap.remove(0, bucket);
+        assert_eq!(heatmap.count(0, bucket), u16::MAX as u32 - 1);
+    }
+}
@@ -0,0 +1,125 @@
+// Counts the colours of a frame into a heatmap, matching the CPU path in
+// prominent_color.rs. LIGHTNESS_MIN, LIGHTNESS_MAX and SATURATION_MIN are
+// prepended from the Rust constants.
+
+struct Params {
+    width: u32,
+    height: u32,
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+    panel_count: u32,
+    step: u32,
+    transform: u32,
+    y_invert: u32,
+    hue_step: u32,
+    saturation_step: u32,
+    lightness_step: u32,
+    hue_buckets: u32,
+    saturation_buckets: u32,
+    lightness_buckets: u32,
+    // Rows of the matrix taking linear light in the output's gamut to linear sRGB.
+    gamut: array<vec4<f32>, 3>,
+    convert_gamut: u32,
+}
+
+@group(0) @binding(0) var frame: texture_2d<f32>;
+@group(0) @binding(1) var<uniform> params: Params;
+@group(0) @binding(2) var<storage, read_write> counts: array<atomic<u32>>;
+// Where each panel's region ends, in the same units as logical_x.
+@group(0) @binding(3) var<storage, read> ends: array<u32>;
+
+// The horizontal position of a pixel once the output's transform is applied.
+fn logical_x(x: u32, y: u32) -> u32 {
+    let max_x = params.width - 1u;
+    let max_y = params.height - 1u;
+    switch params.transform {
+        case 1u, 5u: { return y; }
+        case 2u, 4u: { return max_x - x; }
+        case 3u, 7u: { return max_y - y; }
+        default: { return x; }
+    }
+}
+
+// The first panel whose region ends after the pixel, or the last panel.
+fn panel_index(x: u32) -> u32 {
+    var low = 0u;
+    var high = params.panel_count - 1u;
+    while low < high {
+        let mid = (low + high) / 2u;
+        if x < ends[mid] {
+            high = mid;
+        } else {
+            low = mid + 1u;
+        }
+    }
+    return low;
+}
+
+fn srgb_decode(value: vec3<f32>) -> vec3<f32> {
+    return select(pow((value + 0.055) / 1.055, vec3<f32>(2.4)), value / 12.92, value <= vec3<f32>(0.04045));
+}
+
+fn srgb_encode(value: vec3<f32>) -> vec3<f32> {
+    return select(1.055 * pow(value, vec3<f32>(1.0 / 2.4)) - 0.055, value * 12.92, value <= vec3<f32>(0.0031308));
+}
+
+// Convert a pixel from the output's gamut into sRGB, rounded to 8 bits like the CPU
+// path in gamut.rs.
+fn to_srgb(rgb: vec3<f32>) -> vec3<f32> {
+    let linear = srgb_decode(rgb);
+    let converted = vec3<f32>(dot(params.gamut[0].xyz, linear), dot(params.gamut[1].xyz, linear), dot(params.gamut[2].xyz, linear));
+    return round(srgb_encode(clamp(converted, vec3<f32>(0.0), vec3<f32>(1.0))) * 255.0) / 255.0;
+}
+
+fn bucket_index(value: f32, step: u32, buckets: u32) -> u32 {
+    return min(u32(max(value, 0.0)) / step, buckets - 1u);
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let x = id.x * params.step;
+    let y = id.y;
+    if x < params.left || x > params.right || y < params.top || y > params.bottom {
+        return;
+    }
+    let row = select(y, params.height - 1u - y, params.y_invert != 0u);
+    var rgb = textureLoad(frame, vec2<u32>(x, row), 0).rgb;
+    if params.convert_gamut != 0u {
+        rgb = to_srgb(rgb);
+    }
+
+    let high = max(max(rgb.r, rgb.g), rgb.b);
+    let low = min(min(rgb.r, rgb.g), rgb.b);
+    let lightness = (high + low) / 2.0;
+    // Greys have no saturation, so are always rejected.
+    if high == low {
+        return;
+    }
+    let delta = high - low;
+    let saturation = select(delta / (high + low), delta / (2.0 - high - low), lightness > 0.5);
+    var hue: f32;
+    if rgb.r == high {
+        hue = (rgb.g - rgb.b) / delta + select(0.0, 6.0, rgb.g < rgb.b);
+    } else if rgb.g == high {
+        hue = (rgb.b - rgb.r) / delta + 2.0;
+    } else {
+        hue = (rgb.r - rgb.g) / delta + 4.0;
+    }
+    let h = hue * 60.0;
+    let s = saturation * 100.0;
+    let l = lightness * 100.0;
+
+    // Reject any really dark colours.
+    if LIGHTNESS_MAX < l || l < LIGHTNESS_MIN || s < SATURATION_MIN {
+        return;
+    }
+
+    let panel = panel_index(logical_x(x, y));
+    let bucket = (bucket_index(h, params.hue_step, params.hue_buckets) * params.saturation_buckets
+        + bucket_index(s, params.saturation_step, params.saturation_buckets)) * params.lightness_buckets
+        + bucket_index(l, params.lightness_step, params.lightness_buckets);
+    let per_panel = params.hue_buckets * params.saturation_buckets * params.lightness_buckets;
+    atomicAdd(&counts[panel * per_panel + bucket], 1u);
+}
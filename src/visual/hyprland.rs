@@ -0,0 +1,124 @@
+use std::env;
+use std::error::Error;
+use std::io::{BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::visual::output::OutputPositioning;
+
+#[derive(Deserialize, Debug)]
+struct HyprlandClient {
+    class: String,
+    title: String,
+    at: [i32; 2],
+    size: [i32; 2],
+    monitor: i64,
+    #[serde(default)]
+    hidden: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct HyprlandMonitor {
+    id: i64,
+    name: String,
+    x: i32,
+    y: i32,
+    /// Size of the monitor's mode in buffer pixels, before the transform.
+    width: i32,
+    height: i32,
+    scale: f32,
+    /// The `wl_output` transform, where odd values turn the monitor on its side.
+    transform: i32,
+}
+
+impl HyprlandMonitor {
+    /// The monitor's size in the logical coordinates windows are placed in.
+    fn logical_size(&self) -> (i32, i32) {
+        let (width, height) = if self.transform % 2 == 1 { (self.height, self.width) } else { (self.width, self.height) };
+        let logical = OutputPositioning { x: 0, y: 0, width, height }.to_logical(self.scale);
+        (logical.width, logical.height)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct HyprlandWorkspace {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HyprlandWindow {
+    /// Empty when no window is focused.
+    #[serde(default)]
+    class: String,
+}
+
+/// Whether we're running under Hyprland.
+pub fn running() -> bool {
+    env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some()
+}
+
+/// Location of one of Hyprland's sockets, if we're running under Hyprland.
+fn socket_path(name: &str) -> Option<PathBuf> {
+    let signature = env::var("HYPRLAND_INSTANCE_SIGNATURE").ok()?;
+    let runtime_path = env::var("XDG_RUNTIME_DIR").ok()
+        .map(|dir| PathBuf::from(dir).join("hypr").join(&signature).join(name));
+    match runtime_path {
+        Some(path) if path.exists() => Some(path),
+        // Older releases kept the sockets in /tmp.
+        _ => Some(PathBuf::from("/tmp/hypr").join(&signature).join(name)),
+    }
+}
+
+fn request<T: for<'de> Deserialize<'de>>(command: &str) -> Result<T, Box<dyn Error>> {
+    let path = socket_path(".socket.sock").ok_or("HYPRLAND_INSTANCE_SIGNATURE is not set, is Hyprland running?")?;
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("j/{}", command).as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+/// The name of the focused workspace, and the class of the focused window if there is
+/// one.
+pub fn focus() -> Result<(String, Option<String>), Box<dyn Error>> {
+    let workspace: HyprlandWorkspace = request("activeworkspace")?;
+    let window: HyprlandWindow = request("activewindow")?;
+    Ok((workspace.name, Some(window.class).filter(|class| !class.is_empty())))
+}
+
+/// Hyprland's event socket, which sends a line such as `workspace>>2` for everything
+/// that happens.
+pub fn events() -> Result<BufReader<UnixStream>, Box<dyn Error>> {
+    let path = socket_path(".socket2.sock").ok_or("HYPRLAND_INSTANCE_SIGNATURE is not set, is Hyprland running?")?;
+    Ok(BufReader::new(UnixStream::connect(path)?))
+}
+
+/// Find the region of `output_name` covered by the first visible window whose class or
+/// title matches `window` (ignoring case). The region is in the output's logical
+/// coordinates, clipped to the output.
+pub fn window_region(window: &str, output_name: &str) -> Result<Option<OutputPositioning>, Box<dyn Error>> {
+    let monitors: Vec<HyprlandMonitor> = request("monitors")?;
+    let Some(monitor) = monitors.iter().find(|monitor| monitor.name == output_name) else {
+        return Ok(None);
+    };
+    let clients: Vec<HyprlandClient> = request("clients")?;
+    let client = clients.iter().find(|client| {
+        !client.hidden
+            && client.monitor == monitor.id
+            && (client.class.eq_ignore_ascii_case(window) || client.title.eq_ignore_ascii_case(window))
+    });
+    let (monitor_width, monitor_height) = monitor.logical_size();
+    Ok(client.map(|client| {
+        let (left, top) = (client.at[0] - monitor.x, client.at[1] - monitor.y);
+        let x = left.clamp(0, monitor_width - 1);
+        let y = top.clamp(0, monitor_height - 1);
+        OutputPositioning {
+            x,
+            y,
+            width: ((left + client.size[0]).min(monitor_width) - x).max(1),
+            height: ((top + client.size[1]).min(monitor_height) - y).max(1),
+        }
+    }))
+}
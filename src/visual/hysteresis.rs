@@ -0,0 +1,143 @@
+use colors_transform::Hsl;
+use serde::Deserialize;
+
+use crate::visual::heatmap::Heatmap;
+
+fn default_margin() -> f32 {
+    0.1
+}
+
+fn default_frames() -> u32 {
+    3
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HysteresisConfig {
+    /// How much more prominent, as a fraction, a new colour must be than the current one
+    /// before it can take over.
+    #[serde(default = "default_margin")]
+    pub margin: f32,
+    /// How many frames in a row a new colour must win by `margin` to take over.
+    #[serde(default = "default_frames")]
+    pub frames: u32,
+}
+
+impl Default for HysteresisConfig {
+    fn default() -> Self {
+        HysteresisConfig {
+            margin: default_margin(),
+            frames: default_frames(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct PanelState {
+    incumbent: Option<Hsl>,
+    challenger: Option<(Hsl, u32)>,
+}
+
+/// Keeps each panel on its current colour until another colour is clearly and
+/// consistently more prominent, so near ties don't flip back and forth every frame.
+pub struct ColorHysteresis {
+    config: HysteresisConfig,
+    panels: Vec<PanelState>,
+}
+
+impl ColorHysteresis {
+    pub fn new(config: HysteresisConfig) -> Self {
+        ColorHysteresis {
+            config,
+            panels: Vec::new(),
+        }
+    }
+
+    /// Filter the prominent colours of a frame, using the heatmap they were picked from
+    /// to compare how prominent each colour is.
+    pub fn apply(&mut self, colors: &mut [Hsl], heatmap: &Heatmap) {
+        self.panels.resize(colors.len(), PanelState::default());
+        let bucket = |hsl: &Hsl| heatmap.bucket(hsl);
+        let score = |panel: usize, hsl: &Hsl| heatmap.count(panel, heatmap.bucket(hsl)) as f32;
+
+        for (panel, (color, state)) in colors.iter_mut().zip(self.panels.iter_mut()).enumerate() {
+            let Some(incumbent) = state.incumbent else {
+                state.incumbent = Some(*color);
+                continue;
+            };
+            if bucket(color) == bucket(&incumbent) {
+                state.challenger = None;
+                continue;
+            }
+            // A colour that's no longer on screen at all has nothing to defend, such as
+            // when the screen goes black and the black isn't counted.
+            if score(panel, &incumbent) == 0.0 {
+                state.incumbent = Some(*color);
+                state.challenger = None;
+                continue;
+            }
+            if score(panel, color) <= score(panel, &incumbent) * (1.0 + self.config.margin) {
+                state.challenger = None;
+                *color = incumbent;
+                continue;
+            }
+            let wins = match state.challenger {
+                Some((challenger, wins)) if bucket(&challenger) == bucket(color) => wins + 1,
+                _ => 1,
+            };
+            if wins >= self.config.frames {
+                state.incumbent = Some(*color);
+                state.challenger = None;
+            } else {
+                state.challenger = Some((*color, wins));
+                *color = incumbent;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use colors_transform::Color;
+    use crate::visual::heatmap::HeatmapConfig;
+
+    #[test]
+    fn test_hysteresis() {
+        let mut hysteresis = ColorHysteresis::new(HysteresisConfig { margin: 0.1, frames: 2 });
+        let red = Hsl::from(0.0, 50.0, 50.0);
+        let blue = Hsl::from(240.0, 50.0, 50.0);
+        let mut heatmap = Heatmap::new(1, &HeatmapConfig::default());
+        let mut run = |heatmap: &Heatmap, color: Hsl| {
+            let mut colors = [color];
+            hysteresis.apply(&mut colors, heatmap);
+            colors[0].get_hue()
+        };
+        let see = |heatmap: &mut Heatmap, color: &Hsl, times: u32| {
+            let bucket = heatmap.bucket(color);
+            (0..times).for_each(|_| { heatmap.add(0, bucket); });
+        };
+
+        see(&mut heatmap, &red, 100);
+        assert_eq!(run(&heatmap, red), 0.0);
+        // A near tie doesn't switch.
+        see(&mut heatmap, &blue, 105);
+        assert_eq!(run(&heatmap, blue), 0.0);
+        assert_eq!(run(&heatmap, blue), 0.0);
+        // A clear winner needs to hold on for two frames.
+        see(&mut heatmap, &blue, 45);
+        assert_eq!(run(&heatmap, blue), 0.0);
+        assert_eq!(run(&heatmap, blue), 240.0);
+
+        // Once the screen goes black, nothing is left to hold on to.
+        let black = Hsl::from(0.0, 0.0, 0.0);
+        let mut empty = Heatmap::new(1, &HeatmapConfig::default());
+        let mut colors = [black];
+        hysteresis.apply(&mut colors, &empty);
+        assert_eq!(colors[0].get_lightness(), 0.0);
+        // Nor when the old colour has faded from the heatmap.
+        see(&mut empty, &red, 1);
+        let mut colors = [red];
+        hysteresis.apply(&mut colors, &empty);
+        assert_eq!(colors[0].get_hue(), 0.0);
+    }
+}
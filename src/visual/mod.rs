@@ -1,3 +1,330 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use colors_transform::Color;
+#[cfg(feature = "wayland")]
+use wayland_client::{Connection, QueueHandle};
+#[cfg(feature = "wayland")]
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+#[cfg(feature = "wayland")]
+use wayland_client::protocol::wl_registry;
+
+use crate::crash;
+use crate::notify;
+use crate::effects::ScreenColors;
+use crate::log_throttle::warn_throttled;
+use crate::power::{PowerSaver, GAMING_INTERVAL, IDLE_INTERVAL};
+use crate::stats::{Stage, SESSION};
+
+#[cfg(feature = "wayland")]
 pub mod backend;
+pub mod capture;
+pub mod command;
+pub mod gamut;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod heatmap;
+#[cfg(feature = "wayland")]
+pub mod hyprland;
+pub mod hysteresis;
 pub mod prominent_color;
-pub mod output;
\ No newline at end of file
+#[cfg(feature = "wayland")]
+pub mod output;
+pub mod pixels;
+#[cfg(feature = "wayland")]
+pub mod region;
+pub mod snapshot;
+#[cfg(feature = "wayland")]
+pub mod swatch;
+
+use capture::FrameCopy;
+use capture::FrameSource;
+use gamut::Gamut;
+use heatmap::{Heatmap, HeatmapConfig};
+use prominent_color::FrameHeatmap;
+use hysteresis::{ColorHysteresis, HysteresisConfig};
+#[cfg(feature = "wayland")]
+use output::OutputInfo;
+#[cfg(feature = "wayland")]
+use region::CaptureRegion;
+
+/**
+ * If capturing a frame takes longer than this, assume the compositor has stopped
+ * responding and connect again.
+ */
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ * How often the watchdog checks on the capture thread.
+ */
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * How many times in a row capture can fail before sending a desktop notification.
+ */
+const CAPTURE_FAILURE_LIMIT: u32 = 5;
+
+/**
+ * Capture that lasts this long before failing is treated as having recovered.
+ */
+const CAPTURE_STABLE: Duration = Duration::from_secs(60);
+
+/// How captured frames are turned into a colour for each panel.
+#[derive(Debug, Clone)]
+pub struct AnalysisConfig {
+    /// How wide each panel is, which sets how much of the picture it takes its colour
+    /// from.
+    pub panel_widths: Vec<f32>,
+    /// The gamut of the output being captured.
+    pub gamut: Gamut,
+    pub heatmap: HeatmapConfig,
+    pub hysteresis: HysteresisConfig,
+    /// Frames older than this by the time they're read are skipped rather than
+    /// analysed.
+    pub max_age: Duration,
+    /// Keep one in this many pixels along each row and column of captured frames, to
+    /// save memory.
+    pub downscale: u32,
+}
+
+/// Turns captured frames into the colours of each panel's region.
+pub struct ScreenAnalysis {
+    heatmap: FrameHeatmap,
+    hysteresis: ColorHysteresis,
+}
+
+impl ScreenAnalysis {
+    pub fn new(analysis: &AnalysisConfig) -> Self {
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(analysis.panel_widths.len(), &analysis.heatmap))
+            .with_widths(analysis.panel_widths.clone())
+            .with_gamut(analysis.gamut);
+        if analysis.heatmap.gpu {
+            heatmap = heatmap.with_gpu();
+        }
+        ScreenAnalysis {
+            heatmap,
+            hysteresis: ColorHysteresis::new(analysis.hysteresis.clone()),
+        }
+    }
+
+    pub fn analyse(&mut self, frame_copy: &FrameCopy) -> ScreenColors {
+        let mut primary = self.heatmap.update(frame_copy);
+        self.hysteresis.apply(&mut primary, self.heatmap.heatmap());
+        let accent = prominent_color::determine_accent_colors(self.heatmap.heatmap(), &primary);
+        ScreenColors { primary, accent }
+    }
+}
+
+#[cfg(feature = "wayland")]
+struct AppState;
+
+#[cfg(feature = "wayland")]
+impl wayland_client::Dispatch<wl_registry::WlRegistry, GlobalListContents> for AppState {
+    fn event(
+        _: &mut AppState,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+
+/// Connect to the compositor and set up capture of the chosen output.
+#[cfg(feature = "wayland")]
+pub fn connect(output_name: Option<&str>, window: Option<String>, crop: Option<CaptureRegion>, downscale: u32) -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+    let conn = Connection::connect_to_env()?;
+    let (globals, _) = registry_queue_init::<AppState>(&conn)?;
+    let out: OutputInfo = if let Some(output_name_result) = output_name {
+        output::get_output(
+            output_name_result.trim().to_string(),
+            output::get_all_outputs(&globals, &conn),
+        )
+    } else {
+        output::get_all_outputs(&globals, &conn)
+            .first()
+            .unwrap()
+            .clone()
+    };
+    log::info!("Capturing output {} (transform {:?}, scale {})", out.name, out.transform, out.scale);
+
+    Ok(Box::new(backend::WaylandCapture::new(globals, conn, out, window, crop, downscale)?))
+}
+
+/// Every output the compositor advertises.
+#[cfg(feature = "wayland")]
+pub fn list_outputs() -> Result<Vec<OutputInfo>, Box<dyn Error>> {
+    let conn = Connection::connect_to_env()?;
+    let (globals, _) = registry_queue_init::<AppState>(&conn)?;
+    Ok(output::get_all_outputs(&globals, &conn))
+}
+
+/// Capture the chosen output with wlr-screencopy, sending the colour of each panel's
+/// region whenever it changes.
+#[cfg(feature = "wayland")]
+pub fn configure_display(pause_duration: Duration, analysis: AnalysisConfig, output_name: Option<String>, window: Option<String>, crop: Option<CaptureRegion>, snapshot_requested: Arc<AtomicBool>, power: Arc<PowerSaver>) -> Receiver<ScreenColors> {
+    let downscale = analysis.downscale;
+    analyse_frames(move || connect(output_name.as_deref(), window.clone(), crop, downscale), pause_duration, analysis, snapshot_requested, power)
+}
+
+/// Continuously capture frames on a new thread, sending the prominent colour of each
+/// panel's region whenever it changes. Setting `snapshot_requested` saves the next
+/// frame along with what was made of it. Capture slows down while `power` reports
+/// that nothing is happening.
+///
+/// A watchdog calls `connect` to set up capture again whenever capturing fails, or
+/// stops making progress because the compositor has stopped responding.
+pub fn analyse_frames<F>(connect: F, pause_duration: Duration, analysis: AnalysisConfig, snapshot_requested: Arc<AtomicBool>, power: Arc<PowerSaver>) -> Receiver<ScreenColors>
+where
+    F: Fn() -> Result<Box<dyn FrameSource>, Box<dyn Error>> + Send + 'static,
+{
+    let (tx, rx) = channel();
+
+    crash::spawn("capture watchdog", move || {
+        let mut failures = CaptureFailures::default();
+        loop {
+            let source = match connect() {
+                Ok(source) => source,
+                Err(err) => {
+                    warn_throttled!("Failed to set up screen capture: {}", err);
+                    failures.record(Instant::now(), &err.to_string());
+                    thread::sleep(CAPTURE_TIMEOUT);
+                    continue;
+                }
+            };
+            let started = Instant::now();
+            let health = Arc::new(CaptureHealth {
+                heartbeat: Mutex::new(Instant::now()),
+                abandoned: AtomicBool::new(false),
+            });
+            let capture = {
+                let (tx, health) = (tx.clone(), health.clone());
+                let (analysis, snapshot_requested, power) = (analysis.clone(), snapshot_requested.clone(), power.clone());
+                crash::spawn("screen capture", move || capture_frames(source, tx, pause_duration, analysis, snapshot_requested, power, health))
+            };
+
+            loop {
+                thread::sleep(WATCHDOG_INTERVAL);
+                if capture.is_finished() {
+                    let reason = match capture.join() {
+                        // Nobody is listening any more.
+                        Ok(CaptureEnd::Closed) => return,
+                        Ok(CaptureEnd::Failed) => "Screen capture failed",
+                        Err(_) => "Screen capture panicked",
+                    };
+                    log::warn!("{}, reconnecting", reason);
+                    failures.record(started, reason);
+                    break;
+                }
+                if health.heartbeat.lock().unwrap().elapsed() > CAPTURE_TIMEOUT + IDLE_INTERVAL {
+                    // There's no way to interrupt a blocking dispatch, so leave the stuck thread
+                    // behind. If it ever wakes up it exits without sending anything.
+                    log::warn!("Screen capture stopped responding, reconnecting");
+                    health.abandoned.store(true, Ordering::Relaxed);
+                    failures.record(started, "Screen capture stopped responding");
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Counts capture failing again and again, so someone can be told once it's clear it
+/// isn't going to recover by itself.
+#[derive(Default)]
+struct CaptureFailures {
+    count: u32,
+}
+
+impl CaptureFailures {
+    /// Count a failure of capture set up at `started`. Capture that ran for a while
+    /// before failing starts the count again.
+    fn record(&mut self, started: Instant, reason: &str) {
+        SESSION.capture_restarted();
+        if started.elapsed() > CAPTURE_STABLE {
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count == CAPTURE_FAILURE_LIMIT {
+            log::error!("Screen capture has failed {} times in a row", self.count);
+            notify::notify("leafpipe can't capture the screen", &format!("{}, and has failed {} times in a row. Still retrying.", reason, self.count));
+        }
+    }
+}
+
+enum CaptureEnd {
+    /// The receiving end of the colour channel went away.
+    Closed,
+    Failed,
+}
+
+/// Shared between a capture thread and the watchdog keeping an eye on it.
+struct CaptureHealth {
+    /// When the capture thread last started on a frame.
+    heartbeat: Mutex<Instant>,
+    /// Set by the watchdog once it has given up on the thread.
+    abandoned: AtomicBool,
+}
+
+fn capture_frames(mut source: Box<dyn FrameSource>, tx: Sender<ScreenColors>, pause_duration: Duration, analysis: AnalysisConfig, snapshot_requested: Arc<AtomicBool>, power: Arc<PowerSaver>, health: Arc<CaptureHealth>) -> CaptureEnd {
+    log::info!("Capturing frames");
+    let mut last_value = 0.0f32;
+    let mut screen_analysis = ScreenAnalysis::new(&analysis);
+    loop {
+        let start = Instant::now();
+        *health.heartbeat.lock().unwrap() = start;
+        if power.is_standby() {
+            thread::sleep(IDLE_INTERVAL);
+            continue;
+        }
+        let frame_copy = match source.capture_frame() {
+            Ok(frame_copy) => frame_copy,
+            Err(err) => {
+                log::warn!("Failed to capture frame: {}", err);
+                return CaptureEnd::Failed;
+            }
+        };
+        if health.abandoned.load(Ordering::Relaxed) {
+            return CaptureEnd::Failed;
+        }
+        // The machine is struggling to keep up, so the next frame will be closer to
+        // what's on screen than this one.
+        if frame_copy.captured.elapsed() > analysis.max_age {
+            warn_throttled!("Skipping a frame captured {}ms ago", frame_copy.captured.elapsed().as_millis());
+            continue;
+        }
+        let analysis_start = Instant::now();
+        let colors = screen_analysis.analyse(&frame_copy);
+        SESSION.time(Stage::ScreenAnalysis, analysis_start.elapsed());
+        if snapshot_requested.swap(false, Ordering::Relaxed) {
+            match snapshot::save_snapshot(&frame_copy, &colors.primary, &analysis.panel_widths) {
+                Ok(path) => log::info!("Saved snapshot to {}", path.display()),
+                Err(err) => log::warn!("Failed to save snapshot: {}", err),
+            }
+        }
+        let value_hash: f32 = colors.primary.iter().chain(colors.accent.iter().flatten()).map(|f| f.get_hue() + f.get_lightness() + f.get_saturation()).sum();
+        if value_hash != last_value {
+            if tx.send(colors).is_err() {
+                return CaptureEnd::Closed;
+            }
+            last_value = value_hash;
+            power.screen_changed();
+        }
+        let idle = power.is_idle();
+        let gaming = power.is_gaming();
+        if idle || gaming || pause_duration.ge(&start.elapsed()) {
+            let interval = if idle { IDLE_INTERVAL } else if gaming { GAMING_INTERVAL } else { pause_duration };
+            let sleep_duration = interval.saturating_sub(start.elapsed());
+            if sleep_duration.ge(&Duration::ZERO) {
+                thread::sleep(sleep_duration);
+            }
+        }
+    }
+}
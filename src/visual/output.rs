@@ -2,8 +2,9 @@ use std::{process::exit, sync::Arc, sync::Mutex};
 use wayland_client::{
     delegate_noop,
     globals::GlobalList,
-    protocol::{wl_output, wl_output::WlOutput, wl_registry, wl_registry::WlRegistry},
+    protocol::{wl_output, wl_output::Transform, wl_output::WlOutput, wl_registry, wl_registry::WlRegistry},
     Connection, Dispatch, QueueHandle,
+    WEnum::Value,
 };
 use wayland_protocols::xdg::xdg_output::zv1::client::{
     zxdg_output_manager_v1::ZxdgOutputManagerV1, zxdg_output_v1, zxdg_output_v1::ZxdgOutputV1,
@@ -14,6 +15,27 @@ pub struct OutputInfo {
     pub wl_output: WlOutput,
     pub name: String,
     pub dimensions: OutputPositioning,
+    /// How the compositor rotates / flips the output's buffer for display.
+    pub transform: Transform,
+    /// Integer scale factor the compositor renders this output at.
+    pub scale: i32,
+    /// Size of the current mode in buffer pixels, before the transform.
+    pub mode: (i32, i32),
+}
+
+impl OutputInfo {
+    /// How many buffer pixels there are to each logical pixel, which isn't a whole
+    /// number with fractional scaling. Falls back to the integer scale if the output's
+    /// mode isn't known.
+    pub fn buffer_scale(&self) -> f32 {
+        let (mode_width, mode_height) = self.mode;
+        // The logical size is after the transform, the mode before.
+        let buffer_width = if transform_swaps_axes(self.transform) { mode_height } else { mode_width };
+        if buffer_width <= 0 || self.dimensions.width <= 0 {
+            return self.scale as f32;
+        }
+        buffer_width as f32 / self.dimensions.width as f32
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
@@ -24,6 +46,32 @@ pub struct OutputPositioning {
     pub height: i32,
 }
 
+impl OutputPositioning {
+    /// Scale every edge, rounding outwards so that nothing inside is lost.
+    fn scale_outwards(&self, scale: f32) -> OutputPositioning {
+        let x = (self.x as f32 * scale).floor() as i32;
+        let y = (self.y as f32 * scale).floor() as i32;
+        OutputPositioning {
+            x,
+            y,
+            width: ((self.x + self.width) as f32 * scale).ceil() as i32 - x,
+            height: ((self.y + self.height) as f32 * scale).ceil() as i32 - y,
+        }
+    }
+
+    /// The buffer pixels covering this logical area of an output with the given
+    /// [`OutputInfo::buffer_scale`].
+    pub fn to_buffer(&self, scale: f32) -> OutputPositioning {
+        self.scale_outwards(scale)
+    }
+
+    /// The logical area covering these buffer pixels of an output with the given
+    /// [`OutputInfo::buffer_scale`].
+    pub fn to_logical(&self, scale: f32) -> OutputPositioning {
+        self.scale_outwards(1.0 / scale)
+    }
+}
+
 impl Dispatch<WlRegistry, ()> for OutputCaptureState {
     fn event(
         _: &mut Self,
@@ -62,20 +110,24 @@ impl Dispatch<WlOutput, ()> for OutputCaptureState {
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
-        /* > The name event is sent after binding the output object. This event
-         * is only sent once per output object, and the name does not change
-         * over the lifetime of the wl_output global. */
-        if let wl_output::Event::Name { name } = event {
-            state.outputs.push(OutputInfo {
-                wl_output: wl_output.clone(),
-                name,
-                dimensions: OutputPositioning {
-                    x: 0,
-                    y: 0,
-                    width: 0,
-                    height: 0,
-                },
-            });
+        let output = state.output_mut(wl_output);
+        match event {
+            /* > The name event is sent after binding the output object. This event
+             * is only sent once per output object, and the name does not change
+             * over the lifetime of the wl_output global. */
+            wl_output::Event::Name { name } => {
+                output.name = name;
+            }
+            wl_output::Event::Geometry { transform: Value(transform), .. } => {
+                output.transform = transform;
+            }
+            wl_output::Event::Scale { factor } => {
+                output.scale = factor;
+            }
+            wl_output::Event::Mode { flags: Value(flags), width, height, .. } if flags.contains(wl_output::Mode::Current) => {
+                output.mode = (width, height);
+            }
+            _ => {}
         }
     }
 }
@@ -113,6 +165,27 @@ struct OutputCaptureState {
     outputs: Vec<OutputInfo>,
 }
 
+impl OutputCaptureState {
+    /// Find the info for an output, creating it if this is the first event for it.
+    fn output_mut(&mut self, wl_output: &WlOutput) -> &mut OutputInfo {
+        let index = match self.outputs.iter().position(|output| &output.wl_output == wl_output) {
+            Some(index) => index,
+            None => {
+                self.outputs.push(OutputInfo {
+                    wl_output: wl_output.clone(),
+                    name: String::new(),
+                    dimensions: OutputPositioning::default(),
+                    transform: Transform::Normal,
+                    scale: 1,
+                    mode: (0, 0),
+                });
+                self.outputs.len() - 1
+            }
+        };
+        &mut self.outputs[index]
+    }
+}
+
 pub fn get_all_outputs(globals: &GlobalList, conn: &Connection) -> Vec<OutputInfo> {
     // Connecting to wayland environment.
     let mut state = OutputCaptureState {
@@ -163,13 +236,32 @@ pub fn get_all_outputs(globals: &GlobalList, conn: &Connection) -> Vec<OutputInf
     data
 }
 
-/// Get a wl_output object from the output name.
-pub fn get_wloutput(name: String, outputs: Vec<OutputInfo>) -> WlOutput {
+/// Get an output from its name.
+pub fn get_output(name: String, outputs: Vec<OutputInfo>) -> OutputInfo {
     for output in outputs {
         if output.name == name {
-            return output.wl_output;
+            return output;
         }
     }
     log::error!("Error: No output of name \"{}\" was found", name);
     exit(1);
 }
+
+/// Whether a transform turns the output on its side, swapping its width and height.
+pub fn transform_swaps_axes(transform: Transform) -> bool {
+    matches!(transform, Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fractional_scale() {
+        let area = OutputPositioning { x: 101, y: 0, width: 333, height: 1440 };
+        // At 1.5x, odd logical edges fall between buffer pixels, so are rounded outwards.
+        assert_eq!(area.to_buffer(1.5), OutputPositioning { x: 151, y: 0, width: 500, height: 2160 });
+        assert_eq!(area.to_buffer(1.5).to_logical(1.5), OutputPositioning { x: 100, y: 0, width: 334, height: 1440 });
+        assert_eq!(area.to_buffer(1.0), area);
+    }
+}
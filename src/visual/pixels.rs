@@ -0,0 +1,73 @@
+use crate::visual::capture::FrameCopy;
+
+impl FrameCopy {
+    /// The RGB value of the pixel at `x`, `y`, counting rows from the top of the image
+    /// regardless of how the buffer is stored.
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 3]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let row = if self.y_invert { self.height - 1 - y } else { y };
+        let offset = row as usize * self.stride as usize + x as usize * self.color_type.bytes_per_pixel() as usize;
+        self.data.get(offset..offset + 3).map(|rgb| [rgb[0], rgb[1], rgb[2]])
+    }
+
+    /// Iterate over the pixels of the frame row by row as `(x, y, rgb)`, sampling one
+    /// pixel and then skipping the next `skip` along each row.
+    pub fn pixels(&self, skip: usize) -> Pixels<'_> {
+        Pixels {
+            frame: self,
+            step: skip as u32 + 1,
+            x: 0,
+            y: 0,
+        }
+    }
+}
+
+pub struct Pixels<'a> {
+    frame: &'a FrameCopy,
+    step: u32,
+    x: u32,
+    y: u32,
+}
+
+impl Iterator for Pixels<'_> {
+    type Item = (u32, u32, [u8; 3]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.x >= self.frame.width {
+            self.x = 0;
+            self.y += 1;
+        }
+        let (x, y) = (self.x, self.y);
+        self.x += self.step;
+        self.frame.pixel(x, y).map(|rgb| (x, y, rgb))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use image::ColorType;
+
+    use crate::visual::capture::{FrameCopy, FrameTransform};
+
+    #[test]
+    fn test_pixels_skips_padding_and_inverts() {
+        // Two rows of two RGB pixels, each row padded to 8 bytes.
+        let frame = FrameCopy {
+            width: 2,
+            height: 2,
+            stride: 8,
+            color_type: ColorType::Rgb8,
+            data: vec![1, 1, 1, 2, 2, 2, 0, 0, 3, 3, 3, 4, 4, 4, 0, 0],
+            transform: FrameTransform::Normal,
+            y_invert: true,
+            damage: None,
+            captured: Instant::now(),
+        };
+        let pixels: Vec<(u32, u32, [u8; 3])> = frame.pixels(0).collect();
+        assert_eq!(pixels, vec![(0, 0, [3, 3, 3]), (1, 0, [4, 4, 4]), (0, 1, [1, 1, 1]), (1, 1, [2, 2, 2])]);
+    }
+}
@@ -1,92 +1,521 @@
-use colors_transform::{Hsl, Rgb, Color};
+use colors_transform::{Hsl, Color};
 use image::ColorType;
-use crate::backend::FrameCopy;
+use crate::visual::capture::{FrameCopy, FrameTransform};
+use crate::visual::gamut::{Gamut, GamutConversion};
+use crate::visual::heatmap::{Bucket, Heatmap};
+use crate::simd;
+#[cfg(feature = "gpu")]
+use crate::visual::gpu::GpuCounter;
 
 
 /**
  * Minimum lightness for a pixel.
  */
-const LIGHTNESS_MIN: f32 = 15.0;
+pub const LIGHTNESS_MIN: f32 = 15.0;
 
 /**
  * Maximum lightness for a pixel.
  */
-const LIGHTNESS_MAX: f32 = 95.0;
+pub const LIGHTNESS_MAX: f32 = 95.0;
 
 /**
  * Minimum saturation for a pixel.
  */
-const SATURATION_MIN: f32 = 10.0;
+pub const SATURATION_MIN: f32 = 10.0;
 
 /**
  * How many pixels to skip in a chunk, for performance.
  */
-const SKIP_PIXEL: usize = 8;
+pub const SKIP_PIXEL: usize = 8;
+
+/**
+ * How many sampled pixels are converted to HSL at a time.
+ */
+const PIXEL_BATCH: usize = 256;
+
+/**
+ * How far round the colour wheel, in degrees, an accent colour must be from the
+ * region's most prominent colour.
+ */
+const ACCENT_MIN_HUE_DISTANCE: f32 = 40.0;
+
+/**
+ * How prominent an accent colour must be, as a fraction of how prominent the region's
+ * most prominent colour is.
+ */
+const ACCENT_MIN_SHARE: f32 = 0.2;
+
+/**
+ * Pixels with every channel at or below this value may be part of a black bar.
+ */
+const BLACK_BAR_THRESHOLD: u8 = 24;
+
+/**
+ * Largest fraction of the frame, on each side, that can be treated as a black bar.
+ */
+const BLACK_BAR_MAX_FRACTION: f32 = 0.35;
+
+/// Find the area of the frame inside any letterboxing or pillarboxing, as inclusive
+/// (left, top, right, bottom) image coordinates.
+pub fn content_bounds(frame_copy: &FrameCopy) -> (u32, u32, u32, u32) {
+    let (width, height) = (frame_copy.width as usize, frame_copy.height as usize);
+    if width == 0 || height == 0 {
+        return (0, 0, 0, 0);
+    }
+    let is_dark = |x: usize, y: usize| {
+        frame_copy.pixel(x as u32, y as u32).is_none_or(|pixel| pixel.iter().all(|channel| *channel <= BLACK_BAR_THRESHOLD))
+    };
+    let row_dark = |y: usize| (0..width).step_by(SKIP_PIXEL + 1).all(|x| is_dark(x, y));
+    let column_dark = |x: usize| (0..height).step_by(SKIP_PIXEL + 1).all(|y| is_dark(x, y));
+    let max_rows = (height as f32 * BLACK_BAR_MAX_FRACTION) as usize;
+    let max_columns = (width as f32 * BLACK_BAR_MAX_FRACTION) as usize;
+
+    let top = (0..max_rows).take_while(|y| row_dark(*y)).count();
+    let bottom = (0..max_rows).take_while(|y| row_dark(height - 1 - y)).count();
+    let left = (0..max_columns).take_while(|x| column_dark(*x)).count();
+    let right = (0..max_columns).take_while(|x| column_dark(width - 1 - x)).count();
+    (left as u32, top as u32, (width - 1 - right) as u32, (height - 1 - bottom) as u32)
+}
 
 
-pub fn determine_prominent_color(frame_copy: FrameCopy, heatmap: &mut [Vec<Vec<Vec<u32>>>]) -> Vec<Hsl> {
-    if ColorType::Rgba8 != frame_copy.frame_color_type {
+/// Where the panel regions start horizontally on screen, and where each one ends, when
+/// spreading panels across the given content bounds. Each panel's share of the width
+/// follows its width in `widths`.
+pub fn panel_columns(frame_copy: &FrameCopy, bounds: (u32, u32, u32, u32), widths: &[f32]) -> (u32, Vec<u32>) {
+    let (left, top, right, bottom) = bounds;
+    let (content_x1, _, _, _) = frame_copy.transform.logical_position(left, top, frame_copy.width, frame_copy.height);
+    let (content_x2, _, _, _) = frame_copy.transform.logical_position(right, bottom, frame_copy.width, frame_copy.height);
+    let content_x = content_x1.min(content_x2);
+    let content_width = content_x1.abs_diff(content_x2) + 1;
+    let total: f32 = widths.iter().sum();
+    let mut covered = 0.0;
+    let ends = widths.iter().map(|width| {
+        covered += width;
+        content_x + (content_width as f32 * covered / total).round() as u32
+    }).collect();
+    (content_x, ends)
+}
+
+/// The bucket a pixel counts towards, or `None` if it's too dark, too bright or too
+/// grey to be worth showing. The pixel is first converted into sRGB by `gamut`, if
+/// the output has a wider gamut.
+fn pixel_bucket(rgb: [u8; 3], heatmap: &Heatmap, gamut: Option<&GamutConversion>) -> Option<Bucket> {
+    let mut hsl = [[0.0; 3]];
+    simd::rgb_to_hsl(&[gamut.map_or(rgb, |gamut| gamut.convert(rgb))], &mut hsl);
+    hsl_bucket(hsl[0], heatmap)
+}
+
+/// The bucket a pixel already converted to HSL counts towards, see [`pixel_bucket`].
+fn hsl_bucket([h, s, l]: [f32; 3], heatmap: &Heatmap) -> Option<Bucket> {
+    let hsl = Hsl::from(h, s, l);
+
+    // Reject any really dark colours.
+    if LIGHTNESS_MAX < hsl.get_lightness() || hsl.get_lightness() < LIGHTNESS_MIN {
+        return None;
+    }
+    if hsl.get_saturation() < SATURATION_MIN {
+        return None;
+    }
+    Some(heatmap.bucket(&hsl))
+}
+
+/// How the pixels of a frame are shared out between the panels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PanelLayout {
+    pub width: u32,
+    pub height: u32,
+    pub transform: FrameTransform,
+    /// The content inside any black bars, see [`content_bounds`].
+    pub bounds: (u32, u32, u32, u32),
+    /// Where each panel's region ends, see [`panel_columns`].
+    pub ends: Vec<u32>,
+}
+
+impl PanelLayout {
+    pub fn new(frame_copy: &FrameCopy, widths: &[f32]) -> Self {
+        // Spread the panels across the picture, ignoring any black bars around it.
+        let bounds = content_bounds(frame_copy);
+        let (_, ends) = panel_columns(frame_copy, bounds, widths);
+        PanelLayout {
+            width: frame_copy.width,
+            height: frame_copy.height,
+            transform: frame_copy.transform,
+            bounds,
+            ends,
+        }
+    }
+
+    pub fn panel_count(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// The panel whose region contains the pixel at `x`, `y`, if any.
+    fn panel(&self, x: u32, y: u32) -> Option<usize> {
+        let (left, top, right, bottom) = self.bounds;
+        if x < left || x > right || y < top || y > bottom {
+            return None;
+        }
+        let (logical_x, _, _, _) = self.transform.logical_position(x, y, self.width, self.height);
+        Some(self.ends.partition_point(|end| *end <= logical_x).min(self.panel_count() - 1))
+    }
+}
+
+/// Count every sampled pixel of a frame into `heatmap`, telling `record` where each
+/// counted pixel went.
+fn count_pixels(frame_copy: &FrameCopy, heatmap: &mut Heatmap, layout: &PanelLayout, gamut: Option<&GamutConversion>, mut record: impl FnMut(u32, u32, Bucket)) -> Vec<Hsl> {
+    if !matches!(frame_copy.color_type, ColorType::Rgba8 | ColorType::Rgb8) {
         panic!("Cannot handle frame!")
     };
-    let split_by = heatmap.len();
+    let split_by = heatmap.panel_count();
     let mut most_prominent = vec![Hsl::from(0.0, 0.0, 0.0); split_by];
     let mut most_prominent_idx: Vec<u32> = vec![0; split_by];
-    let split_width: u32 = frame_copy.width / split_by as u32;
-    let chunk_size = 4 + (SKIP_PIXEL*4);
-    
-    for (chunk_idx, chunk) in frame_copy.data.chunks_exact(chunk_size).enumerate() {
-        let x = ((chunk_idx * chunk_size) / 4) % frame_copy.width as usize;
-        let panel_idx = (x as f32 / split_width as f32).floor().min(split_by as f32 - 1.0f32) as usize;
 
+    // Pixels are converted in batches, which is much faster than one at a time.
+    let mut pixels = frame_copy.pixels(SKIP_PIXEL).filter_map(|(x, y, rgb)| Some((layout.panel(x, y)?, x, y, rgb)));
+    let mut positions = Vec::with_capacity(PIXEL_BATCH);
+    let mut rgb = Vec::with_capacity(PIXEL_BATCH);
+    let mut hsl = [[0.0; 3]; PIXEL_BATCH];
+    loop {
+        positions.clear();
+        rgb.clear();
+        for (panel_idx, x, y, pixel) in pixels.by_ref().take(PIXEL_BATCH) {
+            positions.push((panel_idx, x, y));
+            rgb.push(gamut.map_or(pixel, |gamut| gamut.convert(pixel)));
+        }
+        if positions.is_empty() {
+            break;
+        }
+        simd::rgb_to_hsl(&rgb, &mut hsl);
+        for (&(panel_idx, x, y), hsl) in positions.iter().zip(hsl) {
+            let Some(bucket) = hsl_bucket(hsl, heatmap) else {
+                continue;
+            };
+            record(x, y, bucket);
+            // With what's left, primary focus on getting the most prominent colour in the frame.
+            let new_prominence = heatmap.add(panel_idx, bucket);
+            if new_prominence > most_prominent_idx[panel_idx] {
+                most_prominent[panel_idx] = heatmap.color(bucket);
+                most_prominent_idx[panel_idx] = new_prominence;
+            }
+        }
+    }
+    most_prominent
+}
+
+/// A heatmap of just the latest frame, kept up to date by re-sampling only the parts of
+/// each frame that the compositor reports as changed. Small updates, like a clock
+/// ticking, then cost next to nothing to analyse.
+pub struct FrameHeatmap {
+    heatmap: Heatmap,
+    /// How wide each panel is, which sets how much of the picture it's given.
+    widths: Vec<f32>,
+    /// Converts pixels into sRGB, for outputs with a wide gamut.
+    gamut: Option<GamutConversion>,
+    layout: Option<PanelLayout>,
+    /// The bucket each sampled pixel of the latest frame was counted in, row by row.
+    samples: Vec<Option<Bucket>>,
+    prominent: Vec<Hsl>,
+    #[cfg(feature = "gpu")]
+    gpu: Option<GpuCounter>,
+}
+
+impl FrameHeatmap {
+    pub fn new(heatmap: Heatmap) -> Self {
+        FrameHeatmap {
+            widths: vec![1.0; heatmap.panel_count()],
+            heatmap,
+            gamut: None,
+            layout: None,
+            samples: Vec::new(),
+            prominent: Vec::new(),
+            #[cfg(feature = "gpu")]
+            gpu: None,
+        }
+    }
+
+    /// Give each panel a share of the picture that follows its width, rather than the
+    /// same share each. There must be a width for every panel of the heatmap.
+    pub fn with_widths(mut self, widths: Vec<f32>) -> Self {
+        self.widths = widths;
+        self
+    }
+
+    /// Treat captured pixels as being in `gamut`, converting them into sRGB before
+    /// counting them.
+    pub fn with_gamut(mut self, gamut: Gamut) -> Self {
+        self.gamut = GamutConversion::new(gamut);
+        self
+    }
+
+    /// Count whole frames with a compute shader where possible. Damage is then only
+    /// used to skip frames where nothing changed.
+    #[cfg(feature = "gpu")]
+    pub fn with_gpu(mut self) -> Self {
+        match GpuCounter::new(&self.heatmap) {
+            Ok(gpu) => self.gpu = Some(gpu),
+            Err(err) => log::warn!("Could not count colours on the GPU, using the CPU: {}", err),
+        }
+        self
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    pub fn with_gpu(self) -> Self {
+        log::warn!("Built without the gpu feature, counting colours on the CPU");
+        self
+    }
+
+    pub fn heatmap(&self) -> &Heatmap {
+        &self.heatmap
+    }
+
+    fn columns(width: u32) -> usize {
+        (width as usize).div_ceil(SKIP_PIXEL + 1)
+    }
 
-        let hsl = Rgb::from(chunk[0] as f32, chunk[1] as f32, chunk[2] as f32).to_hsl();
+    /// Count a new frame, returning the most prominent colour of each panel's region.
+    /// A single panel takes the colour of the whole picture.
+    pub fn update(&mut self, frame_copy: &FrameCopy) -> Vec<Hsl> {
+        if self.heatmap.panel_count() == 0 {
+            return Vec::new();
+        }
+        let layout = PanelLayout::new(frame_copy, &self.widths);
+        #[cfg(feature = "gpu")]
+        if let Some(gpu) = &mut self.gpu {
+            let unchanged = frame_copy.damage.as_ref().is_some_and(|damage| damage.is_empty()) && self.layout.as_ref() == Some(&layout);
+            if unchanged {
+                return self.prominent.clone();
+            }
+            match gpu.count(frame_copy, &layout, &mut self.heatmap, self.gamut.as_ref()) {
+                Ok(()) => {
+                    self.prominent = (0..layout.panel_count()).map(|panel| self.most_prominent(panel)).collect();
+                    self.layout = Some(layout);
+                    return self.prominent.clone();
+                }
+                Err(err) => {
+                    log::warn!("Counting colours on the GPU failed, using the CPU: {}", err);
+                    self.gpu = None;
+                    self.layout = None;
+                }
+            }
+        }
+        match &frame_copy.damage {
+            Some(damage) if self.layout.as_ref() == Some(&layout) => {
+                for rect in damage {
+                    self.resample(frame_copy, &layout, rect.x, rect.y, rect.width, rect.height);
+                }
+                self.prominent = (0..layout.panel_count()).map(|panel| self.most_prominent(panel)).collect();
+            }
+            _ => {
+                let columns = Self::columns(layout.width);
+                let mut samples = vec![None; columns * layout.height as usize];
+                self.heatmap.clear();
+                self.prominent = count_pixels(frame_copy, &mut self.heatmap, &layout, self.gamut.as_ref(), |x, y, bucket| {
+                    samples[y as usize * columns + x as usize / (SKIP_PIXEL + 1)] = Some(bucket);
+                });
+                self.samples = samples;
+                self.layout = Some(layout);
+            }
+        }
+        self.prominent.clone()
+    }
 
-        // Reject any really dark colours.
-        if LIGHTNESS_MAX < hsl.get_lightness() || hsl.get_lightness() < LIGHTNESS_MIN {
-            continue;
+    /// Sample a damaged rectangle of the buffer again, moving each changed pixel from
+    /// the bucket it used to count towards to its new one.
+    fn resample(&mut self, frame_copy: &FrameCopy, layout: &PanelLayout, x: u32, y: u32, width: u32, height: u32) {
+        let step = SKIP_PIXEL as u32 + 1;
+        let columns = Self::columns(layout.width);
+        let x_end = x.saturating_add(width).min(layout.width);
+        let y_end = y.saturating_add(height).min(layout.height);
+        for row in y..y_end {
+            // Damage is in buffer rows, while samples count from the top of the image.
+            let image_y = if frame_copy.y_invert { layout.height - 1 - row } else { row };
+            for image_x in (x.div_ceil(step) * step..x_end).step_by(step as usize) {
+                let Some(panel) = layout.panel(image_x, image_y) else {
+                    continue;
+                };
+                let sample = &mut self.samples[image_y as usize * columns + (image_x / step) as usize];
+                let bucket = frame_copy.pixel(image_x, image_y).and_then(|rgb| pixel_bucket(rgb, &self.heatmap, self.gamut.as_ref()));
+                if *sample == bucket {
+                    continue;
+                }
+                if let Some(old) = std::mem::replace(sample, bucket) {
+                    self.heatmap.remove(panel, old);
+                }
+                if let Some(new) = bucket {
+                    self.heatmap.add(panel, new);
+                }
+            }
         }
-        if hsl.get_saturation() < SATURATION_MIN {
-            continue;
+    }
+
+    /// The most common colour in a panel's region, preferring the current one on a tie.
+    fn most_prominent(&self, panel: usize) -> Hsl {
+        let current = self.prominent.get(panel).map(|hsl| self.heatmap.bucket(hsl));
+        let mut best = current.map_or(0, |bucket| self.heatmap.count(panel, bucket));
+        let mut prominent = self.prominent.get(panel).copied().unwrap_or(Hsl::from(0.0, 0.0, 0.0));
+        for (bucket, count) in self.heatmap.buckets(panel) {
+            if count > best {
+                best = count;
+                prominent = self.heatmap.color(bucket);
+            }
         }
-        // Split into 36 blocks
-        let h_index = (hsl.get_hue() as usize) / 10;
-        let s_index = (hsl.get_saturation() as usize) / 5;
-        let l_index = (hsl.get_lightness() as usize) / 5;
-        let new_prominence = heatmap[panel_idx][h_index][s_index][l_index] + 1;
-        // With what's left, primary focus on getting the most prominent colour in the frame.
-        heatmap[panel_idx][h_index][s_index][l_index] = new_prominence;
-        if new_prominence > most_prominent_idx[panel_idx] {
-            most_prominent[panel_idx] = Hsl::from(
-                (h_index * 10) as f32,
-                (s_index * 5) as f32,
-                (l_index * 5) as f32,
-            );
-            most_prominent_idx[panel_idx] = new_prominence;
+        if best == 0 {
+            return Hsl::from(0.0, 0.0, 0.0);
         }
+        prominent
     }
-    most_prominent
 }
 
+/// For each region, find the most prominent colour with a clearly different hue to the
+/// region's `primary` colour, as long as it's common enough to stand out.
+pub fn determine_accent_colors(heatmap: &Heatmap, primary: &[Hsl]) -> Vec<Option<Hsl>> {
+    primary.iter().enumerate().map(|(panel, primary)| {
+        let primary_count = heatmap.count(panel, heatmap.bucket(primary));
+        let mut accent: Option<(u32, Hsl)> = None;
+        for (bucket, count) in heatmap.buckets(panel) {
+            if count <= accent.map_or(0, |(best, _)| best) {
+                continue;
+            }
+            let color = heatmap.color(bucket);
+            let distance = (color.get_hue() - primary.get_hue()).abs() % 360.0;
+            if distance.min(360.0 - distance) >= ACCENT_MIN_HUE_DISTANCE {
+                accent = Some((count, color));
+            }
+        }
+        accent.filter(|(count, _)| *count as f32 >= primary_count as f32 * ACCENT_MIN_SHARE).map(|(_, hsl)| hsl)
+    }).collect()
+}
 
 #[cfg(test)]
 mod test {
+    use std::time::Instant;
+
     use colors_transform::Color;
     use image::ColorType;
+    #[cfg(feature = "bench")]
     use test::Bencher;
 
-    use crate::{visual::prominent_color::determine_prominent_color, backend::FrameCopy};
+    use crate::visual::{prominent_color::{content_bounds, determine_accent_colors, FrameHeatmap}, capture::{DamageRect, FrameCopy, FrameTransform}};
+    use crate::visual::heatmap::{Heatmap, HeatmapConfig};
+
+    /// A frame with 30px black bars either side of red, green, blue and yellow stripes.
+    fn pillarboxed_frame() -> FrameCopy {
+        let stripes: [[u8; 4]; 4] = [[200, 30, 30, 255], [30, 200, 30, 255], [30, 30, 200, 255], [200, 200, 30, 255]];
+        let (width, height) = (120u32, 40u32);
+        let mut data = Vec::new();
+        for _ in 0..height {
+            for x in 0..width {
+                if !(30..90).contains(&x) {
+                    data.extend_from_slice(&[0, 0, 0, 255]);
+                } else {
+                    data.extend_from_slice(&stripes[(x as usize - 30) / 15]);
+                }
+            }
+        }
+        FrameCopy {
+            width,
+            height,
+            stride: width * 4,
+            color_type: ColorType::Rgba8,
+            data,
+            transform: FrameTransform::Normal,
+            y_invert: false,
+            damage: None,
+            captured: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_content_bounds() {
+        assert_eq!(content_bounds(&pillarboxed_frame()), (30, 0, 89, 39));
+    }
+
+    #[test]
+    fn test_determine_prominent_color_ignores_black_bars() {
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(4, &HeatmapConfig::default()));
+        let result = heatmap.update(&pillarboxed_frame());
+        let hues: Vec<f32> = result.iter().map(|hsl| hsl.get_hue()).collect();
+        assert_eq!(hues, vec![0.0, 120.0, 240.0, 60.0], "Each panel should see one stripe");
+    }
     
+    #[test]
+    fn test_determine_accent_colors() {
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(2, &HeatmapConfig::default()));
+        let frame = pillarboxed_frame();
+        let primary = heatmap.update(&frame);
+        let accent = determine_accent_colors(heatmap.heatmap(), &primary);
+        // Each half of the frame has two stripes, so the other stripe is the accent.
+        let mut hues: Vec<[f32; 2]> = primary.iter().zip(&accent).map(|(primary, accent)| {
+            let mut pair = [primary.get_hue(), accent.unwrap().get_hue()];
+            pair.sort_by(f32::total_cmp);
+            pair
+        }).collect();
+        hues.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        assert_eq!(hues, vec![[0.0, 120.0], [60.0, 240.0]]);
+    }
+
+    #[test]
+    fn test_frame_heatmap_damage() {
+        let mut frame_heatmap = FrameHeatmap::new(Heatmap::new(4, &HeatmapConfig::default()));
+        let mut frame = pillarboxed_frame();
+        frame_heatmap.update(&frame);
+
+        // Turn the red stripe blue, and only report that part as damaged.
+        for y in 0..frame.height as usize {
+            for x in 30..45 {
+                let offset = y * frame.stride as usize + x * 4;
+                frame.data[offset..offset + 3].copy_from_slice(&[30, 30, 200]);
+            }
+        }
+        frame.damage = Some(vec![DamageRect { x: 30, y: 0, width: 15, height: frame.height }]);
+        let hues: Vec<f32> = frame_heatmap.update(&frame).iter().map(|hsl| hsl.get_hue()).collect();
+        assert_eq!(hues, vec![240.0, 120.0, 240.0, 60.0]);
+
+        frame.damage = None;
+        let mut full = FrameHeatmap::new(Heatmap::new(4, &HeatmapConfig::default()));
+        full.update(&frame);
+        for panel in 0..4 {
+            assert!(full.heatmap().buckets(panel).eq(frame_heatmap.heatmap().buckets(panel)), "Panel {} should match a full pass", panel);
+        }
+    }
+
+    #[test]
+    fn test_panel_counts() {
+        let frame = pillarboxed_frame();
+        let mut empty = FrameHeatmap::new(Heatmap::new(0, &HeatmapConfig::default()));
+        assert!(empty.update(&frame).is_empty());
+
+        // One panel sees every stripe of the picture.
+        let mut single = FrameHeatmap::new(Heatmap::new(1, &HeatmapConfig::default()));
+        assert_eq!(single.update(&frame).len(), 1);
+        assert_eq!(single.heatmap().buckets(0).filter(|(_, count)| *count > 0).count(), 4);
+    }
+
+    #[test]
+    fn test_panel_widths() {
+        // A panel three times as wide as the other takes three of the four stripes.
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(2, &HeatmapConfig::default())).with_widths(vec![1.0, 3.0]);
+        heatmap.update(&pillarboxed_frame());
+        assert_eq!(heatmap.heatmap().buckets(0).filter(|(_, count)| *count > 0).count(), 1);
+        assert_eq!(heatmap.heatmap().buckets(1).filter(|(_, count)| *count > 0).count(), 3);
+    }
+
     #[test]
     fn test_determine_prominent_color() {
         let image = image::open("samples/gradientrb.png").unwrap();
-        let mut heatmap: Vec<Vec<Vec<Vec<u32>>>> = vec![vec![vec![vec![0u32; 21]; 21]; 37]; 1];
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(1, &HeatmapConfig::default()));
     
-        let result = determine_prominent_color( FrameCopy {
+        let result = heatmap.update(&FrameCopy {
             width: image.width(),
             height: image.height(),
-            frame_color_type: ColorType::Rgba8,
-            data: image.clone().into_bytes(),
-        }, &mut heatmap);
+            stride: image.width() * 4,
+            color_type: ColorType::Rgba8,
+            data: image.to_rgba8().into_raw(),
+            transform: FrameTransform::Normal,
+            y_invert: false,
+            damage: None,
+            captured: Instant::now(),
+        });
         let v = result.first().unwrap();
     
         assert_eq!(v.get_hue(), 240.0, "Hue value is incorrect");
@@ -97,14 +526,19 @@ mod test {
     #[test]
     fn test_determine_prominent_color_multiple_panels() {
         let image = image::open("samples/colortray.png").unwrap();
-        let mut heatmap: Vec<Vec<Vec<Vec<u32>>>> = vec![vec![vec![vec![0u32; 21]; 21]; 37]; 4];
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(4, &HeatmapConfig::default()));
     
-        let result = determine_prominent_color( FrameCopy {
+        let result = heatmap.update(&FrameCopy {
             width: image.width(),
             height: image.height(),
-            frame_color_type: ColorType::Rgba8,
-            data: image.clone().into_bytes(),
-        }, &mut heatmap);
+            stride: image.width() * 4,
+            color_type: ColorType::Rgba8,
+            data: image.to_rgba8().into_raw(),
+            transform: FrameTransform::Normal,
+            y_invert: false,
+            damage: None,
+            captured: Instant::now(),
+        });
         let v1 = result.first().unwrap();
         let v2 = result.get(1).unwrap();
         let v3 = result.get(2).unwrap();
@@ -130,28 +564,40 @@ mod test {
     }
 
 
+    #[cfg(feature = "bench")]
     #[bench]
     fn bench_determine_prominent_color_gradient(b: &mut Bencher) {
         let image = image::open("samples/gradientrb.png").unwrap();
-        let mut heatmap: Vec<Vec<Vec<Vec<u32>>>> = vec![vec![vec![vec![0u32; 21]; 21]; 37]; 1];
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(1, &HeatmapConfig::default()));
 
-        b.iter(|| determine_prominent_color( FrameCopy {
+        b.iter(|| heatmap.update(&FrameCopy {
             width: image.width(),
             height: image.height(),
-            frame_color_type: ColorType::Rgba8,
-            data: image.clone().into_bytes(),
-        },&mut heatmap));
+            stride: image.width() * 4,
+            color_type: ColorType::Rgba8,
+            data: image.to_rgba8().into_raw(),
+            transform: FrameTransform::Normal,
+            y_invert: false,
+            damage: None,
+            captured: Instant::now(),
+        }));
     }
 
+    #[cfg(feature = "bench")]
     #[bench]
     fn bench_determine_prominent_color_testcard(b: &mut Bencher) {
         let image = image::open("samples/testcard.png").unwrap();
-        let mut heatmap: Vec<Vec<Vec<Vec<u32>>>> = vec![vec![vec![vec![0u32; 21]; 21]; 37]; 1];
-        b.iter(|| determine_prominent_color( FrameCopy {
+        let mut heatmap = FrameHeatmap::new(Heatmap::new(1, &HeatmapConfig::default()));
+        b.iter(|| heatmap.update(&FrameCopy {
             width: image.width(),
             height: image.height(),
-            frame_color_type: ColorType::Rgba8,
-            data: image.clone().into_bytes(),
-        },&mut heatmap));
+            stride: image.width() * 4,
+            color_type: ColorType::Rgba8,
+            data: image.to_rgba8().into_raw(),
+            transform: FrameTransform::Normal,
+            y_invert: false,
+            damage: None,
+            captured: Instant::now(),
+        }));
     }
 }
@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::visual::output::OutputPositioning;
+
+/// A distance along one axis of the captured area, either in logical pixels or as a
+/// percentage of the area.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Length {
+    Pixels(i32),
+    Percent(f32),
+}
+
+impl Length {
+    fn resolve(self, total: i32) -> i32 {
+        match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Percent(percent) => (total as f32 * percent / 100.0).round() as i32,
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if let Some(percent) = value.strip_suffix('%') {
+            percent.trim().parse().map(Length::Percent).map_err(|_| format!("Invalid percentage \"{}\"", value))
+        } else {
+            value.parse().map(Length::Pixels).map_err(|_| format!("Invalid length \"{}\"", value))
+        }
+    }
+}
+
+impl TryFrom<String> for Length {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The part of the captured area used for colour analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CaptureRegion {
+    pub x: Length,
+    pub y: Length,
+    pub width: Length,
+    pub height: Length,
+}
+
+impl Default for CaptureRegion {
+    fn default() -> Self {
+        CaptureRegion {
+            x: Length::Pixels(0),
+            y: Length::Pixels(0),
+            width: Length::Percent(100.0),
+            height: Length::Percent(100.0),
+        }
+    }
+}
+
+impl CaptureRegion {
+    /// Resolve the region against `area`, clipping it so it never extends outside.
+    pub fn within(&self, area: &OutputPositioning) -> OutputPositioning {
+        if area.width <= 0 || area.height <= 0 {
+            return area.clone();
+        }
+        let x = self.x.resolve(area.width).clamp(0, area.width - 1);
+        let y = self.y.resolve(area.height).clamp(0, area.height - 1);
+        OutputPositioning {
+            x: area.x + x,
+            y: area.y + y,
+            width: self.width.resolve(area.width).clamp(1, area.width - x),
+            height: self.height.resolve(area.height).clamp(1, area.height - y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture_region_within() {
+        let area = OutputPositioning { x: 100, y: 0, width: 1920, height: 1080 };
+        let region = CaptureRegion {
+            x: "15%".parse().unwrap(),
+            y: "100".parse().unwrap(),
+            width: "70%".parse().unwrap(),
+            height: "5000".parse().unwrap(),
+        };
+        assert_eq!(region.within(&area), OutputPositioning { x: 388, y: 100, width: 1344, height: 980 });
+    }
+}
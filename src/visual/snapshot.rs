@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use colors_transform::{Color, Hsl};
+use image::{Rgb, RgbImage};
+
+use crate::visual::capture::FrameCopy;
+use crate::visual::prominent_color::{content_bounds, panel_columns};
+
+/**
+ * Colour used to outline the area inside any detected black bars.
+ */
+const CONTENT_OUTLINE: Rgb<u8> = Rgb([255, 0, 255]);
+
+/**
+ * Colour used to mark the boundaries between panel regions.
+ */
+const PANEL_BOUNDARY: Rgb<u8> = Rgb([255, 255, 255]);
+
+/**
+ * How many times shorter the swatches of chosen colours are than the analysed area.
+ */
+const SWATCH_HEIGHT_DIVISOR: u32 = 10;
+
+/// Set a pixel of the overlay, leaving out any that fall outside it, as the edges of
+/// the regions at either end can.
+fn put(image: &mut RgbImage, x: u32, y: u32, color: Rgb<u8>) {
+    if let Some(pixel) = image.get_pixel_mut_checked(x, y) {
+        *pixel = color;
+    }
+}
+
+/// Render the frame as it appears on screen, with the analysed area outlined, the panel
+/// regions marked and a swatch of the colour chosen for each region along the bottom.
+pub fn render_overlay(frame_copy: &FrameCopy, colors: &[Hsl], widths: &[f32]) -> RgbImage {
+    let (_, _, width, height) = frame_copy.transform.logical_position(0, 0, frame_copy.width, frame_copy.height);
+    let mut image = RgbImage::new(width, height);
+    for (x, y, rgb) in frame_copy.pixels(0) {
+        let (logical_x, logical_y, _, _) = frame_copy.transform.logical_position(x, y, frame_copy.width, frame_copy.height);
+        put(&mut image, logical_x, logical_y, Rgb(rgb));
+    }
+    if width == 0 || height == 0 || colors.is_empty() {
+        return image;
+    }
+
+    let (left, top, right, bottom) = content_bounds(frame_copy);
+    let (x1, y1, _, _) = frame_copy.transform.logical_position(left, top, frame_copy.width, frame_copy.height);
+    let (x2, y2, _, _) = frame_copy.transform.logical_position(right, bottom, frame_copy.width, frame_copy.height);
+    let (content_left, content_right) = (x1.min(x2), x1.max(x2));
+    let (content_top, content_bottom) = (y1.min(y2), y1.max(y2));
+    for x in content_left..=content_right {
+        put(&mut image, x, content_top, CONTENT_OUTLINE);
+        put(&mut image, x, content_bottom, CONTENT_OUTLINE);
+    }
+    for y in content_top..=content_bottom {
+        put(&mut image, content_left, y, CONTENT_OUTLINE);
+        put(&mut image, content_right, y, CONTENT_OUTLINE);
+    }
+
+    let (content_x, ends) = panel_columns(frame_copy, (left, top, right, bottom), widths);
+    let swatch_top = content_bottom.saturating_sub((content_bottom - content_top) / SWATCH_HEIGHT_DIVISOR);
+    for (index, color) in colors.iter().enumerate() {
+        let region_left = if index == 0 { content_x } else { ends[index - 1].min(content_right) };
+        let region_right = if index == colors.len() - 1 { content_right } else { ends[index].min(content_right) };
+        let (r, g, b) = color.to_rgb().as_tuple();
+        let swatch = Rgb([r.round() as u8, g.round() as u8, b.round() as u8]);
+        for x in region_left..region_right {
+            for y in swatch_top..content_bottom {
+                put(&mut image, x, y, swatch);
+            }
+        }
+        if index > 0 {
+            for y in content_top..=content_bottom {
+                put(&mut image, region_left, y, PANEL_BOUNDARY);
+            }
+        }
+    }
+    image
+}
+
+/// Save the overlay for a frame into the cache directory, returning where it was written.
+pub fn save_snapshot(frame_copy: &FrameCopy, colors: &[Hsl], widths: &[f32]) -> Result<PathBuf, Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let path = xdg::BaseDirectories::with_prefix("leafpipe")?
+        .place_cache_file(format!("snapshot-{}.png", timestamp))?;
+    render_overlay(frame_copy, colors, widths).save(&path)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use colors_transform::Hsl;
+    use image::{ColorType, Rgb};
+
+    use super::render_overlay;
+    use crate::visual::capture::{FrameCopy, FrameTransform};
+
+    #[test]
+    fn test_render_overlay_rotated() {
+        let frame = FrameCopy {
+            width: 40,
+            height: 20,
+            stride: 40 * 4,
+            color_type: ColorType::Rgba8,
+            data: [100, 100, 100, 255].repeat(40 * 20),
+            transform: FrameTransform::Rotate90,
+            y_invert: false,
+            damage: None,
+            captured: Instant::now(),
+        };
+        let image = render_overlay(&frame, &[Hsl::from(0.0, 100.0, 50.0), Hsl::from(240.0, 100.0, 50.0)], &[1.0, 1.0]);
+        assert_eq!(image.dimensions(), (20, 40));
+        assert_eq!(*image.get_pixel(5, 38), Rgb([255, 0, 0]));
+        assert_eq!(*image.get_pixel(15, 38), Rgb([0, 0, 255]));
+
+        // More panels than there are pixels across doesn't draw outside the image.
+        let frame = FrameCopy { width: 3, height: 2, stride: 3 * 4, data: [100, 100, 100, 255].repeat(3 * 2), ..frame };
+        let colors = vec![Hsl::from(0.0, 100.0, 50.0); 7];
+        for transform in [FrameTransform::Normal, FrameTransform::Rotate90, FrameTransform::Flipped270] {
+            let image = render_overlay(&FrameCopy { transform, data: frame.data.clone(), damage: None, ..frame }, &colors, &[1.0; 7]);
+            assert_eq!(image.width() * image.height(), 6);
+        }
+    }
+}
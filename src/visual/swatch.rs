@@ -0,0 +1,163 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsFd;
+
+use wayland_client::{
+    delegate_noop,
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{
+        wl_buffer::WlBuffer, wl_compositor::WlCompositor, wl_registry, wl_shm, wl_shm::WlShm,
+        wl_shm_pool::WlShmPool, wl_surface::WlSurface,
+    },
+    Connection, Dispatch, EventQueue, QueueHandle,
+};
+use wayland_protocols::xdg::shell::client::{
+    xdg_surface::{self, XdgSurface},
+    xdg_toplevel::{self, XdgToplevel},
+    xdg_wm_base::{self, XdgWmBase},
+};
+
+use crate::visual::backend::create_shm_fd;
+use crate::visual::output;
+
+/**
+ * Size of the window if the compositor won't make it fullscreen and leaves the size up
+ * to us.
+ */
+const FALLBACK_SIZE: (i32, i32) = (640, 480);
+
+#[derive(Default)]
+struct SwatchState {
+    configured: bool,
+    /// The size the compositor asked for, or zero if it's up to us.
+    size: (i32, i32),
+    closed: bool,
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for SwatchState {
+    fn event(_: &mut Self, _: &wl_registry::WlRegistry, _: wl_registry::Event, _: &GlobalListContents, _: &Connection, _: &QueueHandle<Self>) {
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for SwatchState {
+    fn event(_: &mut Self, wm_base: &XdgWmBase, event: xdg_wm_base::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            wm_base.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, ()> for SwatchState {
+    fn event(state: &mut Self, xdg_surface: &XdgSurface, event: xdg_surface::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            xdg_surface.ack_configure(serial);
+            state.configured = true;
+        }
+    }
+}
+
+impl Dispatch<XdgToplevel, ()> for SwatchState {
+    fn event(state: &mut Self, _: &XdgToplevel, event: xdg_toplevel::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {
+        match event {
+            xdg_toplevel::Event::Configure { width, height, .. } => state.size = (width, height),
+            xdg_toplevel::Event::Close => state.closed = true,
+            _ => {},
+        }
+    }
+}
+
+delegate_noop!(SwatchState: ignore WlCompositor);
+delegate_noop!(SwatchState: ignore WlSurface);
+delegate_noop!(SwatchState: ignore WlShm);
+delegate_noop!(SwatchState: ignore WlShmPool);
+delegate_noop!(SwatchState: ignore WlBuffer);
+
+/// A fullscreen window filled with a single colour, to hold the panels up against.
+pub struct Swatch {
+    event_queue: EventQueue<SwatchState>,
+    state: SwatchState,
+    shm: WlShm,
+    surface: WlSurface,
+    xdg_surface: XdgSurface,
+    toplevel: XdgToplevel,
+    /// The buffer on screen, kept until the next one replaces it.
+    buffer: Option<WlBuffer>,
+}
+
+impl Swatch {
+    /// Open the window fullscreen on the named output, or on whichever output the
+    /// compositor picks.
+    pub fn open(output_name: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, event_queue) = registry_queue_init::<SwatchState>(&conn)?;
+        let qh = event_queue.handle();
+        // Version 4 is needed for damage_buffer.
+        let compositor: WlCompositor = globals.bind(&qh, 4..=5, ())?;
+        let shm: WlShm = globals.bind(&qh, 1..=1, ())?;
+        let wm_base: XdgWmBase = globals.bind(&qh, 1..=2, ())?;
+        let output = output_name.map(|name| output::get_output(name.to_string(), output::get_all_outputs(&globals, &conn)));
+
+        let surface = compositor.create_surface(&qh, ());
+        let xdg_surface = wm_base.get_xdg_surface(&surface, &qh, ());
+        let toplevel = xdg_surface.get_toplevel(&qh, ());
+        toplevel.set_title("leafpipe test pattern".to_string());
+        toplevel.set_app_id("leafpipe".to_string());
+        toplevel.set_fullscreen(output.as_ref().map(|output| &output.wl_output));
+        surface.commit();
+
+        let mut swatch = Swatch { event_queue, state: SwatchState::default(), shm, surface, xdg_surface, toplevel, buffer: None };
+        while !swatch.state.configured {
+            swatch.event_queue.blocking_dispatch(&mut swatch.state)?;
+        }
+        Ok(swatch)
+    }
+
+    /// Whether the window has been closed, such as with the compositor's close binding.
+    pub fn closed(&self) -> bool {
+        self.state.closed
+    }
+
+    /// Fill the window with a colour.
+    pub fn show(&mut self, rgb: [u8; 3]) -> Result<(), Box<dyn Error>> {
+        // Pick up any change of size first.
+        self.event_queue.roundtrip(&mut self.state)?;
+        let (width, height) = match self.state.size {
+            (width, height) if width > 0 && height > 0 => (width, height),
+            _ => FALLBACK_SIZE,
+        };
+        let stride = width * 4;
+
+        let mut file = File::from(create_shm_fd()?);
+        // Xrgb8888 is little endian, so blue comes first.
+        let row = [rgb[2], rgb[1], rgb[0], 255].repeat(width as usize);
+        for _ in 0..height {
+            file.write_all(&row)?;
+        }
+        let qh = self.event_queue.handle();
+        let pool = self.shm.create_pool(file.as_fd(), stride * height, &qh, ());
+        let buffer = pool.create_buffer(0, width, height, stride, wl_shm::Format::Xrgb8888, &qh, ());
+        pool.destroy();
+
+        self.surface.attach(Some(&buffer), 0, 0);
+        self.surface.damage_buffer(0, 0, width, height);
+        self.surface.commit();
+        if let Some(previous) = self.buffer.replace(buffer) {
+            previous.destroy();
+        }
+        self.event_queue.roundtrip(&mut self.state)?;
+        Ok(())
+    }
+}
+
+impl Drop for Swatch {
+    fn drop(&mut self) {
+        self.toplevel.destroy();
+        self.xdg_surface.destroy();
+        self.surface.destroy();
+        if let Some(buffer) = self.buffer.take() {
+            buffer.destroy();
+        }
+        let _ = self.event_queue.flush();
+    }
+}
@@ -0,0 +1,226 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{Local, NaiveDateTime, NaiveTime};
+use serde::Deserialize;
+
+use crate::scene::{LiveScene, SceneConfig};
+
+/**
+ * Name of the scene coloured by the weather, for `idle_scene` or the control socket.
+ */
+pub const WEATHER_SCENE: &str = "weather";
+
+/**
+ * Where forecasts come from. Open-Meteo needs no account or API key.
+ */
+const FORECAST_URL: &str = "https://api.open-meteo.com/v1/forecast";
+
+/**
+ * How often the scene follows the time of day, in between fetching the weather.
+ */
+const TIME_OF_DAY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/**
+ * How long either side of sunrise and sunset gets the colours of the sky at dawn and
+ * dusk.
+ */
+const TWILIGHT_MINUTES: i64 = 45;
+
+/**
+ * How many times a minute the scene's colours scroll across the panels.
+ */
+const DRIFT: f32 = 0.2;
+
+fn default_refresh_minutes() -> f32 {
+    30.0
+}
+
+/// Where to fetch the weather for, to colour the `weather` scene. Without a location,
+/// the scene follows the time of day alone, with the sun taken to rise at 7:00 and set
+/// at 19:00.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WeatherConfig {
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    /// How often to fetch the weather.
+    #[serde(default = "default_refresh_minutes")]
+    pub refresh_minutes: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sky {
+    Clear,
+    Cloudy,
+    Fog,
+    Rain,
+    Snow,
+    Storm,
+}
+
+impl Sky {
+    /// The sky for a WMO weather code, as used by Open-Meteo.
+    fn from_code(code: u32) -> Self {
+        match code {
+            0 | 1 => Sky::Clear,
+            45 | 48 => Sky::Fog,
+            51..=67 | 80..=82 => Sky::Rain,
+            71..=77 | 85 | 86 => Sky::Snow,
+            95..=99 => Sky::Storm,
+            _ => Sky::Cloudy,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeOfDay {
+    Night,
+    Twilight,
+    Day,
+}
+
+impl TimeOfDay {
+    fn at(time: NaiveTime, sunrise: NaiveTime, sunset: NaiveTime) -> Self {
+        let near = |event: NaiveTime| (time - event).num_minutes().abs() <= TWILIGHT_MINUTES;
+        if near(sunrise) || near(sunset) {
+            TimeOfDay::Twilight
+        } else if time > sunrise && time < sunset {
+            TimeOfDay::Day
+        } else {
+            TimeOfDay::Night
+        }
+    }
+}
+
+/// The weather where the panels are, as last fetched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Weather {
+    sky: Sky,
+    sunrise: NaiveTime,
+    sunset: NaiveTime,
+}
+
+impl Default for Weather {
+    fn default() -> Self {
+        Weather {
+            sky: Sky::Clear,
+            sunrise: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+            sunset: NaiveTime::from_hms_opt(19, 0, 0).unwrap(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Forecast {
+    current: CurrentWeather,
+    daily: DailyWeather,
+}
+
+#[derive(Deserialize, Debug)]
+struct CurrentWeather {
+    weather_code: u32,
+}
+
+/// Sunrise and sunset as local times, like `2026-10-16T07:21`.
+#[derive(Deserialize, Debug)]
+struct DailyWeather {
+    sunrise: Vec<String>,
+    sunset: Vec<String>,
+}
+
+impl Forecast {
+    fn weather(&self) -> Result<Weather, Box<dyn Error>> {
+        let time = |times: &[String]| -> Result<NaiveTime, Box<dyn Error>> {
+            let time = times.first().ok_or("The forecast has no sunrise or sunset")?;
+            Ok(NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M")?.time())
+        };
+        Ok(Weather {
+            sky: Sky::from_code(self.current.weather_code),
+            sunrise: time(&self.daily.sunrise)?,
+            sunset: time(&self.daily.sunset)?,
+        })
+    }
+}
+
+async fn fetch(latitude: f64, longitude: f64) -> Result<Weather, Box<dyn Error>> {
+    let url = format!("{FORECAST_URL}?latitude={latitude}&longitude={longitude}&current=weather_code&daily=sunrise,sunset&timezone=auto&forecast_days=1");
+    let forecast: Forecast = reqwest::get(url).await?.error_for_status()?.json().await?;
+    forecast.weather()
+}
+
+/// Colours for the sky: warm hues at dawn and dusk, deep blues at night, cool blues
+/// when it rains and so on.
+fn scene_for(sky: Sky, time: TimeOfDay) -> SceneConfig {
+    let gradient = match (time, sky) {
+        (TimeOfDay::Night, _) => vec![[8, 16, 60], [24, 12, 72], [8, 40, 80]],
+        (TimeOfDay::Twilight, Sky::Clear | Sky::Cloudy) => vec![[255, 110, 30], [255, 50, 70], [110, 30, 140]],
+        (_, Sky::Storm) => vec![[70, 40, 130], [20, 20, 70], [110, 90, 160]],
+        (_, Sky::Rain) => vec![[30, 80, 200], [20, 40, 130], [70, 120, 170]],
+        (_, Sky::Snow) => vec![[210, 225, 255], [150, 175, 230]],
+        (_, Sky::Fog | Sky::Cloudy) => vec![[130, 140, 160], [90, 100, 125]],
+        (_, Sky::Clear) => vec![[255, 190, 110], [110, 180, 255]],
+    };
+    SceneConfig { gradient, panels: Default::default(), drift: DRIFT, noise: None }
+}
+
+fn scene_now(weather: &Weather) -> SceneConfig {
+    scene_for(weather.sky, TimeOfDay::at(Local::now().time(), weather.sunrise, weather.sunset))
+}
+
+/// Keep the `weather` scene coloured for the weather and time of day, fetching the
+/// weather in the background.
+pub fn start(config: WeatherConfig) -> LiveScene {
+    let scene = Arc::new(Mutex::new(scene_now(&Weather::default())));
+    let live = scene.clone();
+    let location = config.latitude.zip(config.longitude);
+    let refresh = Duration::from_secs_f32(config.refresh_minutes.max(1.0) * 60.0);
+    tokio::spawn(async move {
+        let mut weather = Weather::default();
+        let mut fetched: Option<tokio::time::Instant> = None;
+        loop {
+            if let Some((latitude, longitude)) = location.filter(|_| fetched.is_none_or(|fetched| fetched.elapsed() >= refresh)) {
+                fetched = Some(tokio::time::Instant::now());
+                match fetch(latitude, longitude).await {
+                    Ok(latest) => {
+                        log::debug!("Weather is {:?}", latest);
+                        weather = latest;
+                    },
+                    Err(err) => log::warn!("Could not fetch the weather: {}", err),
+                }
+            }
+            *live.lock().unwrap() = scene_now(&weather);
+            tokio::time::sleep(TIME_OF_DAY_INTERVAL).await;
+        }
+    });
+    scene
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_weather_scene() {
+        let forecast: Forecast = serde_json::from_str(r#"{
+            "current": {"time": "2026-10-16T18:30", "interval": 900, "weather_code": 61},
+            "daily": {"time": ["2026-10-16"], "sunrise": ["2026-10-16T07:21"], "sunset": ["2026-10-16T18:33"]}
+        }"#).unwrap();
+        let weather = forecast.weather().unwrap();
+        assert_eq!(weather.sky, Sky::Rain);
+        let at = |hour, minute| TimeOfDay::at(NaiveTime::from_hms_opt(hour, minute, 0).unwrap(), weather.sunrise, weather.sunset);
+        assert_eq!(at(3, 0), TimeOfDay::Night);
+        assert_eq!(at(7, 0), TimeOfDay::Twilight);
+        assert_eq!(at(12, 0), TimeOfDay::Day);
+        assert_eq!(at(19, 10), TimeOfDay::Twilight);
+        assert_eq!(at(23, 0), TimeOfDay::Night);
+
+        assert_eq!(Sky::from_code(95), Sky::Storm);
+        assert_eq!(Sky::from_code(3), Sky::Cloudy);
+        // Rain keeps its colours at dusk, while a clear sky turns warm.
+        assert_eq!(scene_for(Sky::Rain, TimeOfDay::Twilight).gradient, scene_for(Sky::Rain, TimeOfDay::Day).gradient);
+        assert_ne!(scene_for(Sky::Clear, TimeOfDay::Twilight).gradient, scene_for(Sky::Clear, TimeOfDay::Day).gradient);
+    }
+}
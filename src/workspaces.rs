@@ -0,0 +1,266 @@
+use std::env;
+use std::error::Error;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::control::ControlCommand;
+use crate::crash;
+use crate::tuning::{Tuning, TuningChange};
+use crate::visual::hyprland;
+
+/**
+ * Magic string at the start of every message to and from Sway.
+ */
+const SWAY_MAGIC: &[u8] = b"i3-ipc";
+
+const SWAY_GET_WORKSPACES: u32 = 1;
+const SWAY_SUBSCRIBE: u32 = 2;
+
+/**
+ * Event types Sway sends once subscribed, with the high bit set to tell them from
+ * replies.
+ */
+const SWAY_WORKSPACE_EVENT: u32 = 0x80000000;
+const SWAY_WINDOW_EVENT: u32 = 0x80000003;
+
+/// How the lights should look while a workspace or application is focused. Profiles
+/// are checked in order and the first that matches wins; a profile may name a
+/// workspace, an application, or both.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkspaceProfile {
+    /// Workspace name, such as `2` or `code`.
+    #[serde(default)]
+    pub workspace: Option<String>,
+    /// Window class on Hyprland, or app ID (or class, for X11 windows) on Sway.
+    #[serde(default)]
+    pub app: Option<String>,
+    /// Scene to show, or the effect if not given.
+    #[serde(default)]
+    pub scene: Option<String>,
+    /// Intensity to drive the effect at, instead of the one under `[tuning]`.
+    #[serde(default)]
+    pub intensity: Option<f32>,
+}
+
+impl WorkspaceProfile {
+    fn matches(&self, focus: &Focus) -> bool {
+        let matches = |wanted: &Option<String>, focused: &Option<String>| match (wanted, focused) {
+            (None, _) => true,
+            (Some(wanted), Some(focused)) => wanted.eq_ignore_ascii_case(focused),
+            (Some(_), None) => false,
+        };
+        (self.workspace.is_some() || self.app.is_some()) && matches(&self.workspace, &focus.workspace) && matches(&self.app, &focus.app)
+    }
+}
+
+/// Something changing focus on the desktop.
+#[derive(Debug, Clone, PartialEq)]
+enum FocusEvent {
+    Workspace(String),
+    /// The focused window's class, or `None` when no window is focused.
+    App(Option<String>),
+}
+
+#[derive(Debug, Default)]
+struct Focus {
+    workspace: Option<String>,
+    app: Option<String>,
+}
+
+/// Switches profiles as focus changes, by sending the same commands control clients do.
+struct ProfileSwitcher {
+    profiles: Vec<WorkspaceProfile>,
+    /// The tuning from the config, whose intensity is put back on leaving a profile that
+    /// changed it.
+    tuning: Tuning,
+    commands: Sender<ControlCommand>,
+    focus: Focus,
+    /// Which profile is applied, once one has been, with `Some(None)` for none.
+    applied: Option<Option<usize>>,
+}
+
+impl ProfileSwitcher {
+    /// Follow a change of focus. Returns `false` once the lights have stopped.
+    fn update(&mut self, event: FocusEvent) -> bool {
+        match event {
+            FocusEvent::Workspace(workspace) => self.focus.workspace = Some(workspace),
+            FocusEvent::App(app) => self.focus.app = app,
+        }
+        let chosen = self.profiles.iter().position(|profile| profile.matches(&self.focus));
+        if self.applied == Some(chosen) {
+            return true;
+        }
+        let previous = self.applied.flatten().and_then(|index| self.profiles[index].intensity);
+        self.applied = Some(chosen);
+        let profile = chosen.map(|index| &self.profiles[index]);
+        log::info!("Focus moved to {:?}, {}", self.focus, match profile {
+            Some(profile) => format!("switching to {}", profile.scene.as_deref().unwrap_or("the effect")),
+            None => "going back to the effect".to_string(),
+        });
+        // Only the intensity is changed, so tuning done elsewhere, such as in the TUI,
+        // is kept.
+        let intensity = profile.and_then(|profile| profile.intensity).or(previous.map(|_| self.tuning.intensity));
+        let scene = profile.and_then(|profile| profile.scene.clone());
+        if self.commands.send(ControlCommand::Scene { name: scene }).is_err() {
+            return false;
+        }
+        intensity.is_none_or(|intensity| self.commands.send(ControlCommand::Tune(TuningChange { intensity: Some(intensity), ..TuningChange::default() })).is_ok())
+    }
+}
+
+/// Switch to the first profile matching the focused workspace and application, on
+/// Hyprland or Sway, and back to the effect when none match.
+pub fn watch(profiles: Vec<WorkspaceProfile>, tuning: Tuning, commands: Sender<ControlCommand>) -> Result<(), Box<dyn Error>> {
+    let sway_socket = env::var_os("SWAYSOCK").map(PathBuf::from);
+    if !hyprland::running() && sway_socket.is_none() {
+        return Err("Neither Hyprland nor Sway is running".into());
+    }
+    let mut switcher = ProfileSwitcher { profiles, tuning, commands, focus: Focus::default(), applied: None };
+    crash::spawn("workspaces", move || {
+        let followed = match sway_socket {
+            Some(path) if !hyprland::running() => follow_sway(path, |event| switcher.update(event)),
+            _ => follow_hyprland(|event| switcher.update(event)),
+        };
+        if let Err(err) = followed {
+            log::warn!("Stopped following workspaces: {}", err);
+        }
+    });
+    Ok(())
+}
+
+/// Pass Hyprland's focus changes to `on_event` until it returns `false`.
+fn follow_hyprland(mut on_event: impl FnMut(FocusEvent) -> bool) -> Result<(), Box<dyn Error>> {
+    let events = hyprland::events()?;
+    let (workspace, app) = hyprland::focus()?;
+    on_event(FocusEvent::Workspace(workspace));
+    on_event(FocusEvent::App(app));
+    for line in events.lines() {
+        if let Some(event) = parse_hyprland_event(&line?) {
+            if !on_event(event) {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a line from Hyprland's event socket, such as `workspace>>2` or
+/// `activewindow>>firefox,Mozilla Firefox`.
+fn parse_hyprland_event(line: &str) -> Option<FocusEvent> {
+    let (event, data) = line.split_once(">>")?;
+    match event {
+        "workspace" => Some(FocusEvent::Workspace(data.to_string())),
+        // The focused monitor changed, along with the workspace on it.
+        "focusedmon" => data.split_once(',').map(|(_, workspace)| FocusEvent::Workspace(workspace.to_string())),
+        "activewindow" => {
+            let class = data.split_once(',').map_or(data, |(class, _)| class);
+            Some(FocusEvent::App(Some(class.to_string()).filter(|class| !class.is_empty())))
+        },
+        _ => None,
+    }
+}
+
+fn sway_send(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = SWAY_MAGIC.to_vec();
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn sway_receive(stream: &mut UnixStream) -> Result<(u32, Value), Box<dyn Error>> {
+    let mut header = [0; 14];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != SWAY_MAGIC {
+        return Err("Unexpected message from Sway".into());
+    }
+    let length = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+    let kind = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+    let mut payload = vec![0; length as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((kind, serde_json::from_slice(&payload)?))
+}
+
+/// Pass Sway's focus changes to `on_event` until it returns `false`.
+fn follow_sway(path: PathBuf, mut on_event: impl FnMut(FocusEvent) -> bool) -> Result<(), Box<dyn Error>> {
+    let mut stream = UnixStream::connect(path)?;
+    sway_send(&mut stream, SWAY_GET_WORKSPACES, b"")?;
+    let (_, workspaces) = sway_receive(&mut stream)?;
+    let focused = workspaces.as_array().into_iter().flatten().find(|workspace| workspace["focused"] == true);
+    if let Some(name) = focused.and_then(|workspace| workspace["name"].as_str()) {
+        on_event(FocusEvent::Workspace(name.to_string()));
+    }
+    sway_send(&mut stream, SWAY_SUBSCRIBE, json!(["workspace", "window"]).to_string().as_bytes())?;
+    loop {
+        let (kind, payload) = sway_receive(&mut stream)?;
+        if let Some(event) = parse_sway_event(kind, &payload) {
+            if !on_event(event) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Read a workspace or window event from Sway, keeping only changes of focus.
+fn parse_sway_event(kind: u32, payload: &Value) -> Option<FocusEvent> {
+    if payload["change"] != "focus" {
+        return None;
+    }
+    match kind {
+        SWAY_WORKSPACE_EVENT => payload["current"]["name"].as_str().map(|name| FocusEvent::Workspace(name.to_string())),
+        SWAY_WINDOW_EVENT => {
+            let container = &payload["container"];
+            let app = container["app_id"].as_str().or(container["window_properties"]["class"].as_str());
+            Some(FocusEvent::App(app.map(str::to_string)))
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_switches_profiles() {
+        assert_eq!(parse_hyprland_event("workspace>>code"), Some(FocusEvent::Workspace("code".to_string())));
+        assert_eq!(parse_hyprland_event("focusedmon>>DP-1,3"), Some(FocusEvent::Workspace("3".to_string())));
+        assert_eq!(parse_hyprland_event("activewindow>>mpv,video.mkv - mpv"), Some(FocusEvent::App(Some("mpv".to_string()))));
+        assert_eq!(parse_hyprland_event("activewindow>>,"), Some(FocusEvent::App(None)));
+        assert_eq!(parse_hyprland_event("openwindow>>80a6f50,2,kitty,Kitty"), None);
+        let window = json!({"change": "focus", "container": {"app_id": null, "window_properties": {"class": "Steam"}}});
+        assert_eq!(parse_sway_event(SWAY_WINDOW_EVENT, &window), Some(FocusEvent::App(Some("Steam".to_string()))));
+
+        let (commands, received) = mpsc::channel();
+        let tuning = Tuning::default();
+        let mut switcher = ProfileSwitcher {
+            profiles: vec![
+                WorkspaceProfile { workspace: None, app: Some("mpv".to_string()), scene: None, intensity: Some(40.0) },
+                WorkspaceProfile { workspace: Some("code".to_string()), app: None, scene: Some("calm".to_string()), intensity: None },
+            ],
+            tuning,
+            commands,
+            focus: Focus::default(),
+            applied: None,
+        };
+        assert!(switcher.update(FocusEvent::Workspace("code".to_string())));
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), [ControlCommand::Scene { name: Some("calm".to_string()) }]);
+        // Moving between windows on the same profile changes nothing.
+        switcher.update(FocusEvent::App(Some("kitty".to_string())));
+        assert!(received.try_recv().is_err());
+        switcher.update(FocusEvent::App(Some("MPV".to_string())));
+        let intensity = |intensity| ControlCommand::Tune(TuningChange { intensity: Some(intensity), ..TuningChange::default() });
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), [ControlCommand::Scene { name: None }, intensity(40.0)]);
+        // Leaving a profile that changed the intensity puts it back.
+        switcher.update(FocusEvent::Workspace("web".to_string()));
+        switcher.update(FocusEvent::App(None));
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), [ControlCommand::Scene { name: None }, intensity(tuning.intensity)]);
+    }
+}